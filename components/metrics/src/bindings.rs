@@ -7,12 +7,535 @@ pub mod exports {
     pub mod toyota {
         pub mod metrics {
             /// Prometheus-compatible metrics collection
+            ///
+            /// Metrics are registered once with a name, help text and label names, then
+            /// updated through the generic `counter-inc`, `gauge-set`, `gauge-add` and
+            /// `histogram-observe` functions. The `record-*` functions below are kept as
+            /// wrappers around built-in metrics.
             #[allow(dead_code, async_fn_in_trait, unused_imports, clippy::all)]
             pub mod collector {
                 #[used]
                 #[doc(hidden)]
                 static __FORCE_SECTION_REF: fn() = super::super::super::super::__link_custom_section_describing_imports;
                 use super::super::super::super::_rt;
+                /// Kind of a registered metric
+                #[repr(u8)]
+                #[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+                pub enum MetricKind {
+                    /// Monotonically increasing value
+                    Counter,
+                    /// Value that can go up and down
+                    Gauge,
+                    /// Observations counted into buckets
+                    Histogram,
+                }
+                impl ::core::fmt::Debug for MetricKind {
+                    fn fmt(
+                        &self,
+                        f: &mut ::core::fmt::Formatter<'_>,
+                    ) -> ::core::fmt::Result {
+                        match self {
+                            MetricKind::Counter => {
+                                f.debug_tuple("MetricKind::Counter").finish()
+                            }
+                            MetricKind::Gauge => {
+                                f.debug_tuple("MetricKind::Gauge").finish()
+                            }
+                            MetricKind::Histogram => {
+                                f.debug_tuple("MetricKind::Histogram").finish()
+                            }
+                        }
+                    }
+                }
+                impl MetricKind {
+                    #[doc(hidden)]
+                    pub unsafe fn _lift(val: u8) -> MetricKind {
+                        if !cfg!(debug_assertions) {
+                            return ::core::mem::transmute(val);
+                        }
+                        match val {
+                            0 => MetricKind::Counter,
+                            1 => MetricKind::Gauge,
+                            2 => MetricKind::Histogram,
+                            _ => panic!("invalid enum discriminant"),
+                        }
+                    }
+                }
+                /// Definition of a metric
+                #[derive(Clone)]
+                pub struct MetricDefinition {
+                    /// Metric name, `[a-zA-Z_:][a-zA-Z0-9_:]*`
+                    pub name: _rt::String,
+                    pub kind: MetricKind,
+                    /// Help text shown in the exposition
+                    pub help: _rt::String,
+                    /// Label names every sample must carry, `[a-zA-Z_][a-zA-Z0-9_]*`
+                    pub label_names: _rt::Vec<_rt::String>,
+                    /// Histogram bucket upper bounds in increasing order; empty uses the
+                    /// default latency buckets. Must be empty for counters and gauges.
+                    pub buckets: _rt::Vec<f64>,
+                }
+                impl ::core::fmt::Debug for MetricDefinition {
+                    fn fmt(
+                        &self,
+                        f: &mut ::core::fmt::Formatter<'_>,
+                    ) -> ::core::fmt::Result {
+                        f.debug_struct("MetricDefinition")
+                            .field("name", &self.name)
+                            .field("kind", &self.kind)
+                            .field("help", &self.help)
+                            .field("label-names", &self.label_names)
+                            .field("buckets", &self.buckets)
+                            .finish()
+                    }
+                }
+                /// Label attached to a sample
+                #[derive(Clone)]
+                pub struct Label {
+                    pub name: _rt::String,
+                    pub value: _rt::String,
+                }
+                impl ::core::fmt::Debug for Label {
+                    fn fmt(
+                        &self,
+                        f: &mut ::core::fmt::Formatter<'_>,
+                    ) -> ::core::fmt::Result {
+                        f.debug_struct("Label")
+                            .field("name", &self.name)
+                            .field("value", &self.value)
+                            .finish()
+                    }
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_register_cabi<T: Guest>(
+                    arg0: *mut u8,
+                    arg1: usize,
+                    arg2: i32,
+                    arg3: *mut u8,
+                    arg4: usize,
+                    arg5: *mut u8,
+                    arg6: usize,
+                    arg7: *mut u8,
+                    arg8: usize,
+                ) -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let len0 = arg1;
+                    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+                    let len1 = arg4;
+                    let bytes1 = _rt::Vec::from_raw_parts(arg3.cast(), len1, len1);
+                    let base5 = arg5;
+                    let len5 = arg6;
+                    let mut result5 = _rt::Vec::with_capacity(len5);
+                    for i in 0..len5 {
+                        let base = base5
+                            .add(i * (2 * ::core::mem::size_of::<*const u8>()));
+                        let e5 = {
+                            let l2 = *base.add(0).cast::<*mut u8>();
+                            let l3 = *base
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len4 = l3;
+                            let bytes4 = _rt::Vec::from_raw_parts(l2.cast(), len4, len4);
+                            _rt::string_lift(bytes4)
+                        };
+                        result5.push(e5);
+                    }
+                    _rt::cabi_dealloc(
+                        base5,
+                        len5 * (2 * ::core::mem::size_of::<*const u8>()),
+                        ::core::mem::size_of::<*const u8>(),
+                    );
+                    let len6 = arg8;
+                    let result7 = T::register(MetricDefinition {
+                        name: _rt::string_lift(bytes0),
+                        kind: MetricKind::_lift(arg2 as u8),
+                        help: _rt::string_lift(bytes1),
+                        label_names: result5,
+                        buckets: _rt::Vec::from_raw_parts(arg7.cast(), len6, len6),
+                    });
+                    let ptr8 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    match result7 {
+                        Ok(_) => {
+                            *ptr8.add(0).cast::<u8>() = (0i32) as u8;
+                        }
+                        Err(e) => {
+                            *ptr8.add(0).cast::<u8>() = (1i32) as u8;
+                            let vec9 = (e.into_bytes()).into_boxed_slice();
+                            let ptr9 = vec9.as_ptr().cast::<u8>();
+                            let len9 = vec9.len();
+                            ::core::mem::forget(vec9);
+                            *ptr8
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>() = len9;
+                            *ptr8
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>() = ptr9.cast_mut();
+                        }
+                    };
+                    ptr8
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_register<T: Guest>(arg0: *mut u8) {
+                    let l0 = i32::from(*arg0.add(0).cast::<u8>());
+                    match l0 {
+                        0 => {}
+                        _ => {
+                            let l1 = *arg0
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l2 = *arg0
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            _rt::cabi_dealloc(l1, l2, 1);
+                        }
+                    }
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_counter_inc_cabi<T: Guest>(
+                    arg0: *mut u8,
+                    arg1: usize,
+                    arg2: *mut u8,
+                    arg3: usize,
+                    arg4: f64,
+                ) -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let len0 = arg1;
+                    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+                    let base7 = arg2;
+                    let len7 = arg3;
+                    let mut result7 = _rt::Vec::with_capacity(len7);
+                    for i in 0..len7 {
+                        let base = base7
+                            .add(i * (4 * ::core::mem::size_of::<*const u8>()));
+                        let e7 = {
+                            let l1 = *base.add(0).cast::<*mut u8>();
+                            let l2 = *base
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len3 = l2;
+                            let bytes3 = _rt::Vec::from_raw_parts(l1.cast(), len3, len3);
+                            let l4 = *base
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l5 = *base
+                                .add(3 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len6 = l5;
+                            let bytes6 = _rt::Vec::from_raw_parts(l4.cast(), len6, len6);
+                            Label {
+                                name: _rt::string_lift(bytes3),
+                                value: _rt::string_lift(bytes6),
+                            }
+                        };
+                        result7.push(e7);
+                    }
+                    _rt::cabi_dealloc(
+                        base7,
+                        len7 * (4 * ::core::mem::size_of::<*const u8>()),
+                        ::core::mem::size_of::<*const u8>(),
+                    );
+                    let result8 = T::counter_inc(
+                        _rt::string_lift(bytes0),
+                        result7,
+                        arg4,
+                    );
+                    let ptr9 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    match result8 {
+                        Ok(_) => {
+                            *ptr9.add(0).cast::<u8>() = (0i32) as u8;
+                        }
+                        Err(e) => {
+                            *ptr9.add(0).cast::<u8>() = (1i32) as u8;
+                            let vec10 = (e.into_bytes()).into_boxed_slice();
+                            let ptr10 = vec10.as_ptr().cast::<u8>();
+                            let len10 = vec10.len();
+                            ::core::mem::forget(vec10);
+                            *ptr9
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>() = len10;
+                            *ptr9
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>() = ptr10.cast_mut();
+                        }
+                    };
+                    ptr9
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_counter_inc<T: Guest>(arg0: *mut u8) {
+                    let l0 = i32::from(*arg0.add(0).cast::<u8>());
+                    match l0 {
+                        0 => {}
+                        _ => {
+                            let l1 = *arg0
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l2 = *arg0
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            _rt::cabi_dealloc(l1, l2, 1);
+                        }
+                    }
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_gauge_set_cabi<T: Guest>(
+                    arg0: *mut u8,
+                    arg1: usize,
+                    arg2: *mut u8,
+                    arg3: usize,
+                    arg4: f64,
+                ) -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let len0 = arg1;
+                    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+                    let base7 = arg2;
+                    let len7 = arg3;
+                    let mut result7 = _rt::Vec::with_capacity(len7);
+                    for i in 0..len7 {
+                        let base = base7
+                            .add(i * (4 * ::core::mem::size_of::<*const u8>()));
+                        let e7 = {
+                            let l1 = *base.add(0).cast::<*mut u8>();
+                            let l2 = *base
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len3 = l2;
+                            let bytes3 = _rt::Vec::from_raw_parts(l1.cast(), len3, len3);
+                            let l4 = *base
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l5 = *base
+                                .add(3 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len6 = l5;
+                            let bytes6 = _rt::Vec::from_raw_parts(l4.cast(), len6, len6);
+                            Label {
+                                name: _rt::string_lift(bytes3),
+                                value: _rt::string_lift(bytes6),
+                            }
+                        };
+                        result7.push(e7);
+                    }
+                    _rt::cabi_dealloc(
+                        base7,
+                        len7 * (4 * ::core::mem::size_of::<*const u8>()),
+                        ::core::mem::size_of::<*const u8>(),
+                    );
+                    let result8 = T::gauge_set(_rt::string_lift(bytes0), result7, arg4);
+                    let ptr9 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    match result8 {
+                        Ok(_) => {
+                            *ptr9.add(0).cast::<u8>() = (0i32) as u8;
+                        }
+                        Err(e) => {
+                            *ptr9.add(0).cast::<u8>() = (1i32) as u8;
+                            let vec10 = (e.into_bytes()).into_boxed_slice();
+                            let ptr10 = vec10.as_ptr().cast::<u8>();
+                            let len10 = vec10.len();
+                            ::core::mem::forget(vec10);
+                            *ptr9
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>() = len10;
+                            *ptr9
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>() = ptr10.cast_mut();
+                        }
+                    };
+                    ptr9
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_gauge_set<T: Guest>(arg0: *mut u8) {
+                    let l0 = i32::from(*arg0.add(0).cast::<u8>());
+                    match l0 {
+                        0 => {}
+                        _ => {
+                            let l1 = *arg0
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l2 = *arg0
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            _rt::cabi_dealloc(l1, l2, 1);
+                        }
+                    }
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_gauge_add_cabi<T: Guest>(
+                    arg0: *mut u8,
+                    arg1: usize,
+                    arg2: *mut u8,
+                    arg3: usize,
+                    arg4: f64,
+                ) -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let len0 = arg1;
+                    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+                    let base7 = arg2;
+                    let len7 = arg3;
+                    let mut result7 = _rt::Vec::with_capacity(len7);
+                    for i in 0..len7 {
+                        let base = base7
+                            .add(i * (4 * ::core::mem::size_of::<*const u8>()));
+                        let e7 = {
+                            let l1 = *base.add(0).cast::<*mut u8>();
+                            let l2 = *base
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len3 = l2;
+                            let bytes3 = _rt::Vec::from_raw_parts(l1.cast(), len3, len3);
+                            let l4 = *base
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l5 = *base
+                                .add(3 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len6 = l5;
+                            let bytes6 = _rt::Vec::from_raw_parts(l4.cast(), len6, len6);
+                            Label {
+                                name: _rt::string_lift(bytes3),
+                                value: _rt::string_lift(bytes6),
+                            }
+                        };
+                        result7.push(e7);
+                    }
+                    _rt::cabi_dealloc(
+                        base7,
+                        len7 * (4 * ::core::mem::size_of::<*const u8>()),
+                        ::core::mem::size_of::<*const u8>(),
+                    );
+                    let result8 = T::gauge_add(_rt::string_lift(bytes0), result7, arg4);
+                    let ptr9 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    match result8 {
+                        Ok(_) => {
+                            *ptr9.add(0).cast::<u8>() = (0i32) as u8;
+                        }
+                        Err(e) => {
+                            *ptr9.add(0).cast::<u8>() = (1i32) as u8;
+                            let vec10 = (e.into_bytes()).into_boxed_slice();
+                            let ptr10 = vec10.as_ptr().cast::<u8>();
+                            let len10 = vec10.len();
+                            ::core::mem::forget(vec10);
+                            *ptr9
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>() = len10;
+                            *ptr9
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>() = ptr10.cast_mut();
+                        }
+                    };
+                    ptr9
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_gauge_add<T: Guest>(arg0: *mut u8) {
+                    let l0 = i32::from(*arg0.add(0).cast::<u8>());
+                    match l0 {
+                        0 => {}
+                        _ => {
+                            let l1 = *arg0
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l2 = *arg0
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            _rt::cabi_dealloc(l1, l2, 1);
+                        }
+                    }
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_histogram_observe_cabi<T: Guest>(
+                    arg0: *mut u8,
+                    arg1: usize,
+                    arg2: *mut u8,
+                    arg3: usize,
+                    arg4: f64,
+                ) -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let len0 = arg1;
+                    let bytes0 = _rt::Vec::from_raw_parts(arg0.cast(), len0, len0);
+                    let base7 = arg2;
+                    let len7 = arg3;
+                    let mut result7 = _rt::Vec::with_capacity(len7);
+                    for i in 0..len7 {
+                        let base = base7
+                            .add(i * (4 * ::core::mem::size_of::<*const u8>()));
+                        let e7 = {
+                            let l1 = *base.add(0).cast::<*mut u8>();
+                            let l2 = *base
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len3 = l2;
+                            let bytes3 = _rt::Vec::from_raw_parts(l1.cast(), len3, len3);
+                            let l4 = *base
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l5 = *base
+                                .add(3 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            let len6 = l5;
+                            let bytes6 = _rt::Vec::from_raw_parts(l4.cast(), len6, len6);
+                            Label {
+                                name: _rt::string_lift(bytes3),
+                                value: _rt::string_lift(bytes6),
+                            }
+                        };
+                        result7.push(e7);
+                    }
+                    _rt::cabi_dealloc(
+                        base7,
+                        len7 * (4 * ::core::mem::size_of::<*const u8>()),
+                        ::core::mem::size_of::<*const u8>(),
+                    );
+                    let result8 = T::histogram_observe(
+                        _rt::string_lift(bytes0),
+                        result7,
+                        arg4,
+                    );
+                    let ptr9 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    match result8 {
+                        Ok(_) => {
+                            *ptr9.add(0).cast::<u8>() = (0i32) as u8;
+                        }
+                        Err(e) => {
+                            *ptr9.add(0).cast::<u8>() = (1i32) as u8;
+                            let vec10 = (e.into_bytes()).into_boxed_slice();
+                            let ptr10 = vec10.as_ptr().cast::<u8>();
+                            let len10 = vec10.len();
+                            ::core::mem::forget(vec10);
+                            *ptr9
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>() = len10;
+                            *ptr9
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>() = ptr10.cast_mut();
+                        }
+                    };
+                    ptr9
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_histogram_observe<T: Guest>(arg0: *mut u8) {
+                    let l0 = i32::from(*arg0.add(0).cast::<u8>());
+                    match l0 {
+                        0 => {}
+                        _ => {
+                            let l1 = *arg0
+                                .add(::core::mem::size_of::<*const u8>())
+                                .cast::<*mut u8>();
+                            let l2 = *arg0
+                                .add(2 * ::core::mem::size_of::<*const u8>())
+                                .cast::<usize>();
+                            _rt::cabi_dealloc(l1, l2, 1);
+                        }
+                    }
+                }
                 #[doc(hidden)]
                 #[allow(non_snake_case)]
                 pub unsafe fn _export_record_request_cabi<T: Guest>(
@@ -124,7 +647,57 @@ pub mod exports {
                         .cast::<usize>();
                     _rt::cabi_dealloc(l0, l1, 1);
                 }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn _export_export_openmetrics_cabi<T: Guest>() -> *mut u8 {
+                    #[cfg(target_arch = "wasm32")] _rt::run_ctors_once();
+                    let result0 = T::export_openmetrics();
+                    let ptr1 = (&raw mut _RET_AREA.0).cast::<u8>();
+                    let vec2 = (result0.into_bytes()).into_boxed_slice();
+                    let ptr2 = vec2.as_ptr().cast::<u8>();
+                    let len2 = vec2.len();
+                    ::core::mem::forget(vec2);
+                    *ptr1.add(::core::mem::size_of::<*const u8>()).cast::<usize>() = len2;
+                    *ptr1.add(0).cast::<*mut u8>() = ptr2.cast_mut();
+                    ptr1
+                }
+                #[doc(hidden)]
+                #[allow(non_snake_case)]
+                pub unsafe fn __post_return_export_openmetrics<T: Guest>(arg0: *mut u8) {
+                    let l0 = *arg0.add(0).cast::<*mut u8>();
+                    let l1 = *arg0
+                        .add(::core::mem::size_of::<*const u8>())
+                        .cast::<usize>();
+                    _rt::cabi_dealloc(l0, l1, 1);
+                }
                 pub trait Guest {
+                    /// Register a metric. Registering the same definition again is a no-op;
+                    /// a different definition under an existing name is an error.
+                    fn register(definition: MetricDefinition) -> Result<(), _rt::String>;
+                    /// Increase a counter by a non-negative value
+                    fn counter_inc(
+                        name: _rt::String,
+                        labels: _rt::Vec<Label>,
+                        value: f64,
+                    ) -> Result<(), _rt::String>;
+                    /// Set a gauge
+                    fn gauge_set(
+                        name: _rt::String,
+                        labels: _rt::Vec<Label>,
+                        value: f64,
+                    ) -> Result<(), _rt::String>;
+                    /// Add a (possibly negative) delta to a gauge
+                    fn gauge_add(
+                        name: _rt::String,
+                        labels: _rt::Vec<Label>,
+                        delta: f64,
+                    ) -> Result<(), _rt::String>;
+                    /// Record an observation in a histogram
+                    fn histogram_observe(
+                        name: _rt::String,
+                        labels: _rt::Vec<Label>,
+                        value: f64,
+                    ) -> Result<(), _rt::String>;
                     /// Record HTTP request to endpoint
                     fn record_request(endpoint: _rt::String) -> ();
                     /// Record error for endpoint
@@ -153,19 +726,70 @@ pub mod exports {
                     fn record_retry_exhausted() -> ();
                     /// Export metrics in Prometheus text format
                     fn export_prometheus() -> _rt::String;
+                    /// Export metrics in OpenMetrics text format
+                    fn export_openmetrics() -> _rt::String;
                 }
                 #[doc(hidden)]
                 macro_rules! __export_toyota_metrics_collector_0_1_0_cabi {
                     ($ty:ident with_types_in $($path_to_types:tt)*) => {
                         const _ : () = { #[unsafe (export_name =
-                        "toyota:metrics/collector@0.1.0#record-request")] unsafe extern
-                        "C" fn export_record_request(arg0 : * mut u8, arg1 : usize,) {
-                        unsafe { $($path_to_types)*:: _export_record_request_cabi::<$ty >
-                        (arg0, arg1) } } #[unsafe (export_name =
-                        "toyota:metrics/collector@0.1.0#record-error")] unsafe extern "C"
-                        fn export_record_error(arg0 : * mut u8, arg1 : usize,) { unsafe {
-                        $($path_to_types)*:: _export_record_error_cabi::<$ty > (arg0,
-                        arg1) } } #[unsafe (export_name =
+                        "toyota:metrics/collector@0.1.0#register")] unsafe extern "C" fn
+                        export_register(arg0 : * mut u8, arg1 : usize, arg2 : i32, arg3 :
+                        * mut u8, arg4 : usize, arg5 : * mut u8, arg6 : usize, arg7 : *
+                        mut u8, arg8 : usize,) -> * mut u8 { unsafe {
+                        $($path_to_types)*:: _export_register_cabi::<$ty > (arg0, arg1,
+                        arg2, arg3, arg4, arg5, arg6, arg7, arg8) } } #[unsafe
+                        (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#register")] unsafe
+                        extern "C" fn _post_return_register(arg0 : * mut u8,) { unsafe {
+                        $($path_to_types)*:: __post_return_register::<$ty > (arg0) } }
+                        #[unsafe (export_name =
+                        "toyota:metrics/collector@0.1.0#counter-inc")] unsafe extern "C"
+                        fn export_counter_inc(arg0 : * mut u8, arg1 : usize, arg2 : * mut
+                        u8, arg3 : usize, arg4 : f64,) -> * mut u8 { unsafe {
+                        $($path_to_types)*:: _export_counter_inc_cabi::<$ty > (arg0,
+                        arg1, arg2, arg3, arg4) } } #[unsafe (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#counter-inc")] unsafe
+                        extern "C" fn _post_return_counter_inc(arg0 : * mut u8,) { unsafe
+                        { $($path_to_types)*:: __post_return_counter_inc::<$ty > (arg0) }
+                        } #[unsafe (export_name =
+                        "toyota:metrics/collector@0.1.0#gauge-set")] unsafe extern "C" fn
+                        export_gauge_set(arg0 : * mut u8, arg1 : usize, arg2 : * mut u8,
+                        arg3 : usize, arg4 : f64,) -> * mut u8 { unsafe {
+                        $($path_to_types)*:: _export_gauge_set_cabi::<$ty > (arg0, arg1,
+                        arg2, arg3, arg4) } } #[unsafe (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#gauge-set")] unsafe
+                        extern "C" fn _post_return_gauge_set(arg0 : * mut u8,) { unsafe {
+                        $($path_to_types)*:: __post_return_gauge_set::<$ty > (arg0) } }
+                        #[unsafe (export_name =
+                        "toyota:metrics/collector@0.1.0#gauge-add")] unsafe extern "C" fn
+                        export_gauge_add(arg0 : * mut u8, arg1 : usize, arg2 : * mut u8,
+                        arg3 : usize, arg4 : f64,) -> * mut u8 { unsafe {
+                        $($path_to_types)*:: _export_gauge_add_cabi::<$ty > (arg0, arg1,
+                        arg2, arg3, arg4) } } #[unsafe (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#gauge-add")] unsafe
+                        extern "C" fn _post_return_gauge_add(arg0 : * mut u8,) { unsafe {
+                        $($path_to_types)*:: __post_return_gauge_add::<$ty > (arg0) } }
+                        #[unsafe (export_name =
+                        "toyota:metrics/collector@0.1.0#histogram-observe")] unsafe
+                        extern "C" fn export_histogram_observe(arg0 : * mut u8, arg1 :
+                        usize, arg2 : * mut u8, arg3 : usize, arg4 : f64,) -> * mut u8 {
+                        unsafe { $($path_to_types)*::
+                        _export_histogram_observe_cabi::<$ty > (arg0, arg1, arg2, arg3,
+                        arg4) } } #[unsafe (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#histogram-observe")]
+                        unsafe extern "C" fn _post_return_histogram_observe(arg0 : * mut
+                        u8,) { unsafe { $($path_to_types)*::
+                        __post_return_histogram_observe::<$ty > (arg0) } } #[unsafe
+                        (export_name = "toyota:metrics/collector@0.1.0#record-request")]
+                        unsafe extern "C" fn export_record_request(arg0 : * mut u8, arg1
+                        : usize,) { unsafe { $($path_to_types)*::
+                        _export_record_request_cabi::<$ty > (arg0, arg1) } } #[unsafe
+                        (export_name = "toyota:metrics/collector@0.1.0#record-error")]
+                        unsafe extern "C" fn export_record_error(arg0 : * mut u8, arg1 :
+                        usize,) { unsafe { $($path_to_types)*::
+                        _export_record_error_cabi::<$ty > (arg0, arg1) } } #[unsafe
+                        (export_name =
                         "toyota:metrics/collector@0.1.0#record-cache-hit")] unsafe extern
                         "C" fn export_record_cache_hit() { unsafe { $($path_to_types)*::
                         _export_record_cache_hit_cabi::<$ty > () } } #[unsafe
@@ -220,7 +844,16 @@ pub mod exports {
                         "cabi_post_toyota:metrics/collector@0.1.0#export-prometheus")]
                         unsafe extern "C" fn _post_return_export_prometheus(arg0 : * mut
                         u8,) { unsafe { $($path_to_types)*::
-                        __post_return_export_prometheus::<$ty > (arg0) } } };
+                        __post_return_export_prometheus::<$ty > (arg0) } } #[unsafe
+                        (export_name =
+                        "toyota:metrics/collector@0.1.0#export-openmetrics")] unsafe
+                        extern "C" fn export_export_openmetrics() -> * mut u8 { unsafe {
+                        $($path_to_types)*:: _export_export_openmetrics_cabi::<$ty > () }
+                        } #[unsafe (export_name =
+                        "cabi_post_toyota:metrics/collector@0.1.0#export-openmetrics")]
+                        unsafe extern "C" fn _post_return_export_openmetrics(arg0 : * mut
+                        u8,) { unsafe { $($path_to_types)*::
+                        __post_return_export_openmetrics::<$ty > (arg0) } } };
                     };
                 }
                 #[doc(hidden)]
@@ -230,10 +863,10 @@ pub mod exports {
                 struct _RetArea(
                     [::core::mem::MaybeUninit<
                         u8,
-                    >; 2 * ::core::mem::size_of::<*const u8>()],
+                    >; 3 * ::core::mem::size_of::<*const u8>()],
                 );
                 static mut _RET_AREA: _RetArea = _RetArea(
-                    [::core::mem::MaybeUninit::uninit(); 2
+                    [::core::mem::MaybeUninit::uninit(); 3
                         * ::core::mem::size_of::<*const u8>()],
                 );
             }
@@ -243,11 +876,12 @@ pub mod exports {
 #[rustfmt::skip]
 mod _rt {
     #![allow(dead_code, clippy::all)]
+    pub use alloc_crate::string::String;
+    pub use alloc_crate::vec::Vec;
     #[cfg(target_arch = "wasm32")]
     pub fn run_ctors_once() {
         wit_bindgen_rt::run_ctors_once();
     }
-    pub use alloc_crate::vec::Vec;
     pub unsafe fn string_lift(bytes: Vec<u8>) -> String {
         if cfg!(debug_assertions) {
             String::from_utf8(bytes).unwrap()
@@ -255,7 +889,6 @@ mod _rt {
             String::from_utf8_unchecked(bytes)
         }
     }
-    pub use alloc_crate::string::String;
     pub unsafe fn cabi_dealloc(ptr: *mut u8, size: usize, align: usize) {
         if size == 0 {
             return;
@@ -300,19 +933,26 @@ pub(crate) use __export_metrics_impl as export;
 #[unsafe(link_section = "component-type:wit-bindgen:0.41.0:toyota:metrics@0.1.0:metrics:encoded world")]
 #[doc(hidden)]
 #[allow(clippy::octal_escapes)]
-pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 574] = *b"\
-\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\xc0\x03\x01A\x02\x01\
-A\x02\x01B\x11\x01@\x01\x08endpoints\x01\0\x04\0\x0erecord-request\x01\0\x04\0\x0c\
-record-error\x01\0\x01@\0\x01\0\x04\0\x10record-cache-hit\x01\x01\x04\0\x11recor\
-d-cache-miss\x01\x01\x04\0\x14record-login-attempt\x01\x01\x04\0\x14record-login\
--failure\x01\x01\x04\0\x19increment-active-sessions\x01\x01\x04\0\x19decrement-a\
-ctive-sessions\x01\x01\x04\0\x15record-rate-limit-hit\x01\x01\x04\0\x1brecord-ci\
-rcuit-breaker-open\x01\x01\x04\0\x14record-retry-attempt\x01\x01\x04\0\x14record\
--retry-success\x01\x01\x04\0\x16record-retry-exhausted\x01\x01\x01@\0\0s\x04\0\x11\
-export-prometheus\x01\x02\x04\0\x1etoyota:metrics/collector@0.1.0\x05\0\x04\0\x1c\
-toyota:metrics/metrics@0.1.0\x04\0\x0b\x0d\x01\0\x07metrics\x03\0\0\0G\x09produc\
-ers\x01\x0cprocessed-by\x02\x0dwit-component\x070.227.1\x10wit-bindgen-rust\x060\
-.41.0";
+pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 896] = *b"\
+\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\x82\x06\x01A\x02\x01\
+A\x02\x01B$\x01m\x03\x07counter\x05gauge\x09histogram\x04\0\x0bmetric-kind\x03\0\
+\0\x01ps\x01pu\x01r\x05\x04names\x04kind\x01\x04helps\x0blabel-names\x02\x07buck\
+ets\x03\x04\0\x11metric-definition\x03\0\x04\x01r\x02\x04names\x05values\x04\0\x05\
+label\x03\0\x06\x01j\0\x01s\x01@\x01\x0adefinition\x05\0\x08\x04\0\x08register\x01\
+\x09\x01p\x07\x01@\x03\x04names\x06labels\x0a\x05valueu\0\x08\x04\0\x0bcounter-i\
+nc\x01\x0b\x04\0\x09gauge-set\x01\x0b\x01@\x03\x04names\x06labels\x0a\x05deltau\0\
+\x08\x04\0\x09gauge-add\x01\x0c\x04\0\x11histogram-observe\x01\x0b\x01@\x01\x08e\
+ndpoints\x01\0\x04\0\x0erecord-request\x01\x0d\x04\0\x0crecord-error\x01\x0d\x01\
+@\0\x01\0\x04\0\x10record-cache-hit\x01\x0e\x04\0\x11record-cache-miss\x01\x0e\x04\
+\0\x14record-login-attempt\x01\x0e\x04\0\x14record-login-failure\x01\x0e\x04\0\x19\
+increment-active-sessions\x01\x0e\x04\0\x19decrement-active-sessions\x01\x0e\x04\
+\0\x15record-rate-limit-hit\x01\x0e\x04\0\x1brecord-circuit-breaker-open\x01\x0e\
+\x04\0\x14record-retry-attempt\x01\x0e\x04\0\x14record-retry-success\x01\x0e\x04\
+\0\x16record-retry-exhausted\x01\x0e\x01@\0\0s\x04\0\x11export-prometheus\x01\x0f\
+\x04\0\x12export-openmetrics\x01\x0f\x04\0\x1etoyota:metrics/collector@0.1.0\x05\
+\0\x04\0\x1ctoyota:metrics/metrics@0.1.0\x04\0\x0b\x0d\x01\0\x07metrics\x03\0\0\0\
+G\x09producers\x01\x0cprocessed-by\x02\x0dwit-component\x070.227.1\x10wit-bindge\
+n-rust\x060.41.0";
 #[inline(never)]
 #[doc(hidden)]
 pub fn __link_custom_section_describing_imports() {
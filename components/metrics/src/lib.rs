@@ -1,241 +1,788 @@
 // Prometheus-compatible metrics for monitoring
 //
-// Provides basic request metrics, cache statistics, and error tracking
-// in Prometheus text format for integration with monitoring systems.
+// A registry of counters, gauges and histograms with help text and label
+// sets, exposed in Prometheus and OpenMetrics text formats. The fixed
+// `record-*` functions of the original interface are thin wrappers that
+// update built-in metrics in the same registry.
 
 #[allow(warnings)]
 mod bindings;
 
-use bindings::exports::toyota::metrics::collector::Guest;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU64, Ordering};
+use bindings::exports::toyota::metrics::collector::{Guest, Label, MetricDefinition, MetricKind};
+use std::collections::BTreeMap;
 use std::sync::{Mutex, OnceLock};
 
 struct Component;
 
-/// Global metrics collector
-struct Metrics {
-    // Request counters
-    total_requests: AtomicU64,
-    total_errors: AtomicU64,
+/// Histogram bucket upper bounds in seconds, used when a definition has none
+const DEFAULT_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Distinct label sets kept per metric, so unbounded label values cannot
+/// exhaust memory
+const MAX_SERIES_PER_METRIC: usize = 1000;
+
+// Built-in metrics behind the `record-*` wrappers
+const REQUESTS: &str = "myt2abrp_requests_total";
+const ERRORS: &str = "myt2abrp_errors_total";
+const ENDPOINT_REQUESTS: &str = "myt2abrp_endpoint_requests_total";
+const ENDPOINT_ERRORS: &str = "myt2abrp_endpoint_errors_total";
+const CACHE_HITS: &str = "myt2abrp_cache_hits_total";
+const CACHE_MISSES: &str = "myt2abrp_cache_misses_total";
+const LOGIN_ATTEMPTS: &str = "myt2abrp_login_attempts_total";
+const LOGIN_FAILURES: &str = "myt2abrp_login_failures_total";
+const ACTIVE_SESSIONS: &str = "myt2abrp_active_sessions";
+const RATE_LIMIT_HITS: &str = "myt2abrp_rate_limit_hits_total";
+const CIRCUIT_BREAKER_OPENS: &str = "myt2abrp_circuit_breaker_opens_total";
+const RETRY_ATTEMPTS: &str = "myt2abrp_retry_attempts_total";
+const RETRY_SUCCESSES: &str = "myt2abrp_retry_successes_total";
+const RETRY_EXHAUSTED: &str = "myt2abrp_retry_exhausted_total";
+
+const BUILTINS: [(&str, MetricKind, &str, Option<&str>); 14] = [
+    (
+        REQUESTS,
+        MetricKind::Counter,
+        "Total number of HTTP requests",
+        None,
+    ),
+    (ERRORS, MetricKind::Counter, "Total number of errors", None),
+    (
+        ENDPOINT_REQUESTS,
+        MetricKind::Counter,
+        "Requests per endpoint",
+        Some("endpoint"),
+    ),
+    (
+        ENDPOINT_ERRORS,
+        MetricKind::Counter,
+        "Errors per endpoint",
+        Some("endpoint"),
+    ),
+    (CACHE_HITS, MetricKind::Counter, "Total cache hits", None),
+    (
+        CACHE_MISSES,
+        MetricKind::Counter,
+        "Total cache misses",
+        None,
+    ),
+    (
+        LOGIN_ATTEMPTS,
+        MetricKind::Counter,
+        "Total login attempts",
+        None,
+    ),
+    (
+        LOGIN_FAILURES,
+        MetricKind::Counter,
+        "Failed login attempts",
+        None,
+    ),
+    (
+        ACTIVE_SESSIONS,
+        MetricKind::Gauge,
+        "Current active sessions",
+        None,
+    ),
+    (
+        RATE_LIMIT_HITS,
+        MetricKind::Counter,
+        "Requests rejected by rate limiting",
+        None,
+    ),
+    (
+        CIRCUIT_BREAKER_OPENS,
+        MetricKind::Counter,
+        "Circuit breaker open events",
+        None,
+    ),
+    (
+        RETRY_ATTEMPTS,
+        MetricKind::Counter,
+        "Total retry attempts",
+        None,
+    ),
+    (
+        RETRY_SUCCESSES,
+        MetricKind::Counter,
+        "Requests that succeeded after retry",
+        None,
+    ),
+    (
+        RETRY_EXHAUSTED,
+        MetricKind::Counter,
+        "Requests that failed after all retries",
+        None,
+    ),
+];
+
+/// Registered shape of a metric
+#[derive(Debug, Clone, PartialEq)]
+struct Definition {
+    kind: MetricKind,
+    help: String,
+    label_names: Vec<String>,
+    buckets: Vec<f64>,
+}
 
-    // Per-endpoint counters (protected by mutex due to HashMap)
-    endpoint_requests: Mutex<HashMap<String, u64>>,
-    endpoint_errors: Mutex<HashMap<String, u64>>,
+/// Current value of one label set
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Scalar(f64),
+    Histogram {
+        /// Observations per bucket (not cumulative)
+        buckets: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
 
-    // Cache statistics
-    cache_hits: AtomicU64,
-    cache_misses: AtomicU64,
+impl Value {
+    fn new(definition: &Definition) -> Self {
+        match definition.kind {
+            MetricKind::Histogram => Value::Histogram {
+                buckets: vec![0; definition.buckets.len()],
+                sum: 0.0,
+                count: 0,
+            },
+            MetricKind::Counter | MetricKind::Gauge => Value::Scalar(0.0),
+        }
+    }
+}
 
-    // Authentication metrics
-    login_attempts: AtomicU64,
-    login_failures: AtomicU64,
-    active_sessions: AtomicU64,
+/// A metric and its series, keyed by label values in `label_names` order
+#[derive(Debug, Clone)]
+struct Family {
+    definition: Definition,
+    series: BTreeMap<Vec<String>, Value>,
+}
 
-    // Rate limiting
-    rate_limit_hits: AtomicU64,
+/// Text exposition format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Prometheus,
+    OpenMetrics,
+}
 
-    // Circuit breaker
-    circuit_breaker_opens: AtomicU64,
+/// Metric names follow `[a-zA-Z_:][a-zA-Z0-9_:]*`
+fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Label names follow `[a-zA-Z_][a-zA-Z0-9_]*`; `__` is reserved
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
 
-    // Retry logic
-    retry_attempts: AtomicU64,
-    retry_successes: AtomicU64,
-    retry_exhausted: AtomicU64,
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus escapes backslash and newline in help text, OpenMetrics also
+/// escapes double quotes
+fn escape_help(help: &str, format: Format) -> String {
+    let escaped = help.replace('\\', "\\\\").replace('\n', "\\n");
+    match format {
+        Format::Prometheus => escaped,
+        Format::OpenMetrics => escaped.replace('"', "\\\""),
+    }
 }
 
-impl Metrics {
-    fn new() -> Self {
-        Metrics {
-            total_requests: AtomicU64::new(0),
-            total_errors: AtomicU64::new(0),
-            endpoint_requests: Mutex::new(HashMap::new()),
-            endpoint_errors: Mutex::new(HashMap::new()),
-            cache_hits: AtomicU64::new(0),
-            cache_misses: AtomicU64::new(0),
-            login_attempts: AtomicU64::new(0),
-            login_failures: AtomicU64::new(0),
-            active_sessions: AtomicU64::new(0),
-            rate_limit_hits: AtomicU64::new(0),
-            circuit_breaker_opens: AtomicU64::new(0),
-            retry_attempts: AtomicU64::new(0),
-            retry_successes: AtomicU64::new(0),
-            retry_exhausted: AtomicU64::new(0),
+fn format_value(value: f64) -> String {
+    if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Format a label set, with an optional trailing `le` for histogram buckets
+fn format_labels(names: &[String], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+/// Registry of all metrics
+#[derive(Debug, Clone, Default)]
+struct Registry {
+    families: BTreeMap<String, Family>,
+}
+
+impl Registry {
+    /// Registry with the built-in metrics used by the `record-*` functions
+    fn with_builtins() -> Self {
+        let mut registry = Registry::default();
+        for (name, kind, help, label) in BUILTINS {
+            registry
+                .register(MetricDefinition {
+                    name: name.to_string(),
+                    kind,
+                    help: help.to_string(),
+                    label_names: label.into_iter().map(str::to_string).collect(),
+                    buckets: Vec::new(),
+                })
+                .expect("built-in metric definitions are valid");
         }
+        registry
     }
 
-    fn record_request(&self, endpoint: &str) {
-        self.total_requests.fetch_add(1, Ordering::Relaxed);
-        if let Ok(mut map) = self.endpoint_requests.lock() {
-            *map.entry(endpoint.to_string()).or_insert(0) += 1;
+    fn register(&mut self, definition: MetricDefinition) -> Result<(), String> {
+        let name = definition.name;
+        if !is_valid_metric_name(&name) {
+            return Err(format!("Invalid metric name '{}'", name));
         }
+        if definition.kind == MetricKind::Counter && !name.ends_with("_total") {
+            return Err(format!("Counter name '{}' must end with _total", name));
+        }
+
+        for (index, label) in definition.label_names.iter().enumerate() {
+            if !is_valid_label_name(label) {
+                return Err(format!("Invalid label name '{}'", label));
+            }
+            if definition.kind == MetricKind::Histogram && label == "le" {
+                return Err("Label name 'le' is reserved for histogram buckets".to_string());
+            }
+            if definition.label_names[..index].contains(label) {
+                return Err(format!("Duplicate label name '{}'", label));
+            }
+        }
+
+        let buckets = match definition.kind {
+            MetricKind::Histogram if definition.buckets.is_empty() => DEFAULT_BUCKETS.to_vec(),
+            MetricKind::Histogram => {
+                let increasing = definition.buckets.windows(2).all(|pair| pair[0] < pair[1]);
+                if !increasing || definition.buckets.iter().any(|bound| !bound.is_finite()) {
+                    return Err(
+                        "Histogram buckets must be finite and strictly increasing".to_string()
+                    );
+                }
+                definition.buckets
+            }
+            MetricKind::Counter | MetricKind::Gauge => {
+                if !definition.buckets.is_empty() {
+                    return Err(format!("Only histograms take buckets, '{}' does not", name));
+                }
+                Vec::new()
+            }
+        };
+
+        let definition = Definition {
+            kind: definition.kind,
+            help: definition.help,
+            label_names: definition.label_names,
+            buckets,
+        };
+
+        if let Some(existing) = self.families.get(&name) {
+            return if existing.definition == definition {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Metric '{}' is already registered with a different definition",
+                    name
+                ))
+            };
+        }
+
+        // Metrics without labels have exactly one series, exposed from the start
+        let mut series = BTreeMap::new();
+        if definition.label_names.is_empty() {
+            series.insert(Vec::new(), Value::new(&definition));
+        }
+        self.families.insert(name, Family { definition, series });
+        Ok(())
+    }
+
+    /// Find the series for `labels` in metric `name` of the given kind,
+    /// creating it on first use
+    fn series(
+        &mut self,
+        name: &str,
+        kind: MetricKind,
+        labels: &[Label],
+    ) -> Result<&mut Value, String> {
+        let family = self
+            .families
+            .get_mut(name)
+            .ok_or_else(|| format!("Metric '{}' is not registered", name))?;
+        if family.definition.kind != kind {
+            return Err(format!(
+                "Metric '{}' is a {:?}, not a {:?}",
+                name, family.definition.kind, kind
+            ));
+        }
+
+        let names = &family.definition.label_names;
+        if labels.len() != names.len() {
+            return Err(format!(
+                "Metric '{}' expects labels [{}]",
+                name,
+                names.join(", ")
+            ));
+        }
+        let mut values = Vec::with_capacity(names.len());
+        for label_name in names {
+            let mut matching = labels.iter().filter(|label| &label.name == label_name);
+            match (matching.next(), matching.next()) {
+                (Some(label), None) => values.push(label.value.clone()),
+                _ => {
+                    return Err(format!(
+                        "Metric '{}' expects labels [{}]",
+                        name,
+                        names.join(", ")
+                    ))
+                }
+            }
+        }
+
+        if !family.series.contains_key(&values) && family.series.len() >= MAX_SERIES_PER_METRIC {
+            return Err(format!(
+                "Metric '{}' already has {} label sets",
+                name, MAX_SERIES_PER_METRIC
+            ));
+        }
+        let definition = &family.definition;
+        Ok(family
+            .series
+            .entry(values)
+            .or_insert_with(|| Value::new(definition)))
     }
 
-    fn record_error(&self, endpoint: &str) {
-        self.total_errors.fetch_add(1, Ordering::Relaxed);
-        if let Ok(mut map) = self.endpoint_errors.lock() {
-            *map.entry(endpoint.to_string()).or_insert(0) += 1;
+    fn counter_inc(&mut self, name: &str, labels: &[Label], value: f64) -> Result<(), String> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!(
+                "Counter increment must be non-negative, got {}",
+                value
+            ));
         }
+        if let Value::Scalar(current) = self.series(name, MetricKind::Counter, labels)? {
+            *current += value;
+        }
+        Ok(())
+    }
+
+    fn gauge_set(&mut self, name: &str, labels: &[Label], value: f64) -> Result<(), String> {
+        if !value.is_finite() {
+            return Err(format!("Gauge value must be finite, got {}", value));
+        }
+        if let Value::Scalar(current) = self.series(name, MetricKind::Gauge, labels)? {
+            *current = value;
+        }
+        Ok(())
     }
 
-    fn to_prometheus_format(&self) -> String {
+    fn gauge_add(&mut self, name: &str, labels: &[Label], delta: f64) -> Result<(), String> {
+        if !delta.is_finite() {
+            return Err(format!("Gauge delta must be finite, got {}", delta));
+        }
+        if let Value::Scalar(current) = self.series(name, MetricKind::Gauge, labels)? {
+            *current += delta;
+        }
+        Ok(())
+    }
+
+    fn histogram_observe(
+        &mut self,
+        name: &str,
+        labels: &[Label],
+        value: f64,
+    ) -> Result<(), String> {
+        if !value.is_finite() || value < 0.0 {
+            return Err(format!(
+                "Histogram observation must be non-negative, got {}",
+                value
+            ));
+        }
+        let bounds = self
+            .families
+            .get(name)
+            .map(|family| family.definition.buckets.clone())
+            .unwrap_or_default();
+        if let Value::Histogram {
+            buckets,
+            sum,
+            count,
+        } = self.series(name, MetricKind::Histogram, labels)?
+        {
+            if let Some(index) = bounds.iter().position(|bound| value <= *bound) {
+                buckets[index] += 1;
+            }
+            *sum += value;
+            *count += 1;
+        }
+        Ok(())
+    }
+
+    /// Render all metrics in the given text format
+    fn render(&self, format: Format) -> String {
         let mut output = String::new();
 
-        // Total requests
-        output.push_str("# HELP myt2abrp_requests_total Total number of HTTP requests\n");
-        output.push_str("# TYPE myt2abrp_requests_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_requests_total {}\n\n",
-            self.total_requests.load(Ordering::Relaxed)
-        ));
-
-        // Total errors
-        output.push_str("# HELP myt2abrp_errors_total Total number of errors\n");
-        output.push_str("# TYPE myt2abrp_errors_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_errors_total {}\n\n",
-            self.total_errors.load(Ordering::Relaxed)
-        ));
-
-        // Per-endpoint requests
-        output.push_str("# HELP myt2abrp_endpoint_requests_total Requests per endpoint\n");
-        output.push_str("# TYPE myt2abrp_endpoint_requests_total counter\n");
-        if let Ok(map) = self.endpoint_requests.lock() {
-            for (endpoint, count) in map.iter() {
-                output.push_str(&format!(
-                    "myt2abrp_endpoint_requests_total{{endpoint=\"{}\"}} {}\n",
-                    endpoint, count
-                ));
+        for (name, family) in &self.families {
+            let definition = &family.definition;
+            // OpenMetrics names counter families without the `_total` suffix
+            let family_name = match (format, definition.kind) {
+                (Format::OpenMetrics, MetricKind::Counter) => {
+                    name.strip_suffix("_total").unwrap_or(name)
+                }
+                _ => name.as_str(),
+            };
+            let type_name = match definition.kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+                MetricKind::Histogram => "histogram",
+            };
+
+            output.push_str(&format!(
+                "# HELP {} {}\n",
+                family_name,
+                escape_help(&definition.help, format)
+            ));
+            output.push_str(&format!("# TYPE {} {}\n", family_name, type_name));
+
+            for (values, value) in &family.series {
+                let labels = format_labels(&definition.label_names, values, None);
+                match value {
+                    Value::Scalar(value) => {
+                        output.push_str(&format!("{}{} {}\n", name, labels, format_value(*value)));
+                    }
+                    Value::Histogram {
+                        buckets,
+                        sum,
+                        count,
+                    } => {
+                        let mut cumulative = 0;
+                        for (bound, observations) in definition.buckets.iter().zip(buckets) {
+                            cumulative += observations;
+                            output.push_str(&format!(
+                                "{}_bucket{} {}\n",
+                                name,
+                                format_labels(
+                                    &definition.label_names,
+                                    values,
+                                    Some(&format!("{:?}", bound))
+                                ),
+                                cumulative
+                            ));
+                        }
+                        output.push_str(&format!(
+                            "{}_bucket{} {}\n",
+                            name,
+                            format_labels(&definition.label_names, values, Some("+Inf")),
+                            count
+                        ));
+                        output.push_str(&format!(
+                            "{}_sum{} {}\n",
+                            name,
+                            labels,
+                            format_value(*sum)
+                        ));
+                        output.push_str(&format!("{}_count{} {}\n", name, labels, count));
+                    }
+                }
+            }
+
+            // OpenMetrics does not allow blank lines
+            if format == Format::Prometheus {
+                output.push('\n');
             }
         }
-        output.push('\n');
-
-        // Cache hits/misses
-        output.push_str("# HELP myt2abrp_cache_hits_total Total cache hits\n");
-        output.push_str("# TYPE myt2abrp_cache_hits_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_cache_hits_total {}\n\n",
-            self.cache_hits.load(Ordering::Relaxed)
-        ));
-
-        output.push_str("# HELP myt2abrp_cache_misses_total Total cache misses\n");
-        output.push_str("# TYPE myt2abrp_cache_misses_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_cache_misses_total {}\n\n",
-            self.cache_misses.load(Ordering::Relaxed)
-        ));
-
-        // Authentication
-        output.push_str("# HELP myt2abrp_login_attempts_total Total login attempts\n");
-        output.push_str("# TYPE myt2abrp_login_attempts_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_login_attempts_total {}\n\n",
-            self.login_attempts.load(Ordering::Relaxed)
-        ));
-
-        output.push_str("# HELP myt2abrp_active_sessions Current active sessions\n");
-        output.push_str("# TYPE myt2abrp_active_sessions gauge\n");
-        output.push_str(&format!(
-            "myt2abrp_active_sessions {}\n\n",
-            self.active_sessions.load(Ordering::Relaxed)
-        ));
-
-        // Circuit breaker
-        output
-            .push_str("# HELP myt2abrp_circuit_breaker_opens_total Circuit breaker open events\n");
-        output.push_str("# TYPE myt2abrp_circuit_breaker_opens_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_circuit_breaker_opens_total {}\n\n",
-            self.circuit_breaker_opens.load(Ordering::Relaxed)
-        ));
-
-        // Retry metrics
-        output.push_str("# HELP myt2abrp_retry_attempts_total Total retry attempts\n");
-        output.push_str("# TYPE myt2abrp_retry_attempts_total counter\n");
-        output.push_str(&format!(
-            "myt2abrp_retry_attempts_total {}\n\n",
-            self.retry_attempts.load(Ordering::Relaxed)
-        ));
 
+        if format == Format::OpenMetrics {
+            output.push_str("# EOF\n");
+        }
         output
     }
 }
 
 // Global singleton
-static METRICS: OnceLock<Metrics> = OnceLock::new();
+static REGISTRY: OnceLock<Mutex<Registry>> = OnceLock::new();
 
-fn get_metrics() -> &'static Metrics {
-    METRICS.get_or_init(Metrics::new)
+fn with_registry<T>(f: impl FnOnce(&mut Registry) -> T) -> T {
+    let registry = REGISTRY.get_or_init(|| Mutex::new(Registry::with_builtins()));
+    let mut guard = registry.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut guard)
+}
+
+/// Increment a built-in counter; built-ins are always registered, so this
+/// can only fail once a label set limit is reached, which is not worth
+/// surfacing through the fire-and-forget `record-*` functions
+fn inc_builtin(name: &str, labels: &[Label]) {
+    let _ = with_registry(|registry| registry.counter_inc(name, labels, 1.0));
+}
+
+fn endpoint_label(endpoint: String) -> [Label; 1] {
+    [Label {
+        name: "endpoint".to_string(),
+        value: endpoint,
+    }]
 }
 
 impl Guest for Component {
+    fn register(definition: MetricDefinition) -> Result<(), String> {
+        with_registry(|registry| registry.register(definition))
+    }
+
+    fn counter_inc(name: String, labels: Vec<Label>, value: f64) -> Result<(), String> {
+        with_registry(|registry| registry.counter_inc(&name, &labels, value))
+    }
+
+    fn gauge_set(name: String, labels: Vec<Label>, value: f64) -> Result<(), String> {
+        with_registry(|registry| registry.gauge_set(&name, &labels, value))
+    }
+
+    fn gauge_add(name: String, labels: Vec<Label>, delta: f64) -> Result<(), String> {
+        with_registry(|registry| registry.gauge_add(&name, &labels, delta))
+    }
+
+    fn histogram_observe(name: String, labels: Vec<Label>, value: f64) -> Result<(), String> {
+        with_registry(|registry| registry.histogram_observe(&name, &labels, value))
+    }
+
     fn record_request(endpoint: String) {
-        get_metrics().record_request(&endpoint);
+        inc_builtin(REQUESTS, &[]);
+        inc_builtin(ENDPOINT_REQUESTS, &endpoint_label(endpoint));
     }
 
     fn record_error(endpoint: String) {
-        get_metrics().record_error(&endpoint);
+        inc_builtin(ERRORS, &[]);
+        inc_builtin(ENDPOINT_ERRORS, &endpoint_label(endpoint));
     }
 
     fn record_cache_hit() {
-        get_metrics().cache_hits.fetch_add(1, Ordering::Relaxed);
+        inc_builtin(CACHE_HITS, &[]);
     }
 
     fn record_cache_miss() {
-        get_metrics().cache_misses.fetch_add(1, Ordering::Relaxed);
+        inc_builtin(CACHE_MISSES, &[]);
     }
 
     fn record_login_attempt() {
-        get_metrics().login_attempts.fetch_add(1, Ordering::Relaxed);
+        inc_builtin(LOGIN_ATTEMPTS, &[]);
     }
 
     fn record_login_failure() {
-        get_metrics().login_failures.fetch_add(1, Ordering::Relaxed);
+        inc_builtin(LOGIN_FAILURES, &[]);
     }
 
     fn increment_active_sessions() {
-        get_metrics()
-            .active_sessions
-            .fetch_add(1, Ordering::Relaxed);
+        let _ = with_registry(|registry| registry.gauge_add(ACTIVE_SESSIONS, &[], 1.0));
     }
 
     fn decrement_active_sessions() {
-        get_metrics()
-            .active_sessions
-            .fetch_sub(1, Ordering::Relaxed);
+        let _ = with_registry(|registry| registry.gauge_add(ACTIVE_SESSIONS, &[], -1.0));
     }
 
     fn record_rate_limit_hit() {
-        get_metrics()
-            .rate_limit_hits
-            .fetch_add(1, Ordering::Relaxed);
+        inc_builtin(RATE_LIMIT_HITS, &[]);
     }
 
     fn record_circuit_breaker_open() {
-        get_metrics()
-            .circuit_breaker_opens
-            .fetch_add(1, Ordering::Relaxed);
+        inc_builtin(CIRCUIT_BREAKER_OPENS, &[]);
     }
 
     fn record_retry_attempt() {
-        get_metrics().retry_attempts.fetch_add(1, Ordering::Relaxed);
+        inc_builtin(RETRY_ATTEMPTS, &[]);
     }
 
     fn record_retry_success() {
-        get_metrics()
-            .retry_successes
-            .fetch_add(1, Ordering::Relaxed);
+        inc_builtin(RETRY_SUCCESSES, &[]);
     }
 
     fn record_retry_exhausted() {
-        get_metrics()
-            .retry_exhausted
-            .fetch_add(1, Ordering::Relaxed);
+        inc_builtin(RETRY_EXHAUSTED, &[]);
     }
 
     fn export_prometheus() -> String {
-        get_metrics().to_prometheus_format()
+        with_registry(|registry| registry.render(Format::Prometheus))
+    }
+
+    fn export_openmetrics() -> String {
+        with_registry(|registry| registry.render(Format::OpenMetrics))
     }
 }
 
 bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(name: &str, kind: MetricKind, labels: &[&str]) -> MetricDefinition {
+        MetricDefinition {
+            name: name.to_string(),
+            kind,
+            help: format!("Help for {}", name),
+            label_names: labels.iter().map(|label| label.to_string()).collect(),
+            buckets: Vec::new(),
+        }
+    }
+
+    fn label(name: &str, value: &str) -> Label {
+        Label {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_register_validation() {
+        let mut registry = Registry::default();
+
+        assert!(registry
+            .register(definition("9bad", MetricKind::Gauge, &[]))
+            .is_err());
+        assert!(registry
+            .register(definition("jobs", MetricKind::Counter, &[]))
+            .is_err());
+        assert!(registry
+            .register(definition("jobs_total", MetricKind::Counter, &["__name"]))
+            .is_err());
+        assert!(registry
+            .register(definition("jobs_total", MetricKind::Counter, &["a", "a"]))
+            .is_err());
+        assert!(registry
+            .register(definition("latency", MetricKind::Histogram, &["le"]))
+            .is_err());
+
+        let mut unordered = definition("latency", MetricKind::Histogram, &[]);
+        unordered.buckets = vec![1.0, 0.5];
+        assert!(registry.register(unordered).is_err());
+
+        // Identical re-registration is fine, a changed one is not
+        let jobs = definition("jobs_total", MetricKind::Counter, &["queue"]);
+        assert!(registry.register(jobs.clone()).is_ok());
+        assert!(registry.register(jobs).is_ok());
+        assert!(registry
+            .register(definition("jobs_total", MetricKind::Counter, &["worker"]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_updates_check_kind_labels_and_values() {
+        let mut registry = Registry::default();
+        registry
+            .register(definition("jobs_total", MetricKind::Counter, &["queue"]))
+            .unwrap();
+        registry
+            .register(definition("depth", MetricKind::Gauge, &[]))
+            .unwrap();
+
+        assert!(registry
+            .counter_inc("jobs_total", &[label("queue", "a")], 2.0)
+            .is_ok());
+        assert!(registry.counter_inc("jobs_total", &[], 1.0).is_err());
+        assert!(registry
+            .counter_inc("jobs_total", &[label("other", "a")], 1.0)
+            .is_err());
+        assert!(registry
+            .counter_inc("jobs_total", &[label("queue", "a")], -1.0)
+            .is_err());
+        assert!(registry
+            .gauge_set("jobs_total", &[label("queue", "a")], 1.0)
+            .is_err());
+        assert!(registry.counter_inc("missing_total", &[], 1.0).is_err());
+
+        registry.gauge_set("depth", &[], 5.0).unwrap();
+        registry.gauge_add("depth", &[], -2.0).unwrap();
+        assert!(registry.gauge_set("depth", &[], f64::NAN).is_err());
+
+        assert_eq!(
+            registry.families["jobs_total"].series[&vec!["a".to_string()]],
+            Value::Scalar(2.0)
+        );
+        assert_eq!(
+            registry.families["depth"].series[&vec![]],
+            Value::Scalar(3.0)
+        );
+    }
+
+    #[test]
+    fn test_series_limit() {
+        let mut registry = Registry::default();
+        registry
+            .register(definition("hits_total", MetricKind::Counter, &["path"]))
+            .unwrap();
+
+        for i in 0..MAX_SERIES_PER_METRIC {
+            registry
+                .counter_inc("hits_total", &[label("path", &i.to_string())], 1.0)
+                .unwrap();
+        }
+        assert!(registry
+            .counter_inc("hits_total", &[label("path", "new")], 1.0)
+            .is_err());
+        assert!(registry
+            .counter_inc("hits_total", &[label("path", "0")], 1.0)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_prometheus_exposition() {
+        let mut registry = Registry::with_builtins();
+        let mut latency = definition("request_seconds", MetricKind::Histogram, &["route"]);
+        latency.buckets = vec![0.1, 1.0];
+        registry.register(latency).unwrap();
+
+        let route = [label("route", "/a\"b")];
+        registry
+            .histogram_observe("request_seconds", &route, 0.05)
+            .unwrap();
+        registry
+            .histogram_observe("request_seconds", &route, 0.5)
+            .unwrap();
+        registry
+            .histogram_observe("request_seconds", &route, 3.0)
+            .unwrap();
+        registry.counter_inc(REQUESTS, &[], 1.0).unwrap();
+
+        let output = registry.render(Format::Prometheus);
+
+        assert!(output.contains("# HELP myt2abrp_requests_total Total number of HTTP requests\n"));
+        assert!(
+            output.contains("# TYPE myt2abrp_requests_total counter\nmyt2abrp_requests_total 1\n")
+        );
+        assert!(output.contains("myt2abrp_cache_hits_total 0\n"));
+        assert!(output.contains("# TYPE request_seconds histogram\n"));
+        assert!(output.contains("request_seconds_bucket{route=\"/a\\\"b\",le=\"0.1\"} 1\n"));
+        assert!(output.contains("request_seconds_bucket{route=\"/a\\\"b\",le=\"1.0\"} 2\n"));
+        assert!(output.contains("request_seconds_bucket{route=\"/a\\\"b\",le=\"+Inf\"} 3\n"));
+        assert!(output.contains("request_seconds_sum{route=\"/a\\\"b\"} 3.55\n"));
+        assert!(output.contains("request_seconds_count{route=\"/a\\\"b\"} 3\n"));
+        assert!(!output.contains("# EOF"));
+    }
+
+    #[test]
+    fn test_openmetrics_exposition() {
+        let mut registry = Registry::with_builtins();
+        registry.counter_inc(REQUESTS, &[], 2.0).unwrap();
+        registry.gauge_add(ACTIVE_SESSIONS, &[], 1.0).unwrap();
+
+        let output = registry.render(Format::OpenMetrics);
+
+        assert!(output.contains("# TYPE myt2abrp_requests counter\nmyt2abrp_requests_total 2\n"));
+        assert!(
+            output.contains("# TYPE myt2abrp_active_sessions gauge\nmyt2abrp_active_sessions 1\n")
+        );
+        assert!(output.ends_with("# EOF\n"));
+        assert!(!output.contains("\n\n"));
+    }
+}
@@ -274,6 +274,376 @@ pub struct NewChargeInfo {
     pub remaining_charge_time: Option<i32>,
 }
 
+// Remote Command Structures
+
+/// Charging command of `POST /v1/global/remote/electric/command`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElectricCommand {
+    #[serde(rename = "start-charging")]
+    StartCharging,
+    #[serde(rename = "stop-charging")]
+    StopCharging,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ElectricCommandRequest {
+    pub command: ElectricCommand,
+    /// Target state of charge in percent, only honoured by some vehicles
+    #[serde(rename = "chargeLimit", skip_serializing_if = "Option::is_none")]
+    pub charge_limit: Option<i32>,
+}
+
+/// Answer to a remote command; the vehicle executes it asynchronously
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteCommandResponse {
+    pub status: Option<RemoteCommandStatus>,
+    pub payload: Option<RemoteCommandPayload>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteCommandStatus {
+    #[serde(default)]
+    pub messages: Vec<RemoteCommandMessage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteCommandMessage {
+    #[serde(rename = "responseCode")]
+    pub response_code: Option<String>,
+    pub description: Option<String>,
+    #[serde(rename = "detailedDescription")]
+    pub detailed_description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteCommandPayload {
+    #[serde(rename = "returnCode")]
+    pub return_code: Option<String>,
+    /// Request number assigned by Toyota
+    #[serde(rename = "appRequestNo")]
+    pub app_request_no: Option<String>,
+}
+
+/// Return code of an accepted remote command
+pub const REMOTE_COMMAND_ACCEPTED: &str = "000000";
+
+impl RemoteCommandResponse {
+    /// Whether Toyota accepted the command for delivery to the vehicle
+    pub fn is_accepted(&self) -> bool {
+        match self.payload.as_ref().and_then(|p| p.return_code.as_deref()) {
+            Some(code) => code == REMOTE_COMMAND_ACCEPTED,
+            None => self.error_message().is_none(),
+        }
+    }
+
+    /// First error description reported by Toyota, if any
+    pub fn error_message(&self) -> Option<String> {
+        self.status
+            .as_ref()?
+            .messages
+            .iter()
+            .filter(|m| {
+                m.response_code
+                    .as_deref()
+                    .is_some_and(|code| !code.ends_with("-0000") && code != REMOTE_COMMAND_ACCEPTED)
+            })
+            .find_map(|m| {
+                m.detailed_description
+                    .clone()
+                    .or_else(|| m.description.clone())
+            })
+    }
+
+    pub fn request_number(&self) -> Option<String> {
+        self.payload.as_ref()?.app_request_no.clone()
+    }
+}
+
+// Remote Climate Structures
+
+/// Command of `POST /v1/global/remote/climate-control`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClimateCommand {
+    #[serde(rename = "engine-start")]
+    Start,
+    #[serde(rename = "engine-stop")]
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClimateControlRequest {
+    pub command: ClimateCommand,
+}
+
+/// Name of the front defrost air conditioning parameter
+pub const AC_PARAMETER_FRONT_DEFROST: &str = "frontDefrost";
+/// Name of the rear defrost air conditioning parameter
+pub const AC_PARAMETER_REAR_DEFROST: &str = "rearDefrost";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClimateSettingsResponse {
+    pub payload: ClimateSettings,
+}
+
+/// Settings used the next time climate control starts
+/// (`GET`/`PUT /v1/global/remote/climate-settings`)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClimateSettings {
+    #[serde(rename = "settingsOn")]
+    pub settings_on: bool,
+    pub temperature: f32,
+    #[serde(rename = "temperatureUnit")]
+    pub temperature_unit: String,
+    #[serde(rename = "minTemp", skip_serializing_if = "Option::is_none")]
+    pub min_temp: Option<f32>,
+    #[serde(rename = "maxTemp", skip_serializing_if = "Option::is_none")]
+    pub max_temp: Option<f32>,
+    #[serde(rename = "acOperations", default)]
+    pub ac_operations: Vec<AcOperation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AcOperation {
+    #[serde(rename = "categoryName")]
+    pub category_name: String,
+    #[serde(rename = "acParameters", default)]
+    pub ac_parameters: Vec<AcParameter>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AcParameter {
+    pub available: bool,
+    pub enabled: bool,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    pub name: String,
+}
+
+impl ClimateSettings {
+    /// Whether an air conditioning parameter (e.g. front defrost) is enabled
+    pub fn parameter(&self, name: &str) -> Option<bool> {
+        self.ac_operations
+            .iter()
+            .flat_map(|op| op.ac_parameters.iter())
+            .find(|p| p.name == name && p.available)
+            .map(|p| p.enabled)
+    }
+
+    /// Enable or disable a parameter; returns false if the vehicle lacks it
+    pub fn set_parameter(&mut self, name: &str, enabled: bool) -> bool {
+        match self
+            .ac_operations
+            .iter_mut()
+            .flat_map(|op| op.ac_parameters.iter_mut())
+            .find(|p| p.name == name && p.available)
+        {
+            Some(parameter) => {
+                parameter.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClimateStatusResponse {
+    pub payload: Option<ClimateStatus>,
+}
+
+/// Current climate control state (`GET /v1/global/remote/climate-status`)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClimateStatus {
+    #[serde(rename = "type")]
+    pub r#type: Option<String>,
+    /// Whether climate control is running
+    pub status: bool,
+    #[serde(rename = "startedAt")]
+    pub started_at: Option<String>,
+    /// Run time in minutes
+    pub duration: Option<i32>,
+    pub temperature: Option<ClimateTemperature>,
+    pub options: Option<ClimateOptions>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClimateTemperature {
+    pub unit: Option<String>,
+    pub value: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClimateOptions {
+    #[serde(rename = "frontDefrost")]
+    pub front_defrost: Option<bool>,
+    #[serde(rename = "rearDefrost")]
+    pub rear_defrost: Option<bool>,
+}
+
+// Remote Status and Door Command Structures
+
+/// Command of `POST /v1/global/remote/command`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteCommand {
+    #[serde(rename = "door-lock")]
+    DoorLock,
+    #[serde(rename = "door-unlock")]
+    DoorUnlock,
+    #[serde(rename = "hazard-on")]
+    HazardOn,
+    #[serde(rename = "hazard-off")]
+    HazardOff,
+    #[serde(rename = "sound-horn")]
+    SoundHorn,
+    /// Horn and lights
+    #[serde(rename = "find-vehicle")]
+    FindVehicle,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteCommandRequest {
+    pub command: RemoteCommand,
+    #[serde(rename = "beepCount", skip_serializing_if = "Option::is_none")]
+    pub beep_count: Option<u32>,
+}
+
+/// Section names of the remote status
+pub const STATUS_DRIVER_DOOR: &str = "carstatus_item_driver_door";
+pub const STATUS_PASSENGER_DOOR: &str = "carstatus_item_passenger_door";
+pub const STATUS_REAR_LEFT_DOOR: &str = "carstatus_item_rear_left_door";
+pub const STATUS_REAR_RIGHT_DOOR: &str = "carstatus_item_rear_right_door";
+pub const STATUS_REAR_HATCH: &str = "carstatus_item_rear_hatch";
+pub const STATUS_HOOD: &str = "carstatus_item_hood";
+pub const STATUS_DRIVER_WINDOW: &str = "carstatus_item_driver_window";
+pub const STATUS_PASSENGER_WINDOW: &str = "carstatus_item_passenger_window";
+pub const STATUS_REAR_LEFT_WINDOW: &str = "carstatus_item_rear_left_window";
+pub const STATUS_REAR_RIGHT_WINDOW: &str = "carstatus_item_rear_right_window";
+
+/// Door, window, trunk and hood state (`GET /v1/global/remote/status`)
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteStatusResponse {
+    pub payload: RemoteStatusPayload,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoteStatusPayload {
+    #[serde(rename = "vehicleStatus", default)]
+    pub vehicle_status: Vec<VehicleStatusCategory>,
+    #[serde(rename = "occurrenceDate")]
+    pub occurrence_date: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VehicleStatusCategory {
+    pub category: String,
+    #[serde(rename = "displayOrder")]
+    pub display_order: Option<i32>,
+    #[serde(default)]
+    pub sections: Vec<VehicleStatusSection>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VehicleStatusSection {
+    pub section: String,
+    #[serde(default)]
+    pub values: Vec<VehicleStatusValue>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VehicleStatusValue {
+    pub value: String,
+    /// 0 = normal, 1 = needs attention (open or unlocked)
+    pub status: Option<i32>,
+}
+
+impl RemoteStatusPayload {
+    pub fn section(&self, name: &str) -> Option<&VehicleStatusSection> {
+        self.vehicle_status
+            .iter()
+            .flat_map(|category| category.sections.iter())
+            .find(|section| section.section == name)
+    }
+}
+
+impl VehicleStatusSection {
+    fn has_value(&self, value: &str) -> bool {
+        self.values.iter().any(|v| v.value == value)
+    }
+
+    /// `Some(true)` if reported closed, `Some(false)` if open
+    pub fn is_closed(&self) -> Option<bool> {
+        if self.has_value("carstatus_closed") {
+            Some(true)
+        } else if self.has_value("carstatus_open") {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// `Some(true)` if reported locked, `Some(false)` if unlocked
+    pub fn is_locked(&self) -> Option<bool> {
+        if self.has_value("carstatus_locked") {
+            Some(true)
+        } else if self.has_value("carstatus_unlocked") {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+// Charging Schedule Structures
+
+/// Day a charge timer is active on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChargeDay {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChargeScheduleResponse {
+    pub payload: ChargeSchedule,
+}
+
+/// Charge timers of a vehicle
+/// (`GET`/`PUT /v1/global/remote/electric/charge-schedule`)
+///
+/// `PUT` replaces the whole list; Toyota assigns ids to new timers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ChargeSchedule {
+    #[serde(rename = "chargeTimers", default)]
+    pub timers: Vec<ChargeTimer>,
+    /// Number of timers the vehicle can store
+    #[serde(rename = "maxTimers", skip_serializing_if = "Option::is_none")]
+    pub max_timers: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChargeTimer {
+    #[serde(rename = "timerId", skip_serializing_if = "Option::is_none")]
+    pub timer_id: Option<String>,
+    pub enabled: bool,
+    /// Charging starts at this time ("HH:MM")
+    #[serde(rename = "startTime", skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    /// Charging ends at, or without a start time is done by, this time
+    #[serde(rename = "endTime", skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+    #[serde(rename = "daysOfWeek", default)]
+    pub days: Vec<ChargeDay>,
+    /// Target state of charge in percent
+    #[serde(rename = "chargeLimit", skip_serializing_if = "Option::is_none")]
+    pub charge_limit: Option<i32>,
+}
+
 // Trait implementations for new structures
 
 impl IntoBody for AuthenticateRequest {
@@ -294,6 +664,36 @@ impl IntoBody for RefreshTokenRequest {
     }
 }
 
+impl IntoBody for ElectricCommandRequest {
+    fn into_body(self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl IntoBody for ClimateControlRequest {
+    fn into_body(self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl IntoBody for ClimateSettings {
+    fn into_body(self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl IntoBody for ChargeSchedule {
+    fn into_body(self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
+impl IntoBody for RemoteCommandRequest {
+    fn into_body(self) -> Vec<u8> {
+        serde_json::to_vec(&self).unwrap()
+    }
+}
+
 impl Default for AuthenticateRequest {
     fn default() -> Self {
         Self::new()
@@ -378,6 +778,39 @@ impl From<&[u8]> for TelemetryResponse {
     }
 }
 
+impl From<&[u8]> for RemoteCommandResponse {
+    fn from(item: &[u8]) -> Self {
+        serde_json::from_slice(item).unwrap_or(RemoteCommandResponse {
+            status: None,
+            payload: None,
+        })
+    }
+}
+
+impl From<&[u8]> for ClimateSettingsResponse {
+    fn from(item: &[u8]) -> Self {
+        serde_json::from_slice(item).unwrap()
+    }
+}
+
+impl From<&[u8]> for ClimateStatusResponse {
+    fn from(item: &[u8]) -> Self {
+        serde_json::from_slice(item).unwrap()
+    }
+}
+
+impl From<&[u8]> for RemoteStatusResponse {
+    fn from(item: &[u8]) -> Self {
+        serde_json::from_slice(item).unwrap()
+    }
+}
+
+impl From<&[u8]> for ChargeScheduleResponse {
+    fn from(item: &[u8]) -> Self {
+        serde_json::from_slice(item).unwrap()
+    }
+}
+
 impl TokenRequest {
     pub fn new(code: String) -> Self {
         TokenRequest {
@@ -600,4 +1033,138 @@ mod tests {
         assert_eq!(deserialized.id_token, token.id_token);
         assert_eq!(deserialized.expires_in, token.expires_in);
     }
+
+    #[test]
+    fn test_electric_command_request_serialization() {
+        let request = ElectricCommandRequest {
+            command: ElectricCommand::StartCharging,
+            charge_limit: Some(80),
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["command"], "start-charging");
+        assert_eq!(json["chargeLimit"], 80);
+
+        let request = ElectricCommandRequest {
+            command: ElectricCommand::StopCharging,
+            charge_limit: None,
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["command"], "stop-charging");
+        assert!(json.get("chargeLimit").is_none());
+    }
+
+    #[test]
+    fn test_remote_command_response() {
+        let accepted = RemoteCommandResponse::from(
+            br#"{"status":{"messages":[{"responseCode":"OneApp-0000","description":"Request Completed Successfully"}]},"payload":{"returnCode":"000000","appRequestNo":"REQ-1"}}"#
+                .as_slice(),
+        );
+        assert!(accepted.is_accepted());
+        assert_eq!(accepted.error_message(), None);
+        assert_eq!(accepted.request_number(), Some("REQ-1".to_string()));
+
+        let rejected = RemoteCommandResponse::from(
+            br#"{"status":{"messages":[{"responseCode":"OneApp-CMD-403","description":"Forbidden","detailedDescription":"Remote service not subscribed"}]}}"#
+                .as_slice(),
+        );
+        assert!(!rejected.is_accepted());
+        assert_eq!(
+            rejected.error_message(),
+            Some("Remote service not subscribed".to_string())
+        );
+    }
+
+    #[test]
+    fn test_climate_settings() {
+        let response = ClimateSettingsResponse::from(
+            br#"{"payload":{"settingsOn":true,"temperature":21.0,"temperatureUnit":"C","minTemp":18.0,"maxTemp":29.0,"acOperations":[{"categoryName":"defrost","acParameters":[{"available":true,"enabled":false,"displayName":"Front","name":"frontDefrost"},{"available":false,"enabled":false,"displayName":"Rear","name":"rearDefrost"}]}]}}"#
+                .as_slice(),
+        );
+        let mut settings = response.payload;
+        assert_eq!(settings.temperature, 21.0);
+        assert_eq!(settings.parameter(AC_PARAMETER_FRONT_DEFROST), Some(false));
+        // Unavailable parameters cannot be changed
+        assert_eq!(settings.parameter(AC_PARAMETER_REAR_DEFROST), None);
+        assert!(!settings.set_parameter(AC_PARAMETER_REAR_DEFROST, true));
+
+        assert!(settings.set_parameter(AC_PARAMETER_FRONT_DEFROST, true));
+        let json = serde_json::to_value(&settings).unwrap();
+        assert_eq!(json["acOperations"][0]["acParameters"][0]["enabled"], true);
+
+        let request = serde_json::to_value(ClimateControlRequest {
+            command: ClimateCommand::Start,
+        })
+        .unwrap();
+        assert_eq!(request["command"], "engine-start");
+    }
+
+    #[test]
+    fn test_climate_status() {
+        let response = ClimateStatusResponse::from(
+            br#"{"payload":{"type":"BASIC","status":true,"startedAt":"2025-01-15T07:20:00Z","duration":20,"temperature":{"unit":"C","value":21.0},"options":{"frontDefrost":true,"rearDefrost":false}}}"#
+                .as_slice(),
+        );
+        let status = response.payload.unwrap();
+        assert!(status.status);
+        assert_eq!(status.duration, Some(20));
+        assert_eq!(status.options.unwrap().front_defrost, Some(true));
+
+        let idle = ClimateStatusResponse::from(br#"{"payload":{"status":false}}"#.as_slice());
+        assert!(!idle.payload.unwrap().status);
+    }
+
+    #[test]
+    fn test_remote_status() {
+        let response = RemoteStatusResponse::from(
+            br#"{"payload":{"occurrenceDate":"2025-01-15T07:20:00Z","vehicleStatus":[{"category":"carstatus_category_driver","displayOrder":1,"sections":[{"section":"carstatus_item_driver_door","values":[{"value":"carstatus_closed","status":0},{"value":"carstatus_unlocked","status":1}]},{"section":"carstatus_item_driver_window","values":[{"value":"carstatus_open","status":1}]}]},{"category":"carstatus_category_other","sections":[{"section":"carstatus_item_hood","values":[{"value":"carstatus_closed","status":0}]}]}]}}"#
+                .as_slice(),
+        );
+        let payload = response.payload;
+        let door = payload.section(STATUS_DRIVER_DOOR).unwrap();
+        assert_eq!(door.is_closed(), Some(true));
+        assert_eq!(door.is_locked(), Some(false));
+        assert_eq!(
+            payload.section(STATUS_DRIVER_WINDOW).unwrap().is_closed(),
+            Some(false)
+        );
+        assert_eq!(payload.section(STATUS_HOOD).unwrap().is_locked(), None);
+        assert!(payload.section(STATUS_REAR_HATCH).is_none());
+
+        let request = serde_json::to_value(RemoteCommandRequest {
+            command: RemoteCommand::DoorUnlock,
+            beep_count: None,
+        })
+        .unwrap();
+        assert_eq!(request["command"], "door-unlock");
+        assert!(request.get("beepCount").is_none());
+    }
+
+    #[test]
+    fn test_charge_schedule() {
+        let response = ChargeScheduleResponse::from(
+            br#"{"payload":{"maxTimers":3,"chargeTimers":[{"timerId":"1","enabled":true,"startTime":"22:30","endTime":"06:00","daysOfWeek":["MONDAY","FRIDAY"],"chargeLimit":80},{"timerId":"2","enabled":false,"endTime":"07:15","daysOfWeek":["SATURDAY"]}]}}"#
+                .as_slice(),
+        );
+        let schedule = response.payload;
+        assert_eq!(schedule.max_timers, Some(3));
+        assert_eq!(schedule.timers.len(), 2);
+        assert_eq!(
+            schedule.timers[0].days,
+            vec![ChargeDay::Monday, ChargeDay::Friday]
+        );
+        assert_eq!(schedule.timers[1].start_time, None);
+
+        let new_timer = serde_json::to_value(ChargeTimer {
+            timer_id: None,
+            enabled: true,
+            start_time: Some("01:00".to_string()),
+            end_time: None,
+            days: vec![ChargeDay::Sunday],
+            charge_limit: None,
+        })
+        .unwrap();
+        assert_eq!(new_timer["daysOfWeek"][0], "SUNDAY");
+        assert!(new_timer.get("timerId").is_none());
+        assert!(new_timer.get("endTime").is_none());
+    }
 }
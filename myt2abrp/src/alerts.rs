@@ -0,0 +1,636 @@
+// Charging alerts
+//
+// Users configure which alerts they want (`AlertConfig`) and are subscribed
+// to the VIN they configured them for. Every new status snapshot of that VIN
+// is evaluated against each subscriber's rules. A rule that fires opens an
+// alert instance, which stays open (active, then possibly acknowledged) until
+// the rule's clear condition holds. Fire and clear thresholds differ
+// (hysteresis), so a SOC hovering around a threshold does not fire the same
+// alert over and over.
+
+use crate::charging::{self, ChargingSession, ChargingTransition};
+use crate::history::{HistoryRecord, SnapshotKind};
+use crate::settings::FieldError;
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use tracing::info;
+use uuid::Uuid;
+
+const ALERT_CONFIG_KEY_PREFIX: &str = "alert_config_";
+const ALERTS_KEY_PREFIX: &str = "alerts_";
+const ALERT_SUBSCRIBERS_KEY_PREFIX: &str = "alert_subscribers_";
+
+/// Resolved alerts kept per user and VIN (oldest are dropped first)
+const MAX_RESOLVED_ALERTS: usize = 100;
+
+/// SOC thresholds of the fixed rules
+const FULL_SOC: i32 = 100;
+const OPTIMAL_SOC: i32 = 80;
+const LOW_BATTERY_SOC: i32 = 20;
+
+/// SOC points a reading has to move back before an alert resolves
+const SOC_HYSTERESIS: i32 = 5;
+
+/// Accepted range for the custom charge level
+const CUSTOM_LEVEL_MIN: u8 = 50;
+const CUSTOM_LEVEL_MAX: u8 = 100;
+
+/// A session is judged slow only after it ran this long
+const SLOW_CHARGING_MIN_MINUTES: i64 = 30;
+
+/// Charging slower than this (SOC points per hour) is reported, and the
+/// alert resolves once the rate recovers above the second value
+const SLOW_CHARGING_SOC_PER_HOUR: f64 = 5.0;
+const SLOW_CHARGING_CLEAR_SOC_PER_HOUR: f64 = 7.5;
+
+/// Which alerts a user wants
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertConfig {
+    /// Charging reached 100%
+    pub charge_complete: bool,
+    /// Charging reached 80% (optimal for battery longevity)
+    pub optimal_charge: bool,
+    /// Charging reached this level (50-100%), `None` disables the rule
+    pub custom_level: Option<u8>,
+    /// SOC fell below 20%
+    pub low_battery: bool,
+    /// Charging is slower than expected
+    pub charging_slow: bool,
+    /// Charging finished at or above the trip level (custom level, else 80%)
+    pub ready_for_trip: bool,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            charge_complete: true,
+            optimal_charge: true,
+            custom_level: None,
+            low_battery: false,
+            charging_slow: false,
+            ready_for_trip: false,
+        }
+    }
+}
+
+impl AlertConfig {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        match self.custom_level {
+            Some(level) if !(CUSTOM_LEVEL_MIN..=CUSTOM_LEVEL_MAX).contains(&level) => {
+                Err(vec![FieldError {
+                    field: "custom_level".to_string(),
+                    message: format!(
+                        "Must be between {} and {}",
+                        CUSTOM_LEVEL_MIN, CUSTOM_LEVEL_MAX
+                    ),
+                }])
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_enabled(&self, kind: AlertKind) -> bool {
+        match kind {
+            AlertKind::ChargeComplete => self.charge_complete,
+            AlertKind::OptimalCharge => self.optimal_charge,
+            AlertKind::CustomLevel => self.custom_level.is_some(),
+            AlertKind::LowBattery => self.low_battery,
+            AlertKind::ChargingSlow => self.charging_slow,
+            AlertKind::ReadyForTrip => self.ready_for_trip,
+        }
+    }
+
+    fn trip_level(&self) -> i32 {
+        self.custom_level.map(i32::from).unwrap_or(OPTIMAL_SOC)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    ChargeComplete,
+    OptimalCharge,
+    CustomLevel,
+    LowBattery,
+    ChargingSlow,
+    ReadyForTrip,
+}
+
+impl AlertKind {
+    pub const ALL: [AlertKind; 6] = [
+        AlertKind::ChargeComplete,
+        AlertKind::OptimalCharge,
+        AlertKind::CustomLevel,
+        AlertKind::LowBattery,
+        AlertKind::ChargingSlow,
+        AlertKind::ReadyForTrip,
+    ];
+
+    /// UI severity: success, info or warning
+    pub fn severity(&self) -> &'static str {
+        match self {
+            AlertKind::ChargeComplete | AlertKind::ReadyForTrip => "success",
+            AlertKind::OptimalCharge | AlertKind::CustomLevel => "info",
+            AlertKind::LowBattery | AlertKind::ChargingSlow => "warning",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertState {
+    Active,
+    Acknowledged,
+    Resolved,
+}
+
+/// One occurrence of an alert
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertInstance {
+    pub id: String,
+    pub kind: AlertKind,
+    pub state: AlertState,
+    pub title: String,
+    pub message: String,
+    /// SOC when the alert fired
+    pub soc: i32,
+    pub created_at: i64,
+    pub acknowledged_at: Option<i64>,
+    pub resolved_at: Option<i64>,
+}
+
+impl AlertInstance {
+    pub fn is_open(&self) -> bool {
+        self.state != AlertState::Resolved
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        value["severity"] = self.kind.severity().into();
+        value
+    }
+}
+
+/// What the rules see of one status snapshot
+#[derive(Debug, Clone, Copy)]
+pub struct Observation<'a> {
+    pub timestamp: i64,
+    pub soc: i32,
+    pub charging: bool,
+    /// Session in progress after this snapshot
+    pub active_session: Option<&'a ChargingSession>,
+    /// Session this snapshot ended
+    pub finished_session: Option<&'a ChargingSession>,
+}
+
+/// Outcome of one rule for one snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Fire,
+    Clear,
+    /// Inside the hysteresis band: keep whatever state the alert has
+    Hold,
+}
+
+/// Rule for "charging reached `level`"
+fn level_reached(obs: &Observation, level: i32) -> Condition {
+    let charged = obs.charging || obs.finished_session.is_some();
+    if charged && obs.soc >= level {
+        Condition::Fire
+    } else if obs.soc < level - SOC_HYSTERESIS {
+        Condition::Clear
+    } else {
+        Condition::Hold
+    }
+}
+
+fn evaluate_rule(kind: AlertKind, config: &AlertConfig, obs: &Observation) -> Condition {
+    match kind {
+        AlertKind::ChargeComplete => level_reached(obs, FULL_SOC),
+        AlertKind::OptimalCharge => level_reached(obs, OPTIMAL_SOC),
+        AlertKind::CustomLevel => match config.custom_level {
+            Some(level) => level_reached(obs, i32::from(level)),
+            None => Condition::Clear,
+        },
+        AlertKind::LowBattery => {
+            if !obs.charging && obs.soc < LOW_BATTERY_SOC {
+                Condition::Fire
+            } else if obs.soc >= LOW_BATTERY_SOC + SOC_HYSTERESIS {
+                Condition::Clear
+            } else {
+                Condition::Hold
+            }
+        }
+        AlertKind::ChargingSlow => match obs.active_session {
+            Some(session) if obs.charging => {
+                let minutes = (obs.timestamp - session.started_at) / 60;
+                if minutes < SLOW_CHARGING_MIN_MINUTES {
+                    return Condition::Hold;
+                }
+                let rate = session.soc_added() as f64 / (minutes as f64 / 60.0);
+                if rate < SLOW_CHARGING_SOC_PER_HOUR {
+                    Condition::Fire
+                } else if rate >= SLOW_CHARGING_CLEAR_SOC_PER_HOUR {
+                    Condition::Clear
+                } else {
+                    Condition::Hold
+                }
+            }
+            _ => Condition::Clear,
+        },
+        AlertKind::ReadyForTrip => {
+            let level = config.trip_level();
+            match obs.finished_session {
+                Some(session) if session.end_soc >= level => Condition::Fire,
+                _ if obs.charging || obs.soc < level - SOC_HYSTERESIS => Condition::Clear,
+                _ => Condition::Hold,
+            }
+        }
+    }
+}
+
+fn describe(kind: AlertKind, config: &AlertConfig, obs: &Observation) -> (String, String) {
+    match kind {
+        AlertKind::ChargeComplete => (
+            "Charging Complete".to_string(),
+            "Your vehicle is fully charged and ready to go!".to_string(),
+        ),
+        AlertKind::OptimalCharge => (
+            "Optimal Charge Level Reached".to_string(),
+            format!("Battery at {}% - optimal for battery longevity", obs.soc),
+        ),
+        AlertKind::CustomLevel => (
+            "Charge Level Reached".to_string(),
+            format!(
+                "Battery at {}% (target {}%)",
+                obs.soc,
+                config.custom_level.unwrap_or_default()
+            ),
+        ),
+        AlertKind::LowBattery => (
+            "Low Battery".to_string(),
+            format!("Battery at {}% - consider charging soon", obs.soc),
+        ),
+        AlertKind::ChargingSlow => (
+            "Slow Charging".to_string(),
+            format!(
+                "Charging slower than {} % per hour (battery at {}%)",
+                SLOW_CHARGING_SOC_PER_HOUR, obs.soc
+            ),
+        ),
+        AlertKind::ReadyForTrip => (
+            "Ready for Trip".to_string(),
+            format!("Charging finished at {}%", obs.soc),
+        ),
+    }
+}
+
+/// Run all rules against one snapshot, updating `alerts` in place
+///
+/// Returns the alerts opened by this snapshot.
+pub fn evaluate(
+    config: &AlertConfig,
+    alerts: &mut Vec<AlertInstance>,
+    obs: &Observation,
+) -> Vec<AlertInstance> {
+    let mut opened = Vec::new();
+    for kind in AlertKind::ALL {
+        let condition = if config.is_enabled(kind) {
+            evaluate_rule(kind, config, obs)
+        } else {
+            Condition::Clear
+        };
+        let open = alerts.iter_mut().find(|a| a.kind == kind && a.is_open());
+
+        match (condition, open) {
+            (Condition::Fire, None) => {
+                let (title, message) = describe(kind, config, obs);
+                let alert = AlertInstance {
+                    id: Uuid::new_v4().to_string(),
+                    kind,
+                    state: AlertState::Active,
+                    title,
+                    message,
+                    soc: obs.soc,
+                    created_at: obs.timestamp,
+                    acknowledged_at: None,
+                    resolved_at: None,
+                };
+                alerts.push(alert.clone());
+                opened.push(alert);
+            }
+            (Condition::Clear, Some(alert)) => {
+                alert.state = AlertState::Resolved;
+                alert.resolved_at = Some(obs.timestamp);
+            }
+            _ => {}
+        }
+    }
+
+    let resolved = alerts.iter().filter(|a| !a.is_open()).count();
+    if resolved > MAX_RESOLVED_ALERTS {
+        let mut excess = resolved - MAX_RESOLVED_ALERTS;
+        alerts.retain(|a| {
+            if excess > 0 && !a.is_open() {
+                excess -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+    opened
+}
+
+/// Why an alert could not be acknowledged
+#[derive(Debug, Clone, PartialEq)]
+pub enum AckError {
+    NotFound,
+    Resolved,
+}
+
+/// Acknowledge an open alert; acknowledging twice is not an error
+pub fn acknowledge(
+    alerts: &mut [AlertInstance],
+    id: &str,
+    now: i64,
+) -> Result<AlertInstance, AckError> {
+    let alert = alerts
+        .iter_mut()
+        .find(|a| a.id == id)
+        .ok_or(AckError::NotFound)?;
+    match alert.state {
+        AlertState::Active => {
+            alert.state = AlertState::Acknowledged;
+            alert.acknowledged_at = Some(now);
+            Ok(alert.clone())
+        }
+        AlertState::Acknowledged => Ok(alert.clone()),
+        AlertState::Resolved => Err(AckError::Resolved),
+    }
+}
+
+fn config_key(username_hash: &str) -> String {
+    format!("{}{}", ALERT_CONFIG_KEY_PREFIX, username_hash)
+}
+
+fn alerts_key(username_hash: &str, vin: &str) -> String {
+    format!("{}{}_{}", ALERTS_KEY_PREFIX, username_hash, vin)
+}
+
+fn subscribers_key(vin: &str) -> String {
+    format!("{}{}", ALERT_SUBSCRIBERS_KEY_PREFIX, vin)
+}
+
+/// Load the alert configuration of a user, `None` if never saved
+pub async fn get_config(store: &Store, username_hash: &str) -> Option<AlertConfig> {
+    match store.get(&config_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Store the alert configuration and subscribe the user to the VIN
+pub async fn save_config(
+    store: &Store,
+    username_hash: &str,
+    vin: &str,
+    config: &AlertConfig,
+) -> anyhow::Result<()> {
+    store.set(&config_key(username_hash), &serde_json::to_vec(config)?)?;
+
+    let mut subscribers = load_subscribers(store, vin).await;
+    if !subscribers.iter().any(|s| s == username_hash) {
+        subscribers.push(username_hash.to_string());
+        store.set(&subscribers_key(vin), &serde_json::to_vec(&subscribers)?)?;
+    }
+    Ok(())
+}
+
+async fn load_subscribers(store: &Store, vin: &str) -> Vec<String> {
+    match store.get(&subscribers_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Alerts of a user for a VIN, oldest first
+pub async fn load_alerts(store: &Store, username_hash: &str, vin: &str) -> Vec<AlertInstance> {
+    match store.get(&alerts_key(username_hash, vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+pub async fn save_alerts(
+    store: &Store,
+    username_hash: &str,
+    vin: &str,
+    alerts: &[AlertInstance],
+) -> anyhow::Result<()> {
+    store.set(
+        &alerts_key(username_hash, vin),
+        &serde_json::to_vec(alerts)?,
+    )?;
+    Ok(())
+}
+
+/// Evaluate a new status snapshot for every user subscribed to the VIN
+///
+/// `transition` is the charging tracker's result for the same snapshot.
+pub async fn process_status_snapshot(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+    transition: Option<&ChargingTransition>,
+) -> anyhow::Result<Vec<(String, AlertInstance)>> {
+    let mut fired = Vec::new();
+    if record.kind != SnapshotKind::Status {
+        return Ok(fired);
+    }
+    let soc = match record.soc {
+        Some(soc) => soc,
+        None => return Ok(fired),
+    };
+    let subscribers = load_subscribers(store, vin).await;
+    if subscribers.is_empty() {
+        return Ok(fired);
+    }
+
+    let active_session = charging::load_state(store, vin).await.active;
+    let finished_session = match transition {
+        Some(ChargingTransition::Finished(session)) => Some(session),
+        _ => None,
+    };
+    let obs = Observation {
+        timestamp: record.timestamp,
+        soc,
+        charging: record
+            .charging_status
+            .as_deref()
+            .is_some_and(charging::is_actively_charging),
+        active_session: active_session.as_ref(),
+        finished_session,
+    };
+
+    for username_hash in &subscribers {
+        let config = get_config(store, username_hash).await.unwrap_or_default();
+        let mut alerts = load_alerts(store, username_hash, vin).await;
+        let before = alerts.clone();
+
+        for alert in evaluate(&config, &mut alerts, &obs) {
+            info!(vin = vin, kind = ?alert.kind, soc = alert.soc, "Alert fired");
+            fired.push((username_hash.clone(), alert));
+        }
+        if alerts != before {
+            save_alerts(store, username_hash, vin, &alerts).await?;
+        }
+    }
+    Ok(fired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::PlaceKind;
+
+    fn session(started_at: i64, start_soc: i32, end_soc: i32) -> ChargingSession {
+        ChargingSession {
+            id: "s".to_string(),
+            started_at,
+            ended_at: None,
+            last_seen_at: started_at,
+            start_soc,
+            end_soc,
+            place: PlaceKind::Home,
+            lat: None,
+            lon: None,
+            energy_added_kwh: None,
+        }
+    }
+
+    fn obs(timestamp: i64, soc: i32, charging: bool) -> Observation<'static> {
+        Observation {
+            timestamp,
+            soc,
+            charging,
+            active_session: None,
+            finished_session: None,
+        }
+    }
+
+    fn open_kinds(alerts: &[AlertInstance]) -> Vec<AlertKind> {
+        alerts
+            .iter()
+            .filter(|a| a.is_open())
+            .map(|a| a.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_hovering_soc_fires_once() {
+        let config = AlertConfig {
+            charge_complete: false,
+            optimal_charge: true,
+            ..AlertConfig::default()
+        };
+        let mut alerts = Vec::new();
+
+        // Charging to 80% fires once
+        assert_eq!(evaluate(&config, &mut alerts, &obs(0, 79, true)).len(), 0);
+        assert_eq!(evaluate(&config, &mut alerts, &obs(60, 80, true)).len(), 1);
+        // Hovering around the threshold neither resolves nor re-fires
+        assert!(evaluate(&config, &mut alerts, &obs(120, 78, false)).is_empty());
+        assert!(evaluate(&config, &mut alerts, &obs(180, 81, true)).is_empty());
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].state, AlertState::Active);
+
+        // Dropping below the hysteresis band resolves it, the next charge fires again
+        evaluate(&config, &mut alerts, &obs(240, 74, false));
+        assert_eq!(alerts[0].state, AlertState::Resolved);
+        assert_eq!(alerts[0].resolved_at, Some(240));
+        assert_eq!(evaluate(&config, &mut alerts, &obs(300, 80, true)).len(), 1);
+        assert_eq!(alerts.len(), 2);
+    }
+
+    #[test]
+    fn test_acknowledge() {
+        let config = AlertConfig {
+            low_battery: true,
+            ..AlertConfig::default()
+        };
+        let mut alerts = Vec::new();
+        evaluate(&config, &mut alerts, &obs(0, 15, false));
+        let id = alerts[0].id.clone();
+
+        let acked = acknowledge(&mut alerts, &id, 10).unwrap();
+        assert_eq!(acked.state, AlertState::Acknowledged);
+        assert_eq!(acked.acknowledged_at, Some(10));
+        // Acknowledged alerts stay open and are not fired again
+        assert!(evaluate(&config, &mut alerts, &obs(20, 14, false)).is_empty());
+        assert_eq!(
+            acknowledge(&mut alerts, &id, 30).unwrap().acknowledged_at,
+            Some(10)
+        );
+
+        evaluate(&config, &mut alerts, &obs(40, 30, true));
+        assert_eq!(acknowledge(&mut alerts, &id, 50), Err(AckError::Resolved));
+        assert_eq!(
+            acknowledge(&mut alerts, "missing", 50),
+            Err(AckError::NotFound)
+        );
+    }
+
+    #[test]
+    fn test_slow_charging_and_ready_for_trip() {
+        let config = AlertConfig {
+            charge_complete: false,
+            optimal_charge: false,
+            custom_level: Some(90),
+            charging_slow: true,
+            ready_for_trip: true,
+            ..AlertConfig::default()
+        };
+        let mut alerts = Vec::new();
+
+        // 2% in one hour is slow
+        let slow = session(0, 40, 42);
+        let charging = Observation {
+            active_session: Some(&slow),
+            ..obs(3600, 42, true)
+        };
+        evaluate(&config, &mut alerts, &charging);
+        assert_eq!(open_kinds(&alerts), vec![AlertKind::ChargingSlow]);
+
+        // Finishing at the custom level resolves the slow alert and reports
+        // both the level and readiness for the trip
+        let finished = session(0, 40, 90);
+        let done = Observation {
+            finished_session: Some(&finished),
+            ..obs(7200, 90, false)
+        };
+        evaluate(&config, &mut alerts, &done);
+        assert_eq!(
+            open_kinds(&alerts),
+            vec![AlertKind::CustomLevel, AlertKind::ReadyForTrip]
+        );
+
+        // Disabling a rule resolves its open alert
+        let config = AlertConfig {
+            ready_for_trip: false,
+            ..config
+        };
+        evaluate(&config, &mut alerts, &obs(7300, 90, false));
+        assert_eq!(open_kinds(&alerts), vec![AlertKind::CustomLevel]);
+    }
+
+    #[test]
+    fn test_config_validation() {
+        assert!(AlertConfig::default().validate().is_ok());
+        let config = AlertConfig {
+            custom_level: Some(30),
+            ..AlertConfig::default()
+        };
+        assert_eq!(config.validate().unwrap_err()[0].field, "custom_level");
+    }
+}
@@ -0,0 +1,398 @@
+// Security audit log
+//
+// Append-only record of account security events: logins, failed and blocked
+// logins, lockouts, token refreshes and logouts. Every event names the user
+// by username hash and carries the time, client IP, user agent, outcome and
+// request id.
+//
+// Events are stored as one list per user, so each user's log is trimmed to
+// the retention period (`audit_retention_days`) and a size cap on its own,
+// plus a capped list of the most recent events across all users for the
+// admin view. Nothing in the API edits or deletes individual events.
+//
+// Admin API actions are recorded with `ADMIN_ACTOR` as the user and the
+// affected user, VIN or circuit breaker as `target`. They go to their own
+// log (listed with `user=admin`) and, when a user is affected, to that
+// user's log as well.
+
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use tracing::warn;
+use uuid::Uuid;
+
+const AUDIT_KEY_PREFIX: &str = "audit_";
+const RECENT_KEY: &str = "audit_recent";
+
+/// User of admin action events; also selects the admin log
+pub const ADMIN_ACTOR: &str = "admin";
+
+/// Days of audit events kept when `audit_retention_days` is not set
+pub const AUDIT_RETENTION_DAYS_DEFAULT: i64 = 90;
+
+/// Events kept per user (oldest are dropped first)
+const MAX_EVENTS_PER_USER: usize = 500;
+
+/// Events kept in the cross-user list of the admin view
+const MAX_RECENT_EVENTS: usize = 1000;
+
+pub const AUDIT_PAGE_SIZE_DEFAULT: usize = 50;
+pub const AUDIT_PAGE_SIZE_MAX: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    /// Password login, successful, rejected or rate limited
+    Login,
+    /// Too many failed logins locked the account
+    Lockout,
+    /// Access token issued from a refresh token
+    TokenRefresh,
+    /// Token revoked by logging out
+    Logout,
+    /// Call to the admin API, `detail` names the action
+    AdminAction,
+}
+
+impl AuditEventKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+    /// Refused before credentials were checked (rate limit or lockout)
+    Blocked,
+}
+
+impl AuditOutcome {
+    pub fn parse(value: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(value.to_string())).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub id: String,
+    pub timestamp: i64,
+    pub username_hash: String,
+    pub kind: AuditEventKind,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Short reason, e.g. why a login failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// User hash, VIN or circuit breaker an admin action applied to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+/// Client details recorded with each event
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+}
+
+/// Longest user agent stored; longer values are cut
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
+impl ClientInfo {
+    /// `client_addr` is Spin's `spin-client-addr` header ("ip:port")
+    pub fn new(
+        client_addr: Option<&str>,
+        user_agent: Option<&str>,
+        request_id: Option<String>,
+    ) -> Self {
+        ClientInfo {
+            ip_address: client_addr.map(ip_of).filter(|ip| !ip.is_empty()),
+            user_agent: user_agent
+                .filter(|agent| !agent.is_empty())
+                .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            request_id,
+        }
+    }
+}
+
+/// IP part of an "ip:port" or "[ipv6]:port" address
+pub fn ip_of(addr: &str) -> String {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or_default().to_string();
+    }
+    match addr.rsplit_once(':') {
+        // A bare IPv6 address has more than one colon and no port
+        Some((ip, _)) if !ip.contains(':') => ip.to_string(),
+        _ => addr.to_string(),
+    }
+}
+
+impl AuditEvent {
+    pub fn new(
+        username_hash: &str,
+        kind: AuditEventKind,
+        outcome: AuditOutcome,
+        client: &ClientInfo,
+        detail: Option<&str>,
+        now: i64,
+    ) -> Self {
+        AuditEvent {
+            id: Uuid::new_v4().to_string(),
+            timestamp: now,
+            username_hash: username_hash.to_string(),
+            kind,
+            outcome,
+            ip_address: client.ip_address.clone(),
+            user_agent: client.user_agent.clone(),
+            request_id: client.request_id.clone(),
+            detail: detail.map(str::to_string),
+            target: None,
+        }
+    }
+
+    /// Admin API action; `action` is stored as the detail
+    pub fn admin_action(action: &str, target: Option<&str>, client: &ClientInfo, now: i64) -> Self {
+        AuditEvent {
+            target: target.map(str::to_string),
+            ..AuditEvent::new(
+                ADMIN_ACTOR,
+                AuditEventKind::AdminAction,
+                AuditOutcome::Success,
+                client,
+                Some(action),
+                now,
+            )
+        }
+    }
+}
+
+/// Filters of the audit endpoints
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditQuery {
+    pub kind: Option<AuditEventKind>,
+    pub outcome: Option<AuditOutcome>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        self.kind.is_none_or(|kind| event.kind == kind)
+            && self.outcome.is_none_or(|outcome| event.outcome == outcome)
+            && self.from.is_none_or(|from| event.timestamp >= from)
+            && self.to.is_none_or(|to| event.timestamp <= to)
+    }
+}
+
+/// Number of days to keep audit events, from the `audit_retention_days` variable
+pub fn get_retention_days() -> i64 {
+    variables::get("audit_retention_days")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(AUDIT_RETENTION_DAYS_DEFAULT)
+}
+
+fn user_key(username_hash: &str) -> String {
+    format!("{}{}", AUDIT_KEY_PREFIX, username_hash)
+}
+
+fn load_list(store: &Store, key: &str) -> Vec<AuditEvent> {
+    match store.get(key) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Append an event, dropping events older than `cutoff` and the oldest
+/// ones beyond `max`
+pub fn append(events: &mut Vec<AuditEvent>, event: AuditEvent, cutoff: i64, max: usize) {
+    events.retain(|e| e.timestamp >= cutoff);
+    events.push(event);
+    if events.len() > max {
+        let excess = events.len() - max;
+        events.drain(..excess);
+    }
+}
+
+async fn append_to(
+    store: &Store,
+    key: &str,
+    event: AuditEvent,
+    cutoff: i64,
+    max: usize,
+) -> anyhow::Result<()> {
+    let mut events = load_list(store, key);
+    append(&mut events, event, cutoff, max);
+    store.set(key, &serde_json::to_vec(&events)?)?;
+    Ok(())
+}
+
+/// Store an event in the user's log and the cross-user list
+///
+/// Failures are logged rather than returned so that auditing never turns
+/// a login or logout into an error.
+pub async fn record(store: &Store, event: AuditEvent, retention_days: i64) {
+    record_for(store, event, None, retention_days).await
+}
+
+/// Like `record`, and also store the event in the affected user's log
+pub async fn record_for(
+    store: &Store,
+    event: AuditEvent,
+    affected_user: Option<&str>,
+    retention_days: i64,
+) {
+    let cutoff = event.timestamp - retention_days * 86400;
+    let mut keys = vec![user_key(&event.username_hash)];
+    if let Some(username_hash) = affected_user {
+        keys.push(user_key(username_hash));
+    }
+    for key in keys {
+        if let Err(e) = append_to(store, &key, event.clone(), cutoff, MAX_EVENTS_PER_USER).await {
+            warn!(error = %e, kind = ?event.kind, "Failed to write audit event");
+        }
+    }
+    if let Err(e) = append_to(store, RECENT_KEY, event, cutoff, MAX_RECENT_EVENTS).await {
+        warn!(error = %e, "Failed to write audit event to the recent list");
+    }
+}
+
+/// The user's events matching `query`, newest first
+pub async fn load_user_events(
+    store: &Store,
+    username_hash: &str,
+    query: &AuditQuery,
+) -> Vec<AuditEvent> {
+    newest_first(load_list(store, &user_key(username_hash)), query)
+}
+
+/// Recent events of all users matching `query`, newest first
+pub async fn load_recent_events(store: &Store, query: &AuditQuery) -> Vec<AuditEvent> {
+    newest_first(load_list(store, RECENT_KEY), query)
+}
+
+fn newest_first(events: Vec<AuditEvent>, query: &AuditQuery) -> Vec<AuditEvent> {
+    events
+        .into_iter()
+        .rev()
+        .filter(|event| query.matches(event))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: AuditEventKind, outcome: AuditOutcome, timestamp: i64) -> AuditEvent {
+        AuditEvent::new(
+            "hash",
+            kind,
+            outcome,
+            &ClientInfo::default(),
+            None,
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn test_append_applies_retention_and_cap() {
+        let mut events = vec![
+            event(AuditEventKind::Login, AuditOutcome::Success, 100),
+            event(AuditEventKind::Login, AuditOutcome::Failure, 200),
+            event(AuditEventKind::Logout, AuditOutcome::Success, 300),
+        ];
+
+        append(
+            &mut events,
+            event(AuditEventKind::Login, AuditOutcome::Success, 400),
+            150,
+            10,
+        );
+        assert_eq!(
+            events.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            vec![200, 300, 400]
+        );
+
+        append(
+            &mut events,
+            event(AuditEventKind::Lockout, AuditOutcome::Blocked, 500),
+            0,
+            2,
+        );
+        assert_eq!(
+            events.iter().map(|e| e.timestamp).collect::<Vec<_>>(),
+            vec![400, 500]
+        );
+    }
+
+    #[test]
+    fn test_query_filters_and_order() {
+        let events = vec![
+            event(AuditEventKind::Login, AuditOutcome::Success, 100),
+            event(AuditEventKind::Login, AuditOutcome::Failure, 200),
+            event(AuditEventKind::TokenRefresh, AuditOutcome::Success, 300),
+        ];
+
+        let all = newest_first(events.clone(), &AuditQuery::default());
+        assert_eq!(all[0].timestamp, 300);
+
+        let logins = AuditQuery {
+            kind: AuditEventKind::parse("login"),
+            ..Default::default()
+        };
+        assert_eq!(newest_first(events.clone(), &logins).len(), 2);
+
+        let failed = AuditQuery {
+            kind: Some(AuditEventKind::Login),
+            outcome: AuditOutcome::parse("failure"),
+            from: Some(150),
+            to: None,
+        };
+        let matched = newest_first(events, &failed);
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].timestamp, 200);
+
+        assert_eq!(
+            AuditEventKind::parse("token_refresh"),
+            Some(AuditEventKind::TokenRefresh)
+        );
+        assert_eq!(AuditEventKind::parse("nope"), None);
+    }
+
+    #[test]
+    fn test_client_info() {
+        assert_eq!(ip_of("203.0.113.7:51234"), "203.0.113.7");
+        assert_eq!(ip_of("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(ip_of("2001:db8::1"), "2001:db8::1");
+
+        let long_agent = "a".repeat(1000);
+        let client = ClientInfo::new(Some("10.0.0.1:80"), Some(&long_agent), None);
+        assert_eq!(client.ip_address.as_deref(), Some("10.0.0.1"));
+        assert_eq!(client.user_agent.unwrap().len(), MAX_USER_AGENT_LENGTH);
+
+        let event = AuditEvent::new(
+            "hash",
+            AuditEventKind::Login,
+            AuditOutcome::Blocked,
+            &ClientInfo::new(None, Some(""), Some("req-1".to_string())),
+            Some("rate limited"),
+            10,
+        );
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["kind"], "login");
+        assert_eq!(json["outcome"], "blocked");
+        assert_eq!(json["request_id"], "req-1");
+        assert!(json.get("ip_address").is_none());
+        assert!(json.get("user_agent").is_none());
+    }
+}
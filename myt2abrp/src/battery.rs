@@ -0,0 +1,409 @@
+// Battery health estimation
+//
+// Records `ev_range` / `charge_remaining_amount` pairs from status snapshots
+// in a compact long-term series. Scaling the displayed range to 100% gives an
+// estimated full-charge range per sample; its monthly median, compared with a
+// baseline, yields a state-of-health trend. Equivalent full cycles come from
+// the SOC added over detected charging sessions, and the usable capacity from
+// the energy those sessions added per SOC percent; only without session
+// energy is it the nominal capacity scaled by the state of health.
+
+use crate::charging::ChargingSession;
+use crate::history::{HistoryRecord, SnapshotKind};
+use chrono::{DateTime, Datelike};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use std::collections::BTreeMap;
+
+const BATTERY_SAMPLES_KEY_PREFIX: &str = "battery_samples_";
+
+/// Samples kept per VIN (oldest are dropped first)
+const MAX_STORED_SAMPLES: usize = 5000;
+
+/// Minimum time between two stored samples
+const SAMPLE_MIN_INTERVAL_SECONDS: i64 = 3600;
+
+/// Below this SOC the range prediction is too noisy to extrapolate
+const MIN_SOC_FOR_SAMPLE: i32 = 20;
+
+/// Sample count and time span at which the estimate is fully trusted
+const FULL_CONFIDENCE_SAMPLES: f64 = 200.0;
+const FULL_CONFIDENCE_SPAN_DAYS: f64 = 90.0;
+
+/// Confidence penalty when the baseline is derived from the data itself
+const DERIVED_BASELINE_CONFIDENCE_FACTOR: f64 = 0.8;
+
+/// Sessions adding less SOC are too coarse for a capacity estimate
+const MIN_SOC_ADDED_FOR_CAPACITY: i32 = 10;
+
+/// Session count at which the capacity estimate is fully trusted
+const FULL_CONFIDENCE_SESSIONS: f64 = 10.0;
+
+/// One stored range/SOC pair
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BatterySample {
+    pub timestamp: i64,
+    pub soc: i32,
+    pub ev_range: f64,
+}
+
+impl BatterySample {
+    pub fn from_record(record: &HistoryRecord) -> Option<Self> {
+        if record.kind != SnapshotKind::Status {
+            return None;
+        }
+        let soc = record
+            .soc
+            .filter(|soc| (MIN_SOC_FOR_SAMPLE..=100).contains(soc))?;
+        let ev_range = record.ev_range.map(f64::from).filter(|r| *r > 0.0)?;
+        Some(BatterySample {
+            timestamp: record.timestamp,
+            soc,
+            ev_range,
+        })
+    }
+
+    /// Range the vehicle would predict at 100% SOC
+    pub fn full_range(&self) -> f64 {
+        self.ev_range / self.soc as f64 * 100.0
+    }
+}
+
+/// State of health for one calendar month (UTC)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MonthlyHealth {
+    pub month: String,
+    pub samples: usize,
+    pub full_range: f64,
+    pub state_of_health_percent: f64,
+}
+
+/// Result of the battery health estimation
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BatteryHealthEstimate {
+    pub state_of_health_percent: Option<f64>,
+    pub health_status: String,
+    pub estimated_usable_capacity_kwh: Option<f64>,
+    pub nominal_capacity_kwh: Option<f64>,
+    pub equivalent_full_cycles: f64,
+    /// 0.0 (no data) to 1.0 (many samples over a long period and, when the
+    /// capacity comes from charging sessions, many sessions)
+    pub confidence: f64,
+    pub sample_count: usize,
+    /// Charging sessions the usable capacity is computed from, 0 when it is
+    /// derived from the nominal capacity
+    pub capacity_session_count: usize,
+    pub baseline_full_range: Option<f64>,
+    pub trend: Vec<MonthlyHealth>,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+fn month_of(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| format!("{:04}-{:02}", dt.year(), dt.month()))
+        .unwrap_or_default()
+}
+
+/// Human-readable status for a state of health
+pub fn health_status(state_of_health_percent: Option<f64>) -> &'static str {
+    match state_of_health_percent {
+        Some(soh) if soh >= 95.0 => "Excellent",
+        Some(soh) if soh >= 85.0 => "Good",
+        Some(soh) if soh >= 75.0 => "Fair",
+        Some(_) => "Poor",
+        None => "Unknown",
+    }
+}
+
+/// Usable capacity from the energy added per SOC percent over finished
+/// charging sessions, with the number of sessions it is based on
+fn capacity_from_sessions(sessions: &[ChargingSession]) -> Option<(f64, usize)> {
+    let measured: Vec<(f64, i32)> = sessions
+        .iter()
+        .filter(|s| !s.is_in_progress() && s.soc_added() >= MIN_SOC_ADDED_FOR_CAPACITY)
+        .filter_map(|s| Some((s.energy_added_kwh?, s.soc_added())))
+        .collect();
+    if measured.is_empty() {
+        return None;
+    }
+    let energy: f64 = measured.iter().map(|(energy, _)| energy).sum();
+    let soc_added: i32 = measured.iter().map(|(_, soc_added)| soc_added).sum();
+    Some((energy / (soc_added as f64 / 100.0), measured.len()))
+}
+
+/// Estimate battery health from stored samples and charging sessions
+///
+/// Without `rated_full_range` the best monthly median serves as baseline,
+/// which assumes the battery was healthiest in that month.
+pub fn estimate(
+    samples: &[BatterySample],
+    sessions: &[ChargingSession],
+    rated_full_range: Option<f64>,
+    nominal_capacity_kwh: Option<f64>,
+) -> BatteryHealthEstimate {
+    let mut by_month: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    for sample in samples {
+        by_month
+            .entry(month_of(sample.timestamp))
+            .or_default()
+            .push(sample.full_range());
+    }
+    let medians: Vec<(String, usize, f64)> = by_month
+        .into_iter()
+        .map(|(month, mut ranges)| (month, ranges.len(), median(&mut ranges)))
+        .collect();
+
+    let baseline = rated_full_range.or_else(|| {
+        medians
+            .iter()
+            .map(|(_, _, full_range)| *full_range)
+            .max_by(|a, b| a.total_cmp(b))
+    });
+
+    let trend: Vec<MonthlyHealth> = match baseline {
+        Some(baseline) => medians
+            .into_iter()
+            .map(|(month, samples, full_range)| MonthlyHealth {
+                month,
+                samples,
+                full_range,
+                state_of_health_percent: (full_range / baseline * 100.0).min(100.0),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let state_of_health_percent = trend.last().map(|m| m.state_of_health_percent);
+    let equivalent_full_cycles = sessions.iter().map(|s| s.soc_added() as f64 / 100.0).sum();
+
+    let session_capacity = capacity_from_sessions(sessions);
+    let estimated_usable_capacity_kwh = match session_capacity {
+        Some((capacity, _)) => Some(capacity),
+        None => nominal_capacity_kwh
+            .zip(state_of_health_percent)
+            .map(|(capacity, soh)| capacity * soh / 100.0),
+    };
+    let capacity_session_count = session_capacity.map_or(0, |(_, count)| count);
+
+    let sample_confidence = match (samples.first(), samples.last()) {
+        (Some(first), Some(last)) => {
+            let span_days = (last.timestamp - first.timestamp).max(0) as f64 / 86_400.0;
+            let factor = if rated_full_range.is_some() {
+                1.0
+            } else {
+                DERIVED_BASELINE_CONFIDENCE_FACTOR
+            };
+            (samples.len() as f64 / FULL_CONFIDENCE_SAMPLES).min(1.0)
+                * (span_days / FULL_CONFIDENCE_SPAN_DAYS).min(1.0)
+                * factor
+        }
+        _ => 0.0,
+    };
+    // A session-based capacity weighs in as much as the range samples
+    let confidence = if capacity_session_count > 0 {
+        let session_confidence =
+            (capacity_session_count as f64 / FULL_CONFIDENCE_SESSIONS).min(1.0);
+        (sample_confidence + session_confidence) / 2.0
+    } else {
+        sample_confidence
+    };
+
+    BatteryHealthEstimate {
+        state_of_health_percent,
+        health_status: health_status(state_of_health_percent).to_string(),
+        estimated_usable_capacity_kwh,
+        nominal_capacity_kwh,
+        equivalent_full_cycles,
+        confidence: (confidence * 100.0).round() / 100.0,
+        sample_count: samples.len(),
+        capacity_session_count,
+        baseline_full_range: baseline,
+        trend,
+    }
+}
+
+/// Rated full-charge range from the `battery_rated_range_km` variable
+pub fn get_rated_range() -> Option<f64> {
+    variables::get("battery_rated_range_km")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|r| *r > 0.0)
+}
+
+fn samples_key(vin: &str) -> String {
+    format!("{}{}", BATTERY_SAMPLES_KEY_PREFIX, vin)
+}
+
+/// Stored samples of a VIN, oldest first
+pub async fn load_samples(store: &Store, vin: &str) -> Vec<BatterySample> {
+    match store.get(&samples_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Store a range/SOC pair from a status snapshot
+///
+/// Returns `Ok(false)` when the snapshot is unusable or too close to the
+/// previous sample.
+pub async fn record_sample(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+) -> anyhow::Result<bool> {
+    let sample = match BatterySample::from_record(record) {
+        Some(sample) => sample,
+        None => return Ok(false),
+    };
+
+    let mut samples = load_samples(store, vin).await;
+    if samples
+        .last()
+        .is_some_and(|last| sample.timestamp - last.timestamp < SAMPLE_MIN_INTERVAL_SECONDS)
+    {
+        return Ok(false);
+    }
+
+    samples.push(sample);
+    if samples.len() > MAX_STORED_SAMPLES {
+        let excess = samples.len() - MAX_STORED_SAMPLES;
+        samples.drain(..excess);
+    }
+    store.set(&samples_key(vin), &serde_json::to_vec(&samples)?)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::PlaceKind;
+
+    const DAY: i64 = 86_400;
+    // 2024-01-01T00:00:00Z
+    const JAN_2024: i64 = 1_704_067_200;
+
+    fn sample(timestamp: i64, soc: i32, ev_range: f64) -> BatterySample {
+        BatterySample {
+            timestamp,
+            soc,
+            ev_range,
+        }
+    }
+
+    fn session(start_soc: i32, end_soc: i32) -> ChargingSession {
+        ChargingSession {
+            id: "s".to_string(),
+            started_at: 0,
+            ended_at: Some(3600),
+            last_seen_at: 3600,
+            start_soc,
+            end_soc,
+            place: PlaceKind::Home,
+            lat: None,
+            lon: None,
+            energy_added_kwh: None,
+        }
+    }
+
+    #[test]
+    fn test_sample_from_record() {
+        let record = HistoryRecord {
+            soc: Some(50),
+            ev_range: Some(200.0),
+            ..HistoryRecord::empty(1000, SnapshotKind::Status)
+        };
+        let parsed = BatterySample::from_record(&record).unwrap();
+        assert_eq!(parsed.full_range(), 400.0);
+
+        let low_soc = HistoryRecord {
+            soc: Some(10),
+            ..record.clone()
+        };
+        assert_eq!(BatterySample::from_record(&low_soc), None);
+
+        let no_range = HistoryRecord {
+            ev_range: None,
+            ..record
+        };
+        assert_eq!(BatterySample::from_record(&no_range), None);
+    }
+
+    #[test]
+    fn test_estimate_trend_with_rated_range() {
+        let samples = vec![
+            sample(JAN_2024, 50, 200.0),
+            sample(JAN_2024 + DAY, 80, 320.0),
+            sample(JAN_2024 + 40 * DAY, 50, 190.0),
+            sample(JAN_2024 + 41 * DAY, 100, 380.0),
+        ];
+        let sessions = vec![session(20, 80), session(50, 90)];
+
+        let health = estimate(&samples, &sessions, Some(400.0), Some(60.0));
+        assert_eq!(health.trend.len(), 2);
+        assert_eq!(health.trend[0].month, "2024-01");
+        assert_eq!(health.trend[0].state_of_health_percent, 100.0);
+        assert_eq!(health.trend[1].month, "2024-02");
+        assert_eq!(health.state_of_health_percent, Some(95.0));
+        assert_eq!(health.health_status, "Excellent");
+        // Sessions without energy: nominal capacity scaled by the state of health
+        assert_eq!(health.estimated_usable_capacity_kwh, Some(57.0));
+        assert_eq!(health.capacity_session_count, 0);
+        assert!((health.equivalent_full_cycles - 1.0).abs() < 1e-9);
+        assert!(health.confidence > 0.0 && health.confidence < 1.0);
+    }
+
+    #[test]
+    fn test_estimate_capacity_from_sessions() {
+        let samples = vec![sample(JAN_2024, 50, 200.0)];
+        let with_energy = |start_soc, end_soc, energy| ChargingSession {
+            energy_added_kwh: Some(energy),
+            ..session(start_soc, end_soc)
+        };
+        let in_progress = ChargingSession {
+            ended_at: None,
+            ..with_energy(10, 90, 10.0)
+        };
+        let sessions = vec![
+            with_energy(20, 80, 33.0),
+            with_energy(50, 90, 22.0),
+            // Too little SOC added, in progress, or without energy: ignored
+            with_energy(70, 75, 10.0),
+            in_progress,
+            session(10, 90),
+        ];
+
+        let health = estimate(&samples, &sessions, Some(400.0), Some(60.0));
+        assert_eq!(health.capacity_session_count, 2);
+        assert!((health.estimated_usable_capacity_kwh.unwrap() - 55.0).abs() < 1e-9);
+        // No span of samples yet, so the sessions carry the confidence
+        assert_eq!(health.confidence, 0.1);
+    }
+
+    #[test]
+    fn test_estimate_derived_baseline_and_empty() {
+        let samples = vec![
+            sample(JAN_2024, 50, 200.0),
+            sample(JAN_2024 + 40 * DAY, 50, 170.0),
+        ];
+        let health = estimate(&samples, &[], None, None);
+        assert_eq!(health.baseline_full_range, Some(400.0));
+        assert_eq!(health.state_of_health_percent, Some(85.0));
+        assert_eq!(health.health_status, "Good");
+        assert_eq!(health.estimated_usable_capacity_kwh, None);
+
+        let empty = estimate(&[], &[], None, Some(60.0));
+        assert_eq!(empty.state_of_health_percent, None);
+        assert_eq!(empty.health_status, "Unknown");
+        assert_eq!(empty.confidence, 0.0);
+    }
+}
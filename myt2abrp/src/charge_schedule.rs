@@ -0,0 +1,340 @@
+// Charge timers
+//
+// Toyota stores a vehicle's charge timers as one list, so every change reads
+// the current schedule, edits the list, validates it and writes the whole
+// list back. The schedule is then read again and returned, so the client
+// sees what the vehicle actually applied rather than what was requested.
+//
+// Timers are addressed by Toyota's timer id, or by their position (1-based)
+// for vehicles that do not report ids. Times are "HH:MM" in the vehicle's
+// local time; a window may run past midnight.
+
+use crate::commands::{TARGET_SOC_MAX, TARGET_SOC_MIN};
+use myt::{ChargeDay, ChargeSchedule, ChargeTimer};
+use serde::Deserialize;
+
+/// Timers per vehicle when the vehicle does not report its limit
+pub const DEFAULT_MAX_TIMERS: usize = 5;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+const DAYS: [(ChargeDay, &str); 7] = [
+    (ChargeDay::Monday, "monday"),
+    (ChargeDay::Tuesday, "tuesday"),
+    (ChargeDay::Wednesday, "wednesday"),
+    (ChargeDay::Thursday, "thursday"),
+    (ChargeDay::Friday, "friday"),
+    (ChargeDay::Saturday, "saturday"),
+    (ChargeDay::Sunday, "sunday"),
+];
+
+/// Body of `POST /vehicles/{vin}/charge-schedule` and
+/// `PUT /vehicles/{vin}/charge-schedule/{id}`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChargeTimerRequest {
+    #[serde(default = "enabled_default")]
+    pub enabled: bool,
+    /// "HH:MM"; charging starts at this time
+    pub start_time: Option<String>,
+    /// "HH:MM"; charging ends at, or without a start time is done by, this time
+    pub end_time: Option<String>,
+    /// Day names ("monday") or abbreviations ("mon")
+    pub days: Vec<String>,
+    pub target_soc: Option<i32>,
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// Minutes since midnight of an "HH:MM" time
+pub fn parse_time(value: &str) -> Option<u16> {
+    let (hours, minutes) = value.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let hours: u16 = hours.parse().ok()?;
+    let minutes: u16 = minutes.parse().ok()?;
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+fn format_time(minutes: u16) -> String {
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+fn parse_day(value: &str) -> Option<ChargeDay> {
+    let value = value.to_ascii_lowercase();
+    DAYS.iter()
+        .find(|(_, name)| *name == value || (value.len() == 3 && name.starts_with(&value)))
+        .map(|(day, _)| *day)
+}
+
+fn day_name(day: ChargeDay) -> &'static str {
+    DAYS.iter()
+        .find(|(d, _)| *d == day)
+        .map(|(_, name)| *name)
+        .unwrap_or_default()
+}
+
+impl ChargeTimerRequest {
+    /// Validate the request and turn it into a Toyota timer
+    pub fn into_timer(self, timer_id: Option<String>) -> Result<ChargeTimer, String> {
+        let parse = |field: &str, value: &Option<String>| match value {
+            Some(value) => parse_time(value)
+                .map(Some)
+                .ok_or_else(|| format!("{} must be a time between 00:00 and 23:59", field)),
+            None => Ok(None),
+        };
+        let start = parse("start_time", &self.start_time)?;
+        let end = parse("end_time", &self.end_time)?;
+        match (start, end) {
+            (None, None) => return Err("start_time or end_time is required".to_string()),
+            (Some(start), Some(end)) if start == end => {
+                return Err("start_time and end_time must differ".to_string())
+            }
+            _ => {}
+        }
+
+        if self.days.is_empty() {
+            return Err("days must name at least one day".to_string());
+        }
+        let mut days = Vec::with_capacity(self.days.len());
+        for name in &self.days {
+            let day = parse_day(name).ok_or_else(|| format!("Unknown day: {}", name))?;
+            if days.contains(&day) {
+                return Err(format!("{} is listed more than once", day_name(day)));
+            }
+            days.push(day);
+        }
+        days.sort();
+
+        if let Some(soc) = self.target_soc {
+            if !(TARGET_SOC_MIN..=TARGET_SOC_MAX).contains(&soc) {
+                return Err(format!(
+                    "target_soc must be between {} and {}",
+                    TARGET_SOC_MIN, TARGET_SOC_MAX
+                ));
+            }
+        }
+
+        Ok(ChargeTimer {
+            timer_id,
+            enabled: self.enabled,
+            start_time: start.map(format_time),
+            end_time: end.map(format_time),
+            days,
+            charge_limit: self.target_soc,
+        })
+    }
+}
+
+/// Charging windows of a timer in minutes since midnight, split at midnight
+///
+/// Timers with only one of the two times have no fixed window.
+fn windows(timer: &ChargeTimer) -> Vec<(u16, u16)> {
+    let start = timer.start_time.as_deref().and_then(parse_time);
+    let end = timer.end_time.as_deref().and_then(parse_time);
+    match (start, end) {
+        (Some(start), Some(end)) if start < end => vec![(start, end)],
+        (Some(start), Some(end)) => vec![(start, MINUTES_PER_DAY), (0, end)],
+        _ => Vec::new(),
+    }
+}
+
+/// Check the full list before it is written to the vehicle
+///
+/// Enabled timers must not overlap on a shared day.
+pub fn validate_schedule(timers: &[ChargeTimer], max_timers: usize) -> Result<(), String> {
+    if timers.len() > max_timers {
+        return Err(format!("The vehicle stores at most {} timers", max_timers));
+    }
+
+    let enabled: Vec<&ChargeTimer> = timers.iter().filter(|t| t.enabled).collect();
+    for (i, a) in enabled.iter().enumerate() {
+        for b in &enabled[i + 1..] {
+            let Some(day) = a.days.iter().find(|day| b.days.contains(day)) else {
+                continue;
+            };
+            let overlapping = windows(a).iter().any(|&(a_start, a_end)| {
+                windows(b)
+                    .iter()
+                    .any(|&(b_start, b_end)| a_start < b_end && b_start < a_end)
+            });
+            if overlapping {
+                return Err(format!("Timers overlap on {}", day_name(*day)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Maximum number of timers for a schedule
+pub fn max_timers(schedule: &ChargeSchedule) -> usize {
+    schedule
+        .max_timers
+        .map(|max| max as usize)
+        .unwrap_or(DEFAULT_MAX_TIMERS)
+}
+
+/// Id a timer is addressed by: Toyota's id or its position
+fn timer_id(index: usize, timer: &ChargeTimer) -> String {
+    timer
+        .timer_id
+        .clone()
+        .unwrap_or_else(|| (index + 1).to_string())
+}
+
+/// Index of the timer with the given id
+pub fn position(schedule: &ChargeSchedule, id: &str) -> Option<usize> {
+    schedule
+        .timers
+        .iter()
+        .enumerate()
+        .position(|(index, timer)| timer_id(index, timer) == id)
+}
+
+/// Whether the vehicle applied the requested timers (ids aside)
+pub fn is_applied(requested: &[ChargeTimer], effective: &[ChargeTimer]) -> bool {
+    let without_id = |timer: &ChargeTimer| ChargeTimer {
+        timer_id: None,
+        ..timer.clone()
+    };
+    requested.len() == effective.len()
+        && requested.iter().all(|requested| {
+            let requested = without_id(requested);
+            effective.iter().any(|timer| without_id(timer) == requested)
+        })
+}
+
+pub fn timer_to_json(index: usize, timer: &ChargeTimer) -> serde_json::Value {
+    serde_json::json!({
+        "id": timer_id(index, timer),
+        "enabled": timer.enabled,
+        "start_time": timer.start_time,
+        "end_time": timer.end_time,
+        "days": timer.days.iter().map(|day| day_name(*day)).collect::<Vec<_>>(),
+        "target_soc": timer.charge_limit,
+    })
+}
+
+pub fn schedule_to_json(vin: &str, schedule: &ChargeSchedule) -> serde_json::Value {
+    serde_json::json!({
+        "vin": vin,
+        "max_timers": max_timers(schedule),
+        "timers": schedule
+            .timers
+            .iter()
+            .enumerate()
+            .map(|(index, timer)| timer_to_json(index, timer))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(start: Option<&str>, end: Option<&str>, days: &[&str]) -> ChargeTimerRequest {
+        ChargeTimerRequest {
+            enabled: true,
+            start_time: start.map(str::to_string),
+            end_time: end.map(str::to_string),
+            days: days.iter().map(|d| d.to_string()).collect(),
+            target_soc: None,
+        }
+    }
+
+    #[test]
+    fn test_timer_request_validation() {
+        let timer = request(Some("22:30"), Some("06:00"), &["fri", "Monday"])
+            .into_timer(None)
+            .unwrap();
+        assert_eq!(timer.days, vec![ChargeDay::Monday, ChargeDay::Friday]);
+        assert_eq!(timer.start_time.as_deref(), Some("22:30"));
+
+        assert!(request(None, Some("07:00"), &["sat"])
+            .into_timer(None)
+            .is_ok());
+        assert!(request(None, None, &["mon"]).into_timer(None).is_err());
+        assert!(request(Some("24:00"), None, &["mon"])
+            .into_timer(None)
+            .is_err());
+        assert!(request(Some("7:00"), None, &["mon"])
+            .into_timer(None)
+            .is_err());
+        assert!(request(Some("07:00"), Some("07:00"), &["mon"])
+            .into_timer(None)
+            .is_err());
+        assert!(request(Some("07:00"), None, &[]).into_timer(None).is_err());
+        assert!(request(Some("07:00"), None, &["mon", "monday"])
+            .into_timer(None)
+            .is_err());
+        assert!(request(Some("07:00"), None, &["someday"])
+            .into_timer(None)
+            .is_err());
+
+        let mut too_low = request(Some("07:00"), None, &["mon"]);
+        too_low.target_soc = Some(20);
+        assert!(too_low.into_timer(None).is_err());
+    }
+
+    #[test]
+    fn test_validate_schedule() {
+        let timer = |start, end, days: &[&str]| {
+            request(Some(start), Some(end), days)
+                .into_timer(None)
+                .unwrap()
+        };
+        let night = timer("22:00", "06:00", &["mon", "tue"]);
+        let morning = timer("05:00", "08:00", &["tue"]);
+        let evening = timer("18:00", "21:00", &["tue"]);
+
+        assert!(validate_schedule(&[night.clone(), evening.clone()], 5).is_ok());
+        // The night window runs past midnight into the morning one
+        assert_eq!(
+            validate_schedule(&[night.clone(), morning.clone()], 5),
+            Err("Timers overlap on tuesday".to_string())
+        );
+        // Disabled timers and different days do not conflict
+        let disabled = ChargeTimer {
+            enabled: false,
+            ..morning.clone()
+        };
+        assert!(validate_schedule(&[night.clone(), disabled], 5).is_ok());
+        let wednesday = timer("05:00", "08:00", &["wed"]);
+        assert!(validate_schedule(&[night.clone(), wednesday], 5).is_ok());
+
+        assert!(validate_schedule(&[night, evening], 1).is_err());
+    }
+
+    #[test]
+    fn test_timer_ids_and_read_back() {
+        let mut schedule = ChargeSchedule {
+            timers: vec![
+                request(Some("01:00"), Some("05:00"), &["sun"])
+                    .into_timer(Some("abc".to_string()))
+                    .unwrap(),
+                request(None, Some("07:00"), &["mon"])
+                    .into_timer(None)
+                    .unwrap(),
+            ],
+            max_timers: None,
+        };
+        assert_eq!(position(&schedule, "abc"), Some(0));
+        assert_eq!(position(&schedule, "2"), Some(1));
+        assert_eq!(position(&schedule, "1"), None);
+        assert_eq!(schedule_to_json("VIN", &schedule)["max_timers"], 5);
+        assert_eq!(
+            schedule_to_json("VIN", &schedule)["timers"][1]["days"][0],
+            "monday"
+        );
+
+        let requested = schedule.timers.clone();
+        // Toyota assigned an id to the new timer
+        schedule.timers[1].timer_id = Some("def".to_string());
+        assert!(is_applied(&requested, &schedule.timers));
+        schedule.timers[1].enabled = false;
+        assert!(!is_applied(&requested, &schedule.timers));
+    }
+}
@@ -0,0 +1,497 @@
+// Charging session detection
+//
+// Tracks charging sessions from consecutive status snapshots: a session opens
+// when the charging status transitions into charging and closes on the
+// transition out. Each session records start/end SOC, duration, where the
+// car was charged (home/away) and an energy estimate based on the configured
+// usable battery capacity.
+
+use crate::geo::{HomeLocation, PlaceKind};
+use crate::history::{self, HistoryRecord, SnapshotKind};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+const CHARGING_STATE_KEY_PREFIX: &str = "charging_state_";
+const CHARGING_SESSIONS_KEY_PREFIX: &str = "charging_sessions_";
+
+/// Closed sessions kept per VIN (oldest are dropped first)
+const MAX_STORED_SESSIONS: usize = 1000;
+
+/// How old a location snapshot may be to place a session start
+const LOCATION_MAX_AGE_SECONDS: i64 = 3600;
+
+/// Default and maximum page size for session listings
+pub const SESSIONS_PAGE_SIZE_DEFAULT: usize = 20;
+pub const SESSIONS_PAGE_SIZE_MAX: usize = 200;
+
+/// One charging session
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChargingSession {
+    pub id: String,
+    /// Unix timestamp of the first charging snapshot
+    pub started_at: i64,
+    /// Unix timestamp of the first non-charging snapshot, `None` while charging
+    pub ended_at: Option<i64>,
+    /// Unix timestamp of the latest snapshot seen during the session
+    pub last_seen_at: i64,
+    pub start_soc: i32,
+    pub end_soc: i32,
+    pub place: PlaceKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+    /// Estimated energy added, requires `battery_capacity_kwh`
+    pub energy_added_kwh: Option<f64>,
+}
+
+impl ChargingSession {
+    pub fn is_in_progress(&self) -> bool {
+        self.ended_at.is_none()
+    }
+
+    pub fn soc_added(&self) -> i32 {
+        (self.end_soc - self.start_soc).max(0)
+    }
+
+    pub fn duration_minutes(&self) -> i64 {
+        (self.ended_at.unwrap_or(self.last_seen_at) - self.started_at).max(0) / 60
+    }
+
+    fn update_energy(&mut self, capacity_kwh: Option<f64>) {
+        self.energy_added_kwh =
+            capacity_kwh.map(|capacity| capacity * self.soc_added() as f64 / 100.0);
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(object) = value.as_object_mut() {
+            object.insert("in_progress".into(), self.is_in_progress().into());
+            object.insert("duration_minutes".into(), self.duration_minutes().into());
+            object.insert("soc_added".into(), self.soc_added().into());
+        }
+        value
+    }
+}
+
+/// Per-VIN tracker state persisted between snapshots
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ChargingTrackerState {
+    pub active: Option<ChargingSession>,
+    pub last_timestamp: i64,
+}
+
+/// Result of feeding a snapshot into the tracker
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChargingTransition {
+    Started(ChargingSession),
+    Finished(ChargingSession),
+}
+
+/// Whether a Toyota charging status means energy is flowing right now
+///
+/// Statuses such as `NOT_CHARGING` or `CHARGING_COMPLETE` also contain the
+/// word "CHARGING", so those are excluded explicitly.
+pub fn is_actively_charging(charging_status: &str) -> bool {
+    let status = charging_status.to_uppercase();
+    status.contains("CHARGING")
+        && !status.contains("NOT")
+        && !status.contains("COMPLETE")
+        && !status.contains("STOP")
+}
+
+/// Whether a Toyota charging status means a cable is connected
+///
+/// Covers active charging as well as plugged-in states such as `CONNECTED`,
+/// `PLUGGED_IN` or `CHARGING_COMPLETE`.
+pub fn is_plugged_in(charging_status: &str) -> bool {
+    let status = charging_status.to_uppercase();
+    if status.contains("NOT") || status.contains("UNPLUG") || status.contains("DISCONNECT") {
+        return false;
+    }
+    is_actively_charging(&status)
+        || status.contains("PLUG")
+        || status.contains("CONNECT")
+        || status.contains("COMPLETE")
+}
+
+/// Feed one status snapshot into the tracker
+///
+/// `position` is only consulted when a new session starts.
+pub fn apply_status(
+    state: &mut ChargingTrackerState,
+    record: &HistoryRecord,
+    home: Option<&HomeLocation>,
+    position: Option<(f64, f64)>,
+    capacity_kwh: Option<f64>,
+) -> Option<ChargingTransition> {
+    if record.kind != SnapshotKind::Status || record.timestamp <= state.last_timestamp {
+        return None;
+    }
+    let soc = record.soc?;
+    state.last_timestamp = record.timestamp;
+
+    let charging = record
+        .charging_status
+        .as_deref()
+        .is_some_and(is_actively_charging);
+
+    match (state.active.take(), charging) {
+        (None, true) => {
+            let mut session = ChargingSession {
+                id: Uuid::new_v4().to_string(),
+                started_at: record.timestamp,
+                ended_at: None,
+                last_seen_at: record.timestamp,
+                start_soc: soc,
+                end_soc: soc,
+                place: HomeLocation::classify(home, position),
+                lat: position.map(|(lat, _)| lat),
+                lon: position.map(|(_, lon)| lon),
+                energy_added_kwh: None,
+            };
+            session.update_energy(capacity_kwh);
+            state.active = Some(session.clone());
+            Some(ChargingTransition::Started(session))
+        }
+        (Some(mut session), true) => {
+            session.end_soc = soc;
+            session.last_seen_at = record.timestamp;
+            session.update_energy(capacity_kwh);
+            state.active = Some(session);
+            None
+        }
+        (Some(mut session), false) => {
+            session.end_soc = soc;
+            session.last_seen_at = record.timestamp;
+            session.ended_at = Some(record.timestamp);
+            session.update_energy(capacity_kwh);
+            Some(ChargingTransition::Finished(session))
+        }
+        (None, false) => None,
+    }
+}
+
+/// Usable battery capacity from the `battery_capacity_kwh` variable
+pub fn get_battery_capacity_kwh() -> Option<f64> {
+    variables::get("battery_capacity_kwh")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|c| *c > 0.0)
+}
+
+fn state_key(vin: &str) -> String {
+    format!("{}{}", CHARGING_STATE_KEY_PREFIX, vin)
+}
+
+fn sessions_key(vin: &str) -> String {
+    format!("{}{}", CHARGING_SESSIONS_KEY_PREFIX, vin)
+}
+
+pub async fn load_state(store: &Store, vin: &str) -> ChargingTrackerState {
+    match store.get(&state_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => ChargingTrackerState::default(),
+    }
+}
+
+/// Closed sessions of a VIN, oldest first
+pub async fn load_sessions(store: &Store, vin: &str) -> Vec<ChargingSession> {
+    match store.get(&sessions_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Run a new status snapshot through the tracker and persist the result
+pub async fn process_status_snapshot(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+) -> anyhow::Result<Option<ChargingTransition>> {
+    let mut state = load_state(store, vin).await;
+
+    let position = if state.active.is_none() {
+        history::latest_record(
+            store,
+            vin,
+            SnapshotKind::Location,
+            record.timestamp,
+            LOCATION_MAX_AGE_SECONDS,
+        )
+        .await
+        .and_then(|r| Some((r.lat?, r.lon?)))
+    } else {
+        None
+    };
+
+    let home = HomeLocation::from_variables();
+    let transition = apply_status(
+        &mut state,
+        record,
+        home.as_ref(),
+        position,
+        get_battery_capacity_kwh(),
+    );
+
+    match &transition {
+        Some(ChargingTransition::Started(session)) => {
+            info!(
+                vin = vin,
+                start_soc = session.start_soc,
+                "Charging session started"
+            );
+        }
+        Some(ChargingTransition::Finished(session)) => {
+            info!(
+                vin = vin,
+                start_soc = session.start_soc,
+                end_soc = session.end_soc,
+                duration_minutes = session.duration_minutes(),
+                "Charging session finished"
+            );
+            let mut sessions = load_sessions(store, vin).await;
+            sessions.push(session.clone());
+            if sessions.len() > MAX_STORED_SESSIONS {
+                let excess = sessions.len() - MAX_STORED_SESSIONS;
+                sessions.drain(..excess);
+            }
+            store.set(&sessions_key(vin), &serde_json::to_vec(&sessions)?)?;
+        }
+        None => debug!("No charging transition for VIN {}", vin),
+    }
+
+    store.set(&state_key(vin), &serde_json::to_vec(&state)?)?;
+    Ok(transition)
+}
+
+/// All sessions of a VIN, newest first, including one in progress
+pub async fn all_sessions_newest_first(store: &Store, vin: &str) -> Vec<ChargingSession> {
+    let mut sessions = load_sessions(store, vin).await;
+    if let Some(active) = load_state(store, vin).await.active {
+        sessions.push(active);
+    }
+    sessions.reverse();
+    sessions
+}
+
+/// Recompute the energy of `sessions` with a user's battery capacity
+///
+/// Energy is stored with the `battery_capacity_kwh` variable when a session
+/// ends; a capacity from the user's settings replaces it. Without one the
+/// stored values are kept.
+pub fn with_capacity(
+    mut sessions: Vec<ChargingSession>,
+    capacity_kwh: Option<f64>,
+) -> Vec<ChargingSession> {
+    if capacity_kwh.is_some() {
+        for session in &mut sessions {
+            session.update_energy(capacity_kwh);
+        }
+    }
+    sessions
+}
+
+/// Sessions that started within `[from, to]`, newest first
+pub fn filter_sessions(
+    sessions: Vec<ChargingSession>,
+    from: Option<i64>,
+    to: Option<i64>,
+) -> Vec<ChargingSession> {
+    sessions
+        .into_iter()
+        .filter(|s| from.is_none_or(|from| s.started_at >= from))
+        .filter(|s| to.is_none_or(|to| s.started_at <= to))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::status_record;
+
+    #[test]
+    fn test_is_actively_charging() {
+        assert!(is_actively_charging("CHARGING"));
+        assert!(is_actively_charging("charging"));
+        assert!(!is_actively_charging("NOT_CHARGING"));
+        assert!(!is_actively_charging("CHARGING_COMPLETE"));
+        assert!(!is_actively_charging("CONNECTED"));
+    }
+
+    #[test]
+    fn test_is_plugged_in() {
+        assert!(is_plugged_in("CHARGING"));
+        assert!(is_plugged_in("CONNECTED"));
+        assert!(is_plugged_in("plugged_in"));
+        assert!(is_plugged_in("CHARGING_COMPLETE"));
+        assert!(!is_plugged_in("NOT_CHARGING"));
+        assert!(!is_plugged_in("DISCONNECTED"));
+        assert!(!is_plugged_in("UNPLUGGED"));
+        assert!(!is_plugged_in("NONE"));
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let home = HomeLocation {
+            lat: 52.52,
+            lon: 13.405,
+            radius_meters: 150.0,
+        };
+        let mut state = ChargingTrackerState::default();
+
+        assert_eq!(
+            apply_status(
+                &mut state,
+                &status_record(1000, 40, "NOT_CHARGING"),
+                None,
+                None,
+                None
+            ),
+            None
+        );
+
+        let started = apply_status(
+            &mut state,
+            &status_record(2000, 41, "CHARGING"),
+            Some(&home),
+            Some((52.5201, 13.4051)),
+            Some(60.0),
+        );
+        match started {
+            Some(ChargingTransition::Started(session)) => {
+                assert_eq!(session.start_soc, 41);
+                assert_eq!(session.place, PlaceKind::Home);
+            }
+            other => panic!("expected start, got {:?}", other),
+        }
+
+        assert_eq!(
+            apply_status(
+                &mut state,
+                &status_record(3800, 60, "CHARGING"),
+                None,
+                None,
+                Some(60.0)
+            ),
+            None
+        );
+
+        let finished = apply_status(
+            &mut state,
+            &status_record(5600, 80, "CHARGING_COMPLETE"),
+            None,
+            None,
+            Some(60.0),
+        );
+        match finished {
+            Some(ChargingTransition::Finished(session)) => {
+                assert_eq!(session.start_soc, 41);
+                assert_eq!(session.end_soc, 80);
+                assert_eq!(session.ended_at, Some(5600));
+                assert_eq!(session.duration_minutes(), 60);
+                assert_eq!(session.place, PlaceKind::Home);
+                let energy = session.energy_added_kwh.unwrap();
+                assert!((energy - 23.4).abs() < 0.001);
+            }
+            other => panic!("expected finish, got {:?}", other),
+        }
+        assert!(state.active.is_none());
+    }
+
+    #[test]
+    fn test_out_of_order_snapshots_ignored() {
+        let mut state = ChargingTrackerState::default();
+        apply_status(
+            &mut state,
+            &status_record(2000, 50, "CHARGING"),
+            None,
+            None,
+            None,
+        );
+
+        // An older snapshot must not close the session
+        assert_eq!(
+            apply_status(
+                &mut state,
+                &status_record(1500, 45, "NOT_CHARGING"),
+                None,
+                None,
+                None
+            ),
+            None
+        );
+        assert!(state.active.is_some());
+    }
+
+    #[test]
+    fn test_session_without_capacity_has_no_energy() {
+        let mut state = ChargingTrackerState::default();
+        apply_status(
+            &mut state,
+            &status_record(1000, 20, "CHARGING"),
+            None,
+            None,
+            None,
+        );
+        let session = state.active.clone().unwrap();
+
+        assert_eq!(session.energy_added_kwh, None);
+        assert_eq!(session.place, PlaceKind::Unknown);
+        assert!(session.is_in_progress());
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut state = ChargingTrackerState::default();
+        apply_status(
+            &mut state,
+            &status_record(1000, 20, "CHARGING"),
+            None,
+            None,
+            None,
+        );
+        apply_status(
+            &mut state,
+            &status_record(2000, 70, "CHARGING"),
+            None,
+            None,
+            None,
+        );
+        let session = state.active.clone().unwrap();
+
+        let recomputed = with_capacity(vec![session.clone()], Some(80.0));
+        assert_eq!(recomputed[0].energy_added_kwh, Some(40.0));
+
+        let mut stored = session;
+        stored.energy_added_kwh = Some(30.0);
+        let kept = with_capacity(vec![stored], None);
+        assert_eq!(kept[0].energy_added_kwh, Some(30.0));
+    }
+
+    #[test]
+    fn test_filter_sessions_and_json() {
+        let mut state = ChargingTrackerState::default();
+        apply_status(
+            &mut state,
+            &status_record(1000, 20, "CHARGING"),
+            None,
+            None,
+            None,
+        );
+        let first = state.active.clone().unwrap();
+        let mut second = first.clone();
+        second.started_at = 5000;
+
+        let filtered = filter_sessions(vec![first, second.clone()], Some(2000), None);
+        assert_eq!(filtered, vec![second.clone()]);
+
+        let json = second.to_json();
+        assert_eq!(json["in_progress"], true);
+        assert_eq!(json["soc_added"], 0);
+        assert_eq!(json["place"], "unknown");
+    }
+}
@@ -192,6 +192,43 @@ impl CircuitBreaker {
     pub fn get_failure_count(&self) -> u32 {
         self.state.lock().unwrap().failure_count
     }
+
+    /// State, counters and configuration for the admin API
+    pub fn to_json(&self) -> serde_json::Value {
+        let state = self.state.lock().unwrap();
+        serde_json::json!({
+            "state": state.current_state.as_str(),
+            "failure_count": state.failure_count,
+            "success_count": state.success_count,
+            "last_failure_time": (state.last_failure_time > 0).then_some(state.last_failure_time),
+            "failure_threshold": self.config.failure_threshold,
+            "timeout_seconds": self.config.timeout_seconds,
+            "success_threshold": self.config.success_threshold,
+        })
+    }
+
+    /// Close the circuit and forget past failures
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        info!(
+            "Circuit breaker: {:?} → CLOSED (manual reset)",
+            state.current_state
+        );
+        state.current_state = CircuitState::Closed;
+        state.failure_count = 0;
+        state.success_count = 0;
+        state.last_failure_time = 0;
+    }
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
 }
 
 /// Errors from circuit breaker
@@ -229,6 +266,14 @@ pub fn toyota_api_breaker() -> &'static CircuitBreaker {
     })
 }
 
+/// All circuit breakers by name, for the admin API
+///
+/// Breaker state lives in the component instance, so it describes (and a
+/// reset affects) only the instance serving the request.
+pub fn all_breakers() -> [(&'static str, &'static CircuitBreaker); 1] {
+    [("toyota_api", toyota_api_breaker())]
+}
+
 // Helper to get current timestamp
 fn current_timestamp() -> u64 {
     SystemTime::now()
@@ -319,4 +364,26 @@ mod tests {
         breaker.record_success();
         assert_eq!(breaker.get_state(), CircuitState::Closed);
     }
+
+    #[test]
+    fn test_circuit_breaker_reset() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            timeout_seconds: 60,
+            success_threshold: 2,
+        };
+        let breaker = CircuitBreaker::new(config);
+
+        breaker.record_failure();
+        breaker.record_failure();
+        assert_eq!(breaker.to_json()["state"], "open");
+        assert!(breaker.can_attempt().is_err());
+
+        breaker.reset();
+        let status = breaker.to_json();
+        assert_eq!(status["state"], "closed");
+        assert_eq!(status["failure_count"], 0);
+        assert!(status["last_failure_time"].is_null());
+        assert!(breaker.can_attempt().is_ok());
+    }
 }
@@ -0,0 +1,664 @@
+// Remote vehicle commands
+//
+// Toyota accepts a remote command right away and the vehicle executes it
+// later, so every command gets a record with its own id. The record moves
+// from `sent` to `succeeded` once a fresh vehicle status shows the expected
+// effect, to `failed` when Toyota rejects it, or to `timed_out` when nothing
+// happened in time. Status checks happen lazily when the client polls
+// `GET /commands/{id}`. Charging commands are checked against the charging
+// status, climate commands against the climate status and lock commands
+// against the door lock state. Hazard lights and horn leave no state to
+// check, so they succeed once Toyota accepts them.
+//
+// Commands require an `Idempotency-Key` header: retrying a request with the
+// same key returns the original command instead of sending it again.
+
+use crate::charging::is_actively_charging;
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use uuid::Uuid;
+
+const COMMAND_KEY_PREFIX: &str = "command_";
+const IDEMPOTENCY_KEY_PREFIX: &str = "command_idempotency_";
+
+/// A command that has not shown its effect after this long has timed out
+pub const COMMAND_TIMEOUT_SECONDS: i64 = 300;
+
+/// Minimum time between two vehicle status checks of one command
+pub const COMMAND_POLL_INTERVAL_SECONDS: i64 = 15;
+
+/// How long an idempotency key maps to its command
+const IDEMPOTENCY_TTL_SECONDS: i64 = 24 * 3600;
+
+const IDEMPOTENCY_KEY_MAX_LENGTH: usize = 255;
+
+/// Accepted charge limit range in percent
+pub const TARGET_SOC_MIN: i32 = 50;
+pub const TARGET_SOC_MAX: i32 = 100;
+
+/// Accepted cabin temperature range in °C
+const TARGET_TEMPERATURE_MIN: f32 = 16.0;
+const TARGET_TEMPERATURE_MAX: f32 = 30.0;
+
+/// Accepted climate run time in minutes
+pub const CLIMATE_DURATION_MIN: i32 = 5;
+pub const CLIMATE_DURATION_MAX: i32 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargeAction {
+    Start,
+    Stop,
+}
+
+/// Body of `POST /vehicles/{vin}/commands/charge`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChargeCommandRequest {
+    pub action: ChargeAction,
+    /// Charge limit in percent, only with `start`
+    pub target_soc: Option<i32>,
+}
+
+impl ChargeCommandRequest {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.action, self.target_soc) {
+            (ChargeAction::Stop, Some(_)) => {
+                Err("target_soc can only be set when starting to charge".to_string())
+            }
+            (_, Some(soc)) if !(TARGET_SOC_MIN..=TARGET_SOC_MAX).contains(&soc) => Err(format!(
+                "target_soc must be between {} and {}",
+                TARGET_SOC_MIN, TARGET_SOC_MAX
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClimateAction {
+    Start,
+    Stop,
+}
+
+/// Body of `POST /vehicles/{vin}/commands/climate`
+///
+/// Temperature and defrost are written to the vehicle's climate settings
+/// before starting. With a duration, the gateway stops climate control again
+/// after that many minutes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClimateCommandRequest {
+    pub action: ClimateAction,
+    /// Cabin temperature in °C
+    pub target_temperature: Option<f32>,
+    pub front_defrost: Option<bool>,
+    pub rear_defrost: Option<bool>,
+    pub duration_minutes: Option<i32>,
+}
+
+impl ClimateCommandRequest {
+    pub fn stop() -> Self {
+        ClimateCommandRequest {
+            action: ClimateAction::Stop,
+            target_temperature: None,
+            front_defrost: None,
+            rear_defrost: None,
+            duration_minutes: None,
+        }
+    }
+
+    /// Whether the vehicle's climate settings need to change before starting
+    pub fn changes_settings(&self) -> bool {
+        self.target_temperature.is_some()
+            || self.front_defrost.is_some()
+            || self.rear_defrost.is_some()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.action == ClimateAction::Stop
+            && (self.changes_settings() || self.duration_minutes.is_some())
+        {
+            return Err("Settings can only be given when starting climate control".to_string());
+        }
+        if let Some(temperature) = self.target_temperature {
+            if !(TARGET_TEMPERATURE_MIN..=TARGET_TEMPERATURE_MAX).contains(&temperature) {
+                return Err(format!(
+                    "target_temperature must be between {} and {}",
+                    TARGET_TEMPERATURE_MIN, TARGET_TEMPERATURE_MAX
+                ));
+            }
+        }
+        if let Some(duration) = self.duration_minutes {
+            if !(CLIMATE_DURATION_MIN..=CLIMATE_DURATION_MAX).contains(&duration) {
+                return Err(format!(
+                    "duration_minutes must be between {} and {}",
+                    CLIMATE_DURATION_MIN, CLIMATE_DURATION_MAX
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LockAction {
+    Lock,
+    Unlock,
+}
+
+/// Body of `POST /vehicles/{vin}/commands/lock`; unlock needs step-up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LockCommandRequest {
+    pub action: LockAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HazardAction {
+    On,
+    Off,
+}
+
+/// Body of `POST /vehicles/{vin}/commands/hazard`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HazardCommandRequest {
+    pub action: HazardAction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FindAction {
+    Horn,
+    HornAndLights,
+}
+
+/// Body of `POST /vehicles/{vin}/commands/find` ("find my car")
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FindCommandRequest {
+    pub action: FindAction,
+}
+
+/// What a command asks the vehicle to do
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandRequest {
+    Charge(ChargeCommandRequest),
+    Climate(ClimateCommandRequest),
+    Lock(LockCommandRequest),
+    Hazard(HazardCommandRequest),
+    Find(FindCommandRequest),
+}
+
+impl CommandRequest {
+    /// Parse and validate the body of `POST /vehicles/{vin}/commands/{kind}`
+    pub fn parse(kind: &str, body: &[u8]) -> Result<Self, String> {
+        let request = match kind {
+            "charge" => serde_json::from_slice(body).map(CommandRequest::Charge),
+            "climate" => serde_json::from_slice(body).map(CommandRequest::Climate),
+            "lock" => serde_json::from_slice(body).map(CommandRequest::Lock),
+            "hazard" => serde_json::from_slice(body).map(CommandRequest::Hazard),
+            "find" => serde_json::from_slice(body).map(CommandRequest::Find),
+            _ => return Err(format!("Unknown command: {}", kind)),
+        }
+        .map_err(|e| e.to_string())?;
+        request.validate()?;
+        Ok(request)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            CommandRequest::Charge(request) => request.validate(),
+            CommandRequest::Climate(request) => request.validate(),
+            CommandRequest::Lock(_) | CommandRequest::Hazard(_) | CommandRequest::Find(_) => Ok(()),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CommandRequest::Charge(_) => "charge",
+            CommandRequest::Climate(_) => "climate",
+            CommandRequest::Lock(_) => "lock",
+            CommandRequest::Hazard(_) => "hazard",
+            CommandRequest::Find(_) => "find",
+        }
+    }
+
+    /// Whether the command needs a PIN or step-up token
+    pub fn requires_step_up(&self) -> bool {
+        matches!(
+            self,
+            CommandRequest::Lock(LockCommandRequest {
+                action: LockAction::Unlock
+            })
+        )
+    }
+
+    /// Whether the vehicle reports the state the command changes
+    fn is_observable(&self) -> bool {
+        !matches!(self, CommandRequest::Hazard(_) | CommandRequest::Find(_))
+    }
+
+    /// Whether the command switches something on (charging, climate, lock)
+    fn is_start(&self) -> bool {
+        match self {
+            CommandRequest::Charge(request) => request.action == ChargeAction::Start,
+            CommandRequest::Climate(request) => request.action == ClimateAction::Start,
+            CommandRequest::Lock(request) => request.action == LockAction::Lock,
+            CommandRequest::Hazard(request) => request.action == HazardAction::On,
+            CommandRequest::Find(_) => true,
+        }
+    }
+
+    fn success_message(&self) -> &'static str {
+        match (self, self.is_start()) {
+            (CommandRequest::Charge(_), true) => "Vehicle is charging",
+            (CommandRequest::Charge(_), false) => "Vehicle stopped charging",
+            (CommandRequest::Climate(_), true) => "Climate control is running",
+            (CommandRequest::Climate(_), false) => "Climate control stopped",
+            (CommandRequest::Lock(_), true) => "Vehicle is locked",
+            (CommandRequest::Lock(_), false) => "Vehicle is unlocked",
+            (CommandRequest::Hazard(_) | CommandRequest::Find(_), _) => {
+                "Sent to the vehicle; it does not report this state"
+            }
+        }
+    }
+
+    /// Request fields as a JSON object
+    fn fields(&self) -> serde_json::Map<String, serde_json::Value> {
+        let value = match self {
+            CommandRequest::Charge(request) => serde_json::to_value(request),
+            CommandRequest::Climate(request) => serde_json::to_value(request),
+            CommandRequest::Lock(request) => serde_json::to_value(request),
+            CommandRequest::Hazard(request) => serde_json::to_value(request),
+            CommandRequest::Find(request) => serde_json::to_value(request),
+        };
+        match value {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandState {
+    /// Idempotency key claimed, not yet answered by Toyota
+    Pending,
+    /// Accepted by Toyota, waiting for the vehicle
+    Sent,
+    Succeeded,
+    Failed,
+    TimedOut,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CommandRecord {
+    pub id: String,
+    pub username_hash: String,
+    pub vin: String,
+    pub request: CommandRequest,
+    pub state: CommandState,
+    /// Request number assigned by Toyota
+    pub toyota_request_no: Option<String>,
+    pub message: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub last_checked_at: Option<i64>,
+}
+
+impl CommandRecord {
+    pub fn new(username_hash: &str, vin: &str, request: CommandRequest, now: i64) -> Self {
+        CommandRecord {
+            id: Uuid::new_v4().to_string(),
+            username_hash: username_hash.to_string(),
+            vin: vin.to_string(),
+            request,
+            state: CommandState::Pending,
+            toyota_request_no: None,
+            message: None,
+            created_at: now,
+            updated_at: now,
+            last_checked_at: None,
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self.state,
+            CommandState::Succeeded | CommandState::Failed | CommandState::TimedOut
+        )
+    }
+
+    pub fn fail(&mut self, message: String, now: i64) {
+        self.state = CommandState::Failed;
+        self.message = Some(message);
+        self.updated_at = now;
+    }
+
+    /// Record that Toyota accepted the command
+    ///
+    /// Commands without observable effect are done at this point.
+    pub fn accept(&mut self, toyota_request_no: Option<String>, now: i64) {
+        self.toyota_request_no = toyota_request_no;
+        self.updated_at = now;
+        if self.request.is_observable() {
+            self.state = CommandState::Sent;
+        } else {
+            self.state = CommandState::Succeeded;
+            self.message = Some(self.request.success_message().to_string());
+        }
+    }
+
+    /// Whether a vehicle status check is due
+    pub fn needs_check(&self, now: i64) -> bool {
+        self.state == CommandState::Sent
+            && self
+                .last_checked_at
+                .is_none_or(|last| now - last >= COMMAND_POLL_INTERVAL_SECONDS)
+    }
+
+    /// Time out a command that is still waiting after the deadline
+    pub fn expire(&mut self, now: i64) -> bool {
+        if self.is_final() || now - self.created_at < COMMAND_TIMEOUT_SECONDS {
+            return false;
+        }
+        self.state = CommandState::TimedOut;
+        self.message = Some(format!(
+            "Vehicle did not confirm the command within {} seconds",
+            COMMAND_TIMEOUT_SECONDS
+        ));
+        self.updated_at = now;
+        true
+    }
+
+    /// Apply a fresh charging status; returns whether the record changed
+    pub fn apply_charging_status(&mut self, charging_status: Option<&str>, now: i64) -> bool {
+        let charging = charging_status.map(is_actively_charging);
+        self.apply_observed_state(charging, now)
+    }
+
+    /// Apply a fresh climate status; returns whether the record changed
+    pub fn apply_climate_status(&mut self, running: Option<bool>, now: i64) -> bool {
+        self.apply_observed_state(running, now)
+    }
+
+    /// Apply a fresh door lock state; returns whether the record changed
+    pub fn apply_lock_status(&mut self, locked: Option<bool>, now: i64) -> bool {
+        self.apply_observed_state(locked, now)
+    }
+
+    /// Succeed once the vehicle reports the state the command asked for
+    fn apply_observed_state(&mut self, active: Option<bool>, now: i64) -> bool {
+        if self.state != CommandState::Sent {
+            return false;
+        }
+        self.last_checked_at = Some(now);
+        if active == Some(self.request.is_start()) {
+            self.state = CommandState::Succeeded;
+            self.message = Some(self.request.success_message().to_string());
+            self.updated_at = now;
+        } else {
+            self.expire(now);
+        }
+        true
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "id": self.id,
+            "vin": self.vin,
+            "kind": self.request.kind(),
+            "state": self.state,
+            "final": self.is_final(),
+            "message": self.message,
+            "toyota_request_no": self.toyota_request_no,
+            "created_at": self.created_at,
+            "updated_at": self.updated_at,
+            "status_url": format!("/commands/{}", self.id),
+        });
+        if let Some(object) = json.as_object_mut() {
+            object.extend(self.request.fields());
+        }
+        json
+    }
+}
+
+/// Check an `Idempotency-Key` header value
+pub fn validate_idempotency_key(key: &str) -> Result<(), String> {
+    if key.is_empty() || key.len() > IDEMPOTENCY_KEY_MAX_LENGTH {
+        return Err(format!(
+            "Idempotency-Key must be 1 to {} characters",
+            IDEMPOTENCY_KEY_MAX_LENGTH
+        ));
+    }
+    if !key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err("Idempotency-Key must be printable ASCII without spaces".to_string());
+    }
+    Ok(())
+}
+
+/// Stored mapping from an idempotency key to the command it created
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct IdempotencyEntry {
+    command_id: String,
+    created_at: i64,
+}
+
+fn command_key(id: &str) -> String {
+    format!("{}{}", COMMAND_KEY_PREFIX, id)
+}
+
+fn idempotency_key(username_hash: &str, key: &str) -> String {
+    format!("{}{}_{}", IDEMPOTENCY_KEY_PREFIX, username_hash, key)
+}
+
+pub async fn get_command(store: &Store, id: &str) -> Option<CommandRecord> {
+    match store.get(&command_key(id)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+pub async fn save_command(store: &Store, record: &CommandRecord) -> anyhow::Result<()> {
+    store.set(&command_key(&record.id), &serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+/// Command previously created with this idempotency key, if still valid
+pub async fn find_by_idempotency_key(
+    store: &Store,
+    username_hash: &str,
+    key: &str,
+    now: i64,
+) -> Option<CommandRecord> {
+    let entry: IdempotencyEntry = match store.get(&idempotency_key(username_hash, key)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok()?,
+        _ => return None,
+    };
+    if now - entry.created_at > IDEMPOTENCY_TTL_SECONDS {
+        return None;
+    }
+    get_command(store, &entry.command_id).await
+}
+
+/// Claim an idempotency key for a new, still pending command
+///
+/// Spin KV has no compare-and-swap, so like the single-flight lock the claim
+/// is written and read back. The command is stored before the key points to
+/// it. Returns the command owning the key when another request was first.
+pub async fn claim_idempotency_key(
+    store: &Store,
+    username_hash: &str,
+    key: &str,
+    record: &CommandRecord,
+) -> anyhow::Result<Option<CommandRecord>> {
+    let now = record.created_at;
+    if let Some(existing) = find_by_idempotency_key(store, username_hash, key, now).await {
+        return Ok(Some(existing));
+    }
+
+    save_command(store, record).await?;
+    remember_idempotency_key(store, username_hash, key, record).await?;
+
+    match find_by_idempotency_key(store, username_hash, key, now).await {
+        Some(current) if current.id != record.id => {
+            let _ = store.delete(&command_key(&record.id));
+            Ok(Some(current))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Remember which command an idempotency key created
+async fn remember_idempotency_key(
+    store: &Store,
+    username_hash: &str,
+    key: &str,
+    record: &CommandRecord,
+) -> anyhow::Result<()> {
+    let entry = IdempotencyEntry {
+        command_id: record.id.clone(),
+        created_at: record.created_at,
+    };
+    store.set(
+        &idempotency_key(username_hash, key),
+        &serde_json::to_vec(&entry)?,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(target_soc: Option<i32>) -> ChargeCommandRequest {
+        ChargeCommandRequest {
+            action: ChargeAction::Start,
+            target_soc,
+        }
+    }
+
+    fn charge(request: ChargeCommandRequest) -> CommandRequest {
+        CommandRequest::Charge(request)
+    }
+
+    #[test]
+    fn test_request_validation() {
+        assert!(start(None).validate().is_ok());
+        assert!(start(Some(80)).validate().is_ok());
+        assert!(start(Some(30)).validate().is_err());
+        let stop_with_target = ChargeCommandRequest {
+            action: ChargeAction::Stop,
+            target_soc: Some(80),
+        };
+        assert!(stop_with_target.validate().is_err());
+
+        assert!(validate_idempotency_key("3f1c-42").is_ok());
+        assert!(validate_idempotency_key("").is_err());
+        assert!(validate_idempotency_key("has space").is_err());
+    }
+
+    #[test]
+    fn test_start_command_succeeds_when_charging() {
+        let mut record = CommandRecord::new("user", "VIN", charge(start(None)), 1000);
+        assert_eq!(record.state, CommandState::Pending);
+        assert!(!record.is_final());
+        assert!(!record.needs_check(1000));
+        // Status before Toyota answered is ignored
+        assert!(!record.apply_charging_status(Some("CHARGING"), 1000));
+
+        record.accept(Some("REQ".to_string()), 1000);
+        assert!(record.needs_check(1000));
+
+        record.apply_charging_status(Some("NOT_CHARGING"), 1010);
+        assert_eq!(record.state, CommandState::Sent);
+        assert!(!record.needs_check(1020));
+        assert!(record.needs_check(1010 + COMMAND_POLL_INTERVAL_SECONDS));
+
+        record.apply_charging_status(Some("CHARGING"), 1030);
+        assert_eq!(record.state, CommandState::Succeeded);
+        assert!(record.is_final());
+        assert!(!record.needs_check(2000));
+    }
+
+    #[test]
+    fn test_stop_command_times_out() {
+        let stop = ChargeCommandRequest {
+            action: ChargeAction::Stop,
+            target_soc: None,
+        };
+        let mut record = CommandRecord::new("user", "VIN", charge(stop), 0);
+        record.accept(None, 0);
+        record.apply_charging_status(Some("CHARGING"), 60);
+        assert_eq!(record.state, CommandState::Sent);
+
+        record.apply_charging_status(Some("CHARGING"), COMMAND_TIMEOUT_SECONDS);
+        assert_eq!(record.state, CommandState::TimedOut);
+        // Final states are not changed by later status
+        assert!(!record.apply_charging_status(Some("NOT_CHARGING"), COMMAND_TIMEOUT_SECONDS + 60));
+        assert_eq!(record.state, CommandState::TimedOut);
+    }
+
+    #[test]
+    fn test_climate_command() {
+        let request = CommandRequest::parse(
+            "climate",
+            br#"{"action":"start","target_temperature":21.5,"front_defrost":true}"#,
+        )
+        .unwrap();
+        assert_eq!(request.kind(), "climate");
+        assert!(
+            CommandRequest::parse("climate", br#"{"action":"start","target_temperature":40}"#)
+                .is_err()
+        );
+        assert!(
+            CommandRequest::parse("climate", br#"{"action":"stop","duration_minutes":10}"#)
+                .is_err()
+        );
+        assert!(CommandRequest::parse("doors", br#"{"action":"start"}"#).is_err());
+
+        let mut record = CommandRecord::new("user", "VIN", request, 0);
+        let json = record.to_json();
+        assert_eq!(json["kind"], "climate");
+        assert_eq!(json["action"], "start");
+        assert_eq!(json["target_temperature"], 21.5);
+
+        record.accept(None, 0);
+        record.apply_climate_status(Some(false), 20);
+        assert_eq!(record.state, CommandState::Sent);
+        record.apply_climate_status(Some(true), 40);
+        assert_eq!(record.state, CommandState::Succeeded);
+        assert_eq!(
+            record.message.as_deref(),
+            Some("Climate control is running")
+        );
+    }
+
+    #[test]
+    fn test_lock_and_find_commands() {
+        let unlock = CommandRequest::parse("lock", br#"{"action":"unlock"}"#).unwrap();
+        assert!(unlock.requires_step_up());
+        let lock = CommandRequest::parse("lock", br#"{"action":"lock"}"#).unwrap();
+        assert!(!lock.requires_step_up());
+        assert!(CommandRequest::parse("lock", br#"{"action":"open"}"#).is_err());
+
+        let mut record = CommandRecord::new("user", "VIN", lock, 0);
+        record.accept(Some("REQ".to_string()), 0);
+        assert_eq!(record.state, CommandState::Sent);
+        record.apply_lock_status(Some(true), 20);
+        assert_eq!(record.state, CommandState::Succeeded);
+
+        // Horn and lights are done once accepted
+        let find = CommandRequest::parse("find", br#"{"action":"horn_and_lights"}"#).unwrap();
+        let mut record = CommandRecord::new("user", "VIN", find, 0);
+        record.accept(None, 5);
+        assert!(record.is_final());
+        assert_eq!(record.state, CommandState::Succeeded);
+    }
+}
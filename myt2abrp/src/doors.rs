@@ -0,0 +1,145 @@
+// Door, window and lock state
+//
+// Reduces Toyota's remote status (categories of sections with display
+// values) to a fixed shape: each door with closed/locked, each window, the
+// trunk and the hood, plus whether the car is locked and fully closed.
+// Parts a vehicle does not report are `null`.
+
+use myt::{
+    RemoteStatusPayload, STATUS_DRIVER_DOOR, STATUS_DRIVER_WINDOW, STATUS_HOOD,
+    STATUS_PASSENGER_DOOR, STATUS_PASSENGER_WINDOW, STATUS_REAR_HATCH, STATUS_REAR_LEFT_DOOR,
+    STATUS_REAR_LEFT_WINDOW, STATUS_REAR_RIGHT_DOOR, STATUS_REAR_RIGHT_WINDOW,
+};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct OpeningState {
+    pub closed: Option<bool>,
+    pub locked: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Doors {
+    pub driver: OpeningState,
+    pub passenger: OpeningState,
+    pub rear_left: OpeningState,
+    pub rear_right: OpeningState,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Windows {
+    pub driver: Option<bool>,
+    pub passenger: Option<bool>,
+    pub rear_left: Option<bool>,
+    pub rear_right: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SecurityStatus {
+    /// All reported doors and the trunk locked; `None` if none report a lock
+    pub locked: Option<bool>,
+    /// All reported doors, windows, trunk and hood closed
+    pub all_closed: Option<bool>,
+    pub doors: Doors,
+    /// `true` if the window is closed
+    pub windows: Windows,
+    pub trunk: OpeningState,
+    pub hood: OpeningState,
+    pub occurred_at: Option<String>,
+}
+
+fn opening(payload: &RemoteStatusPayload, name: &str) -> OpeningState {
+    payload
+        .section(name)
+        .map(|section| OpeningState {
+            closed: section.is_closed(),
+            locked: section.is_locked(),
+        })
+        .unwrap_or_default()
+}
+
+fn window(payload: &RemoteStatusPayload, name: &str) -> Option<bool> {
+    payload
+        .section(name)
+        .and_then(|section| section.is_closed())
+}
+
+/// `Some(true)` if every known value is true, `None` if none are known
+fn all_known(values: impl IntoIterator<Item = Option<bool>>) -> Option<bool> {
+    values
+        .into_iter()
+        .flatten()
+        .fold(None, |all, value| Some(all.unwrap_or(true) && value))
+}
+
+pub fn summarize(payload: &RemoteStatusPayload) -> SecurityStatus {
+    let doors = Doors {
+        driver: opening(payload, STATUS_DRIVER_DOOR),
+        passenger: opening(payload, STATUS_PASSENGER_DOOR),
+        rear_left: opening(payload, STATUS_REAR_LEFT_DOOR),
+        rear_right: opening(payload, STATUS_REAR_RIGHT_DOOR),
+    };
+    let windows = Windows {
+        driver: window(payload, STATUS_DRIVER_WINDOW),
+        passenger: window(payload, STATUS_PASSENGER_WINDOW),
+        rear_left: window(payload, STATUS_REAR_LEFT_WINDOW),
+        rear_right: window(payload, STATUS_REAR_RIGHT_WINDOW),
+    };
+    let trunk = opening(payload, STATUS_REAR_HATCH);
+    let hood = opening(payload, STATUS_HOOD);
+
+    let lockable = [
+        doors.driver,
+        doors.passenger,
+        doors.rear_left,
+        doors.rear_right,
+        trunk,
+    ];
+    let locked = all_known(lockable.iter().map(|o| o.locked));
+    let all_closed = all_known(lockable.iter().chain([&hood]).map(|o| o.closed).chain([
+        windows.driver,
+        windows.passenger,
+        windows.rear_left,
+        windows.rear_right,
+    ]));
+
+    SecurityStatus {
+        locked,
+        all_closed,
+        doors,
+        windows,
+        trunk,
+        hood,
+        occurred_at: payload.occurrence_date.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(json: &str) -> RemoteStatusPayload {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_summarize() {
+        let status = summarize(&payload(
+            r#"{"vehicleStatus":[{"category":"driver","sections":[
+                {"section":"carstatus_item_driver_door","values":[{"value":"carstatus_closed"},{"value":"carstatus_locked"}]},
+                {"section":"carstatus_item_passenger_door","values":[{"value":"carstatus_closed"},{"value":"carstatus_unlocked"}]},
+                {"section":"carstatus_item_driver_window","values":[{"value":"carstatus_closed"}]},
+                {"section":"carstatus_item_hood","values":[{"value":"carstatus_open"}]}]}]}"#,
+        ));
+        assert_eq!(status.locked, Some(false));
+        assert_eq!(status.all_closed, Some(false));
+        assert_eq!(status.doors.driver.locked, Some(true));
+        assert_eq!(status.windows.driver, Some(true));
+        assert_eq!(status.windows.rear_left, None);
+        assert_eq!(status.hood.closed, Some(false));
+
+        let unknown = summarize(&payload(r#"{"vehicleStatus":[]}"#));
+        assert_eq!(unknown.locked, None);
+        assert_eq!(unknown.all_closed, None);
+    }
+}
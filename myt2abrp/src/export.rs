@@ -0,0 +1,567 @@
+// History export
+//
+// Turns stored location snapshots into GPX and GeoJSON documents. Each point
+// carries the most recent SOC and odometer reading known at that time, and
+// points are split into separate tracks per detected trip so that parked
+// periods do not draw lines across the map.
+//
+// All recorded snapshots can also be exported as CSV or NDJSON rows with
+// selectable columns and distance units. Rows are rendered one at a time so
+// the caller can stream them without buffering the whole range.
+
+use crate::history::{HistoryRecord, SnapshotKind, HISTORY_FIELDS};
+use crate::trips::{odometer_to_km, Trip, TRIP_END_IDLE_SECONDS};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+
+/// Namespace of the GPX point extensions
+pub const GPX_EXTENSION_NAMESPACE: &str = "https://github.com/avrabe/spin_myT2ABRP/gpx/1";
+
+/// Default export window when no `from` is given
+pub const EXPORT_DEFAULT_RANGE_SECONDS: i64 = 7 * 86400;
+
+/// One exported position with the vehicle state at that time
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackPoint {
+    pub timestamp: i64,
+    pub lat: f64,
+    pub lon: f64,
+    pub soc: Option<i32>,
+    pub odometer_km: Option<f64>,
+}
+
+/// Consecutive points belonging to one trip (or one parked period)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub trip_id: Option<String>,
+    pub points: Vec<TrackPoint>,
+}
+
+/// Build track points from time-ordered history records
+///
+/// SOC and odometer are carried forward from the latest status and odometer
+/// records seen before each location.
+pub fn build_points(records: &[HistoryRecord]) -> Vec<TrackPoint> {
+    let mut soc = None;
+    let mut odometer_km = None;
+    let mut points = Vec::new();
+
+    for record in records {
+        match record.kind {
+            SnapshotKind::Status => soc = record.soc.or(soc),
+            SnapshotKind::Odometer => {
+                if let Some(odometer) = record.odometer {
+                    odometer_km = Some(odometer_to_km(odometer, record.odometer_unit.as_deref()));
+                }
+            }
+            SnapshotKind::Location => {
+                if let (Some(lat), Some(lon)) = (record.lat, record.lon) {
+                    points.push(TrackPoint {
+                        timestamp: record.timestamp,
+                        lat,
+                        lon,
+                        soc,
+                        odometer_km,
+                    });
+                }
+            }
+        }
+    }
+    points
+}
+
+fn trip_at(trips: &[Trip], timestamp: i64) -> Option<&Trip> {
+    trips.iter().find(|t| {
+        let end = t.ended_at.unwrap_or(t.end.timestamp);
+        timestamp >= t.start.timestamp && timestamp <= end
+    })
+}
+
+/// Split points into segments, one per trip
+///
+/// Points outside of any known trip are split wherever two fixes are further
+/// apart than the trip idle time.
+pub fn segment_points(points: Vec<TrackPoint>, trips: &[Trip]) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for point in points {
+        let trip_id = trip_at(trips, point.timestamp).map(|t| t.id.clone());
+        let continues = segments.last().is_some_and(|segment| {
+            segment.trip_id == trip_id
+                && (trip_id.is_some()
+                    || segment.points.last().is_some_and(|last| {
+                        point.timestamp - last.timestamp <= TRIP_END_IDLE_SECONDS
+                    }))
+        });
+
+        match segments.last_mut() {
+            Some(segment) if continues => segment.points.push(point),
+            _ => segments.push(Segment {
+                trip_id,
+                points: vec![point],
+            }),
+        }
+    }
+    segments
+}
+
+fn rfc3339(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|dt| dt.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn segment_name(segment: &Segment) -> String {
+    match (&segment.trip_id, segment.points.first()) {
+        (Some(id), _) => format!("Trip {}", id),
+        (None, Some(first)) => format!("Parked {}", rfc3339(first.timestamp)),
+        (None, None) => "Parked".to_string(),
+    }
+}
+
+/// Render segments as a GPX 1.1 document with one track per segment
+pub fn to_gpx(vin: &str, segments: &[Segment]) -> String {
+    let mut gpx = String::new();
+    gpx.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    gpx.push_str(&format!(
+        "<gpx version=\"1.1\" creator=\"myt2abrp\" xmlns=\"http://www.topografix.com/GPX/1/1\" xmlns:myt=\"{}\">\n",
+        GPX_EXTENSION_NAMESPACE
+    ));
+    gpx.push_str(&format!(
+        "  <metadata><name>{}</name></metadata>\n",
+        escape_xml(vin)
+    ));
+
+    for segment in segments {
+        gpx.push_str("  <trk>\n");
+        gpx.push_str(&format!(
+            "    <name>{}</name>\n",
+            escape_xml(&segment_name(segment))
+        ));
+        gpx.push_str("    <trkseg>\n");
+        for point in &segment.points {
+            gpx.push_str(&format!(
+                "      <trkpt lat=\"{}\" lon=\"{}\"><time>{}</time>",
+                point.lat,
+                point.lon,
+                rfc3339(point.timestamp)
+            ));
+            if point.soc.is_some() || point.odometer_km.is_some() {
+                gpx.push_str("<extensions>");
+                if let Some(soc) = point.soc {
+                    gpx.push_str(&format!("<myt:soc>{}</myt:soc>", soc));
+                }
+                if let Some(odometer_km) = point.odometer_km {
+                    gpx.push_str(&format!(
+                        "<myt:odometer_km>{}</myt:odometer_km>",
+                        odometer_km
+                    ));
+                }
+                gpx.push_str("</extensions>");
+            }
+            gpx.push_str("</trkpt>\n");
+        }
+        gpx.push_str("    </trkseg>\n");
+        gpx.push_str("  </trk>\n");
+    }
+
+    gpx.push_str("</gpx>\n");
+    gpx
+}
+
+/// Render segments as a GeoJSON FeatureCollection
+///
+/// Every segment becomes a `LineString` feature and every point a `Point`
+/// feature carrying SOC and odometer as properties.
+pub fn to_geojson(vin: &str, segments: &[Segment]) -> serde_json::Value {
+    let mut features = Vec::new();
+
+    for (index, segment) in segments.iter().enumerate() {
+        let coordinates: Vec<[f64; 2]> = segment.points.iter().map(|p| [p.lon, p.lat]).collect();
+        features.push(serde_json::json!({
+            "type": "Feature",
+            "geometry": { "type": "LineString", "coordinates": coordinates },
+            "properties": {
+                "segment": index,
+                "name": segment_name(segment),
+                "trip_id": segment.trip_id,
+                "start_time": segment.points.first().map(|p| rfc3339(p.timestamp)),
+                "end_time": segment.points.last().map(|p| rfc3339(p.timestamp)),
+                "point_count": segment.points.len(),
+            }
+        }));
+
+        for point in &segment.points {
+            features.push(serde_json::json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [point.lon, point.lat] },
+                "properties": {
+                    "segment": index,
+                    "trip_id": segment.trip_id,
+                    "timestamp": point.timestamp,
+                    "time": rfc3339(point.timestamp),
+                    "soc": point.soc,
+                    "odometer_km": point.odometer_km,
+                }
+            }));
+        }
+    }
+
+    serde_json::json!({
+        "type": "FeatureCollection",
+        "properties": { "vin": vin },
+        "features": features,
+    })
+}
+
+/// Columns always available in tabular exports, before the history fields
+const EXPORT_BASE_COLUMNS: &[&str] = &["timestamp", "time", "kind"];
+
+const MILES_PER_KM: f64 = 1.0 / 1.609344;
+
+/// Distance unit of exported ranges and odometer readings
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceUnit {
+    Km,
+    Mi,
+}
+
+impl DistanceUnit {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "km" => Some(DistanceUnit::Km),
+            "mi" => Some(DistanceUnit::Mi),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DistanceUnit::Km => "km",
+            DistanceUnit::Mi => "mi",
+        }
+    }
+
+    fn convert_km(&self, km: f64) -> f64 {
+        match self {
+            DistanceUnit::Km => km,
+            DistanceUnit::Mi => km * MILES_PER_KM,
+        }
+    }
+}
+
+/// Row format of a tabular export
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TabularFormat {
+    Csv,
+    Ndjson,
+}
+
+/// Settings of a CSV or NDJSON history export
+#[derive(Debug, Clone, PartialEq)]
+pub struct TabularExport {
+    pub format: TabularFormat,
+    pub columns: Vec<String>,
+    pub unit: DistanceUnit,
+}
+
+/// Parse a comma-separated column list, `None` selects all columns
+pub fn parse_columns(value: Option<&str>) -> Result<Vec<String>, String> {
+    let all = EXPORT_BASE_COLUMNS.iter().chain(HISTORY_FIELDS.iter());
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(all.map(|c| c.to_string()).collect()),
+    };
+
+    let columns: Vec<String> = value
+        .split(',')
+        .map(|c| c.trim().to_string())
+        .filter(|c| !c.is_empty())
+        .collect();
+    if columns.is_empty() {
+        return Err("'columns' must name at least one column".to_string());
+    }
+    if let Some(unknown) = columns.iter().find(|c| {
+        !EXPORT_BASE_COLUMNS.contains(&c.as_str()) && !HISTORY_FIELDS.contains(&c.as_str())
+    }) {
+        return Err(format!(
+            "Unknown column '{}', expected one of: {}",
+            unknown,
+            all.copied().collect::<Vec<_>>().join(", ")
+        ));
+    }
+    Ok(columns)
+}
+
+fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl TabularExport {
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            TabularFormat::Csv => "text/csv; charset=utf-8",
+            TabularFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            TabularFormat::Csv => "csv",
+            TabularFormat::Ndjson => "ndjson",
+        }
+    }
+
+    /// Header line for CSV, nothing for NDJSON
+    pub fn header(&self) -> Option<String> {
+        match self.format {
+            TabularFormat::Csv => Some(format!("{}\n", self.columns.join(","))),
+            TabularFormat::Ndjson => None,
+        }
+    }
+
+    fn column_value(&self, record: &HistoryRecord, column: &str) -> serde_json::Value {
+        let distance = |km: Option<f32>| km.map(|km| self.unit.convert_km(km as f64));
+        let odometer = record.odometer.map(|value| {
+            self.unit
+                .convert_km(odometer_to_km(value, record.odometer_unit.as_deref()))
+        });
+        match column {
+            "timestamp" => record.timestamp.into(),
+            "time" => rfc3339(record.timestamp).into(),
+            "kind" => serde_json::to_value(record.kind).unwrap_or_default(),
+            "soc" => record.soc.into(),
+            "charging_status" => record.charging_status.clone().into(),
+            "ev_range" => distance(record.ev_range).into(),
+            "ev_range_with_ac" => distance(record.ev_range_with_ac).into(),
+            "remaining_charge_time" => record.remaining_charge_time.into(),
+            "lat" => record.lat.into(),
+            "lon" => record.lon.into(),
+            "odometer" => odometer.into(),
+            "odometer_unit" => odometer.map(|_| self.unit.as_str()).into(),
+            _ => serde_json::Value::Null,
+        }
+    }
+
+    /// Render one record as a newline-terminated row
+    pub fn render_row(&self, record: &HistoryRecord) -> String {
+        match self.format {
+            TabularFormat::Csv => {
+                let cells: Vec<String> = self
+                    .columns
+                    .iter()
+                    .map(|column| match self.column_value(record, column) {
+                        serde_json::Value::Null => String::new(),
+                        serde_json::Value::String(value) => csv_escape(&value),
+                        value => value.to_string(),
+                    })
+                    .collect();
+                format!("{}\n", cells.join(","))
+            }
+            TabularFormat::Ndjson => {
+                let object: serde_json::Map<String, serde_json::Value> = self
+                    .columns
+                    .iter()
+                    .map(|column| (column.clone(), self.column_value(record, column)))
+                    .collect();
+                format!("{}\n", serde_json::Value::Object(object))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geo::PlaceKind;
+    use crate::trips::VehiclePoint;
+
+    fn point(timestamp: i64) -> TrackPoint {
+        TrackPoint {
+            timestamp,
+            lat: 52.52,
+            lon: 13.405,
+            soc: None,
+            odometer_km: None,
+        }
+    }
+
+    fn trip(id: &str, start: i64, end: i64) -> Trip {
+        Trip {
+            id: id.to_string(),
+            start: VehiclePoint {
+                timestamp: start,
+                ..VehiclePoint::default()
+            },
+            end: VehiclePoint {
+                timestamp: end,
+                ..VehiclePoint::default()
+            },
+            start_place: PlaceKind::Unknown,
+            end_place: PlaceKind::Unknown,
+            gps_distance_km: 0.0,
+            ended_at: Some(end),
+        }
+    }
+
+    #[test]
+    fn test_build_points_carries_soc_and_odometer() {
+        let records = vec![
+            HistoryRecord::empty(100, SnapshotKind::Location),
+            HistoryRecord {
+                soc: Some(80),
+                ..HistoryRecord::empty(200, SnapshotKind::Status)
+            },
+            HistoryRecord {
+                odometer: Some(100.0),
+                odometer_unit: Some("mi".to_string()),
+                ..HistoryRecord::empty(250, SnapshotKind::Odometer)
+            },
+            HistoryRecord {
+                lat: Some(52.5),
+                lon: Some(13.4),
+                ..HistoryRecord::empty(300, SnapshotKind::Location)
+            },
+        ];
+
+        let points = build_points(&records);
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].soc, Some(80));
+        assert!((points[0].odometer_km.unwrap() - 160.9344).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_segment_points_by_trip_and_gap() {
+        let trips = vec![trip("a", 1000, 2000)];
+        let points = vec![
+            point(500),
+            point(900),
+            point(1000),
+            point(1500),
+            point(2500),
+            point(5000),
+        ];
+
+        let segments = segment_points(points, &trips);
+        let shape: Vec<(Option<&str>, usize)> = segments
+            .iter()
+            .map(|s| (s.trip_id.as_deref(), s.points.len()))
+            .collect();
+        assert_eq!(shape, vec![(None, 2), (Some("a"), 2), (None, 1), (None, 1)]);
+    }
+
+    #[test]
+    fn test_to_gpx() {
+        let segments = vec![Segment {
+            trip_id: Some("a".to_string()),
+            points: vec![TrackPoint {
+                soc: Some(75),
+                odometer_km: Some(15000.5),
+                ..point(1_704_067_200)
+            }],
+        }];
+
+        let gpx = to_gpx("VIN<1>", &segments);
+        assert!(gpx.contains("<metadata><name>VIN&lt;1&gt;</name></metadata>"));
+        assert!(gpx.contains("<name>Trip a</name>"));
+        assert!(
+            gpx.contains("<trkpt lat=\"52.52\" lon=\"13.405\"><time>2024-01-01T00:00:00Z</time>")
+        );
+        assert!(gpx.contains("<myt:soc>75</myt:soc><myt:odometer_km>15000.5</myt:odometer_km>"));
+        assert!(gpx.trim_end().ends_with("</gpx>"));
+    }
+
+    #[test]
+    fn test_to_geojson() {
+        let segments = vec![Segment {
+            trip_id: None,
+            points: vec![point(100), point(200)],
+        }];
+
+        let geojson = to_geojson("VIN1", &segments);
+        assert_eq!(geojson["type"], "FeatureCollection");
+        let features = geojson["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[0]["geometry"]["type"], "LineString");
+        assert_eq!(features[0]["geometry"]["coordinates"][0][0], 13.405);
+        assert_eq!(features[1]["geometry"]["type"], "Point");
+        assert_eq!(features[1]["properties"]["timestamp"], 100);
+        assert!(features[1]["properties"]["soc"].is_null());
+    }
+
+    fn status_record() -> HistoryRecord {
+        HistoryRecord {
+            soc: Some(80),
+            charging_status: Some("CHARGING, FAST".to_string()),
+            ev_range: Some(160.9344),
+            ..HistoryRecord::empty(1_704_067_200, SnapshotKind::Status)
+        }
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let all = parse_columns(None).unwrap();
+        assert_eq!(&all[..3], &["timestamp", "time", "kind"]);
+        assert_eq!(all.len(), 3 + HISTORY_FIELDS.len());
+
+        assert_eq!(
+            parse_columns(Some("time, soc")).unwrap(),
+            vec!["time".to_string(), "soc".to_string()]
+        );
+        assert!(parse_columns(Some("soc,bogus")).is_err());
+        assert!(parse_columns(Some(",")).is_err());
+    }
+
+    #[test]
+    fn test_render_csv_row() {
+        let export = TabularExport {
+            format: TabularFormat::Csv,
+            columns: parse_columns(Some("time,kind,soc,charging_status,ev_range,odometer"))
+                .unwrap(),
+            unit: DistanceUnit::Mi,
+        };
+        assert_eq!(
+            export.header().unwrap(),
+            "time,kind,soc,charging_status,ev_range,odometer\n"
+        );
+        let row = export.render_row(&status_record());
+        assert!(row.starts_with("2024-01-01T00:00:00Z,status,80,\"CHARGING, FAST\",100"));
+        assert!(row.ends_with(",\n"));
+    }
+
+    #[test]
+    fn test_render_ndjson_row_with_units() {
+        let export = TabularExport {
+            format: TabularFormat::Ndjson,
+            columns: parse_columns(Some("timestamp,odometer,odometer_unit")).unwrap(),
+            unit: DistanceUnit::Km,
+        };
+        assert_eq!(export.header(), None);
+
+        let odometer = HistoryRecord {
+            odometer: Some(100.0),
+            odometer_unit: Some("mi".to_string()),
+            ..HistoryRecord::empty(1000, SnapshotKind::Odometer)
+        };
+        let row = export.render_row(&odometer);
+        assert!(row.ends_with('\n'));
+        let value: serde_json::Value = serde_json::from_str(row.trim_end()).unwrap();
+        assert_eq!(value["timestamp"], 1000);
+        assert!((value["odometer"].as_f64().unwrap() - 160.9344).abs() < 1e-9);
+        assert_eq!(value["odometer_unit"], "km");
+    }
+}
@@ -0,0 +1,111 @@
+// Geographic helpers
+//
+// Distance calculation between coordinates and the configured home location,
+// used to classify charging sessions and trips as home or away.
+
+use serde::{Deserialize, Serialize};
+use spin_sdk::variables;
+
+const EARTH_RADIUS_METERS: f64 = 6_371_000.0;
+
+/// Default radius around the home coordinates that still counts as home
+pub const HOME_RADIUS_METERS_DEFAULT: f64 = 150.0;
+
+/// Great-circle distance between two coordinates in meters (haversine)
+pub fn distance_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let d_lat = (lat2 - lat1).to_radians();
+    let d_lon = (lon2 - lon1).to_radians();
+    let a = (d_lat / 2.0).sin().powi(2)
+        + lat1.to_radians().cos() * lat2.to_radians().cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().asin()
+}
+
+/// Where the vehicle was relative to the configured home
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaceKind {
+    Home,
+    Away,
+    Unknown,
+}
+
+/// Home coordinates and radius
+#[derive(Debug, Clone, PartialEq)]
+pub struct HomeLocation {
+    pub lat: f64,
+    pub lon: f64,
+    pub radius_meters: f64,
+}
+
+impl HomeLocation {
+    /// Read `home_latitude`, `home_longitude` and `home_radius_meters`
+    ///
+    /// Returns `None` when no home is configured.
+    pub fn from_variables() -> Option<Self> {
+        let lat = variables::get("home_latitude").ok()?.parse::<f64>().ok()?;
+        let lon = variables::get("home_longitude").ok()?.parse::<f64>().ok()?;
+        let radius_meters = variables::get("home_radius_meters")
+            .ok()
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|r| *r > 0.0)
+            .unwrap_or(HOME_RADIUS_METERS_DEFAULT);
+        Some(HomeLocation {
+            lat,
+            lon,
+            radius_meters,
+        })
+    }
+
+    pub fn classify(home: Option<&HomeLocation>, position: Option<(f64, f64)>) -> PlaceKind {
+        match (home, position) {
+            (Some(home), Some((lat, lon))) => {
+                if distance_meters(home.lat, home.lon, lat, lon) <= home.radius_meters {
+                    PlaceKind::Home
+                } else {
+                    PlaceKind::Away
+                }
+            }
+            _ => PlaceKind::Unknown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distance_meters() {
+        // Berlin Brandenburg Gate to Berlin TV tower: about 2.2 km
+        let d = distance_meters(52.5163, 13.3777, 52.5208, 13.4094);
+        assert!((d - 2200.0).abs() < 100.0, "distance was {}", d);
+
+        assert_eq!(distance_meters(52.52, 13.405, 52.52, 13.405), 0.0);
+    }
+
+    #[test]
+    fn test_classify_place() {
+        let home = HomeLocation {
+            lat: 52.52,
+            lon: 13.405,
+            radius_meters: 150.0,
+        };
+
+        assert_eq!(
+            HomeLocation::classify(Some(&home), Some((52.5201, 13.4051))),
+            PlaceKind::Home
+        );
+        assert_eq!(
+            HomeLocation::classify(Some(&home), Some((52.53, 13.405))),
+            PlaceKind::Away
+        );
+        assert_eq!(
+            HomeLocation::classify(None, Some((52.52, 13.405))),
+            PlaceKind::Unknown
+        );
+        assert_eq!(
+            HomeLocation::classify(Some(&home), None),
+            PlaceKind::Unknown
+        );
+    }
+}
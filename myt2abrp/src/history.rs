@@ -0,0 +1,596 @@
+// Vehicle status history store
+//
+// Persists every distinct status, location and odometer snapshot fetched from
+// Toyota as a time-series record per VIN, so questions like "what was my SOC
+// yesterday at 18:00?" can be answered after the cache entry is gone.
+//
+// Records are grouped into one KV entry per VIN and UTC day. A per-VIN index
+// lists the stored days, which keeps range queries and retention pruning
+// cheap without scanning the whole store.
+
+use chrono::{DateTime, Utc};
+use myt::{ElectricStatusResponse, LocationResponse, TelemetryResponse};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use tracing::debug;
+
+const HISTORY_KEY_PREFIX: &str = "history_";
+const HISTORY_INDEX_KEY_PREFIX: &str = "history_index_";
+
+/// Default number of days records are kept
+pub const HISTORY_RETENTION_DAYS_DEFAULT: i64 = 365;
+
+/// Default and maximum page size for history queries
+pub const HISTORY_PAGE_SIZE_DEFAULT: usize = 100;
+pub const HISTORY_PAGE_SIZE_MAX: usize = 1000;
+
+/// Fields that can be selected with `?fields=`
+pub const HISTORY_FIELDS: &[&str] = &[
+    "soc",
+    "charging_status",
+    "ev_range",
+    "ev_range_with_ac",
+    "remaining_charge_time",
+    "lat",
+    "lon",
+    "odometer",
+    "odometer_unit",
+];
+
+/// Source of a history record
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotKind {
+    Status,
+    Location,
+    Odometer,
+}
+
+impl SnapshotKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotKind::Status => "status",
+            SnapshotKind::Location => "location",
+            SnapshotKind::Odometer => "odometer",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "status" => Some(SnapshotKind::Status),
+            "location" => Some(SnapshotKind::Location),
+            "odometer" => Some(SnapshotKind::Odometer),
+            _ => None,
+        }
+    }
+}
+
+/// One stored snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryRecord {
+    /// Toyota's last update timestamp (Unix seconds)
+    pub timestamp: i64,
+    pub kind: SnapshotKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub soc: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charging_status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ev_range: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ev_range_with_ac: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_charge_time: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lat: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lon: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub odometer: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub odometer_unit: Option<String>,
+}
+
+impl HistoryRecord {
+    /// Record of `kind` without any fields, also used to build test fixtures
+    pub(crate) fn empty(timestamp: i64, kind: SnapshotKind) -> Self {
+        HistoryRecord {
+            timestamp,
+            kind,
+            soc: None,
+            charging_status: None,
+            ev_range: None,
+            ev_range_with_ac: None,
+            remaining_charge_time: None,
+            lat: None,
+            lon: None,
+            odometer: None,
+            odometer_unit: None,
+        }
+    }
+
+    /// `None` when Toyota's update timestamp cannot be read
+    pub fn from_status(status: &ElectricStatusResponse) -> Option<Self> {
+        let vehicle_info = &status.payload.vehicle_info;
+        let charge_info = &vehicle_info.charge_info;
+        let timestamp = parse_update_timestamp(&vehicle_info.last_update_timestamp)?;
+        Some(HistoryRecord {
+            soc: charge_info.charge_remaining_amount,
+            charging_status: charge_info.charging_status.clone(),
+            ev_range: charge_info.ev_range,
+            ev_range_with_ac: charge_info.ev_range_with_ac,
+            remaining_charge_time: charge_info.remaining_charge_time,
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Status)
+        })
+    }
+
+    /// `None` when Toyota's update timestamp cannot be read
+    pub fn from_location(location: &LocationResponse) -> Option<Self> {
+        let vehicle_info = &location.payload.vehicle_info;
+        let timestamp = parse_update_timestamp(&vehicle_info.last_update_timestamp)?;
+        Some(HistoryRecord {
+            lat: Some(vehicle_info.location.lat),
+            lon: Some(vehicle_info.location.lon),
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Location)
+        })
+    }
+
+    /// Telemetry carries no update timestamp, so the fetch time is used
+    pub fn from_telemetry(telemetry: &TelemetryResponse, current_time: i64) -> Option<Self> {
+        let odometer = telemetry.payload.vehicle_info.odometer.as_ref()?;
+        Some(HistoryRecord {
+            odometer: Some(odometer.value?),
+            odometer_unit: odometer.unit.clone(),
+            ..HistoryRecord::empty(current_time, SnapshotKind::Odometer)
+        })
+    }
+
+    /// Whether the record carries at least one of the selected fields
+    fn has_any_field(&self, fields: &[String]) -> bool {
+        fields.iter().any(|field| match field.as_str() {
+            "soc" => self.soc.is_some(),
+            "charging_status" => self.charging_status.is_some(),
+            "ev_range" => self.ev_range.is_some(),
+            "ev_range_with_ac" => self.ev_range_with_ac.is_some(),
+            "remaining_charge_time" => self.remaining_charge_time.is_some(),
+            "lat" => self.lat.is_some(),
+            "lon" => self.lon.is_some(),
+            "odometer" => self.odometer.is_some(),
+            "odometer_unit" => self.odometer_unit.is_some(),
+            _ => false,
+        })
+    }
+
+    /// Serialize keeping only `timestamp`, `kind` and the selected fields
+    pub fn to_json_with_fields(&self, fields: Option<&[String]>) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let (Some(fields), Some(object)) = (fields, value.as_object_mut()) {
+            object.retain(|key, _| {
+                key == "timestamp" || key == "kind" || fields.iter().any(|f| f == key)
+            });
+        }
+        value
+    }
+
+    fn cursor(&self) -> String {
+        format!("{}-{}", self.timestamp, self.kind.as_str())
+    }
+}
+
+/// Parse Toyota's `lastUpdateTimestamp`
+///
+/// Accepts RFC 3339 and the `+0000` offset form. There is no fallback to the
+/// current time: deduplication relies on the upstream timestamp.
+pub fn parse_update_timestamp(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .ok()
+        .map(|dt| dt.timestamp())
+}
+
+/// Whether `record` repeats data already stored in `existing`
+///
+/// Status and location snapshots are deduplicated on Toyota's update
+/// timestamp. Odometer readings have no upstream timestamp, so a reading
+/// equal to the latest stored one is treated as a duplicate.
+pub fn is_duplicate(existing: &[HistoryRecord], record: &HistoryRecord) -> bool {
+    match record.kind {
+        SnapshotKind::Odometer => existing
+            .iter()
+            .rev()
+            .find(|r| r.kind == SnapshotKind::Odometer)
+            .is_some_and(|last| last.odometer == record.odometer),
+        kind => existing
+            .iter()
+            .any(|r| r.kind == kind && r.timestamp == record.timestamp),
+    }
+}
+
+/// Days with stored records for a VIN
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct HistoryIndex {
+    days: Vec<String>,
+}
+
+fn day_of(timestamp: i64) -> String {
+    DateTime::<Utc>::from_timestamp(timestamp, 0)
+        .unwrap_or_default()
+        .format("%Y%m%d")
+        .to_string()
+}
+
+fn bucket_key(vin: &str, day: &str) -> String {
+    format!("{}{}_{}", HISTORY_KEY_PREFIX, vin, day)
+}
+
+fn index_key(vin: &str) -> String {
+    format!("{}{}", HISTORY_INDEX_KEY_PREFIX, vin)
+}
+
+async fn load_index(store: &Store, vin: &str) -> HistoryIndex {
+    match store.get(&index_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => HistoryIndex::default(),
+    }
+}
+
+async fn load_bucket(store: &Store, vin: &str, day: &str) -> Vec<HistoryRecord> {
+    match store.get(&bucket_key(vin, day)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Number of days to keep history, from the `history_retention_days` variable
+pub fn get_retention_days() -> i64 {
+    spin_sdk::variables::get("history_retention_days")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(HISTORY_RETENTION_DAYS_DEFAULT)
+}
+
+/// Append a snapshot to the VIN's history unless it is a duplicate
+///
+/// Returns `true` if a new record was stored. Buckets older than the
+/// retention period are pruned on the way.
+pub async fn record_snapshot(
+    store: &Store,
+    vin: &str,
+    record: HistoryRecord,
+    retention_days: i64,
+    current_time: i64,
+) -> anyhow::Result<bool> {
+    let day = day_of(record.timestamp);
+    let mut bucket = load_bucket(store, vin, &day).await;
+
+    if is_duplicate(&bucket, &record) {
+        return Ok(false);
+    }
+
+    bucket.push(record);
+    bucket.sort_by_key(|r| (r.timestamp, r.kind));
+    store.set(&bucket_key(vin, &day), &serde_json::to_vec(&bucket)?)?;
+
+    let mut index = load_index(store, vin).await;
+    if !index.days.contains(&day) {
+        index.days.push(day);
+        index.days.sort();
+    }
+
+    let cutoff_day = day_of(current_time - retention_days * 86400);
+    let (expired, kept): (Vec<String>, Vec<String>) =
+        index.days.into_iter().partition(|d| *d < cutoff_day);
+    for day in &expired {
+        debug!("Pruning history bucket {} for VIN {}", day, vin);
+        let _ = store.delete(&bucket_key(vin, day));
+    }
+    index.days = kept;
+    store.set(&index_key(vin), &serde_json::to_vec(&index)?)?;
+
+    Ok(true)
+}
+
+/// Parameters of a history range query
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    pub from: i64,
+    pub to: i64,
+    pub fields: Option<Vec<String>>,
+    pub limit: usize,
+    pub cursor: Option<(i64, SnapshotKind)>,
+}
+
+/// One page of query results
+#[derive(Debug)]
+pub struct HistoryPage {
+    pub records: Vec<HistoryRecord>,
+    pub next_cursor: Option<String>,
+}
+
+/// Parse a pagination cursor of the form `<timestamp>-<kind>`
+///
+/// Split at the last dash, the timestamp may be negative.
+pub fn parse_cursor(cursor: &str) -> Option<(i64, SnapshotKind)> {
+    let (timestamp, kind) = cursor.rsplit_once('-')?;
+    Some((timestamp.parse().ok()?, SnapshotKind::parse(kind)?))
+}
+
+/// Parse and validate a comma-separated `fields` list
+pub fn parse_fields(fields: &str) -> Result<Vec<String>, String> {
+    let parsed: Vec<String> = fields
+        .split(',')
+        .map(|f| f.trim().to_string())
+        .filter(|f| !f.is_empty())
+        .collect();
+
+    if let Some(unknown) = parsed
+        .iter()
+        .find(|f| !HISTORY_FIELDS.contains(&f.as_str()))
+    {
+        return Err(format!(
+            "Unknown field '{}'. Allowed fields: {}",
+            unknown,
+            HISTORY_FIELDS.join(", ")
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Select one page of records matching the query from time-ordered records
+pub fn select_page(records: Vec<HistoryRecord>, query: &HistoryQuery) -> HistoryPage {
+    let mut matching = records.into_iter().filter(|r| {
+        r.timestamp >= query.from
+            && r.timestamp <= query.to
+            && query
+                .cursor
+                .is_none_or(|cursor| (r.timestamp, r.kind) > cursor)
+            && query
+                .fields
+                .as_deref()
+                .is_none_or(|fields| r.has_any_field(fields))
+    });
+
+    let records: Vec<HistoryRecord> = matching.by_ref().take(query.limit).collect();
+    let next_cursor = if matching.next().is_some() {
+        records.last().map(HistoryRecord::cursor)
+    } else {
+        None
+    };
+
+    HistoryPage {
+        records,
+        next_cursor,
+    }
+}
+
+/// Stored days of a VIN overlapping `from`..=`to`, oldest first
+pub async fn days_in_range(store: &Store, vin: &str, from: i64, to: i64) -> Vec<String> {
+    let from_day = day_of(from);
+    let to_day = day_of(to);
+    load_index(store, vin)
+        .await
+        .days
+        .into_iter()
+        .filter(|d| *d >= from_day && *d <= to_day)
+        .collect()
+}
+
+/// Records of one stored day between `from` and `to` (inclusive), time-ordered
+pub async fn load_day(
+    store: &Store,
+    vin: &str,
+    day: &str,
+    from: i64,
+    to: i64,
+) -> Vec<HistoryRecord> {
+    let mut records = load_bucket(store, vin, day).await;
+    records.retain(|r| r.timestamp >= from && r.timestamp <= to);
+    records
+}
+
+/// Load all records of a VIN between `from` and `to` (inclusive), time-ordered
+pub async fn load_range(store: &Store, vin: &str, from: i64, to: i64) -> Vec<HistoryRecord> {
+    let mut records = Vec::new();
+    for day in days_in_range(store, vin, from, to).await {
+        records.extend(load_day(store, vin, &day, from, to).await);
+    }
+    records
+}
+
+/// Most recent record of `kind` at or before `at`, no older than `max_age` seconds
+pub async fn latest_record(
+    store: &Store,
+    vin: &str,
+    kind: SnapshotKind,
+    at: i64,
+    max_age: i64,
+) -> Option<HistoryRecord> {
+    load_range(store, vin, at - max_age, at)
+        .await
+        .into_iter()
+        .rev()
+        .find(|r| r.kind == kind)
+}
+
+/// Run a paginated history query for a VIN
+pub async fn query(store: &Store, vin: &str, query: &HistoryQuery) -> HistoryPage {
+    let records = load_range(store, vin, query.from, query.to).await;
+    select_page(records, query)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_record(timestamp: i64, soc: i32) -> HistoryRecord {
+        HistoryRecord {
+            soc: Some(soc),
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Status)
+        }
+    }
+
+    fn odometer_record(timestamp: i64, odometer: f64) -> HistoryRecord {
+        HistoryRecord {
+            odometer: Some(odometer),
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Odometer)
+        }
+    }
+
+    fn query(limit: usize) -> HistoryQuery {
+        HistoryQuery {
+            from: 0,
+            to: i64::MAX,
+            fields: None,
+            limit,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn test_record_from_status() {
+        let json = r#"{
+            "payload": {
+                "vehicleInfo": {
+                    "chargeInfo": {
+                        "chargeRemainingAmount": 72,
+                        "chargingStatus": "CHARGING",
+                        "evRange": 210.0
+                    },
+                    "lastUpdateTimestamp": "2025-01-15T18:00:00Z"
+                }
+            }
+        }"#;
+        let status: ElectricStatusResponse = serde_json::from_str(json).unwrap();
+        let record = HistoryRecord::from_status(&status).unwrap();
+
+        assert_eq!(record.kind, SnapshotKind::Status);
+        assert_eq!(record.timestamp, 1736964000);
+        assert_eq!(record.soc, Some(72));
+        assert_eq!(record.charging_status, Some("CHARGING".to_string()));
+        assert_eq!(record.lat, None);
+    }
+
+    #[test]
+    fn test_parse_update_timestamp() {
+        assert_eq!(
+            parse_update_timestamp("2025-01-15T18:00:00Z"),
+            Some(1736964000)
+        );
+        assert_eq!(
+            parse_update_timestamp("2025-01-15T18:00:00.000+0000"),
+            Some(1736964000)
+        );
+        assert_eq!(
+            parse_update_timestamp("2025-01-15T19:00:00+0100"),
+            Some(1736964000)
+        );
+        assert_eq!(parse_update_timestamp("not a timestamp"), None);
+        assert_eq!(parse_update_timestamp(""), None);
+    }
+
+    #[test]
+    fn test_duplicate_detection() {
+        let existing = vec![status_record(1000, 80), odometer_record(1000, 15000.0)];
+
+        // Same Toyota timestamp is a duplicate even if polled again
+        assert!(is_duplicate(&existing, &status_record(1000, 80)));
+        assert!(!is_duplicate(&existing, &status_record(1300, 79)));
+
+        // Unchanged odometer reading is a duplicate
+        assert!(is_duplicate(&existing, &odometer_record(1300, 15000.0)));
+        assert!(!is_duplicate(&existing, &odometer_record(1300, 15012.5)));
+    }
+
+    #[test]
+    fn test_day_of() {
+        assert_eq!(day_of(1736964000), "20250115");
+        assert_eq!(day_of(0), "19700101");
+    }
+
+    #[test]
+    fn test_select_page_with_cursor() {
+        let records: Vec<HistoryRecord> = (0..5).map(|i| status_record(1000 + i, 80)).collect();
+
+        let first = select_page(records.clone(), &query(2));
+        assert_eq!(first.records.len(), 2);
+        assert_eq!(first.next_cursor, Some("1001-status".to_string()));
+
+        let mut next = query(2);
+        next.cursor = parse_cursor(first.next_cursor.as_deref().unwrap());
+        let second = select_page(records.clone(), &next);
+        assert_eq!(second.records[0].timestamp, 1002);
+
+        let mut last = query(10);
+        last.cursor = Some((1003, SnapshotKind::Status));
+        let third = select_page(records, &last);
+        assert_eq!(third.records.len(), 1);
+        assert_eq!(third.next_cursor, None);
+    }
+
+    #[test]
+    fn test_select_page_time_range_and_fields() {
+        let records = vec![
+            status_record(1000, 80),
+            odometer_record(1100, 15000.0),
+            status_record(1200, 78),
+        ];
+
+        let mut q = query(10);
+        q.from = 1050;
+        let page = select_page(records.clone(), &q);
+        assert_eq!(page.records.len(), 2);
+
+        q.fields = Some(vec!["odometer".to_string()]);
+        let page = select_page(records, &q);
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.records[0].kind, SnapshotKind::Odometer);
+    }
+
+    #[test]
+    fn test_to_json_with_fields() {
+        let record = HistoryRecord {
+            charging_status: Some("CHARGING".to_string()),
+            ..status_record(1000, 80)
+        };
+
+        let all = record.to_json_with_fields(None);
+        assert_eq!(all["charging_status"], "CHARGING");
+
+        let fields = vec!["soc".to_string()];
+        let selected = record.to_json_with_fields(Some(&fields));
+        assert_eq!(selected["soc"], 80);
+        assert_eq!(selected["kind"], "status");
+        assert!(selected.get("charging_status").is_none());
+    }
+
+    #[test]
+    fn test_parse_fields_and_cursor() {
+        assert_eq!(
+            parse_fields("soc, lat,lon").unwrap(),
+            vec!["soc".to_string(), "lat".to_string(), "lon".to_string()]
+        );
+        assert!(parse_fields("soc,password").is_err());
+
+        assert_eq!(
+            parse_cursor("1736964000-location"),
+            Some((1736964000, SnapshotKind::Location))
+        );
+        assert_eq!(parse_cursor("garbage"), None);
+        assert_eq!(parse_cursor("123-unknown"), None);
+    }
+
+    #[test]
+    fn test_cursor_round_trip() {
+        for record in [odometer_record(-5, 15000.0), status_record(1736964000, 80)] {
+            assert_eq!(
+                parse_cursor(&record.cursor()),
+                Some((record.timestamp, record.kind))
+            );
+        }
+        assert_eq!(
+            parse_cursor("-5-odometer"),
+            Some((-5, SnapshotKind::Odometer))
+        );
+    }
+}
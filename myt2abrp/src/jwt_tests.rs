@@ -29,7 +29,7 @@ fn cleanup_test_env() {
 fn test_generate_access_token_success() {
     setup_test_env();
 
-    let result = generate_access_token("test@example.com");
+    let result = generate_access_token("test@example.com", SCOPE_READ);
 
     assert!(result.is_ok());
     let token = result.unwrap();
@@ -48,7 +48,7 @@ fn test_generate_access_token_success() {
 fn test_generate_refresh_token_success() {
     setup_test_env();
 
-    let result = generate_refresh_token("test@example.com");
+    let result = generate_refresh_token("test@example.com", SCOPE_READ);
 
     assert!(result.is_ok());
     let token = result.unwrap();
@@ -65,8 +65,8 @@ fn test_generate_refresh_token_success() {
 fn test_access_token_and_refresh_token_are_different() {
     setup_test_env();
 
-    let access_token = generate_access_token("test@example.com").unwrap();
-    let refresh_token = generate_refresh_token("test@example.com").unwrap();
+    let access_token = generate_access_token("test@example.com", SCOPE_READ).unwrap();
+    let refresh_token = generate_refresh_token("test@example.com", SCOPE_READ).unwrap();
 
     // Tokens should be different (different token_type and expiry)
     assert_ne!(access_token, refresh_token);
@@ -79,7 +79,7 @@ fn test_access_token_and_refresh_token_are_different() {
 fn test_verify_valid_access_token() {
     setup_test_env();
 
-    let token = generate_access_token("test@example.com").unwrap();
+    let token = generate_access_token("test@example.com", SCOPE_READ).unwrap();
     let result = verify_token(&token);
 
     assert!(result.is_ok());
@@ -107,7 +107,7 @@ fn test_verify_valid_access_token() {
 fn test_verify_valid_refresh_token() {
     setup_test_env();
 
-    let token = generate_refresh_token("test@example.com").unwrap();
+    let token = generate_refresh_token("test@example.com", SCOPE_READ).unwrap();
     let result = verify_token(&token);
 
     assert!(result.is_ok());
@@ -136,7 +136,7 @@ fn test_verify_invalid_token_format() {
 fn test_verify_tampered_token() {
     setup_test_env();
 
-    let token = generate_access_token("test@example.com").unwrap();
+    let token = generate_access_token("test@example.com", SCOPE_READ).unwrap();
 
     // Tamper with the token by changing one character in the signature
     let mut parts: Vec<&str> = token.split('.').collect();
@@ -155,7 +155,7 @@ fn test_verify_tampered_token() {
 fn test_verify_token_with_wrong_secret() {
     setup_test_env();
 
-    let token = generate_access_token("test@example.com").unwrap();
+    let token = generate_access_token("test@example.com", SCOPE_READ).unwrap();
 
     // Change the secret
     env::set_var("JWT_SECRET", "different-secret-32-bytes-long!");
@@ -173,8 +173,8 @@ fn test_verify_token_with_wrong_secret() {
 fn test_token_expiry_values() {
     setup_test_env();
 
-    let access_token = generate_access_token("test@example.com").unwrap();
-    let refresh_token = generate_refresh_token("test@example.com").unwrap();
+    let access_token = generate_access_token("test@example.com", SCOPE_READ).unwrap();
+    let refresh_token = generate_refresh_token("test@example.com", SCOPE_READ).unwrap();
 
     let access_claims = verify_token(&access_token).unwrap();
     let refresh_claims = verify_token(&refresh_token).unwrap();
@@ -211,8 +211,8 @@ fn test_extract_bearer_token_valid() {
 fn test_different_users_get_different_tokens() {
     setup_test_env();
 
-    let token1 = generate_access_token("user1@example.com").unwrap();
-    let token2 = generate_access_token("user2@example.com").unwrap();
+    let token1 = generate_access_token("user1@example.com", SCOPE_READ).unwrap();
+    let token2 = generate_access_token("user2@example.com", SCOPE_READ).unwrap();
 
     assert_ne!(token1, token2);
 
@@ -230,10 +230,10 @@ fn test_different_users_get_different_tokens() {
 fn test_token_jti_uniqueness() {
     setup_test_env();
 
-    let token1 = generate_access_token("test@example.com").unwrap();
+    let token1 = generate_access_token("test@example.com", SCOPE_READ).unwrap();
     // Small delay to ensure different timestamp
     std::thread::sleep(std::time::Duration::from_millis(10));
-    let token2 = generate_access_token("test@example.com").unwrap();
+    let token2 = generate_access_token("test@example.com", SCOPE_READ).unwrap();
 
     let claims1 = verify_token(&token1).unwrap();
     let claims2 = verify_token(&token2).unwrap();
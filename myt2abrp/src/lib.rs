@@ -9,17 +9,23 @@ use chrono::Utc;
 use hmac::{Hmac, Mac};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use myt::{
-    AuthenticateRequest, AuthenticateResponse, CachedToken, ElectricStatusResponse,
-    LocationResponse, RefreshTokenRequest, TelemetryResponse, TokenRequest, TokenResponse,
-    VehicleListResponse,
+    AuthenticateRequest, AuthenticateResponse, CachedToken, ChargeSchedule, ChargeScheduleResponse,
+    ClimateCommand, ClimateControlRequest, ClimateSettings, ClimateSettingsResponse,
+    ClimateStatusResponse, ElectricCommand, ElectricCommandRequest, ElectricStatusResponse,
+    LocationResponse, RefreshTokenRequest, RemoteCommand, RemoteCommandRequest,
+    RemoteCommandResponse, RemoteStatusResponse, TelemetryResponse, TokenRequest, TokenResponse,
+    VehicleListResponse, AC_PARAMETER_FRONT_DEFROST, AC_PARAMETER_REAR_DEFROST,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::Sha256;
-use spin_sdk::http::{IncomingRequest, IntoResponse, Request, Response};
+use spin_sdk::http::conversions::IntoBody;
+use spin_sdk::http::{
+    Fields, IncomingRequest, IntoResponse, OutgoingResponse, Request, Response, ResponseOutparam,
+};
 use spin_sdk::key_value::Store;
 use spin_sdk::{http_component, variables};
 use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, warn, Instrument};
 use utoipa::{OpenApi, ToSchema};
 use uuid::Uuid;
 
@@ -31,6 +37,75 @@ use metrics::METRICS;
 mod circuit_breaker;
 use circuit_breaker::toyota_api_breaker;
 
+// Single-flight locking to coalesce concurrent upstream fetches
+mod single_flight;
+
+// Time-series history of vehicle snapshots
+mod history;
+
+// Distance and home/away helpers
+mod geo;
+
+// Charging session detection from status snapshots
+mod charging;
+
+// Trip detection from odometer and location snapshots
+mod trips;
+
+// Electricity tariffs and charging cost calculation
+mod tariffs;
+
+// Battery health estimation from range/SOC samples
+mod battery;
+
+// GPX, GeoJSON, CSV and NDJSON export of stored history
+mod export;
+
+// Per-user settings documents with versioned updates
+mod settings;
+
+// Charging alert rules evaluated against status snapshots
+mod alerts;
+
+// Remote command records, status polling and idempotency keys
+mod commands;
+
+// Scheduled climate actions (departure preconditioning, auto-stop)
+mod precondition;
+
+// Door, window and lock state from the remote status
+mod doors;
+
+// Command PIN and step-up tokens for unlocking
+mod step_up;
+
+// Charge timer validation and editing
+mod charge_schedule;
+
+// Signed outbound webhooks on vehicle events, retries and dead letters
+mod webhooks;
+
+// Alert notifications through ntfy, Gotify, e-mail relays and Telegram
+mod notifications;
+
+// Vehicle state over MQTT with Home Assistant discovery
+mod mqtt;
+
+// Minimal MQTT client for retained messages and a last will
+mod mqtt_client;
+
+// Request and upstream spans, W3C traceparent and OTLP/HTTP export
+mod telemetry;
+
+// Append-only security audit log of logins, lockouts and token events
+mod audit;
+
+// Admin API helpers: username hash validation and redacted configuration
+mod admin;
+
+// Token-bucket rate limits per route group, user, client IP and API key
+mod rate_limit;
+
 // OpenAPI Documentation
 #[derive(OpenApi)]
 #[openapi(
@@ -77,6 +152,7 @@ const TOKEN_CACHE_KEY_PREFIX: &str = "toyota_auth_token_";
 const SESSION_KEY_PREFIX: &str = "session_";
 const RATE_LIMIT_KEY_PREFIX: &str = "ratelimit_";
 const REVOKED_TOKEN_KEY_PREFIX: &str = "revoked_";
+const USER_TOKENS_REVOKED_KEY_PREFIX: &str = "tokens_revoked_before_";
 
 // Toyota OAuth Token Cache Settings
 const TOKEN_TTL_SECONDS: i64 = 3600; // 1 hour cache for Toyota OAuth tokens
@@ -85,6 +161,9 @@ const TOKEN_TTL_SECONDS: i64 = 3600; // 1 hour cache for Toyota OAuth tokens
 const VEHICLE_DATA_CACHE_KEY_PREFIX: &str = "vehicle_data_";
 const VEHICLE_DATA_TTL_SECONDS: i64 = 300; // 5 minutes cache for vehicle data
 
+/// Suffixes of the per-VIN vehicle data cache entries
+const VEHICLE_DATA_TYPES: [&str; 3] = ["_status", "_location", "_telemetry"];
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 // JWT Token Settings
@@ -92,6 +171,10 @@ const JWT_ACCESS_TOKEN_EXPIRY: i64 = 900; // 15 minutes
 const JWT_REFRESH_TOKEN_EXPIRY: i64 = 604800; // 7 days
 const JWT_ALGORITHM: Algorithm = Algorithm::HS256;
 
+// Token scopes: reading vehicle data, and sending remote commands (opt-in at login)
+const SCOPE_READ: &str = "vehicle:read";
+const SCOPE_COMMAND: &str = "vehicle:command";
+
 // SECURITY: JWT secret key
 // CRITICAL: Must be set via environment variable in production
 const JWT_SECRET_DEFAULT: &[u8] = b"toyota-gateway-jwt-secret-CHANGE-IN-PRODUCTION";
@@ -102,7 +185,8 @@ const HMAC_KEY_DEFAULT: &[u8] = b"toyota-myt-gateway-hmac-key-change-in-producti
 
 // Rate Limiting Settings
 const RATE_LIMIT_PER_USER_HOUR: u32 = 100; // 100 requests per user per hour
-#[allow(dead_code)] // Reserved for future IP-based rate limiting
+const RATE_LIMIT_AUTH_HOUR: u32 = 30; // 30 auth requests per user per hour
+const RATE_LIMIT_COMMANDS_HOUR: u32 = 20; // 20 vehicle commands per user per hour
 const RATE_LIMIT_PER_IP_HOUR: u32 = 1000; // 1000 requests per IP per hour
 const RATE_LIMIT_LOGIN_ATTEMPTS: u32 = 5; // 5 failed login attempts
 const RATE_LIMIT_LOGIN_LOCKOUT_SECONDS: i64 = 900; // 15 minutes lockout
@@ -120,6 +204,8 @@ struct CurrentStatus {
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Charging status (CHARGING, NOT_CHARGING, etc.)
     pub charging_status: Option<String>,
+    /// Whether energy is flowing right now, derived from `charging_status`
+    pub is_charging: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Estimated EV range in km
     pub ev_range: Option<f32>,
@@ -145,6 +231,9 @@ impl CurrentStatus {
         CurrentStatus {
             soc,
             access_date,
+            is_charging: charging_status
+                .as_deref()
+                .is_some_and(charging::is_actively_charging),
             charging_status,
             ev_range,
             ev_range_with_ac,
@@ -209,6 +298,15 @@ struct Claims {
     pub jti: String,
     /// Token type: "access" or "refresh"
     pub token_type: String,
+    /// Space-separated scopes, empty for tokens issued before scopes existed
+    #[serde(default)]
+    pub scope: String,
+}
+
+impl Claims {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|s| s == scope)
+    }
 }
 
 // Login Request
@@ -218,6 +316,9 @@ struct LoginRequest {
     pub username: String,
     /// User password
     pub password: String,
+    /// Requested scopes, "vehicle:read" (default) and/or "vehicle:command"
+    #[serde(default)]
+    pub scope: Option<String>,
 }
 
 // Login Response
@@ -231,6 +332,8 @@ struct LoginResponse {
     pub token_type: String,
     /// Access token expiry in seconds
     pub expires_in: i64,
+    /// Granted scopes
+    pub scope: String,
 }
 
 // Refresh Token Request
@@ -423,6 +526,38 @@ fn get_hmac_key() -> Vec<u8> {
         .into_bytes()
 }
 
+/// Secret the scheduler tick must present; empty disables the tick
+fn get_scheduler_secret() -> String {
+    variables::get("scheduler_secret").unwrap_or_default()
+}
+
+/// Secret the admin endpoints require; empty disables them
+fn get_admin_secret() -> String {
+    variables::get("admin_secret").unwrap_or_default()
+}
+
+/// Per user and API key policy of a route group, from its `rate_limit_*`
+/// variable ("<requests>/<seconds>")
+fn get_rate_limit_policy(group: rate_limit::RouteGroup) -> rate_limit::Policy {
+    let default = match group {
+        rate_limit::RouteGroup::Auth => rate_limit::Policy::per_hour(RATE_LIMIT_AUTH_HOUR),
+        rate_limit::RouteGroup::Data => rate_limit::Policy::per_hour(RATE_LIMIT_PER_USER_HOUR),
+        rate_limit::RouteGroup::Commands => rate_limit::Policy::per_hour(RATE_LIMIT_COMMANDS_HOUR),
+    };
+    variables::get(group.variable())
+        .ok()
+        .and_then(|value| rate_limit::Policy::parse(&value))
+        .unwrap_or(default)
+}
+
+/// Per client IP policy of every route group, from `rate_limit_per_ip`
+fn get_rate_limit_ip_policy() -> rate_limit::Policy {
+    variables::get("rate_limit_per_ip")
+        .ok()
+        .and_then(|value| rate_limit::Policy::parse(&value))
+        .unwrap_or(rate_limit::Policy::per_hour(RATE_LIMIT_PER_IP_HOUR))
+}
+
 fn get_cors_origin() -> String {
     variables::get("cors_origin").unwrap_or_else(|_| "*".to_string())
 }
@@ -477,7 +612,24 @@ fn validate_production_config() {
     info!("✓ Production configuration validated successfully");
 }
 
-fn generate_access_token(username: &str) -> anyhow::Result<String> {
+/// Normalize a requested scope string; `None` means read-only
+fn parse_scope(requested: Option<&str>) -> Result<String, String> {
+    let mut scopes = vec![SCOPE_READ];
+    for scope in requested.unwrap_or_default().split_whitespace() {
+        match scope {
+            SCOPE_READ => {}
+            SCOPE_COMMAND => {
+                if !scopes.contains(&SCOPE_COMMAND) {
+                    scopes.push(SCOPE_COMMAND);
+                }
+            }
+            other => return Err(format!("Unknown scope: {}", other)),
+        }
+    }
+    Ok(scopes.join(" "))
+}
+
+fn generate_access_token(username: &str, scope: &str) -> anyhow::Result<String> {
     let now = get_current_timestamp();
     let claims = Claims {
         sub: username.to_string(),
@@ -485,6 +637,7 @@ fn generate_access_token(username: &str) -> anyhow::Result<String> {
         iat: now,
         jti: Uuid::new_v4().to_string(),
         token_type: "access".to_string(),
+        scope: scope.to_string(),
     };
 
     let secret = get_jwt_secret();
@@ -498,7 +651,7 @@ fn generate_access_token(username: &str) -> anyhow::Result<String> {
     Ok(token)
 }
 
-fn generate_refresh_token(username: &str) -> anyhow::Result<String> {
+fn generate_refresh_token(username: &str, scope: &str) -> anyhow::Result<String> {
     let now = get_current_timestamp();
     let claims = Claims {
         sub: username.to_string(),
@@ -506,6 +659,7 @@ fn generate_refresh_token(username: &str) -> anyhow::Result<String> {
         iat: now,
         jti: Uuid::new_v4().to_string(),
         token_type: "refresh".to_string(),
+        scope: scope.to_string(),
     };
 
     let secret = get_jwt_secret();
@@ -519,6 +673,29 @@ fn generate_refresh_token(username: &str) -> anyhow::Result<String> {
     Ok(token)
 }
 
+/// Short-lived, single-use token confirming the user re-entered their PIN
+fn generate_step_up_token(username: &str) -> anyhow::Result<String> {
+    let now = get_current_timestamp();
+    let claims = Claims {
+        sub: username.to_string(),
+        exp: now + step_up::STEP_UP_TOKEN_EXPIRY,
+        iat: now,
+        jti: Uuid::new_v4().to_string(),
+        token_type: step_up::STEP_UP_TOKEN_TYPE.to_string(),
+        scope: SCOPE_COMMAND.to_string(),
+    };
+
+    let secret = get_jwt_secret();
+    let token = encode(
+        &Header::new(JWT_ALGORITHM),
+        &claims,
+        &EncodingKey::from_secret(&secret),
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to generate step-up token: {}", e))?;
+
+    Ok(token)
+}
+
 fn verify_token(token: &str) -> anyhow::Result<Claims> {
     let secret = get_jwt_secret();
     let validation = Validation::new(JWT_ALGORITHM);
@@ -544,11 +721,45 @@ fn extract_bearer_token(request: &IncomingRequest) -> Option<String> {
     None
 }
 
+/// Value of a request header (case-insensitive name)
+fn get_request_header(request: &IncomingRequest, header_name: &str) -> Option<String> {
+    request
+        .headers()
+        .entries()
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(header_name))
+        .map(|(_, value)| String::from_utf8_lossy(&value).trim().to_string())
+}
+
 async fn is_token_revoked(store: &Store, jti: &str) -> bool {
     let key = format!("{}{}", REVOKED_TOKEN_KEY_PREFIX, jti);
     store.get(&key).is_ok_and(|opt| opt.is_some())
 }
 
+/// Whether the token was issued before all tokens of its user were revoked
+async fn is_user_token_revoked(store: &Store, claims: &Claims) -> bool {
+    get_tokens_revoked_before(store, &hash_username(&claims.sub))
+        .await
+        .is_some_and(|revoked_before| claims.iat <= revoked_before)
+}
+
+async fn get_tokens_revoked_before(store: &Store, username_hash: &str) -> Option<i64> {
+    let key = format!("{}{}", USER_TOKENS_REVOKED_KEY_PREFIX, username_hash);
+    match store.get(&key) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Revoke every token issued to the user so far
+async fn revoke_user_tokens(store: &Store, username_hash: &str, now: i64) -> anyhow::Result<()> {
+    let key = format!("{}{}", USER_TOKENS_REVOKED_KEY_PREFIX, username_hash);
+    store
+        .set(&key, &serde_json::to_vec(&now)?)
+        .map_err(|e| anyhow::anyhow!("Failed to revoke tokens: {}", e))?;
+    Ok(())
+}
+
 async fn revoke_token(store: &Store, jti: &str, exp: i64) -> anyhow::Result<()> {
     let key = format!("{}{}", REVOKED_TOKEN_KEY_PREFIX, jti);
     let _ttl = (exp - get_current_timestamp()).max(0) as u64;
@@ -566,61 +777,75 @@ async fn revoke_token(store: &Store, jti: &str, exp: i64) -> anyhow::Result<()>
 // RATE LIMITING FUNCTIONS
 // ============================================================================
 
-async fn check_rate_limit(
+/// Take a token from the group's bucket for `id`; the 429 response if the
+/// bucket is empty
+async fn enforce_rate_limit(
     store: &Store,
-    identifier: &str,
-    limit: u32,
-    window_seconds: i64,
-) -> anyhow::Result<bool> {
-    let key = format!("{}{}", RATE_LIMIT_KEY_PREFIX, identifier);
-    let now = get_current_timestamp();
-
-    let mut rate_info = match store.get(&key) {
-        Ok(Some(bytes)) => serde_json::from_slice::<RateLimitInfo>(&bytes).unwrap_or({
-            RateLimitInfo {
-                count: 0,
-                window_start: now,
-                lockout_until: None,
-            }
-        }),
-        _ => RateLimitInfo {
-            count: 0,
-            window_start: now,
-            lockout_until: None,
-        },
+    group: rate_limit::RouteGroup,
+    dimension: rate_limit::Dimension,
+    id: &str,
+) -> anyhow::Result<Option<Response>> {
+    let policy = match dimension {
+        rate_limit::Dimension::Ip => get_rate_limit_ip_policy(),
+        rate_limit::Dimension::User | rate_limit::Dimension::ApiKey => get_rate_limit_policy(group),
+    };
+    let decision = rate_limit::check(store, group, dimension, id, &policy).await?;
+    let Some(retry_after) = decision.retry_after_seconds else {
+        return Ok(None);
     };
 
-    // Check lockout
-    if let Some(lockout_until) = rate_info.lockout_until {
-        if now < lockout_until {
-            return Ok(false); // Still locked out
-        }
-        rate_info.lockout_until = None; // Lockout expired
-    }
+    METRICS.record_rate_limit_hit();
+    warn!(
+        group = group.as_str(),
+        ?dimension,
+        retry_after,
+        "Rate limit exceeded"
+    );
+    let mut response = json_error_response(
+        429,
+        "Rate limit exceeded",
+        &format!(
+            "Maximum {} {} requests per {} seconds, retry in {} seconds",
+            policy.limit,
+            group.as_str(),
+            policy.window_seconds,
+            retry_after
+        ),
+    );
+    response.set_header("retry-after", retry_after.to_string());
+    Ok(Some(response))
+}
 
-    // Reset window if expired
-    if (now - rate_info.window_start) > window_seconds {
-        rate_info.count = 0;
-        rate_info.window_start = now;
-    }
+/// Rate limit a service secret (scheduler, admin) presented as Bearer token
+async fn enforce_api_key_rate_limit(request: &IncomingRequest) -> anyhow::Result<Option<Response>> {
+    let Some(token) = extract_bearer_token(request) else {
+        return Ok(None);
+    };
+    let store = Store::open_default()?;
+    enforce_rate_limit(
+        &store,
+        rate_limit::RouteGroup::Data,
+        rate_limit::Dimension::ApiKey,
+        &hash_username(&token),
+    )
+    .await
+}
 
-    // Increment count
-    rate_info.count += 1;
+fn failed_login_key(username_hash: &str) -> String {
+    format!("{}failed_login_{}", RATE_LIMIT_KEY_PREFIX, username_hash)
+}
 
-    // Check limit
-    if rate_info.count > limit {
-        return Ok(false);
+/// Failed login count and lockout of a user, `None` after a successful login
+async fn get_failed_logins(store: &Store, username_hash: &str) -> Option<RateLimitInfo> {
+    match store.get(&failed_login_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
     }
-
-    // Save updated rate info
-    let bytes = serde_json::to_vec(&rate_info)?;
-    store.set(&key, &bytes)?;
-
-    Ok(true)
 }
 
-async fn record_failed_login(store: &Store, identifier: &str) -> anyhow::Result<()> {
-    let key = format!("{}login_{}", RATE_LIMIT_KEY_PREFIX, identifier);
+/// Count a failed login; returns `true` if this attempt triggered a lockout
+async fn record_failed_login(store: &Store, username_hash: &str) -> anyhow::Result<bool> {
+    let key = failed_login_key(username_hash);
     let now = get_current_timestamp();
 
     let mut rate_info = match store.get(&key) {
@@ -641,10 +866,11 @@ async fn record_failed_login(store: &Store, identifier: &str) -> anyhow::Result<
     rate_info.count += 1;
 
     // Trigger lockout after threshold
-    if rate_info.count >= RATE_LIMIT_LOGIN_ATTEMPTS {
+    let locked_out = rate_info.count >= RATE_LIMIT_LOGIN_ATTEMPTS;
+    if locked_out {
         rate_info.lockout_until = Some(now + RATE_LIMIT_LOGIN_LOCKOUT_SECONDS);
         warn!(
-            identifier = identifier,
+            username_hash = username_hash,
             lockout_seconds = RATE_LIMIT_LOGIN_LOCKOUT_SECONDS,
             failed_attempts = rate_info.count,
             "User locked out after failed login attempts"
@@ -654,12 +880,91 @@ async fn record_failed_login(store: &Store, identifier: &str) -> anyhow::Result<
     let bytes = serde_json::to_vec(&rate_info)?;
     store.set(&key, &bytes)?;
 
+    Ok(locked_out)
+}
+
+async fn clear_failed_logins(store: &Store, username_hash: &str) -> anyhow::Result<()> {
+    let _ = store.delete(&failed_login_key(username_hash));
     Ok(())
 }
 
-async fn clear_failed_logins(store: &Store, identifier: &str) -> anyhow::Result<()> {
-    let key = format!("{}login_{}", RATE_LIMIT_KEY_PREFIX, identifier);
-    let _ = store.delete(&key);
+// ============================================================================
+// AUDIT LOG FUNCTIONS
+// ============================================================================
+
+/// Client IP, user agent and request id of the current request
+fn audit_client(request: &IncomingRequest) -> audit::ClientInfo {
+    audit::ClientInfo::new(
+        get_request_header(request, "spin-client-addr").as_deref(),
+        get_request_header(request, "user-agent").as_deref(),
+        telemetry::request_id(),
+    )
+}
+
+async fn record_audit(
+    store: &Store,
+    username_hash: &str,
+    kind: audit::AuditEventKind,
+    outcome: audit::AuditOutcome,
+    client: &audit::ClientInfo,
+    detail: Option<&str>,
+) {
+    let event = audit::AuditEvent::new(
+        username_hash,
+        kind,
+        outcome,
+        client,
+        detail,
+        get_current_timestamp(),
+    );
+    audit::record(store, event, audit::get_retention_days()).await;
+}
+
+/// Record an admin API call; `affected_user` also gets it in their log
+async fn record_admin_action(
+    store: &Store,
+    client: &audit::ClientInfo,
+    action: &str,
+    target: Option<&str>,
+    affected_user: Option<&str>,
+) {
+    let event = audit::AuditEvent::admin_action(action, target, client, get_current_timestamp());
+    audit::record_for(store, event, affected_user, audit::get_retention_days()).await;
+}
+
+/// Record a failed login and, if it locked the account, the lockout
+async fn record_failed_login_audited(
+    store: &Store,
+    username: &str,
+    client: &audit::ClientInfo,
+    outcome: audit::AuditOutcome,
+    detail: &str,
+) -> anyhow::Result<()> {
+    let username_hash = hash_username(username);
+    record_audit(
+        store,
+        &username_hash,
+        audit::AuditEventKind::Login,
+        outcome,
+        client,
+        Some(detail),
+    )
+    .await;
+
+    if record_failed_login(store, &username_hash).await? {
+        record_audit(
+            store,
+            &username_hash,
+            audit::AuditEventKind::Lockout,
+            audit::AuditOutcome::Blocked,
+            client,
+            Some(&format!(
+                "Locked for {} seconds after {} failed attempts",
+                RATE_LIMIT_LOGIN_LOCKOUT_SECONDS, RATE_LIMIT_LOGIN_ATTEMPTS
+            )),
+        )
+        .await;
+    }
     Ok(())
 }
 
@@ -779,7 +1084,51 @@ async fn set_cached_vehicle_data(
     Ok(())
 }
 
-async fn send_request_once(request: Request) -> anyhow::Result<Response> {
+/// Send a request to Toyota in a client span of the current trace
+///
+/// The span's `traceparent` is propagated and the trace id replaces the
+/// request's `x-correlationid`.
+/// Split an outbound URL into host and path, dropping the query string
+fn split_url(uri: &str) -> (String, String) {
+    let uri = get_path_without_query(uri);
+    match uri.split_once("://").map(|(_, rest)| rest) {
+        Some(rest) => match rest.find('/') {
+            Some(index) => (rest[..index].to_string(), rest[index..].to_string()),
+            None => (rest.to_string(), "/".to_string()),
+        },
+        None => (String::new(), uri.to_string()),
+    }
+}
+
+async fn send_request_once(mut request: Request) -> anyhow::Result<Response> {
+    let (host, url_path) = split_url(request.uri());
+    let method = request.method().to_string();
+
+    let mut span = telemetry::start_span(
+        format!("{} {}", method, telemetry::route_template(&url_path)),
+        telemetry::SpanKind::Client,
+    );
+    span.attribute("http.request.method", method);
+    span.attribute("server.address", host);
+    span.attribute("url.path", url_path);
+    request.set_header("traceparent", span.traceparent());
+    request.set_header("x-correlationid", telemetry::correlation_id());
+
+    let result = send_request_with_breaker(request).await;
+    match &result {
+        Ok(response) => {
+            span.attribute("http.response.status_code", *response.status() as i64);
+            if *response.status() >= 400 {
+                span.set_error(format!("HTTP {}", response.status()));
+            }
+        }
+        Err(e) => span.set_error(e),
+    }
+    span.end();
+    result
+}
+
+async fn send_request_with_breaker(request: Request) -> anyhow::Result<Response> {
     // Check circuit breaker before attempting request
     let breaker = toyota_api_breaker();
 
@@ -789,7 +1138,10 @@ async fn send_request_once(request: Request) -> anyhow::Result<Response> {
     }
 
     // Attempt the request
+    let endpoint = telemetry::route_template(&split_url(request.uri()).1);
+    let started = std::time::Instant::now();
     let result = spin_sdk::http::send::<Request, Response>(request).await;
+    METRICS.record_upstream_call(&endpoint, started.elapsed());
 
     match result {
         Ok(response) => {
@@ -889,19 +1241,182 @@ async fn send_request(request: Request) -> anyhow::Result<Response> {
 }
 
 /// Fetch electric status with caching support
+///
+/// Fresh upstream results are also appended to the VIN's history.
 async fn fetch_or_get_cached_electric_status(
     store: &Store,
     token: &CachedToken,
     vin: &str,
 ) -> anyhow::Result<(ElectricStatusResponse, bool)> {
-    // Check cache first
-    if let Ok(Some(cached_data)) = get_cached_vehicle_data(store, vin, "_status").await {
-        debug!("Using cached electric status for VIN {}", vin);
-        let status: ElectricStatusResponse = serde_json::from_str(&cached_data)?;
-        return Ok((status, true)); // true = from cache
+    let (status, from_cache) = fetch_or_get_cached_vehicle_data(store, vin, "_status", || {
+        fetch_vehicle_electric_status(token, vin)
+    })
+    .await?;
+
+    if !from_cache {
+        match history::HistoryRecord::from_status(&status) {
+            Some(record) => record_history(store, vin, record).await,
+            None => warn!(
+                "Skipped status snapshot for VIN {} with unreadable timestamp {:?}",
+                vin, status.payload.vehicle_info.last_update_timestamp
+            ),
+        }
+    }
+
+    Ok((status, from_cache))
+}
+
+/// Fetch vehicle location with caching support, recording fresh results
+async fn fetch_or_get_cached_location(
+    store: &Store,
+    token: &CachedToken,
+    vin: &str,
+) -> anyhow::Result<(LocationResponse, bool)> {
+    let (location, from_cache) = fetch_or_get_cached_vehicle_data(store, vin, "_location", || {
+        fetch_vehicle_location(token, vin)
+    })
+    .await?;
+
+    if !from_cache {
+        match history::HistoryRecord::from_location(&location) {
+            Some(record) => record_history(store, vin, record).await,
+            None => warn!(
+                "Skipped location snapshot for VIN {} with unreadable timestamp {:?}",
+                vin, location.payload.vehicle_info.last_update_timestamp
+            ),
+        }
+    }
+
+    Ok((location, from_cache))
+}
+
+/// Fetch vehicle telemetry with caching support, recording fresh results
+async fn fetch_or_get_cached_telemetry(
+    store: &Store,
+    token: &CachedToken,
+    vin: &str,
+) -> anyhow::Result<(TelemetryResponse, bool)> {
+    let (telemetry, from_cache) =
+        fetch_or_get_cached_vehicle_data(store, vin, "_telemetry", || {
+            fetch_vehicle_telemetry(token, vin)
+        })
+        .await?;
+
+    if !from_cache {
+        if let Some(record) =
+            history::HistoryRecord::from_telemetry(&telemetry, get_current_timestamp())
+        {
+            record_history(store, vin, record).await;
+        }
+    }
+
+    Ok((telemetry, from_cache))
+}
+
+/// Usable battery capacity for a user: the vehicle setting, falling back to
+/// the `battery_capacity_kwh` variable
+async fn get_user_battery_capacity_kwh(store: &Store, username_hash: &str) -> Option<f64> {
+    settings::get_settings(store, username_hash)
+        .await
+        .vehicle
+        .battery_capacity_kwh
+        .or_else(charging::get_battery_capacity_kwh)
+}
+
+/// Append a snapshot to the history store (best effort)
+///
+/// New (non-duplicate) snapshots are passed on to the snapshot processors.
+async fn record_history(store: &Store, vin: &str, record: history::HistoryRecord) {
+    match history::record_snapshot(
+        store,
+        vin,
+        record.clone(),
+        history::get_retention_days(),
+        get_current_timestamp(),
+    )
+    .await
+    {
+        Ok(true) => {
+            debug!("Recorded history snapshot for VIN {}", vin);
+            process_new_snapshot(store, vin, &record).await;
+        }
+        Ok(false) => debug!("Skipped duplicate history snapshot for VIN {}", vin),
+        Err(e) => warn!(error = %e, "Failed to record history snapshot for VIN {}", vin),
+    }
+}
+
+/// Derive sessions and other state from a newly recorded snapshot
+async fn process_new_snapshot(store: &Store, vin: &str, record: &history::HistoryRecord) {
+    let mut transition = None;
+    if record.kind == history::SnapshotKind::Status {
+        transition = match charging::process_status_snapshot(store, vin, record).await {
+            Ok(transition) => transition,
+            Err(e) => {
+                warn!(error = %e, "Failed to update charging sessions for VIN {}", vin);
+                None
+            }
+        };
+        if let Err(e) = battery::record_sample(store, vin, record).await {
+            warn!(error = %e, "Failed to record battery sample for VIN {}", vin);
+        }
+        match alerts::process_status_snapshot(store, vin, record, transition.as_ref()).await {
+            Ok(fired) => {
+                let now = get_current_timestamp();
+                if let Err(e) = notifications::notify_alerts(store, vin, &fired, now).await {
+                    warn!(error = %e, "Failed to queue notifications for VIN {}", vin);
+                }
+            }
+            Err(e) => warn!(error = %e, "Failed to evaluate alerts for VIN {}", vin),
+        }
+    }
+    if let Err(e) = trips::process_snapshot(store, vin, record).await {
+        warn!(error = %e, "Failed to update trips for VIN {}", vin);
+    }
+    let moving = trips::is_moving(store, vin).await;
+    if let Err(e) = webhooks::process_snapshot(
+        store,
+        vin,
+        record,
+        transition.as_ref(),
+        moving,
+        get_current_timestamp(),
+    )
+    .await
+    {
+        warn!(error = %e, "Failed to dispatch webhooks for VIN {}", vin);
     }
+    if let Err(e) = mqtt::process_snapshot(store, vin, record).await {
+        warn!(error = %e, "Failed to publish MQTT state for VIN {}", vin);
+    }
+}
+
+/// Check that a VIN belongs to the user's Toyota account
+///
+/// Uses the user's vehicle list, cached like other vehicle data. Needed for
+/// endpoints that serve stored data without asking Toyota first.
+async fn user_owns_vin(
+    store: &Store,
+    token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> anyhow::Result<bool> {
+    let (vehicle_list, _) =
+        fetch_or_get_cached_vehicle_data(store, username_hash, "_vehicles", || {
+            fetch_vehicle_list(token)
+        })
+        .await?;
+
+    Ok(vehicle_list
+        .payload
+        .vehicle_info
+        .iter()
+        .any(|v| v.vin == vin))
+}
 
-    // Cache miss - fetch from API with retry logic
+async fn fetch_vehicle_electric_status(
+    token: &CachedToken,
+    vin: &str,
+) -> anyhow::Result<ElectricStatusResponse> {
     debug!("Fetching electric status from API for VIN {}", vin);
     let status_url = format!("{}/v1/global/remote/electric/status?vin={}", API_BASE, vin);
     let access_token = token.access_token.clone();
@@ -912,7 +1427,6 @@ async fn fetch_or_get_cached_electric_status(
             .header("accept", "application/json")
             .header("authorization", format!("Bearer {}", access_token))
             .header("datetime", get_timestamp_ms())
-            .header("x-correlationid", Uuid::new_v4().to_string())
             .build()
     })
     .await?;
@@ -924,64 +1438,509 @@ async fn fetch_or_get_cached_electric_status(
         );
     }
 
-    let status: ElectricStatusResponse = serde_json::from_slice(response.body())?;
-
-    // Cache the response
-    let json_data = serde_json::to_string(&status)?;
-    let _ = set_cached_vehicle_data(store, vin, "_status", &json_data).await;
-
-    Ok((status, false)) // false = fresh from API
+    Ok(serde_json::from_slice(response.body())?)
 }
 
-async fn get_per_user_cached_token(
-    store: &Store,
-    username_hash: &str,
-) -> anyhow::Result<Option<PerUserCachedToken>> {
-    let cache_key = get_user_token_cache_key(username_hash);
-    match store.get(&cache_key) {
-        Ok(Some(bytes)) => {
-            let per_user_token: PerUserCachedToken =
-                serde_json::from_slice(&bytes).map_err(|e| {
-                    anyhow::anyhow!("Failed to deserialize per-user cached token: {}", e)
-                })?;
+/// Send a remote command to Toyota
+///
+/// Sent exactly once: remote commands are not idempotent at Toyota, so a
+/// failed attempt is reported instead of retried.
+async fn send_remote_command(
+    token: &CachedToken,
+    method: spin_sdk::http::Method,
+    url: &str,
+    body: impl IntoBody,
+) -> anyhow::Result<RemoteCommandResponse> {
+    let response = send_request_once(
+        Request::builder()
+            .method(method)
+            .uri(url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("authorization", format!("Bearer {}", token.access_token))
+            .header("datetime", get_timestamp_ms())
+            .body(body)
+            .build(),
+    )
+    .await?;
 
-            // Return without updating - let caller check expiration first
-            Ok(Some(per_user_token))
-        }
-        Ok(None) | Err(_) => Ok(None), // Key doesn't exist or error reading
+    let status = *response.status();
+    let command_response = RemoteCommandResponse::from(response.body());
+    if !(200..300).contains(&status) {
+        anyhow::bail!(
+            "{}",
+            command_response
+                .error_message()
+                .unwrap_or_else(|| format!("Remote command failed with status: {}", status))
+        );
     }
+    Ok(command_response)
 }
 
-async fn save_per_user_token_to_cache(
-    store: &Store,
-    username_hash: &str,
-    per_user_token: &PerUserCachedToken,
-) -> anyhow::Result<()> {
-    let cache_key = get_user_token_cache_key(username_hash);
-    let bytes = serde_json::to_vec(per_user_token)
-        .map_err(|e| anyhow::anyhow!("Failed to serialize per-user token for caching: {}", e))?;
-    store
-        .set(&cache_key, &bytes)
-        .map_err(|e| anyhow::anyhow!("Failed to save per-user token to cache: {}", e))?;
-    Ok(())
-}
-
-async fn cleanup_expired_user_token(store: &Store, username_hash: &str) -> anyhow::Result<()> {
-    let cache_key = get_user_token_cache_key(username_hash);
-    store
-        .delete(&cache_key)
-        .map_err(|e| anyhow::anyhow!("Failed to delete expired token from cache: {}", e))?;
+/// Send a charging command to the vehicle
+async fn send_charge_command(
+    token: &CachedToken,
+    vin: &str,
+    request: &commands::ChargeCommandRequest,
+) -> anyhow::Result<RemoteCommandResponse> {
     debug!(
-        username_hash = username_hash,
-        "Cleaned up expired token for user"
+        "Sending charge command {:?} for VIN {}",
+        request.action, vin
     );
-    Ok(())
+    let command_url = format!("{}/v1/global/remote/electric/command?vin={}", API_BASE, vin);
+    let body = ElectricCommandRequest {
+        command: match request.action {
+            commands::ChargeAction::Start => ElectricCommand::StartCharging,
+            commands::ChargeAction::Stop => ElectricCommand::StopCharging,
+        },
+        charge_limit: request.target_soc,
+    };
+    send_remote_command(token, spin_sdk::http::Method::Post, &command_url, body).await
 }
 
-async fn refresh_access_token(refresh_token: String) -> anyhow::Result<(TokenResponse, String)> {
-    info!("Refreshing access token...");
+async fn fetch_climate_settings(token: &CachedToken, vin: &str) -> anyhow::Result<ClimateSettings> {
+    debug!("Fetching climate settings from API for VIN {}", vin);
+    let settings_url = format!("{}/v1/global/remote/climate-settings?vin={}", API_BASE, vin);
+    let access_token = token.access_token.clone();
 
-    let refresh_request = RefreshTokenRequest::new(refresh_token.clone());
+    let response = send_request_with_retry(|| {
+        Request::get(&settings_url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("authorization", format!("Bearer {}", access_token))
+            .header("datetime", get_timestamp_ms())
+            .build()
+    })
+    .await?;
+
+    if *response.status() != 200 {
+        anyhow::bail!(
+            "Climate settings request failed with status: {}",
+            response.status()
+        );
+    }
+
+    let settings: ClimateSettingsResponse = serde_json::from_slice(response.body())?;
+    Ok(settings.payload)
+}
+
+async fn fetch_climate_status(
+    token: &CachedToken,
+    vin: &str,
+) -> anyhow::Result<ClimateStatusResponse> {
+    debug!("Fetching climate status from API for VIN {}", vin);
+    let status_url = format!("{}/v1/global/remote/climate-status?vin={}", API_BASE, vin);
+    let access_token = token.access_token.clone();
+
+    let response = send_request_with_retry(|| {
+        Request::get(&status_url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("authorization", format!("Bearer {}", access_token))
+            .header("datetime", get_timestamp_ms())
+            .build()
+    })
+    .await?;
+
+    if *response.status() != 200 {
+        anyhow::bail!(
+            "Climate status request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+/// Fetch door, window and lock state, bypassing the cache
+async fn fetch_remote_status(
+    token: &CachedToken,
+    vin: &str,
+) -> anyhow::Result<RemoteStatusResponse> {
+    debug!("Fetching remote status from API for VIN {}", vin);
+    let status_url = format!("{}/v1/global/remote/status?vin={}", API_BASE, vin);
+    let access_token = token.access_token.clone();
+
+    let response = send_request_with_retry(|| {
+        Request::get(&status_url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("authorization", format!("Bearer {}", access_token))
+            .header("datetime", get_timestamp_ms())
+            .build()
+    })
+    .await?;
+
+    if *response.status() != 200 {
+        anyhow::bail!(
+            "Remote status request failed with status: {}",
+            response.status()
+        );
+    }
+
+    Ok(serde_json::from_slice(response.body())?)
+}
+
+/// Send a door lock, hazard light or horn command
+async fn send_door_command(
+    token: &CachedToken,
+    vin: &str,
+    command: RemoteCommand,
+) -> anyhow::Result<RemoteCommandResponse> {
+    debug!("Sending remote command {:?} for VIN {}", command, vin);
+    let command_url = format!("{}/v1/global/remote/command?vin={}", API_BASE, vin);
+    let body = RemoteCommandRequest {
+        command,
+        beep_count: None,
+    };
+    send_remote_command(token, spin_sdk::http::Method::Post, &command_url, body).await
+}
+
+/// Fetch the vehicle's charge timers, bypassing the cache
+async fn fetch_charge_schedule(token: &CachedToken, vin: &str) -> anyhow::Result<ChargeSchedule> {
+    debug!("Fetching charge schedule from API for VIN {}", vin);
+    let schedule_url = format!(
+        "{}/v1/global/remote/electric/charge-schedule?vin={}",
+        API_BASE, vin
+    );
+    let access_token = token.access_token.clone();
+
+    let response = send_request_with_retry(|| {
+        Request::get(&schedule_url)
+            .header("content-type", "application/json")
+            .header("accept", "application/json")
+            .header("authorization", format!("Bearer {}", access_token))
+            .header("datetime", get_timestamp_ms())
+            .build()
+    })
+    .await?;
+
+    if *response.status() != 200 {
+        anyhow::bail!(
+            "Charge schedule request failed with status: {}",
+            response.status()
+        );
+    }
+
+    let schedule: ChargeScheduleResponse = serde_json::from_slice(response.body())?;
+    Ok(schedule.payload)
+}
+
+/// Write the full list of charge timers to the vehicle
+async fn save_charge_schedule(
+    token: &CachedToken,
+    vin: &str,
+    schedule: &ChargeSchedule,
+) -> anyhow::Result<RemoteCommandResponse> {
+    debug!(
+        "Writing {} charge timers for VIN {}",
+        schedule.timers.len(),
+        vin
+    );
+    let schedule_url = format!(
+        "{}/v1/global/remote/electric/charge-schedule?vin={}",
+        API_BASE, vin
+    );
+    send_remote_command(
+        token,
+        spin_sdk::http::Method::Put,
+        &schedule_url,
+        schedule.clone(),
+    )
+    .await
+}
+
+/// Write the requested temperature and defrost into climate settings
+fn apply_climate_request(
+    settings: &mut ClimateSettings,
+    request: &commands::ClimateCommandRequest,
+) -> anyhow::Result<()> {
+    settings.settings_on = true;
+    if let Some(celsius) = request.target_temperature {
+        settings.temperature = if settings.temperature_unit.eq_ignore_ascii_case("F") {
+            celsius * 9.0 / 5.0 + 32.0
+        } else {
+            celsius
+        };
+    }
+    for (name, enabled) in [
+        (AC_PARAMETER_FRONT_DEFROST, request.front_defrost),
+        (AC_PARAMETER_REAR_DEFROST, request.rear_defrost),
+    ] {
+        if let Some(enabled) = enabled {
+            if !settings.set_parameter(name, enabled) {
+                anyhow::bail!("Vehicle does not support {}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send a climate command, updating the climate settings first if needed
+async fn send_climate_command(
+    token: &CachedToken,
+    vin: &str,
+    request: &commands::ClimateCommandRequest,
+) -> anyhow::Result<RemoteCommandResponse> {
+    debug!(
+        "Sending climate command {:?} for VIN {}",
+        request.action, vin
+    );
+    if request.action == commands::ClimateAction::Start && request.changes_settings() {
+        let mut settings = fetch_climate_settings(token, vin).await?;
+        apply_climate_request(&mut settings, request)?;
+        let settings_url = format!("{}/v1/global/remote/climate-settings?vin={}", API_BASE, vin);
+        let response =
+            send_remote_command(token, spin_sdk::http::Method::Put, &settings_url, settings)
+                .await?;
+        if !response.is_accepted() {
+            return Ok(response);
+        }
+    }
+
+    let control_url = format!("{}/v1/global/remote/climate-control?vin={}", API_BASE, vin);
+    let body = ClimateControlRequest {
+        command: match request.action {
+            commands::ClimateAction::Start => ClimateCommand::Start,
+            commands::ClimateAction::Stop => ClimateCommand::Stop,
+        },
+    };
+    send_remote_command(token, spin_sdk::http::Method::Post, &control_url, body).await
+}
+
+/// Send a new command and record Toyota's answer
+///
+/// Accepted climate starts with a duration also schedule their stop.
+async fn execute_command(
+    store: &Store,
+    token: &CachedToken,
+    record: &mut commands::CommandRecord,
+    now: i64,
+) -> anyhow::Result<()> {
+    let result = match &record.request {
+        commands::CommandRequest::Charge(request) => {
+            send_charge_command(token, &record.vin, request).await
+        }
+        commands::CommandRequest::Climate(request) => {
+            send_climate_command(token, &record.vin, request).await
+        }
+        commands::CommandRequest::Lock(request) => {
+            let command = match request.action {
+                commands::LockAction::Lock => RemoteCommand::DoorLock,
+                commands::LockAction::Unlock => RemoteCommand::DoorUnlock,
+            };
+            send_door_command(token, &record.vin, command).await
+        }
+        commands::CommandRequest::Hazard(request) => {
+            let command = match request.action {
+                commands::HazardAction::On => RemoteCommand::HazardOn,
+                commands::HazardAction::Off => RemoteCommand::HazardOff,
+            };
+            send_door_command(token, &record.vin, command).await
+        }
+        commands::CommandRequest::Find(request) => {
+            let command = match request.action {
+                commands::FindAction::Horn => RemoteCommand::SoundHorn,
+                commands::FindAction::HornAndLights => RemoteCommand::FindVehicle,
+            };
+            send_door_command(token, &record.vin, command).await
+        }
+    };
+    match result {
+        Ok(response) if response.is_accepted() => {
+            record.accept(response.request_number(), now);
+            info!(
+                "{} command {} accepted for VIN {}",
+                record.request.kind(),
+                record.id,
+                record.vin
+            );
+            if let commands::CommandRequest::Climate(commands::ClimateCommandRequest {
+                action: commands::ClimateAction::Start,
+                duration_minutes: Some(minutes),
+                ..
+            }) = record.request
+            {
+                let stop_at = now + i64::from(minutes) * 60;
+                precondition::schedule_auto_stop(
+                    store,
+                    &record.username_hash,
+                    &record.vin,
+                    stop_at,
+                    now,
+                )
+                .await?;
+            }
+        }
+        Ok(response) => {
+            let message = response
+                .error_message()
+                .unwrap_or_else(|| "Command rejected by Toyota".to_string());
+            record.fail(message, now);
+        }
+        Err(e) => record.fail(e.to_string(), now),
+    }
+    commands::save_command(store, record).await
+}
+
+/// Fetch the current vehicle state for a pending command
+///
+/// Bypasses the vehicle data cache, which would hide the command's effect.
+async fn check_command(token: &CachedToken, record: &mut commands::CommandRecord, now: i64) {
+    let result = match record.request {
+        commands::CommandRequest::Charge(_) => fetch_vehicle_electric_status(token, &record.vin)
+            .await
+            .map(|status| {
+                let charging_status = status.payload.vehicle_info.charge_info.charging_status;
+                record.apply_charging_status(charging_status.as_deref(), now);
+            }),
+        commands::CommandRequest::Climate(_) => {
+            fetch_climate_status(token, &record.vin)
+                .await
+                .map(|status| {
+                    record.apply_climate_status(status.payload.map(|p| p.status), now);
+                })
+        }
+        commands::CommandRequest::Lock(_) => {
+            fetch_remote_status(token, &record.vin).await.map(|status| {
+                record.apply_lock_status(doors::summarize(&status.payload).locked, now);
+            })
+        }
+        // Done once accepted, nothing to check
+        commands::CommandRequest::Hazard(_) | commands::CommandRequest::Find(_) => Ok(()),
+    };
+    if let Err(e) = result {
+        warn!(error = %e, "Failed to check command {}", record.id);
+        record.last_checked_at = Some(now);
+        record.expire(now);
+    }
+}
+
+/// Fetch vehicle data through the cache, coalescing concurrent upstream calls
+///
+/// Only one request per `(vin, data_type)` calls Toyota after a cache miss;
+/// concurrent requests wait (bounded) for that result to land in the cache.
+/// Returns the data and whether it was served from cache.
+async fn fetch_or_get_cached_vehicle_data<T, F, Fut>(
+    store: &Store,
+    vin: &str,
+    data_type: &str,
+    fetch: F,
+) -> anyhow::Result<(T, bool)>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    if let Ok(Some(cached_data)) = get_cached_vehicle_data(store, vin, data_type).await {
+        return Ok((serde_json::from_str(&cached_data)?, true));
+    }
+
+    let owner = Uuid::new_v4().to_string();
+    let is_leader =
+        single_flight::try_acquire(store, vin, data_type, &owner, get_current_timestamp())
+            .await
+            .unwrap_or(true); // KV trouble must not block fetching
+
+    if !is_leader {
+        debug!(
+            "Waiting for in-flight fetch of VIN {} data_type {}",
+            vin, data_type
+        );
+        if let Some(cached_data) = wait_for_in_flight_fetch(store, vin, data_type).await {
+            METRICS.record_coalesced_request();
+            return Ok((serde_json::from_str(&cached_data)?, true));
+        }
+        warn!(
+            "In-flight fetch for VIN {} data_type {} did not complete, fetching directly",
+            vin, data_type
+        );
+    }
+
+    let result = fetch().await;
+    if let Ok(data) = &result {
+        if let Ok(json_data) = serde_json::to_string(data) {
+            let _ = set_cached_vehicle_data(store, vin, data_type, &json_data).await;
+        }
+    }
+    if is_leader {
+        single_flight::release(store, vin, data_type, &owner).await;
+    }
+
+    result.map(|data| (data, false)) // false = fresh from API
+}
+
+/// Poll the cache until the leader has stored fresh data
+///
+/// Gives up early when the leader released its lock without caching anything
+/// (upstream failure) and after `SINGLE_FLIGHT_MAX_WAIT_MS` at the latest.
+async fn wait_for_in_flight_fetch(store: &Store, vin: &str, data_type: &str) -> Option<String> {
+    let mut waited_ms = 0;
+
+    while waited_ms < single_flight::SINGLE_FLIGHT_MAX_WAIT_MS {
+        single_flight::sleep(single_flight::SINGLE_FLIGHT_POLL_INTERVAL_MS).await;
+        waited_ms += single_flight::SINGLE_FLIGHT_POLL_INTERVAL_MS;
+
+        if let Ok(Some(data)) = get_cached_vehicle_data(store, vin, data_type).await {
+            return Some(data);
+        }
+
+        // Lock gone without cached data: the leader failed
+        single_flight::get_lock(store, vin, data_type).await?;
+    }
+
+    None
+}
+
+async fn get_per_user_cached_token(
+    store: &Store,
+    username_hash: &str,
+) -> anyhow::Result<Option<PerUserCachedToken>> {
+    let cache_key = get_user_token_cache_key(username_hash);
+    match store.get(&cache_key) {
+        Ok(Some(bytes)) => {
+            let per_user_token: PerUserCachedToken =
+                serde_json::from_slice(&bytes).map_err(|e| {
+                    anyhow::anyhow!("Failed to deserialize per-user cached token: {}", e)
+                })?;
+
+            // Return without updating - let caller check expiration first
+            Ok(Some(per_user_token))
+        }
+        Ok(None) | Err(_) => Ok(None), // Key doesn't exist or error reading
+    }
+}
+
+async fn save_per_user_token_to_cache(
+    store: &Store,
+    username_hash: &str,
+    per_user_token: &PerUserCachedToken,
+) -> anyhow::Result<()> {
+    let cache_key = get_user_token_cache_key(username_hash);
+    let bytes = serde_json::to_vec(per_user_token)
+        .map_err(|e| anyhow::anyhow!("Failed to serialize per-user token for caching: {}", e))?;
+    store
+        .set(&cache_key, &bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to save per-user token to cache: {}", e))?;
+    Ok(())
+}
+
+async fn cleanup_expired_user_token(store: &Store, username_hash: &str) -> anyhow::Result<()> {
+    let cache_key = get_user_token_cache_key(username_hash);
+    store
+        .delete(&cache_key)
+        .map_err(|e| anyhow::anyhow!("Failed to delete expired token from cache: {}", e))?;
+    debug!(
+        username_hash = username_hash,
+        "Cleaned up expired token for user"
+    );
+    Ok(())
+}
+
+async fn refresh_access_token(refresh_token: String) -> anyhow::Result<(TokenResponse, String)> {
+    info!("Refreshing access token...");
+
+    let refresh_request = RefreshTokenRequest::new(refresh_token.clone());
     let token_body = format!(
         "grant_type={}&refresh_token={}",
         refresh_request.grant_type, refresh_request.refresh_token
@@ -1131,7 +2090,6 @@ async fn fetch_vehicle_location(
         .header("accept", "application/json")
         .header("authorization", format!("Bearer {}", token.access_token))
         .header("datetime", get_timestamp_ms())
-        .header("x-correlationid", Uuid::new_v4().to_string())
         .build();
 
     let response = send_request(request).await?;
@@ -1155,7 +2113,6 @@ async fn fetch_vehicle_telemetry(
         .header("accept", "application/json")
         .header("authorization", format!("Bearer {}", token.access_token))
         .header("datetime", get_timestamp_ms())
-        .header("x-correlationid", Uuid::new_v4().to_string())
         .build();
 
     let response = send_request(request).await?;
@@ -1179,7 +2136,6 @@ async fn fetch_vehicle_list(token: &CachedToken) -> anyhow::Result<VehicleListRe
         .header("accept", "application/json")
         .header("authorization", format!("Bearer {}", token.access_token))
         .header("datetime", get_timestamp_ms())
-        .header("x-correlationid", Uuid::new_v4().to_string())
         .build();
 
     let response = send_request(request).await?;
@@ -1203,6 +2159,16 @@ fn parse_iso8601_to_timestamp(iso_string: &str) -> i64 {
     Utc::now().timestamp()
 }
 
+/// Parse a time query parameter given as Unix seconds or RFC 3339
+fn parse_time_param(value: &str) -> Option<i64> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Some(timestamp);
+    }
+    chrono::DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|dt| dt.timestamp())
+}
+
 /// Extract the path component from a URI (without query string)
 fn get_path_without_query(uri: &str) -> &str {
     uri.split('?').next().unwrap_or(uri)
@@ -1235,10 +2201,17 @@ fn add_cors_headers(
 ) -> spin_sdk::http::ResponseBuilder {
     let cors_origin = get_cors_origin();
     builder.header("access-control-allow-origin", cors_origin);
-    builder.header("access-control-allow-methods", "GET, POST, OPTIONS");
+    builder.header(
+        "access-control-allow-methods",
+        "GET, POST, PUT, DELETE, OPTIONS",
+    );
     builder.header(
         "access-control-allow-headers",
-        "Content-Type, Authorization",
+        "Content-Type, Authorization, traceparent, X-Request-Id",
+    );
+    builder.header(
+        "access-control-expose-headers",
+        "X-Request-Id, RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset, Retry-After",
     );
     builder
 }
@@ -1266,12 +2239,6 @@ fn log_response(
         }
     }
 
-    // Record error metrics for 4xx and 5xx responses
-    let status_code = response.status();
-    if *status_code >= 400 {
-        METRICS.record_error(path);
-    }
-
     if let Some(user) = username {
         info!(
             method = %method_str,
@@ -1366,12 +2333,40 @@ async fn get_or_refresh_token_for_user(
     Ok(cached_token)
 }
 
+/// Toyota token for running a scheduled action without a user request
+///
+/// Uses the token from the user's last login, refreshed if it expired. The
+/// user has to log in again once the refresh token is no longer accepted.
+async fn scheduled_toyota_token(
+    store: &Store,
+    username_hash: &str,
+    now: i64,
+) -> anyhow::Result<CachedToken> {
+    let per_user_token = get_per_user_cached_token(store, username_hash)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Toyota session expired, please login again"))?;
+    if !per_user_token.token.is_expired(now) {
+        return Ok(per_user_token.token);
+    }
+
+    let (token_response, uuid) =
+        refresh_access_token(per_user_token.token.refresh_token.clone()).await?;
+    let token = CachedToken::from_token_response(token_response, uuid, now);
+    let refreshed = PerUserCachedToken {
+        token: token.clone(),
+        ..per_user_token
+    };
+    save_per_user_token_to_cache(store, username_hash, &refreshed).await?;
+    Ok(token)
+}
+
 // ============================================================================
 // AUTH ENDPOINT HANDLERS
 // ============================================================================
 
 async fn handle_login(request: IncomingRequest) -> Result<Response, anyhow::Error> {
     let store = Store::open_default()?;
+    let client = audit_client(&request);
 
     // Parse request body
     let body_bytes = request.into_body().await?;
@@ -1393,19 +2388,76 @@ async fn handle_login(request: IncomingRequest) -> Result<Response, anyhow::Erro
             .build());
     }
 
+    let scope = match parse_scope(login_req.scope.as_deref()) {
+        Ok(scope) => scope,
+        Err(message) => {
+            return Ok(Response::builder()
+                .status(400)
+                .header("content-type", "application/json")
+                .body(
+                    serde_json::json!({
+                        "error": "Invalid scope",
+                        "message": message
+                    })
+                    .to_string(),
+                )
+                .build());
+        }
+    };
+
     // Check rate limit for this username
-    let rate_limit_key = format!("login_{}", hash_username(&login_req.username));
-    if !check_rate_limit(&store, &rate_limit_key, RATE_LIMIT_PER_USER_HOUR, 3600).await? {
-        METRICS.record_rate_limit_hit();
+    let username_hash = hash_username(&login_req.username);
+    if let Some(response) = enforce_rate_limit(
+        &store,
+        rate_limit::RouteGroup::Auth,
+        rate_limit::Dimension::User,
+        &username_hash,
+    )
+    .await?
+    {
         METRICS.record_login_failure();
-        record_failed_login(&store, &login_req.username).await?;
+        record_failed_login_audited(
+            &store,
+            &login_req.username,
+            &client,
+            audit::AuditOutcome::Blocked,
+            "Rate limit exceeded",
+        )
+        .await?;
+        return Ok(response);
+    }
+
+    // Refuse while the account is locked after repeated failed logins
+    let now = get_current_timestamp();
+    if let Some(lockout_until) = get_failed_logins(&store, &username_hash)
+        .await
+        .and_then(|info| info.lockout_until)
+        .filter(|until| *until > now)
+    {
+        METRICS.record_login_failure();
+        record_audit(
+            &store,
+            &username_hash,
+            audit::AuditEventKind::Login,
+            audit::AuditOutcome::Blocked,
+            &client,
+            Some("Account locked"),
+        )
+        .await;
         return Ok(Response::builder()
             .status(429)
             .header("content-type", "application/json")
-            .body(serde_json::json!({
-                "error": "Rate limit exceeded",
-                "message": format!("Too many login attempts. Please try again in {} minutes", RATE_LIMIT_LOGIN_LOCKOUT_SECONDS / 60)
-            }).to_string())
+            .header("retry-after", (lockout_until - now).to_string())
+            .body(
+                serde_json::json!({
+                    "error": "Account locked",
+                    "message": format!(
+                        "Too many failed logins. Please try again in {} minutes",
+                        (lockout_until - now + 59) / 60
+                    )
+                })
+                .to_string(),
+            )
             .build());
     }
 
@@ -1416,33 +2468,42 @@ async fn handle_login(request: IncomingRequest) -> Result<Response, anyhow::Erro
             METRICS.record_login_attempt();
 
             // Generate JWT tokens
-            let access_token = generate_access_token(&login_req.username)?;
-            let refresh_token = generate_refresh_token(&login_req.username)?;
+            let access_token = generate_access_token(&login_req.username, &scope)?;
+            let refresh_token = generate_refresh_token(&login_req.username, &scope)?;
 
             // Create session
             let session_id = Uuid::new_v4().to_string();
-            let ip_address = None; // Could extract from headers if available
-            let user_agent = None; // Could extract from headers if available
             create_session(
                 &store,
                 &login_req.username,
                 &session_id,
-                ip_address,
-                user_agent,
+                client.ip_address.clone(),
+                client.user_agent.clone(),
             )
             .await?;
 
+            record_audit(
+                &store,
+                &username_hash,
+                audit::AuditEventKind::Login,
+                audit::AuditOutcome::Success,
+                &client,
+                None,
+            )
+            .await;
+
             // Increment active sessions
             METRICS.increment_active_sessions();
 
             // Clear failed login attempts
-            clear_failed_logins(&store, &login_req.username).await?;
+            clear_failed_logins(&store, &username_hash).await?;
 
             let response = LoginResponse {
                 access_token,
                 refresh_token,
                 token_type: "Bearer".to_string(),
                 expires_in: JWT_ACCESS_TOKEN_EXPIRY,
+                scope,
             };
 
             Ok(Response::builder()
@@ -1455,7 +2516,14 @@ async fn handle_login(request: IncomingRequest) -> Result<Response, anyhow::Erro
             // Record failed login attempt
             METRICS.record_login_attempt();
             METRICS.record_login_failure();
-            record_failed_login(&store, &login_req.username).await?;
+            record_failed_login_audited(
+                &store,
+                &login_req.username,
+                &client,
+                audit::AuditOutcome::Failure,
+                "Invalid username or password",
+            )
+            .await?;
 
             Ok(Response::builder()
                 .status(401)
@@ -1474,6 +2542,7 @@ async fn handle_login(request: IncomingRequest) -> Result<Response, anyhow::Erro
 
 async fn handle_refresh(request: IncomingRequest) -> Result<Response, anyhow::Error> {
     let store = Store::open_default()?;
+    let client = audit_client(&request);
 
     // Parse request body
     let body_bytes = request.into_body().await?;
@@ -1484,6 +2553,15 @@ async fn handle_refresh(request: IncomingRequest) -> Result<Response, anyhow::Er
     let claims = match verify_token(&refresh_req.refresh_token) {
         Ok(claims) => {
             if claims.token_type != "refresh" {
+                record_audit(
+                    &store,
+                    &hash_username(&claims.sub),
+                    audit::AuditEventKind::TokenRefresh,
+                    audit::AuditOutcome::Failure,
+                    &client,
+                    Some("Not a refresh token"),
+                )
+                .await;
                 return Ok(Response::builder()
                     .status(401)
                     .header("content-type", "application/json")
@@ -1514,7 +2592,16 @@ async fn handle_refresh(request: IncomingRequest) -> Result<Response, anyhow::Er
     };
 
     // Check if token is revoked
-    if is_token_revoked(&store, &claims.jti).await {
+    if is_token_revoked(&store, &claims.jti).await || is_user_token_revoked(&store, &claims).await {
+        record_audit(
+            &store,
+            &hash_username(&claims.sub),
+            audit::AuditEventKind::TokenRefresh,
+            audit::AuditOutcome::Failure,
+            &client,
+            Some("Refresh token revoked"),
+        )
+        .await;
         return Ok(Response::builder()
             .status(401)
             .header("content-type", "application/json")
@@ -1528,13 +2615,25 @@ async fn handle_refresh(request: IncomingRequest) -> Result<Response, anyhow::Er
             .build());
     }
 
-    // Generate new access token
-    let access_token = generate_access_token(&claims.sub)?;
+    // Generate new access token with the scopes granted at login
+    let scope = parse_scope(Some(&claims.scope)).unwrap_or_else(|_| SCOPE_READ.to_string());
+    let access_token = generate_access_token(&claims.sub, &scope)?;
+
+    record_audit(
+        &store,
+        &hash_username(&claims.sub),
+        audit::AuditEventKind::TokenRefresh,
+        audit::AuditOutcome::Success,
+        &client,
+        None,
+    )
+    .await;
 
     let response = serde_json::json!({
         "access_token": access_token,
         "token_type": "Bearer",
-        "expires_in": JWT_ACCESS_TOKEN_EXPIRY
+        "expires_in": JWT_ACCESS_TOKEN_EXPIRY,
+        "scope": scope
     });
 
     Ok(Response::builder()
@@ -1585,27 +2684,2380 @@ async fn handle_logout(request: &IncomingRequest) -> Result<Response, anyhow::Er
     // Revoke the token
     revoke_token(&store, &claims.jti, claims.exp).await?;
 
-    // Decrement active sessions
-    METRICS.decrement_active_sessions();
+    record_audit(
+        &store,
+        &hash_username(&claims.sub),
+        audit::AuditEventKind::Logout,
+        audit::AuditOutcome::Success,
+        &audit_client(request),
+        None,
+    )
+    .await;
+
+    // Decrement active sessions
+    METRICS.decrement_active_sessions();
+
+    // Could also delete all sessions for this user
+    // For now, just revoke the token
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(
+            serde_json::json!({
+                "message": "Logged out successfully"
+            })
+            .to_string(),
+        )
+        .build())
+}
+
+// ============================================================================
+// HISTORY ENDPOINT HANDLERS
+// ============================================================================
+
+fn json_error_response(status: u16, error: &str, message: &str) -> Response {
+    let error_json = serde_json::json!({
+        "error": error,
+        "message": message,
+        "version": VERSION
+    });
+    add_cors_headers(Response::builder())
+        .status(status)
+        .header("content-type", "application/json")
+        .body(error_json.to_string())
+        .build()
+}
+
+async fn handle_history(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+) -> Result<Response, anyhow::Error> {
+    let now = get_current_timestamp();
+
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to.unwrap_or(now),
+        Err(response) => return Ok(response),
+    };
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from.unwrap_or(to - 86400), // Last 24 hours
+        Err(response) => return Ok(response),
+    };
+    if from > to {
+        return Ok(json_error_response(
+            400,
+            "Invalid parameter",
+            "'from' must not be after 'to'",
+        ));
+    }
+
+    let fields = match get_query_param(full_uri, "fields") {
+        Some(value) => match history::parse_fields(&value) {
+            Ok(fields) => Some(fields),
+            Err(message) => return Ok(json_error_response(400, "Invalid parameter", &message)),
+        },
+        None => None,
+    };
+
+    let limit = get_query_param(full_uri, "limit")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(history::HISTORY_PAGE_SIZE_DEFAULT)
+        .clamp(1, history::HISTORY_PAGE_SIZE_MAX);
+
+    let cursor = match get_query_param(full_uri, "cursor") {
+        Some(value) => match history::parse_cursor(&value) {
+            Some(cursor) => Some(cursor),
+            None => {
+                return Ok(json_error_response(
+                    400,
+                    "Invalid parameter",
+                    "Invalid cursor",
+                ))
+            }
+        },
+        None => None,
+    };
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let query = history::HistoryQuery {
+        from,
+        to,
+        fields,
+        limit,
+        cursor,
+    };
+    let page = history::query(store, vin, &query).await;
+
+    let records: Vec<serde_json::Value> = page
+        .records
+        .iter()
+        .map(|r| r.to_json_with_fields(query.fields.as_deref()))
+        .collect();
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "from": from,
+        "to": to,
+        "count": records.len(),
+        "records": records,
+        "next_cursor": page.next_cursor,
+        "version": VERSION
+    });
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+/// Verify VIN ownership, returning an error response if access is denied
+async fn check_vin_access(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> Option<Response> {
+    match user_owns_vin(store, toyota_token, username_hash, vin).await {
+        Ok(true) => None,
+        Ok(false) => Some(json_error_response(
+            403,
+            "Forbidden",
+            "VIN is not registered to this Toyota account",
+        )),
+        Err(e) => Some(json_error_response(
+            502,
+            "Failed to verify vehicle ownership",
+            &e.to_string(),
+        )),
+    }
+}
+
+/// Parse an optional time query parameter, `Err` holds a 400 response
+fn optional_time_param(full_uri: &str, name: &str) -> Result<Option<i64>, Response> {
+    match get_query_param(full_uri, name) {
+        Some(value) => parse_time_param(&value).map(Some).ok_or_else(|| {
+            json_error_response(
+                400,
+                "Invalid parameter",
+                &format!("'{}' must be Unix seconds or an RFC 3339 timestamp", name),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+async fn handle_charging_sessions(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+) -> Result<Response, anyhow::Error> {
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from,
+        Err(response) => return Ok(response),
+    };
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to,
+        Err(response) => return Ok(response),
+    };
+
+    let limit = get_query_param(full_uri, "limit")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(charging::SESSIONS_PAGE_SIZE_DEFAULT)
+        .clamp(1, charging::SESSIONS_PAGE_SIZE_MAX);
+    let offset = get_query_param(full_uri, "offset")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let capacity_kwh = get_user_battery_capacity_kwh(store, username_hash).await;
+    let sessions = charging::filter_sessions(
+        charging::with_capacity(
+            charging::all_sessions_newest_first(store, vin).await,
+            capacity_kwh,
+        ),
+        from,
+        to,
+    );
+    let total = sessions.len();
+    let page: Vec<serde_json::Value> = sessions
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(charging::ChargingSession::to_json)
+        .collect();
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "total": total,
+        "offset": offset,
+        "limit": limit,
+        "sessions": page,
+        "battery_capacity_kwh": capacity_kwh,
+        "version": VERSION
+    });
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+async fn handle_trips(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+) -> Result<Response, anyhow::Error> {
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from,
+        Err(response) => return Ok(response),
+    };
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to,
+        Err(response) => return Ok(response),
+    };
+
+    let limit = get_query_param(full_uri, "limit")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(trips::TRIPS_PAGE_SIZE_DEFAULT)
+        .clamp(1, trips::TRIPS_PAGE_SIZE_MAX);
+    let offset = get_query_param(full_uri, "offset")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let capacity_kwh = get_user_battery_capacity_kwh(store, username_hash).await;
+    let trips = trips::filter_trips(trips::all_trips_newest_first(store, vin).await, from, to);
+    let page: Vec<serde_json::Value> = trips
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|trip| trip.to_json(capacity_kwh))
+        .collect();
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "total": trips.len(),
+        "offset": offset,
+        "limit": limit,
+        "summary": trips::summarize(&trips, capacity_kwh),
+        "trips": page,
+        "battery_capacity_kwh": capacity_kwh,
+        "version": VERSION
+    });
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+async fn handle_get_tariff(store: &Store, username_hash: &str) -> Response {
+    match tariffs::get_tariff(store, username_hash).await {
+        Some(config) => add_cors_headers(Response::builder())
+            .status(200)
+            .header("content-type", "application/json")
+            .body(serde_json::to_string(&config).unwrap_or_default())
+            .build(),
+        None => json_error_response(
+            404,
+            "Tariff not configured",
+            "Configure a tariff with PUT /tariff",
+        ),
+    }
+}
+
+async fn handle_put_tariff(
+    store: &Store,
+    username_hash: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    let body_bytes = request.into_body().await?;
+    let config: tariffs::TariffConfig = match serde_json::from_slice(&body_bytes) {
+        Ok(config) => config,
+        Err(e) => return Ok(json_error_response(400, "Invalid tariff", &e.to_string())),
+    };
+
+    if let Err(errors) = config.validate() {
+        let error_json = serde_json::json!({
+            "error": "Invalid tariff",
+            "message": errors.join("; "),
+            "errors": errors,
+            "version": VERSION
+        });
+        return Ok(add_cors_headers(Response::builder())
+            .status(400)
+            .header("content-type", "application/json")
+            .body(error_json.to_string())
+            .build());
+    }
+
+    tariffs::save_tariff(store, username_hash, &config).await?;
+    info!("Saved tariff configuration");
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&config)?)
+        .build())
+}
+
+async fn handle_get_settings(store: &Store, username_hash: &str, path: &str) -> Response {
+    let settings = settings::get_settings(store, username_hash).await;
+    let body = match path.strip_prefix("/settings/") {
+        None => settings.to_json(),
+        Some(name) => match settings::Section::parse(name) {
+            Some(section) => settings.section_json(section),
+            None => {
+                return json_error_response(
+                    404,
+                    "Not found",
+                    "Settings sections are vehicle, api and notifications",
+                )
+            }
+        },
+    };
+    add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build()
+}
+
+async fn handle_put_settings(
+    store: &Store,
+    username_hash: &str,
+    path: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    let section = match path
+        .strip_prefix("/settings/")
+        .and_then(settings::Section::parse)
+    {
+        Some(section) => section,
+        None => {
+            return Ok(json_error_response(
+                405,
+                "Method not allowed",
+                "Use PUT /settings/{vehicle|api|notifications}",
+            ))
+        }
+    };
+
+    let body_bytes = request.into_body().await?;
+    let input: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(input) => input,
+        Err(e) => return Ok(json_error_response(400, "Invalid settings", &e.to_string())),
+    };
+
+    let mut user_settings = settings::get_settings(store, username_hash).await;
+    let now = Utc::now().timestamp();
+    let (status, error_json) = match user_settings.apply_update(section, &input, now) {
+        Ok(()) => {
+            settings::save_settings(store, username_hash, &user_settings).await?;
+            info!("Saved settings version {}", user_settings.version);
+            return Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .body(user_settings.section_json(section).to_string())
+                .build());
+        }
+        Err(settings::UpdateError::Invalid(errors)) => (
+            400,
+            serde_json::json!({
+                "error": "Invalid settings",
+                "message": errors
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+                "errors": errors,
+                "version": VERSION
+            }),
+        ),
+        Err(settings::UpdateError::Conflict { current_version }) => (
+            409,
+            serde_json::json!({
+                "error": "Version conflict",
+                "message": "Settings were changed elsewhere; reload and try again",
+                "current_version": current_version,
+                "version": VERSION
+            }),
+        ),
+    };
+
+    Ok(add_cors_headers(Response::builder())
+        .status(status)
+        .header("content-type", "application/json")
+        .body(error_json.to_string())
+        .build())
+}
+
+async fn handle_get_alert_config(store: &Store, username_hash: &str) -> Response {
+    let config = alerts::get_config(store, username_hash)
+        .await
+        .unwrap_or_default();
+    add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&config).unwrap_or_default())
+        .build()
+}
+
+async fn handle_put_alert_config(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let body_bytes = request.into_body().await?;
+    let config: alerts::AlertConfig = match serde_json::from_slice(&body_bytes) {
+        Ok(config) => config,
+        Err(e) => {
+            return Ok(json_error_response(
+                400,
+                "Invalid alert config",
+                &e.to_string(),
+            ))
+        }
+    };
+
+    if let Err(errors) = config.validate() {
+        let error_json = serde_json::json!({
+            "error": "Invalid alert config",
+            "message": errors
+                .iter()
+                .map(|e| format!("{}: {}", e.field, e.message))
+                .collect::<Vec<_>>()
+                .join("; "),
+            "errors": errors,
+            "version": VERSION
+        });
+        return Ok(add_cors_headers(Response::builder())
+            .status(400)
+            .header("content-type", "application/json")
+            .body(error_json.to_string())
+            .build());
+    }
+
+    alerts::save_config(store, username_hash, vin, &config).await?;
+    info!("Saved alert configuration for VIN {}", vin);
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&config)?)
+        .build())
+}
+
+async fn handle_active_alerts(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let open: Vec<serde_json::Value> = alerts::load_alerts(store, username_hash, vin)
+        .await
+        .iter()
+        .rev()
+        .filter(|alert| alert.is_open())
+        .map(|alert| alert.to_json())
+        .collect();
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "count": open.len(),
+        "alerts": open,
+        "version": VERSION
+    });
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+async fn handle_ack_alert(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    alert_id: &str,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let mut stored = alerts::load_alerts(store, username_hash, vin).await;
+    let alert = match alerts::acknowledge(&mut stored, alert_id, get_current_timestamp()) {
+        Ok(alert) => alert,
+        Err(alerts::AckError::NotFound) => {
+            return Ok(json_error_response(
+                404,
+                "Alert not found",
+                "No alert with this id for the VIN",
+            ))
+        }
+        Err(alerts::AckError::Resolved) => {
+            return Ok(json_error_response(
+                409,
+                "Alert resolved",
+                "Resolved alerts cannot be acknowledged",
+            ))
+        }
+    };
+    alerts::save_alerts(store, username_hash, vin, &stored).await?;
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(alert.to_json().to_string())
+        .build())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_vehicle_command(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    claims: &Claims,
+    vin: &str,
+    kind: &str,
+    idempotency_key: Option<String>,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    if !claims.has_scope(SCOPE_COMMAND) {
+        return Ok(json_error_response(
+            403,
+            "Insufficient scope",
+            "Remote commands need a token issued with the vehicle:command scope",
+        ));
+    }
+
+    let idempotency_key = match idempotency_key {
+        Some(key) => key,
+        None => {
+            return Ok(json_error_response(
+                400,
+                "Idempotency key required",
+                "Send a unique Idempotency-Key header with every command",
+            ))
+        }
+    };
+    if let Err(message) = commands::validate_idempotency_key(&idempotency_key) {
+        return Ok(json_error_response(
+            400,
+            "Invalid idempotency key",
+            &message,
+        ));
+    }
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let pin = get_request_header(&request, "x-command-pin");
+    let step_up_token = get_request_header(&request, "x-step-up-token");
+    let body_bytes = request.into_body().await?;
+    let command = match commands::CommandRequest::parse(kind, &body_bytes) {
+        Ok(command) => command,
+        Err(message) => return Ok(json_error_response(400, "Invalid command", &message)),
+    };
+
+    let now = get_current_timestamp();
+    if let Some(existing) =
+        commands::find_by_idempotency_key(store, username_hash, &idempotency_key, now).await
+    {
+        return Ok(idempotent_replay(&existing, vin, &command));
+    }
+
+    if command.requires_step_up() {
+        let confirmation = verify_step_up(
+            store,
+            claims,
+            username_hash,
+            pin.as_deref(),
+            step_up_token.as_deref(),
+            now,
+        )
+        .await?;
+        if let Some(response) = confirmation {
+            return Ok(response);
+        }
+    }
+
+    // Claim the key before Toyota is called, so a retry arriving meanwhile
+    // cannot send the command a second time
+    let mut record = commands::CommandRecord::new(username_hash, vin, command, now);
+    if let Some(existing) =
+        commands::claim_idempotency_key(store, username_hash, &idempotency_key, &record).await?
+    {
+        return Ok(idempotent_replay(&existing, vin, &record.request));
+    }
+    execute_command(store, toyota_token, &mut record, now).await?;
+
+    if record.state == commands::CommandState::Failed {
+        warn!(
+            "{} command {} failed: {:?}",
+            record.request.kind(),
+            record.id,
+            record.message
+        );
+        let error_json = serde_json::json!({
+            "error": "Command failed",
+            "message": record.message,
+            "command": record.to_json(),
+            "version": VERSION
+        });
+        return Ok(add_cors_headers(Response::builder())
+            .status(502)
+            .header("content-type", "application/json")
+            .body(error_json.to_string())
+            .build());
+    }
+
+    Ok(add_cors_headers(Response::builder())
+        .status(202)
+        .header("content-type", "application/json")
+        .header("location", format!("/commands/{}", record.id))
+        .body(record.to_json().to_string())
+        .build())
+}
+
+/// Response for a command whose idempotency key is already taken
+///
+/// The same command is replayed, or answered with 409 while it is still
+/// being sent; a different command under the key is rejected.
+fn idempotent_replay(
+    existing: &commands::CommandRecord,
+    vin: &str,
+    command: &commands::CommandRequest,
+) -> Response {
+    if existing.vin != vin || existing.request != *command {
+        return json_error_response(
+            422,
+            "Idempotency key reused",
+            "This Idempotency-Key was already used for a different command",
+        );
+    }
+    if existing.state == commands::CommandState::Pending {
+        debug!("Command {} for idempotency key still pending", existing.id);
+        let error_json = serde_json::json!({
+            "error": "Command in progress",
+            "message": "A request with this Idempotency-Key is still being sent; poll the command instead of retrying",
+            "command": existing.to_json(),
+            "version": VERSION
+        });
+        return add_cors_headers(Response::builder())
+            .status(409)
+            .header("content-type", "application/json")
+            .header("location", format!("/commands/{}", existing.id))
+            .body(error_json.to_string())
+            .build();
+    }
+    debug!("Replaying command {} for idempotency key", existing.id);
+    add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .header("idempotent-replayed", "true")
+        .body(existing.to_json().to_string())
+        .build()
+}
+
+/// Response for a PIN that was not accepted
+fn pin_error_response(error: step_up::PinError, now: i64) -> Response {
+    match error {
+        step_up::PinError::NotSet => json_error_response(
+            403,
+            "PIN not set",
+            "Set a command PIN with PUT /auth/pin first",
+        ),
+        step_up::PinError::Wrong { remaining_attempts } => {
+            let error_json = serde_json::json!({
+                "error": "Invalid PIN",
+                "message": "The command PIN is not correct",
+                "remaining_attempts": remaining_attempts,
+                "version": VERSION
+            });
+            add_cors_headers(Response::builder())
+                .status(403)
+                .header("content-type", "application/json")
+                .body(error_json.to_string())
+                .build()
+        }
+        step_up::PinError::Locked { until } => {
+            let error_json = serde_json::json!({
+                "error": "PIN locked",
+                "message": format!(
+                    "Too many wrong PINs. Please try again in {} minutes",
+                    (until - now + 59) / 60
+                ),
+                "version": VERSION
+            });
+            add_cors_headers(Response::builder())
+                .status(429)
+                .header("content-type", "application/json")
+                .header("retry-after", (until - now).to_string())
+                .body(error_json.to_string())
+                .build()
+        }
+    }
+}
+
+/// Check the step-up confirmation of a sensitive command
+///
+/// Accepts the command PIN or an unused step-up token of the same user.
+/// Returns the error response if the command must not run.
+async fn verify_step_up(
+    store: &Store,
+    claims: &Claims,
+    username_hash: &str,
+    pin: Option<&str>,
+    step_up_token: Option<&str>,
+    now: i64,
+) -> anyhow::Result<Option<Response>> {
+    if let Some(token) = step_up_token {
+        let step_up_claims = match verify_token(token) {
+            Ok(c) if c.token_type == step_up::STEP_UP_TOKEN_TYPE && c.sub == claims.sub => c,
+            _ => {
+                return Ok(Some(json_error_response(
+                    403,
+                    "Invalid step-up token",
+                    "Step-up token is invalid, expired or issued to another user",
+                )))
+            }
+        };
+        if is_token_revoked(store, &step_up_claims.jti).await {
+            return Ok(Some(json_error_response(
+                403,
+                "Invalid step-up token",
+                "Step-up token was already used",
+            )));
+        }
+        // Single use
+        revoke_token(store, &step_up_claims.jti, step_up_claims.exp).await?;
+        return Ok(None);
+    }
+
+    match pin {
+        Some(pin) => Ok(step_up::check_pin(store, username_hash, pin, now)
+            .await?
+            .err()
+            .map(|e| pin_error_response(e, now))),
+        None => Ok(Some(json_error_response(
+            403,
+            "Step-up required",
+            "Unlocking needs the command PIN (X-Command-PIN header) or a step-up token (X-Step-Up-Token header)",
+        ))),
+    }
+}
+
+/// Set or change the command PIN (`PUT /auth/pin`)
+async fn handle_set_pin(
+    store: &Store,
+    claims: &Claims,
+    username_hash: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    if !claims.has_scope(SCOPE_COMMAND) {
+        return Ok(json_error_response(
+            403,
+            "Insufficient scope",
+            "Setting the command PIN needs a token issued with the vehicle:command scope",
+        ));
+    }
+
+    let body_bytes = request.into_body().await?;
+    let set_pin: step_up::SetPinRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(set_pin) => set_pin,
+        Err(e) => return Ok(json_error_response(400, "Invalid request", &e.to_string())),
+    };
+    if let Err(message) = step_up::validate_pin(&set_pin.pin) {
+        return Ok(json_error_response(400, "Invalid PIN", &message));
+    }
+
+    let now = get_current_timestamp();
+    if step_up::get_pin(store, username_hash).await.is_some() {
+        let Some(current_pin) = set_pin.current_pin.as_deref() else {
+            return Ok(json_error_response(
+                400,
+                "Current PIN required",
+                "Changing the command PIN needs current_pin",
+            ));
+        };
+        if let Err(e) = step_up::check_pin(store, username_hash, current_pin, now).await? {
+            return Ok(pin_error_response(e, now));
+        }
+    }
+
+    step_up::save_pin(
+        store,
+        username_hash,
+        &step_up::PinRecord::new(step_up::hash_pin(username_hash, &set_pin.pin), now),
+    )
+    .await?;
+    info!("Command PIN set");
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::json!({ "pin_set": true }).to_string())
+        .build())
+}
+
+/// Exchange the command PIN for a step-up token (`POST /auth/step-up`)
+async fn handle_step_up(
+    store: &Store,
+    claims: &Claims,
+    username_hash: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    if !claims.has_scope(SCOPE_COMMAND) {
+        return Ok(json_error_response(
+            403,
+            "Insufficient scope",
+            "Step-up needs a token issued with the vehicle:command scope",
+        ));
+    }
+
+    let body_bytes = request.into_body().await?;
+    let step_up_request: step_up::StepUpRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(step_up_request) => step_up_request,
+        Err(e) => return Ok(json_error_response(400, "Invalid request", &e.to_string())),
+    };
+
+    let now = get_current_timestamp();
+    if let Err(e) = step_up::check_pin(store, username_hash, &step_up_request.pin, now).await? {
+        return Ok(pin_error_response(e, now));
+    }
+
+    let token = generate_step_up_token(&claims.sub)?;
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(
+            serde_json::json!({
+                "step_up_token": token,
+                "token_type": "step_up",
+                "expires_in": step_up::STEP_UP_TOKEN_EXPIRY,
+            })
+            .to_string(),
+        )
+        .build())
+}
+
+/// Doors, windows, trunk, hood and lock state
+async fn handle_remote_status(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    match fetch_remote_status(toyota_token, vin).await {
+        Ok(status) => {
+            let mut body = serde_json::to_value(doors::summarize(&status.payload))?;
+            body["vin"] = serde_json::json!(vin);
+            Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .header("cache-control", "no-store")
+                .body(body.to_string())
+                .build())
+        }
+        Err(e) => Ok(json_error_response(
+            502,
+            "Failed to fetch remote status",
+            &e.to_string(),
+        )),
+    }
+}
+
+async fn handle_get_command(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    command_id: &str,
+) -> Result<Response, anyhow::Error> {
+    let mut record = match commands::get_command(store, command_id).await {
+        Some(record) if record.username_hash == username_hash => record,
+        _ => {
+            return Ok(json_error_response(
+                404,
+                "Command not found",
+                "No command with this id",
+            ))
+        }
+    };
+
+    let now = get_current_timestamp();
+    let mut changed = record.expire(now);
+    if record.needs_check(now) {
+        check_command(toyota_token, &mut record, now).await;
+        changed = true;
+    }
+    if changed {
+        commands::save_command(store, &record).await?;
+    }
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(record.to_json().to_string())
+        .build())
+}
+
+/// Current climate state and settings, plus the scheduled precondition
+async fn handle_climate(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let status = fetch_climate_status(toyota_token, vin).await;
+    let settings = fetch_climate_settings(toyota_token, vin).await;
+    if let (Err(e), Err(_)) = (&status, &settings) {
+        return Ok(json_error_response(
+            502,
+            "Failed to fetch climate status",
+            &e.to_string(),
+        ));
+    }
+    let status = status.ok().and_then(|s| s.payload);
+    let actions = precondition::load_actions(store).await;
+    let schedule = precondition::latest_precondition(&actions, username_hash, vin)
+        .map(precondition::ScheduledAction::to_json);
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "running": status.as_ref().map(|s| s.status),
+        "status": status,
+        "settings": settings.ok(),
+        "schedule": schedule,
+    });
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(body.to_string())
+        .build())
+}
+
+/// GET, PUT or DELETE the one-shot departure precondition of a vehicle
+#[allow(clippy::too_many_arguments)]
+async fn handle_climate_schedule(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    claims: &Claims,
+    vin: &str,
+    method: spin_sdk::http::Method,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    use spin_sdk::http::Method;
+
+    if method != Method::Get && !claims.has_scope(SCOPE_COMMAND) {
+        return Ok(json_error_response(
+            403,
+            "Insufficient scope",
+            "Scheduling needs a token issued with the vehicle:command scope",
+        ));
+    }
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let mut actions = precondition::load_actions(store).await;
+    let now = get_current_timestamp();
+    match method {
+        Method::Get => match precondition::latest_precondition(&actions, username_hash, vin) {
+            Some(action) => Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .body(action.to_json().to_string())
+                .build()),
+            None => Ok(json_error_response(
+                404,
+                "No precondition scheduled",
+                "No precondition has been scheduled for this vehicle",
+            )),
+        },
+        Method::Put => {
+            let body_bytes = request.into_body().await?;
+            let action = serde_json::from_slice::<precondition::PreconditionRequest>(&body_bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|req| req.into_action(username_hash, vin, now));
+            let action = match action {
+                Ok(action) => action,
+                Err(message) => return Ok(json_error_response(400, "Invalid schedule", &message)),
+            };
+            let body = action.to_json().to_string();
+            precondition::set_precondition(&mut actions, action);
+            precondition::save_actions(store, &actions).await?;
+            info!("Precondition scheduled for VIN {}", vin);
+
+            Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .body(body)
+                .build())
+        }
+        Method::Delete => {
+            if !precondition::cancel_precondition(&mut actions, username_hash, vin) {
+                return Ok(json_error_response(
+                    404,
+                    "No precondition scheduled",
+                    "No pending precondition for this vehicle",
+                ));
+            }
+            precondition::save_actions(store, &actions).await?;
+            Ok(add_cors_headers(Response::builder())
+                .status(204)
+                .body("")
+                .build())
+        }
+        _ => Ok(json_error_response(
+            405,
+            "Method not allowed",
+            "Use GET, PUT or DELETE",
+        )),
+    }
+}
+
+/// CRUD on the charge timers of a vehicle
+///
+/// `GET` lists the timers (or one with an id), `POST` adds a timer, `PUT`
+/// replaces and `DELETE` removes the timer with the id. Changes answer with
+/// the schedule read back from the vehicle and whether it matches the
+/// request.
+#[allow(clippy::too_many_arguments)]
+async fn handle_charge_schedule(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    claims: &Claims,
+    vin: &str,
+    timer_id: Option<&str>,
+    method: spin_sdk::http::Method,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    use spin_sdk::http::Method;
+
+    if method != Method::Get && !claims.has_scope(SCOPE_COMMAND) {
+        return Ok(json_error_response(
+            403,
+            "Insufficient scope",
+            "Changing charge timers needs a token issued with the vehicle:command scope",
+        ));
+    }
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let mut schedule = match fetch_charge_schedule(toyota_token, vin).await {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            return Ok(json_error_response(
+                502,
+                "Failed to fetch charge schedule",
+                &e.to_string(),
+            ))
+        }
+    };
+    let index = match timer_id {
+        Some(id) => match charge_schedule::position(&schedule, id) {
+            Some(index) => Some(index),
+            None => {
+                return Ok(json_error_response(
+                    404,
+                    "Timer not found",
+                    "No charge timer with this id",
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let created = match (&method, index) {
+        (Method::Get, None) => {
+            let body = charge_schedule::schedule_to_json(vin, &schedule);
+            return Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .header("cache-control", "no-store")
+                .body(body.to_string())
+                .build());
+        }
+        (Method::Get, Some(index)) => {
+            let body = charge_schedule::timer_to_json(index, &schedule.timers[index]);
+            return Ok(add_cors_headers(Response::builder())
+                .status(200)
+                .header("content-type", "application/json")
+                .header("cache-control", "no-store")
+                .body(body.to_string())
+                .build());
+        }
+        (Method::Post, None) | (Method::Put, Some(_)) => {
+            let body_bytes = request.into_body().await?;
+            let existing_id = index.and_then(|index| schedule.timers[index].timer_id.clone());
+            let timer = serde_json::from_slice::<charge_schedule::ChargeTimerRequest>(&body_bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|req| req.into_timer(existing_id));
+            let timer = match timer {
+                Ok(timer) => timer,
+                Err(message) => return Ok(json_error_response(400, "Invalid timer", &message)),
+            };
+            match index {
+                Some(index) => schedule.timers[index] = timer,
+                None => schedule.timers.push(timer),
+            }
+            index.is_none()
+        }
+        (Method::Delete, Some(index)) => {
+            schedule.timers.remove(index);
+            false
+        }
+        _ => {
+            return Ok(json_error_response(
+                405,
+                "Method not allowed",
+                "Use GET or POST on the schedule, GET, PUT or DELETE on a timer",
+            ))
+        }
+    };
+
+    if let Err(message) =
+        charge_schedule::validate_schedule(&schedule.timers, charge_schedule::max_timers(&schedule))
+    {
+        return Ok(json_error_response(400, "Invalid schedule", &message));
+    }
+
+    match save_charge_schedule(toyota_token, vin, &schedule).await {
+        Ok(response) if response.is_accepted() => {}
+        Ok(response) => {
+            return Ok(json_error_response(
+                502,
+                "Charge schedule rejected",
+                &response
+                    .error_message()
+                    .unwrap_or_else(|| "Toyota rejected the charge schedule".to_string()),
+            ))
+        }
+        Err(e) => {
+            return Ok(json_error_response(
+                502,
+                "Failed to save charge schedule",
+                &e.to_string(),
+            ))
+        }
+    }
+    info!("Charge schedule updated for VIN {}", vin);
+
+    // Report what the vehicle applied, falling back to the request
+    let body = match fetch_charge_schedule(toyota_token, vin).await {
+        Ok(effective) => {
+            let mut body = charge_schedule::schedule_to_json(vin, &effective);
+            body["applied"] = serde_json::json!(charge_schedule::is_applied(
+                &schedule.timers,
+                &effective.timers
+            ));
+            body
+        }
+        Err(e) => {
+            warn!("Failed to read back charge schedule for VIN {}: {}", vin, e);
+            let mut body = charge_schedule::schedule_to_json(vin, &schedule);
+            body["applied"] = serde_json::Value::Null;
+            body
+        }
+    };
+    Ok(add_cors_headers(Response::builder())
+        .status(if created { 201 } else { 200 })
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(body.to_string())
+        .build())
+}
+
+fn no_store_json_response(status: u16, body: &serde_json::Value) -> Response {
+    add_cors_headers(Response::builder())
+        .status(status)
+        .header("content-type", "application/json")
+        .header("cache-control", "no-store")
+        .body(body.to_string())
+        .build()
+}
+
+// ============================================================================
+// AUDIT LOG ENDPOINT HANDLERS
+// ============================================================================
+
+/// Check the admin secret, returning the error response if it is missing
+fn check_admin_secret(request: &IncomingRequest) -> Option<Response> {
+    let secret = get_admin_secret();
+    if secret.is_empty() {
+        return Some(json_error_response(
+            503,
+            "Admin API disabled",
+            "Set the admin_secret variable to enable the admin API",
+        ));
+    }
+    let authorized = extract_bearer_token(request).is_some_and(|token| {
+        // Compare through HMAC to avoid leaking the secret through timing
+        hash_username(&token) == hash_username(&secret)
+    });
+    if authorized {
+        None
+    } else {
+        Some(json_error_response(
+            401,
+            "Unauthorized",
+            "Admin secret required as Bearer token",
+        ))
+    }
+}
+
+/// Dispatch `/admin/...` requests once the admin secret has been checked
+///
+/// Every call except reading the audit log is recorded as an admin action.
+async fn handle_admin(
+    request: &IncomingRequest,
+    full_uri: &str,
+    path: &str,
+    method: &spin_sdk::http::Method,
+) -> Result<Response, anyhow::Error> {
+    use spin_sdk::http::Method;
+
+    let store = Store::open_default()?;
+    let client = audit_client(request);
+    let segments: Vec<&str> = path
+        .strip_prefix("/admin/")
+        .unwrap_or_default()
+        .split('/')
+        .collect();
+
+    let response = match segments.as_slice() {
+        ["audit"] => match method {
+            Method::Get => {
+                let user = get_query_param(full_uri, "user");
+                if user.as_deref().is_some_and(|user| {
+                    user != audit::ADMIN_ACTOR && !admin::is_valid_username_hash(user)
+                }) {
+                    return Ok(json_error_response(
+                        400,
+                        "Invalid parameter",
+                        "'user' must be a username hash or 'admin'",
+                    ));
+                }
+                record_admin_action(&store, &client, "view_audit", user.as_deref(), None).await;
+                return Ok(handle_audit_events(&store, full_uri, user.as_deref()).await);
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET"),
+        },
+        ["config"] => match method {
+            Method::Get => {
+                record_admin_action(&store, &client, "view_config", None, None).await;
+                no_store_json_response(
+                    200,
+                    &serde_json::json!({
+                        "config": admin::redacted_config(|name| variables::get(name).ok()),
+                        "version": VERSION
+                    }),
+                )
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET"),
+        },
+        ["circuit-breakers"] => match method {
+            Method::Get => {
+                record_admin_action(&store, &client, "view_circuit_breakers", None, None).await;
+                let breakers: serde_json::Map<String, serde_json::Value> =
+                    circuit_breaker::all_breakers()
+                        .iter()
+                        .map(|(name, breaker)| (name.to_string(), breaker.to_json()))
+                        .collect();
+                no_store_json_response(
+                    200,
+                    &serde_json::json!({ "circuit_breakers": breakers, "version": VERSION }),
+                )
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET"),
+        },
+        ["circuit-breakers", name, "reset"] => match method {
+            Method::Post => match circuit_breaker::all_breakers()
+                .into_iter()
+                .find(|(breaker_name, _)| breaker_name == name)
+            {
+                Some((name, breaker)) => {
+                    breaker.reset();
+                    record_admin_action(&store, &client, "reset_circuit_breaker", Some(name), None)
+                        .await;
+                    no_store_json_response(
+                        200,
+                        &serde_json::json!({ name: breaker.to_json(), "version": VERSION }),
+                    )
+                }
+                None => json_error_response(404, "Not found", "Unknown circuit breaker"),
+            },
+            _ => json_error_response(405, "Method not allowed", "Use POST"),
+        },
+        ["users", username_hash, ..] if !admin::is_valid_username_hash(username_hash) => {
+            json_error_response(
+                400,
+                "Invalid username hash",
+                "Users are addressed by their 64 character hex username hash",
+            )
+        }
+        ["users", username_hash] => match method {
+            Method::Get => {
+                record_admin_action(&store, &client, "lookup_user", Some(username_hash), None)
+                    .await;
+                handle_admin_user(&store, username_hash).await
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET"),
+        },
+        ["users", username_hash, "lockout"] => match method {
+            Method::Delete => {
+                clear_failed_logins(&store, username_hash).await?;
+                record_admin_action(
+                    &store,
+                    &client,
+                    "clear_lockout",
+                    Some(username_hash),
+                    Some(username_hash),
+                )
+                .await;
+                add_cors_headers(Response::builder())
+                    .status(204)
+                    .body("")
+                    .build()
+            }
+            _ => json_error_response(405, "Method not allowed", "Use DELETE"),
+        },
+        ["users", username_hash, "revoke-tokens"] => match method {
+            Method::Post => {
+                let now = get_current_timestamp();
+                revoke_user_tokens(&store, username_hash, now).await?;
+                // Also drop the Toyota session so scheduled actions stop
+                let _ = store.delete(&get_user_token_cache_key(username_hash));
+                record_admin_action(
+                    &store,
+                    &client,
+                    "revoke_tokens",
+                    Some(username_hash),
+                    Some(username_hash),
+                )
+                .await;
+                no_store_json_response(
+                    200,
+                    &serde_json::json!({
+                        "username_hash": username_hash,
+                        "tokens_revoked_before": now,
+                        "version": VERSION
+                    }),
+                )
+            }
+            _ => json_error_response(405, "Method not allowed", "Use POST"),
+        },
+        ["vehicles", vin, "cache"] => match method {
+            Method::Delete => {
+                if !settings::is_valid_vin(vin) {
+                    return Ok(json_error_response(400, "Invalid VIN", "VIN is not valid"));
+                }
+                let mut flushed = Vec::new();
+                for data_type in VEHICLE_DATA_TYPES {
+                    let key = format!("{}{}{}", VEHICLE_DATA_CACHE_KEY_PREFIX, vin, data_type);
+                    if store.exists(&key).unwrap_or(false) {
+                        store.delete(&key)?;
+                        flushed.push(data_type.trim_start_matches('_'));
+                    }
+                }
+                record_admin_action(&store, &client, "flush_vehicle_cache", Some(vin), None).await;
+                no_store_json_response(
+                    200,
+                    &serde_json::json!({ "vin": vin, "flushed": flushed, "version": VERSION }),
+                )
+            }
+            _ => json_error_response(405, "Method not allowed", "Use DELETE"),
+        },
+        _ => json_error_response(404, "Not found", "Unknown admin endpoint"),
+    };
+    Ok(response)
+}
+
+/// What the gateway keeps about a user (`GET /admin/users/{hash}`)
+async fn handle_admin_user(store: &Store, username_hash: &str) -> Response {
+    let now = get_current_timestamp();
+    let toyota_session = get_per_user_cached_token(store, username_hash)
+        .await
+        .ok()
+        .flatten();
+    let failed_logins = get_failed_logins(store, username_hash).await;
+    let recent_events: Vec<audit::AuditEvent> =
+        audit::load_user_events(store, username_hash, &audit::AuditQuery::default())
+            .await
+            .into_iter()
+            .take(10)
+            .collect();
+
+    no_store_json_response(
+        200,
+        &serde_json::json!({
+            "username_hash": username_hash,
+            "toyota_session": toyota_session.map(|session| serde_json::json!({
+                "last_accessed": session.last_accessed,
+                "expires_at": session.token.expires_at,
+                "expired": session.is_ttl_expired(now) || session.token.expires_at <= now,
+            })),
+            "failed_logins": failed_logins.as_ref().map_or(0, |info| info.count),
+            "locked_until": failed_logins
+                .and_then(|info| info.lockout_until)
+                .filter(|until| *until > now),
+            "tokens_revoked_before": get_tokens_revoked_before(store, username_hash).await,
+            "settings_version": settings::get_settings(store, username_hash).await.version,
+            "webhooks": webhooks::load_webhooks(store, username_hash).await.len(),
+            "notification_channels": notifications::load_channels(store, username_hash).await.len(),
+            "recent_audit_events": recent_events,
+            "version": VERSION
+        }),
+    )
+}
+
+/// Audit events, newest first (`GET /auth/audit`, `GET /admin/audit`)
+///
+/// With a `username_hash` the user's own log is listed, otherwise the
+/// recent events of all users. Filters: `kind`, `outcome`, `from`, `to`,
+/// plus `limit` and `offset` for paging.
+async fn handle_audit_events(
+    store: &Store,
+    full_uri: &str,
+    username_hash: Option<&str>,
+) -> Response {
+    let kind = match get_query_param(full_uri, "kind") {
+        Some(value) => match audit::AuditEventKind::parse(&value) {
+            Some(kind) => Some(kind),
+            None => {
+                return json_error_response(
+                    400,
+                    "Invalid parameter",
+                    "'kind' must be login, lockout, token_refresh or logout",
+                )
+            }
+        },
+        None => None,
+    };
+    let outcome = match get_query_param(full_uri, "outcome") {
+        Some(value) => match audit::AuditOutcome::parse(&value) {
+            Some(outcome) => Some(outcome),
+            None => {
+                return json_error_response(
+                    400,
+                    "Invalid parameter",
+                    "'outcome' must be success, failure or blocked",
+                )
+            }
+        },
+        None => None,
+    };
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from,
+        Err(response) => return response,
+    };
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to,
+        Err(response) => return response,
+    };
+    let limit = get_query_param(full_uri, "limit")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(audit::AUDIT_PAGE_SIZE_DEFAULT)
+        .clamp(1, audit::AUDIT_PAGE_SIZE_MAX);
+    let offset = get_query_param(full_uri, "offset")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    let query = audit::AuditQuery {
+        kind,
+        outcome,
+        from,
+        to,
+    };
+    let events = match username_hash {
+        Some(username_hash) => audit::load_user_events(store, username_hash, &query).await,
+        None => audit::load_recent_events(store, &query).await,
+    };
+    let page: Vec<&audit::AuditEvent> = events.iter().skip(offset).take(limit).collect();
+
+    no_store_json_response(
+        200,
+        &serde_json::json!({
+            "total": events.len(),
+            "offset": offset,
+            "limit": limit,
+            "events": page,
+            "retention_days": audit::get_retention_days(),
+            "version": VERSION
+        }),
+    )
+}
+
+/// Subscribe a URL to events of the selected vehicle (`POST /webhooks`)
+///
+/// The answer is the only time the signing secret is shown.
+async fn handle_create_webhook(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    let allowed_hosts = webhooks::get_allowed_hosts();
+    if allowed_hosts.is_empty() {
+        return Ok(json_error_response(
+            503,
+            "Webhooks disabled",
+            "Set the webhook_allowed_hosts variable to enable webhooks",
+        ));
+    }
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let mut hooks = webhooks::load_webhooks(store, username_hash).await;
+    if hooks.len() >= webhooks::MAX_WEBHOOKS {
+        return Ok(json_error_response(
+            409,
+            "Too many webhooks",
+            &format!("At most {} webhooks per user", webhooks::MAX_WEBHOOKS),
+        ));
+    }
+
+    let body_bytes = request.into_body().await?;
+    let webhook = serde_json::from_slice::<webhooks::WebhookRequest>(&body_bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|req| {
+            req.into_webhook(username_hash, vin, &allowed_hosts, get_current_timestamp())
+        });
+    let webhook = match webhook {
+        Ok(webhook) => webhook,
+        Err(message) => return Ok(json_error_response(400, "Invalid webhook", &message)),
+    };
+
+    let mut body = webhook.to_json();
+    body["secret"] = serde_json::json!(webhook.secret);
+    hooks.push(webhook);
+    webhooks::save_webhooks(store, username_hash, &hooks, vin).await?;
+    info!("Webhook created for VIN {}", vin);
+
+    Ok(no_store_json_response(201, &body))
+}
+
+/// GET or DELETE one webhook
+async fn handle_webhook(
+    store: &Store,
+    username_hash: &str,
+    webhook_id: &str,
+    method: spin_sdk::http::Method,
+) -> Result<Response, anyhow::Error> {
+    let mut hooks = webhooks::load_webhooks(store, username_hash).await;
+    let Some(index) = hooks.iter().position(|w| w.id == webhook_id) else {
+        return Ok(json_error_response(
+            404,
+            "Webhook not found",
+            "No webhook with this id",
+        ));
+    };
+
+    match method {
+        spin_sdk::http::Method::Get => Ok(no_store_json_response(200, &hooks[index].to_json())),
+        spin_sdk::http::Method::Delete => {
+            let webhook = hooks.remove(index);
+            webhooks::save_webhooks(store, username_hash, &hooks, &webhook.vin).await?;
+            Ok(add_cors_headers(Response::builder())
+                .status(204)
+                .body("")
+                .build())
+        }
+        _ => Ok(json_error_response(
+            405,
+            "Method not allowed",
+            "Use GET or DELETE",
+        )),
+    }
+}
+
+/// Send a test event to a webhook and report the receiver's answer
+///
+/// Test deliveries are not retried.
+async fn handle_test_webhook(
+    store: &Store,
+    username_hash: &str,
+    webhook_id: &str,
+) -> Result<Response, anyhow::Error> {
+    let hooks = webhooks::load_webhooks(store, username_hash).await;
+    let Some(webhook) = hooks.iter().find(|w| w.id == webhook_id) else {
+        return Ok(json_error_response(
+            404,
+            "Webhook not found",
+            "No webhook with this id",
+        ));
+    };
+
+    let now = get_current_timestamp();
+    let delivery = webhooks::Delivery::new(webhook, &webhooks::VehicleEvent::Test, now);
+    let body = match webhooks::send(webhook, &delivery, now).await {
+        Ok(status) => serde_json::json!({
+            "delivery_id": delivery.id,
+            "delivered": true,
+            "status": status,
+        }),
+        Err(error) => serde_json::json!({
+            "delivery_id": delivery.id,
+            "delivered": false,
+            "error": error,
+        }),
+    };
+    Ok(no_store_json_response(200, &body))
+}
+
+/// Register a notification channel (`POST /notifications/channels`)
+async fn handle_create_notification_channel(
+    store: &Store,
+    username_hash: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    let allowed_hosts = notifications::get_allowed_hosts();
+    if allowed_hosts.is_empty() {
+        return Ok(json_error_response(
+            503,
+            "Notifications disabled",
+            "Set the notification_allowed_hosts variable to enable notification channels",
+        ));
+    }
+
+    let mut channels = notifications::load_channels(store, username_hash).await;
+    if channels.len() >= notifications::MAX_CHANNELS {
+        return Ok(json_error_response(
+            409,
+            "Too many channels",
+            &format!(
+                "At most {} notification channels per user",
+                notifications::MAX_CHANNELS
+            ),
+        ));
+    }
+
+    let body_bytes = request.into_body().await?;
+    let config = serde_json::from_slice::<notifications::ChannelConfig>(&body_bytes)
+        .map_err(|e| e.to_string())
+        .and_then(|config| config.validate(&allowed_hosts).map(|_| config));
+    let config = match config {
+        Ok(config) => config,
+        Err(message) => return Ok(json_error_response(400, "Invalid channel", &message)),
+    };
+
+    let channel = notifications::Channel::new(
+        config,
+        &notifications::get_encryption_key(),
+        get_current_timestamp(),
+    );
+    let body = channel.to_json();
+    channels.push(channel);
+    notifications::save_channels(store, username_hash, &channels).await?;
+    info!("Notification channel created");
+
+    Ok(no_store_json_response(201, &body))
+}
+
+/// GET or DELETE one notification channel
+async fn handle_notification_channel(
+    store: &Store,
+    username_hash: &str,
+    channel_id: &str,
+    method: spin_sdk::http::Method,
+) -> Result<Response, anyhow::Error> {
+    let mut channels = notifications::load_channels(store, username_hash).await;
+    let Some(index) = channels.iter().position(|c| c.id == channel_id) else {
+        return Ok(json_error_response(
+            404,
+            "Channel not found",
+            "No notification channel with this id",
+        ));
+    };
+
+    match method {
+        spin_sdk::http::Method::Get => Ok(no_store_json_response(200, &channels[index].to_json())),
+        spin_sdk::http::Method::Delete => {
+            channels.remove(index);
+            notifications::save_channels(store, username_hash, &channels).await?;
+            Ok(add_cors_headers(Response::builder())
+                .status(204)
+                .body("")
+                .build())
+        }
+        _ => Ok(json_error_response(
+            405,
+            "Method not allowed",
+            "Use GET or DELETE",
+        )),
+    }
+}
+
+/// Send a test message through a channel and report the server's answer
+///
+/// Test messages ignore quiet hours.
+async fn handle_test_notification_channel(
+    store: &Store,
+    username_hash: &str,
+    channel_id: &str,
+) -> Result<Response, anyhow::Error> {
+    let channels = notifications::load_channels(store, username_hash).await;
+    let Some(channel) = channels.iter().find(|c| c.id == channel_id) else {
+        return Ok(json_error_response(
+            404,
+            "Channel not found",
+            "No notification channel with this id",
+        ));
+    };
+
+    let preferences = notifications::get_preferences(store, username_hash).await;
+    let body = match notifications::send(channel, &preferences.test_message()).await {
+        Ok(status) => serde_json::json!({
+            "delivered": true,
+            "status": status,
+        }),
+        Err(error) => serde_json::json!({
+            "delivered": false,
+            "error": error,
+        }),
+    };
+    Ok(no_store_json_response(200, &body))
+}
+
+async fn handle_put_notification_preferences(
+    store: &Store,
+    username_hash: &str,
+    request: IncomingRequest,
+) -> Result<Response, anyhow::Error> {
+    let body_bytes = request.into_body().await?;
+    let preferences: notifications::NotificationPreferences =
+        match serde_json::from_slice(&body_bytes) {
+            Ok(preferences) => preferences,
+            Err(e) => {
+                return Ok(json_error_response(
+                    400,
+                    "Invalid preferences",
+                    &e.to_string(),
+                ))
+            }
+        };
+
+    if let Err(errors) = preferences.validate() {
+        let error_json = serde_json::json!({
+            "error": "Invalid preferences",
+            "message": errors.join("; "),
+            "errors": errors,
+            "version": VERSION
+        });
+        return Ok(add_cors_headers(Response::builder())
+            .status(400)
+            .header("content-type", "application/json")
+            .body(error_json.to_string())
+            .build());
+    }
+
+    notifications::save_preferences(store, username_hash, &preferences).await?;
+    info!("Saved notification preferences");
+
+    Ok(no_store_json_response(
+        200,
+        &serde_json::to_value(&preferences)?,
+    ))
+}
+
+/// Run due scheduled climate actions
+///
+/// Called by an external timer (cron, Spin cron trigger, ...) with the
+/// `scheduler_secret` as Bearer token. Disabled while no secret is set.
+async fn handle_scheduler_tick(request: &IncomingRequest) -> Result<Response, anyhow::Error> {
+    let secret = get_scheduler_secret();
+    if secret.is_empty() {
+        return Ok(json_error_response(
+            503,
+            "Scheduler disabled",
+            "Set the scheduler_secret variable to enable scheduled actions",
+        ));
+    }
+    let authorized = extract_bearer_token(request).is_some_and(|token| {
+        // Compare through HMAC to avoid leaking the secret through timing
+        hash_username(&token) == hash_username(&secret)
+    });
+    if !authorized {
+        return Ok(json_error_response(
+            401,
+            "Unauthorized",
+            "Scheduler secret required as Bearer token",
+        ));
+    }
+    if let Some(response) = enforce_api_key_rate_limit(request).await? {
+        return Ok(response);
+    }
+
+    let store = Store::open_default()?;
+    let now = get_current_timestamp();
+    let mut actions = precondition::load_actions(&store).await;
+    let due = precondition::take_due(&mut actions, now);
+    precondition::save_actions(&store, &actions).await?;
+
+    let mut results = Vec::new();
+    for action in &due {
+        let result = match scheduled_toyota_token(&store, &action.username_hash, now).await {
+            Ok(token) => {
+                let request = commands::CommandRequest::Climate(action.request.clone());
+                let mut record =
+                    commands::CommandRecord::new(&action.username_hash, &action.vin, request, now);
+                execute_command(&store, &token, &mut record, now)
+                    .await
+                    .map(|_| record)
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(record) => {
+                info!(
+                    "Scheduled {:?} for VIN {} sent as command {}",
+                    action.purpose, action.vin, record.id
+                );
+                results.push((action.id.clone(), Some(record.id), record.message));
+            }
+            Err(e) => {
+                warn!(error = %e, "Scheduled {:?} for VIN {} failed", action.purpose, action.vin);
+                results.push((action.id.clone(), None, Some(e.to_string())));
+            }
+        }
+    }
+
+    // Reload: the commands above may have scheduled auto-stops
+    if !results.is_empty() {
+        actions = precondition::load_actions(&store).await;
+        for (id, command_id, message) in results {
+            precondition::record_result(&mut actions, &id, command_id, message);
+        }
+        precondition::save_actions(&store, &actions).await?;
+    }
+
+    let webhook_retries = match webhooks::retry_due(&store, now).await {
+        Ok(attempted) => attempted,
+        Err(e) => {
+            warn!(error = %e, "Failed to retry webhook deliveries");
+            0
+        }
+    };
+    let notifications_sent = match notifications::send_queued(&store).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            warn!(error = %e, "Failed to send queued notifications");
+            0
+        }
+    };
+    let mqtt_vehicles = match mqtt::republish_all(&store).await {
+        Ok(vehicles) => vehicles,
+        Err(e) => {
+            warn!(error = %e, "Failed to republish MQTT state");
+            0
+        }
+    };
+
+    let body = serde_json::json!({
+        "executed": due.len(),
+        "webhook_retries": webhook_retries,
+        "notifications": notifications_sent,
+        "mqtt_vehicles": mqtt_vehicles,
+        "pending": actions.iter().filter(|a| a.is_pending()).count(),
+        "timestamp": now,
+    });
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+async fn handle_charging_costs(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+) -> Result<Response, anyhow::Error> {
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from,
+        Err(response) => return Ok(response),
+    };
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let config = match tariffs::get_tariff(store, username_hash).await {
+        Some(config) => config,
+        None => {
+            return Ok(json_error_response(
+                404,
+                "Tariff not configured",
+                "Configure a tariff with PUT /tariff",
+            ))
+        }
+    };
+
+    let sessions = charging::filter_sessions(
+        charging::with_capacity(
+            charging::all_sessions_newest_first(store, vin).await,
+            get_user_battery_capacity_kwh(store, username_hash).await,
+        ),
+        from,
+        to,
+    );
+    let priced: Vec<(f64, f64)> = sessions
+        .iter()
+        .filter_map(|s| Some((s.energy_added_kwh?, config.session_cost(s)?)))
+        .collect();
+    let total_energy: f64 = priced.iter().map(|(energy, _)| energy).sum();
+    let total_cost: f64 = priced.iter().map(|(_, cost)| cost).sum();
+    let avg_price_per_kwh = (total_energy > 0.0).then(|| total_cost / total_energy);
+    let per_session_avg = (!priced.is_empty()).then(|| total_cost / priced.len() as f64);
+
+    let session_costs: Vec<serde_json::Value> = sessions
+        .iter()
+        .map(|s| {
+            serde_json::json!({
+                "id": s.id,
+                "started_at": s.started_at,
+                "ended_at": s.ended_at,
+                "place": s.place,
+                "energy_kwh": s.energy_added_kwh,
+                "cost": config.session_cost(s).map(tariffs::round_money),
+            })
+        })
+        .collect();
+    let monthly: Vec<serde_json::Value> = tariffs::monthly_totals(&config, &sessions)
+        .into_iter()
+        .map(|m| {
+            serde_json::json!({
+                "month": m.month,
+                "sessions": m.sessions,
+                "energy_kwh": m.energy_kwh,
+                "cost": tariffs::round_money(m.cost),
+            })
+        })
+        .collect();
+
+    let body = serde_json::json!({
+        "vin": vin,
+        "currency": config.currency,
+        "session_count": sessions.len(),
+        "total_energy_kwh": total_energy,
+        "total_cost": tariffs::round_money(total_cost),
+        "per_session_avg": per_session_avg.map(tariffs::round_money),
+        "avg_price_per_kwh": avg_price_per_kwh,
+        "monthly": monthly,
+        "sessions": session_costs,
+        "version": VERSION
+    });
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+async fn handle_battery_health(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+) -> Result<Response, anyhow::Error> {
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let samples = battery::load_samples(store, vin).await;
+    let capacity_kwh = get_user_battery_capacity_kwh(store, username_hash).await;
+    let sessions = charging::with_capacity(
+        charging::all_sessions_newest_first(store, vin).await,
+        capacity_kwh,
+    );
+    let estimate = battery::estimate(
+        &samples,
+        &sessions,
+        battery::get_rated_range(),
+        capacity_kwh,
+    );
+
+    let mut body = serde_json::to_value(&estimate)?;
+    if let Some(object) = body.as_object_mut() {
+        object.insert("vin".into(), vin.into());
+        object.insert("version".into(), VERSION.into());
+    }
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .build())
+}
+
+/// Export stored locations as GPX (`gpx`) or GeoJSON (`geojson`)
+async fn handle_location_export(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+    format: &str,
+) -> Result<Response, anyhow::Error> {
+    let now = get_current_timestamp();
+
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to.unwrap_or(now),
+        Err(response) => return Ok(response),
+    };
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from.unwrap_or(to - export::EXPORT_DEFAULT_RANGE_SECONDS),
+        Err(response) => return Ok(response),
+    };
+    if from > to {
+        return Ok(json_error_response(
+            400,
+            "Invalid parameter",
+            "'from' must not be after 'to'",
+        ));
+    }
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let records = history::load_range(store, vin, from, to).await;
+    let trips = trips::filter_trips(
+        trips::all_trips_newest_first(store, vin).await,
+        None,
+        Some(to),
+    );
+    let segments = export::segment_points(export::build_points(&records), &trips);
+    debug!(
+        "Exporting {} location segments for VIN {} as {}",
+        segments.len(),
+        vin,
+        format
+    );
+
+    let (content_type, body) = if format == "gpx" {
+        ("application/gpx+xml", export::to_gpx(vin, &segments))
+    } else {
+        (
+            "application/geo+json",
+            export::to_geojson(vin, &segments).to_string(),
+        )
+    };
+
+    Ok(add_cors_headers(Response::builder())
+        .status(200)
+        .header("content-type", content_type)
+        .header(
+            "content-disposition",
+            format!("attachment; filename=\"locations-{}.{}\"", vin, format),
+        )
+        .body(body)
+        .build())
+}
+
+/// Export stored history as CSV or NDJSON, streaming one day at a time
+///
+/// Validation and access errors are returned as regular responses. Once the
+/// export starts, the response is written to `response_out` directly and the
+/// returned response only describes it for logging.
+async fn handle_history_export(
+    store: &Store,
+    toyota_token: &CachedToken,
+    username_hash: &str,
+    vin: &str,
+    full_uri: &str,
+    format: export::TabularFormat,
+    response_out: &mut Option<ResponseOutparam>,
+) -> Result<Response, anyhow::Error> {
+    use futures::SinkExt;
+
+    let now = get_current_timestamp();
+
+    let to = match optional_time_param(full_uri, "to") {
+        Ok(to) => to.unwrap_or(now),
+        Err(response) => return Ok(response),
+    };
+    let from = match optional_time_param(full_uri, "from") {
+        Ok(from) => from.unwrap_or(to - export::EXPORT_DEFAULT_RANGE_SECONDS),
+        Err(response) => return Ok(response),
+    };
+    if from > to {
+        return Ok(json_error_response(
+            400,
+            "Invalid parameter",
+            "'from' must not be after 'to'",
+        ));
+    }
+
+    let columns = match export::parse_columns(get_query_param(full_uri, "columns").as_deref()) {
+        Ok(columns) => columns,
+        Err(message) => return Ok(json_error_response(400, "Invalid parameter", &message)),
+    };
+    let unit = match get_query_param(full_uri, "units") {
+        Some(value) => match export::DistanceUnit::parse(&value) {
+            Some(unit) => unit,
+            None => {
+                return Ok(json_error_response(
+                    400,
+                    "Invalid parameter",
+                    "'units' must be 'km' or 'mi'",
+                ))
+            }
+        },
+        // Unit from the user's vehicle settings
+        None => {
+            settings::get_settings(store, username_hash)
+                .await
+                .vehicle
+                .distance_unit
+        }
+    };
+
+    if let Some(response) = check_vin_access(store, toyota_token, username_hash, vin).await {
+        return Ok(response);
+    }
+
+    let export = export::TabularExport {
+        format,
+        columns,
+        unit,
+    };
+    let response_out = response_out
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Response has already been sent"))?;
+
+    let headers = Fields::from_list(
+        &[
+            ("content-type".to_owned(), export.content_type().into()),
+            (
+                "content-disposition".to_owned(),
+                format!(
+                    "attachment; filename=\"history-{}.{}\"",
+                    vin,
+                    export.extension()
+                )
+                .into(),
+            ),
+            (
+                "access-control-allow-origin".to_owned(),
+                get_cors_origin().into(),
+            ),
+            (
+                telemetry::REQUEST_ID_HEADER.to_owned(),
+                telemetry::request_id().unwrap_or_default().into(),
+            ),
+        ]
+        .into_iter()
+        .chain(
+            rate_limit::current()
+                .into_iter()
+                .flat_map(|decision| decision.headers())
+                .map(|(name, value)| (name.to_owned(), value.into_bytes())),
+        )
+        .collect::<Vec<_>>(),
+    )
+    .map_err(|e| anyhow::anyhow!("Invalid export headers: {:?}", e))?;
+    let response = OutgoingResponse::new(headers);
+    response
+        .set_status_code(200)
+        .map_err(|_| anyhow::anyhow!("Invalid status code"))?;
+    let mut body = response.take_body();
+    response_out.set(response);
+
+    let mut chunks = export.header().into_iter().collect::<Vec<_>>();
+    let mut rows = 0usize;
+    'days: for day in history::days_in_range(store, vin, from, to).await {
+        let records = history::load_day(store, vin, &day, from, to).await;
+        rows += records.len();
+        chunks.push(records.iter().map(|r| export.render_row(r)).collect());
+
+        for chunk in chunks.drain(..) {
+            if let Err(e) = body.send(chunk.into_bytes()).await {
+                warn!(error = %e, "History export for VIN {} aborted", vin);
+                break 'days;
+            }
+        }
+    }
+    // Header of an empty export
+    for chunk in chunks {
+        let _ = body.send(chunk.into_bytes()).await;
+    }
+
+    info!(
+        vin = vin,
+        rows = rows,
+        format = export.extension(),
+        "Streamed history export"
+    );
+
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", export.content_type())
+        .build())
+}
+
+/// Send an HTTP request and return the response.
+#[http_component]
+async fn handle_request(request: IncomingRequest, response_out: ResponseOutparam) {
+    static LOGGING: std::sync::Once = std::sync::Once::new();
+    LOGGING.call_once(init_logging);
+
+    let start_time = std::time::Instant::now();
+    let method = request.method().to_string();
+    let path = get_path_without_query(&request.uri()).to_string();
+    let request_id = telemetry::begin_request(
+        &method,
+        &path,
+        get_request_header(&request, "traceparent").as_deref(),
+        get_request_header(&request, telemetry::REQUEST_ID_HEADER).as_deref(),
+    );
+
+    // Every event logged while serving the request carries its id
+    let span = tracing::info_span!("request", request_id = %request_id);
+    async move {
+        let mut response_out = Some(response_out);
+        let mut response = route_request(request, &mut response_out)
+            .await
+            .into_response();
+        record_response_metrics(&response, start_time, &method, &path);
+
+        // Streaming handlers take the outparam and send the response themselves
+        let status = response_out.as_ref().map(|_| *response.status());
+        let rate_limit = rate_limit::end_request();
+        if let Some(response_out) = response_out {
+            add_request_id(&mut response, &request_id);
+            if let Some(decision) = rate_limit {
+                decision.apply(&mut response);
+            }
+            send_response(response_out, response).await;
+        }
+
+        // Exported after the response is sent, so clients do not wait for it
+        if let Some(spans) = telemetry::end_request(status) {
+            telemetry::export(spans).await;
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Record the final status and latency of a request, whichever branch answered
+fn record_response_metrics(
+    response: &Response,
+    start_time: std::time::Instant,
+    method: &str,
+    path: &str,
+) {
+    let status = *response.status();
+    if status >= 400 {
+        METRICS.record_error(path);
+    }
+    METRICS.record_response(
+        &telemetry::route_template(path),
+        method,
+        status,
+        start_time.elapsed(),
+    );
+}
+
+/// Log to stderr, which Spin collects; the filter comes from `RUST_LOG`
+fn init_logging() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init();
+}
 
-    // Could also delete all sessions for this user
-    // For now, just revoke the token
+/// Add the request id to the response headers and to JSON error bodies
+fn add_request_id(response: &mut Response, request_id: &str) {
+    response.set_header(telemetry::REQUEST_ID_HEADER, request_id);
+    if *response.status() < 400 {
+        return;
+    }
+    if let Ok(serde_json::Value::Object(mut body)) =
+        serde_json::from_slice::<serde_json::Value>(response.body())
+    {
+        if body.contains_key("error") {
+            body.insert("request_id".to_string(), request_id.into());
+            *response.body_mut() = serde_json::Value::Object(body).to_string().into_bytes();
+        }
+    }
+}
 
-    Ok(Response::builder()
-        .status(200)
-        .header("content-type", "application/json")
-        .body(
-            serde_json::json!({
-                "message": "Logged out successfully"
-            })
-            .to_string(),
-        )
-        .build())
+/// Write a buffered response to the outparam
+async fn send_response(response_out: ResponseOutparam, mut response: Response) {
+    let body = std::mem::take(response.body_mut());
+    match OutgoingResponse::try_from(response) {
+        Ok(outgoing) => {
+            if let Err(e) = response_out.set_with_body(outgoing, body).await {
+                error!(error = %e, "Could not send response");
+            }
+        }
+        Err(e) => error!(error = %e, "Could not convert response"),
+    }
 }
 
-/// Send an HTTP request and return the response.
-#[http_component]
-async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, anyhow::Error> {
+async fn route_request(
+    request: IncomingRequest,
+    response_out: &mut Option<ResponseOutparam>,
+) -> Result<impl IntoResponse, anyhow::Error> {
     let start_time = std::time::Instant::now();
 
     // Validate production configuration on first request (static initialization)
@@ -1627,6 +5079,33 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
     // Record request metrics
     METRICS.record_request(path);
 
+    // Rate limiting per client IP (Spin passes the peer address), before any
+    // branch answers so every response carries the RateLimit-* headers
+    if let Some(client_addr) = get_request_header(&request, "spin-client-addr") {
+        let limited = match Store::open_default() {
+            Ok(store) => {
+                enforce_rate_limit(
+                    &store,
+                    rate_limit::RouteGroup::of(path, &method),
+                    rate_limit::Dimension::Ip,
+                    &audit::ip_of(&client_addr),
+                )
+                .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        match limited {
+            Ok(Some(response)) => {
+                log_response(&response, start_time, method, path, None);
+                return Ok(response);
+            }
+            Ok(None) => {}
+            // The health check reports a broken KV store itself
+            Err(e) if path == "/health" => warn!(error = %e, "Skipped rate limit check"),
+            Err(e) => return Err(e),
+        }
+    }
+
     // Handle OPTIONS requests for CORS preflight
     if method == spin_sdk::http::Method::Options {
         debug!("CORS preflight request");
@@ -1712,6 +5191,30 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
         return Ok(response);
     }
 
+    // Scheduler tick (protected by the scheduler secret, not a user token)
+    if path == "/scheduler/tick" {
+        let response = if method == spin_sdk::http::Method::Post {
+            handle_scheduler_tick(&request).await?
+        } else {
+            json_error_response(405, "Method not allowed", "Use POST")
+        };
+        log_response(&response, start_time, method, path, None);
+        return Ok(response);
+    }
+
+    // Admin API (protected by the admin secret)
+    if path.starts_with("/admin/") {
+        let response = match check_admin_secret(&request) {
+            Some(response) => response,
+            None => match enforce_api_key_rate_limit(&request).await? {
+                Some(response) => response,
+                None => handle_admin(&request, &full_uri, path, &method).await?,
+            },
+        };
+        log_response(&response, start_time, method, path, None);
+        return Ok(response);
+    }
+
     // Auth endpoints (handle login, refresh, logout)
     if path == "/auth/login" && method == spin_sdk::http::Method::Post {
         let response = handle_login(request).await?;
@@ -1795,7 +5298,7 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
     let username = &claims.sub;
 
     // Check if token is revoked
-    if is_token_revoked(&store, &claims.jti).await {
+    if is_token_revoked(&store, &claims.jti).await || is_user_token_revoked(&store, &claims).await {
         let error_json = serde_json::json!({
             "error": "Token revoked",
             "message": "This token has been revoked",
@@ -1808,39 +5311,89 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
             .build());
     }
 
+    let username_hash = hash_username(&claims.sub);
+
     // Rate limiting per user
-    let rate_limit_key = format!("api_{}", hash_username(&claims.sub));
-    if !check_rate_limit(&store, &rate_limit_key, RATE_LIMIT_PER_USER_HOUR, 3600).await? {
-        let error_json = serde_json::json!({
-            "error": "Rate limit exceeded",
-            "message": format!("Maximum {} requests per hour", RATE_LIMIT_PER_USER_HOUR),
-            "version": VERSION
-        });
-        return Ok(add_cors_headers(Response::builder())
-            .status(429)
-            .header("content-type", "application/json")
-            .header("retry-after", "3600")
-            .body(error_json.to_string())
-            .build());
+    if let Some(response) = enforce_rate_limit(
+        &store,
+        rate_limit::RouteGroup::of(path, &method),
+        rate_limit::Dimension::User,
+        &username_hash,
+    )
+    .await?
+    {
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Command PIN and step-up tokens (no vehicle involved)
+    if path == "/auth/pin" {
+        let response = if method == spin_sdk::http::Method::Put {
+            handle_set_pin(&store, &claims, &username_hash, request).await?
+        } else {
+            json_error_response(405, "Method not allowed", "Use PUT")
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if path == "/auth/audit" {
+        let response = if method == spin_sdk::http::Method::Get {
+            handle_audit_events(&store, &full_uri, Some(&username_hash)).await
+        } else {
+            json_error_response(405, "Method not allowed", "Use GET")
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if path == "/auth/step-up" {
+        let response = if method == spin_sdk::http::Method::Post {
+            handle_step_up(&store, &claims, &username_hash, request).await?
+        } else {
+            json_error_response(405, "Method not allowed", "Use POST")
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
     }
 
-    // Get VIN from query parameter, or fall back to environment variable
+    // Get VIN from query parameter, then the user's default VIN setting, or
+    // fall back to environment variable
     let vin_from_query = get_query_param(&full_uri, "vin");
+    let vin_from_settings = match vin_from_query {
+        Some(_) => None,
+        None => {
+            settings::get_settings(&store, &username_hash)
+                .await
+                .vehicle
+                .default_vin
+        }
+    };
     let vin = vin_from_query
         .clone()
+        .or_else(|| vin_from_settings.clone())
         .or_else(|| variables::get("vin").ok())
         .unwrap_or_default();
 
     if let Some(ref vin_param) = vin_from_query {
         debug!("Using VIN from query parameter: {}", vin_param);
+    } else if vin_from_settings.is_some() {
+        debug!("Using default VIN from user settings");
     } else if !vin.is_empty() {
         debug!("Using VIN from environment variable");
     }
 
-    if vin.is_empty() && path != "/vehicles" {
+    if vin.is_empty()
+        && path != "/vehicles"
+        && path != "/tariff"
+        && path != "/settings"
+        && !path.starts_with("/settings/")
+        && !path.starts_with("/vehicles/")
+        && !path.starts_with("/commands/")
+    {
         let error_json = serde_json::json!({
             "error": "VIN required",
-            "message": "VIN must be provided via query parameter (?vin=XXX) or environment variable (SPIN_VARIABLE_VIN)",
+            "message": "VIN must be provided via query parameter (?vin=XXX), the default_vin setting (PUT /settings/vehicle) or environment variable (SPIN_VARIABLE_VIN)",
             "version": VERSION
         });
         debug!("VIN not provided for path: {}", path);
@@ -1852,7 +5405,6 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
     }
 
     // Get cached Toyota token for this user
-    let username_hash = hash_username(&claims.sub);
     let toyota_token = match get_per_user_cached_token(&store, &username_hash).await? {
         Some(per_user_token) => per_user_token.token,
         None => {
@@ -1899,29 +5451,15 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
 
     // Handle /location endpoint
     if path == "/location" {
-        // Check cache first
-        if let Ok(Some(cached_data)) = get_cached_vehicle_data(&store, &vin, "_location").await {
-            return Ok(add_cors_headers(Response::builder())
-                .status(200)
-                .header("content-type", "application/json")
-                .header("x-cache", "HIT")
-                .body(cached_data)
-                .build());
-        }
-
-        // Cache miss - fetch from API
-        match fetch_vehicle_location(&toyota_token, &vin).await {
-            Ok(location) => {
+        match fetch_or_get_cached_location(&store, &toyota_token, &vin).await {
+            Ok((location, from_cache)) => {
                 let json_response = serde_json::to_string(&location)
                     .map_err(|e| anyhow::anyhow!("Failed to serialize location: {}", e))?;
 
-                // Cache the response
-                let _ = set_cached_vehicle_data(&store, &vin, "_location", &json_response).await;
-
                 return Ok(add_cors_headers(Response::builder())
                     .status(200)
                     .header("content-type", "application/json")
-                    .header("x-cache", "MISS")
+                    .header("x-cache", if from_cache { "HIT" } else { "MISS" })
                     .body(json_response)
                     .build());
             }
@@ -1942,29 +5480,15 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
 
     // Handle /telemetry endpoint
     if path == "/telemetry" {
-        // Check cache first
-        if let Ok(Some(cached_data)) = get_cached_vehicle_data(&store, &vin, "_telemetry").await {
-            return Ok(add_cors_headers(Response::builder())
-                .status(200)
-                .header("content-type", "application/json")
-                .header("x-cache", "HIT")
-                .body(cached_data)
-                .build());
-        }
-
-        // Cache miss - fetch from API
-        match fetch_vehicle_telemetry(&toyota_token, &vin).await {
-            Ok(telemetry) => {
+        match fetch_or_get_cached_telemetry(&store, &toyota_token, &vin).await {
+            Ok((telemetry, from_cache)) => {
                 let json_response = serde_json::to_string(&telemetry)
                     .map_err(|e| anyhow::anyhow!("Failed to serialize telemetry: {}", e))?;
 
-                // Cache the response
-                let _ = set_cached_vehicle_data(&store, &vin, "_telemetry", &json_response).await;
-
                 return Ok(add_cors_headers(Response::builder())
                     .status(200)
                     .header("content-type", "application/json")
-                    .header("x-cache", "MISS")
+                    .header("x-cache", if from_cache { "HIT" } else { "MISS" })
                     .body(json_response)
                     .build());
             }
@@ -1983,6 +5507,343 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
         }
     }
 
+    // Handle /history endpoint - stored snapshots for a VIN
+    if path == "/history" {
+        let response =
+            handle_history(&store, &toyota_token, &username_hash, &vin, &full_uri).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /charging/sessions endpoint - detected charging sessions
+    if path == "/charging/sessions" {
+        let response =
+            handle_charging_sessions(&store, &toyota_token, &username_hash, &vin, &full_uri)
+                .await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /tariff endpoint - the user's electricity tariff
+    if path == "/tariff" {
+        let response = match method {
+            spin_sdk::http::Method::Get => handle_get_tariff(&store, &username_hash).await,
+            spin_sdk::http::Method::Put => {
+                handle_put_tariff(&store, &username_hash, request).await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or PUT"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /settings endpoints - the user's settings document
+    if path == "/settings" || path.starts_with("/settings/") {
+        let response = match method {
+            spin_sdk::http::Method::Get => handle_get_settings(&store, &username_hash, path).await,
+            spin_sdk::http::Method::Put => {
+                handle_put_settings(&store, &username_hash, path, request).await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or PUT"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /notifications endpoints - channels, test messages, preferences
+    if path == "/notifications/channels" {
+        let response = match method {
+            spin_sdk::http::Method::Get => {
+                let channels = notifications::load_channels(&store, &username_hash).await;
+                let body: Vec<_> = channels
+                    .iter()
+                    .map(notifications::Channel::to_json)
+                    .collect();
+                no_store_json_response(200, &serde_json::json!(body))
+            }
+            spin_sdk::http::Method::Post => {
+                handle_create_notification_channel(&store, &username_hash, request).await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or POST"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if path == "/notifications/preferences" {
+        let response = match method {
+            spin_sdk::http::Method::Get => {
+                let preferences = notifications::get_preferences(&store, &username_hash).await;
+                no_store_json_response(200, &serde_json::to_value(&preferences)?)
+            }
+            spin_sdk::http::Method::Put => {
+                handle_put_notification_preferences(&store, &username_hash, request).await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or PUT"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if let Some(channel_path) = path.strip_prefix("/notifications/channels/") {
+        let response = match channel_path.strip_suffix("/test") {
+            Some(channel_id) if method == spin_sdk::http::Method::Post => {
+                handle_test_notification_channel(&store, &username_hash, channel_id).await?
+            }
+            Some(_) => json_error_response(405, "Method not allowed", "Use POST"),
+            None => {
+                handle_notification_channel(&store, &username_hash, channel_path, method.clone())
+                    .await?
+            }
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /webhooks endpoints - subscriptions, test events, dead letters
+    if path == "/webhooks" {
+        let response = match method {
+            spin_sdk::http::Method::Get => {
+                let hooks = webhooks::load_webhooks(&store, &username_hash).await;
+                let body: Vec<_> = hooks.iter().map(webhooks::Webhook::to_json).collect();
+                no_store_json_response(200, &serde_json::json!(body))
+            }
+            spin_sdk::http::Method::Post => {
+                handle_create_webhook(&store, &toyota_token, &username_hash, &vin, request).await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or POST"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if path == "/webhooks/dead-letters" {
+        let response = match method {
+            spin_sdk::http::Method::Get => {
+                let dead_letters = webhooks::load_dead_letters(&store, &username_hash).await;
+                let body: Vec<_> = dead_letters
+                    .iter()
+                    .rev()
+                    .map(webhooks::Delivery::to_json)
+                    .collect();
+                no_store_json_response(200, &serde_json::json!(body))
+            }
+            spin_sdk::http::Method::Delete => {
+                webhooks::clear_dead_letters(&store, &username_hash).await?;
+                add_cors_headers(Response::builder())
+                    .status(204)
+                    .body("")
+                    .build()
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or DELETE"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if let Some(webhook_path) = path.strip_prefix("/webhooks/") {
+        let response = match webhook_path.strip_suffix("/test") {
+            Some(webhook_id) if method == spin_sdk::http::Method::Post => {
+                handle_test_webhook(&store, &username_hash, webhook_id).await?
+            }
+            Some(_) => json_error_response(405, "Method not allowed", "Use POST"),
+            None => handle_webhook(&store, &username_hash, webhook_path, method.clone()).await?,
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /alerts endpoints - alert configuration, open alerts, acknowledgement
+    if path == "/alerts/config" {
+        let response = match method {
+            spin_sdk::http::Method::Get => handle_get_alert_config(&store, &username_hash).await,
+            spin_sdk::http::Method::Put => {
+                handle_put_alert_config(&store, &toyota_token, &username_hash, &vin, request)
+                    .await?
+            }
+            _ => json_error_response(405, "Method not allowed", "Use GET or PUT"),
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if path == "/alerts/active" {
+        let response = handle_active_alerts(&store, &toyota_token, &username_hash, &vin).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    if let Some(alert_id) = path
+        .strip_prefix("/alerts/")
+        .and_then(|rest| rest.strip_suffix("/ack"))
+    {
+        let response = if method == spin_sdk::http::Method::Post {
+            handle_ack_alert(&store, &toyota_token, &username_hash, &vin, alert_id).await?
+        } else {
+            json_error_response(405, "Method not allowed", "Use POST")
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /vehicles/{vin}/commands/{kind} - remote charge and climate commands
+    if let Some((command_vin, kind)) = path
+        .strip_prefix("/vehicles/")
+        .and_then(|rest| rest.split_once("/commands/"))
+    {
+        let response = if method == spin_sdk::http::Method::Post {
+            let idempotency_key = get_request_header(&request, "idempotency-key");
+            handle_vehicle_command(
+                &store,
+                &toyota_token,
+                &username_hash,
+                &claims,
+                command_vin,
+                kind,
+                idempotency_key,
+                request,
+            )
+            .await?
+        } else {
+            json_error_response(405, "Method not allowed", "Use POST")
+        };
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /vehicles/{vin}/remote-status - doors, windows and lock state
+    if let Some(status_vin) = path
+        .strip_prefix("/vehicles/")
+        .and_then(|rest| rest.strip_suffix("/remote-status"))
+    {
+        let response =
+            handle_remote_status(&store, &toyota_token, &username_hash, status_vin).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /vehicles/{vin}/climate - climate status and settings
+    if let Some(climate_vin) = path
+        .strip_prefix("/vehicles/")
+        .and_then(|rest| rest.strip_suffix("/climate"))
+    {
+        let response = handle_climate(&store, &toyota_token, &username_hash, climate_vin).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /vehicles/{vin}/climate/schedule - departure preconditioning
+    if let Some(climate_vin) = path
+        .strip_prefix("/vehicles/")
+        .and_then(|rest| rest.strip_suffix("/climate/schedule"))
+    {
+        let response = handle_climate_schedule(
+            &store,
+            &toyota_token,
+            &username_hash,
+            &claims,
+            climate_vin,
+            method.clone(),
+            request,
+        )
+        .await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /vehicles/{vin}/charge-schedule[/{id}] - charge timers
+    if let Some((schedule_vin, rest)) = path
+        .strip_prefix("/vehicles/")
+        .and_then(|rest| rest.split_once("/charge-schedule"))
+        .filter(|(_, rest)| rest.is_empty() || rest.starts_with('/'))
+    {
+        let timer_id = rest.strip_prefix('/').filter(|id| !id.is_empty());
+        let response = handle_charge_schedule(
+            &store,
+            &toyota_token,
+            &username_hash,
+            &claims,
+            schedule_vin,
+            timer_id,
+            method.clone(),
+            request,
+        )
+        .await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /commands/{id} - command status, checked against the vehicle
+    if let Some(command_id) = path.strip_prefix("/commands/") {
+        let response =
+            handle_get_command(&store, &toyota_token, &username_hash, command_id).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /charging/costs endpoint - session costs and monthly totals
+    if path == "/charging/costs" {
+        let response =
+            handle_charging_costs(&store, &toyota_token, &username_hash, &vin, &full_uri).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /battery/health endpoint - estimated state of health
+    if path == "/battery/health" {
+        let response = handle_battery_health(&store, &toyota_token, &username_hash, &vin).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /export/locations.gpx and .geojson - location history export
+    if path == "/export/locations.gpx" || path == "/export/locations.geojson" {
+        let format = if path.ends_with(".gpx") {
+            "gpx"
+        } else {
+            "geojson"
+        };
+        let response = handle_location_export(
+            &store,
+            &toyota_token,
+            &username_hash,
+            &vin,
+            &full_uri,
+            format,
+        )
+        .await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /export/history.csv and .ndjson - streamed history export
+    if path == "/export/history.csv" || path == "/export/history.ndjson" {
+        let format = if path.ends_with(".csv") {
+            export::TabularFormat::Csv
+        } else {
+            export::TabularFormat::Ndjson
+        };
+        let response = handle_history_export(
+            &store,
+            &toyota_token,
+            &username_hash,
+            &vin,
+            &full_uri,
+            format,
+            response_out,
+        )
+        .await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
+    // Handle /trips endpoint - detected trips with consumption metrics
+    if path == "/trips" {
+        let response = handle_trips(&store, &toyota_token, &username_hash, &vin, &full_uri).await?;
+        log_response(&response, start_time, method, path, Some(username));
+        return Ok(response);
+    }
+
     // Handle /abrp endpoint - ABRP-formatted telemetry
     if path == "/abrp" {
         // Fetch electric status with caching
@@ -2004,10 +5865,16 @@ async fn handle_request(request: IncomingRequest) -> Result<impl IntoResponse, a
             };
 
         // Fetch location (best effort)
-        let location = fetch_vehicle_location(&toyota_token, &vin).await.ok();
+        let location = fetch_or_get_cached_location(&store, &toyota_token, &vin)
+            .await
+            .ok()
+            .map(|(location, _)| location);
 
         // Fetch telemetry (best effort)
-        let telemetry = fetch_vehicle_telemetry(&toyota_token, &vin).await.ok();
+        let telemetry = fetch_or_get_cached_telemetry(&store, &toyota_token, &vin)
+            .await
+            .ok()
+            .map(|(telemetry, _)| telemetry);
 
         let charge_info = &electric_status.payload.vehicle_info.charge_info;
         let soc = charge_info.charge_remaining_amount.unwrap_or(0) as f64;
@@ -2175,6 +6042,7 @@ mod tests {
         assert_eq!(status.soc, 85);
         assert_eq!(status.access_date, "2025-01-01T12:00:00Z");
         assert_eq!(status.charging_status, Some("CHARGING".to_string()));
+        assert!(status.is_charging);
         assert_eq!(status.ev_range, Some(250.5));
         assert_eq!(status.ev_range_with_ac, Some(230.0));
         assert_eq!(status.remaining_charge_time, Some(120));
@@ -2194,6 +6062,7 @@ mod tests {
         assert_eq!(status.soc, 75);
         assert_eq!(status.access_date, "2025-01-01T13:00:00Z");
         assert_eq!(status.charging_status, None);
+        assert!(!status.is_charging);
         assert_eq!(status.ev_range, None);
         assert_eq!(status.ev_range_with_ac, None);
         assert_eq!(status.remaining_charge_time, None);
@@ -2379,6 +6248,13 @@ mod tests {
         assert!(timestamp3 > 0);
     }
 
+    #[test]
+    fn test_parse_time_param() {
+        assert_eq!(parse_time_param("1736964000"), Some(1736964000));
+        assert_eq!(parse_time_param("2025-01-15T18:00:00Z"), Some(1736964000));
+        assert_eq!(parse_time_param("yesterday"), None);
+    }
+
     #[test]
     fn test_cached_vehicle_data_expiry() {
         let current_time = 1000;
@@ -2472,6 +6348,7 @@ mod tests {
             refresh_token: "test-refresh-token".to_string(),
             token_type: "Bearer".to_string(),
             expires_in: 900,
+            scope: SCOPE_READ.to_string(),
         };
 
         let json = serde_json::to_string(&response).unwrap();
@@ -2488,6 +6365,7 @@ mod tests {
             iat: 1736942400,
             jti: "test-jti-123".to_string(),
             token_type: "access".to_string(),
+            scope: SCOPE_READ.to_string(),
         };
 
         let json = serde_json::to_string(&claims).unwrap();
@@ -2496,6 +6374,35 @@ mod tests {
         assert!(json.contains("\"jti\":\"test-jti-123\""));
     }
 
+    #[test]
+    fn test_parse_scope() {
+        assert_eq!(parse_scope(None).unwrap(), SCOPE_READ);
+        assert_eq!(
+            parse_scope(Some("vehicle:command vehicle:command")).unwrap(),
+            "vehicle:read vehicle:command"
+        );
+        assert!(parse_scope(Some("admin")).is_err());
+    }
+
+    #[test]
+    fn test_claims_has_scope() {
+        let claims = Claims {
+            sub: "test@example.com".to_string(),
+            exp: 0,
+            iat: 0,
+            jti: "jti".to_string(),
+            token_type: "access".to_string(),
+            scope: "vehicle:read vehicle:command".to_string(),
+        };
+        assert!(claims.has_scope(SCOPE_COMMAND));
+
+        // Tokens issued before scopes existed only allow reading
+        let legacy: Claims =
+            serde_json::from_str(r#"{"sub":"a","exp":0,"iat":0,"jti":"j","token_type":"access"}"#)
+                .unwrap();
+        assert!(!legacy.has_scope(SCOPE_COMMAND));
+    }
+
     #[test]
     fn test_session_struct() {
         let session = Session {
@@ -2528,6 +6435,32 @@ mod tests {
         assert_eq!(deserialized.lockout_until, Some(2000));
     }
 
+    #[test]
+    fn test_add_request_id() {
+        let mut response = Response::builder()
+            .status(404)
+            .body(r#"{"error":"not_found","message":"No such vehicle"}"#)
+            .build();
+        add_request_id(&mut response, "req-123");
+        assert_eq!(
+            response
+                .header(telemetry::REQUEST_ID_HEADER)
+                .and_then(|v| v.as_str()),
+            Some("req-123")
+        );
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["request_id"], "req-123");
+        assert_eq!(body["error"], "not_found");
+
+        // Successful bodies are left alone
+        let mut response = Response::builder()
+            .status(200)
+            .body(r#"{"ok":true}"#)
+            .build();
+        add_request_id(&mut response, "req-123");
+        assert_eq!(response.body(), br#"{"ok":true}"#);
+    }
+
     #[test]
     fn test_constant_values() {
         // Verify critical constants are set correctly
@@ -2,10 +2,96 @@
 //
 // Provides basic request metrics, cache statistics, and error tracking
 // in Prometheus text format for integration with monitoring systems.
+//
+// Request latency is tracked as histograms per route template (`/vehicles/{vin}`)
+// and per upstream Toyota endpoint, so p95 can be computed with
+// `histogram_quantile`. Request counts are labelled by route, method and
+// status class (`2xx`, `4xx`, ...).
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+/// Histogram bucket upper bounds in seconds
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Distinct route or endpoint labels kept before folding the rest into "other",
+/// so scanners probing random paths cannot blow up the series count
+pub const MAX_LABEL_VALUES: usize = 200;
+
+/// Label used once `MAX_LABEL_VALUES` is reached
+const OVERFLOW_LABEL: &str = "other";
+
+/// Latency histogram with fixed buckets
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    /// Observations per bucket (not cumulative), one slot per `LATENCY_BUCKETS` entry
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, seconds: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    /// Render the `_bucket`, `_sum` and `_count` series; `labels` is the
+    /// already formatted label list without braces
+    fn render(&self, name: &str, labels: &str, output: &mut String) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count;
+            output.push_str(&format!(
+                "{}_bucket{{{}{}le=\"{}\"}} {}\n",
+                name, labels, separator, bound, cumulative
+            ));
+        }
+        output.push_str(&format!(
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}\n",
+            name, labels, separator, self.count
+        ));
+        output.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, self.sum));
+        output.push_str(&format!("{}_count{{{}}} {}\n", name, labels, self.count));
+    }
+}
+
+/// Status class label for an HTTP status code
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Escape a label value for the Prometheus text format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Use `value` as a label unless too many distinct values are already in use
+fn bounded_label<'a, 'k>(values: impl Iterator<Item = &'k str>, value: &'a str) -> &'a str {
+    let values: BTreeSet<&str> = values.collect();
+    if values.len() >= MAX_LABEL_VALUES && !values.contains(value) {
+        OVERFLOW_LABEL
+    } else {
+        value
+    }
+}
 
 /// Global metrics collector
 pub struct Metrics {
@@ -17,9 +103,17 @@ pub struct Metrics {
     endpoint_requests: Mutex<HashMap<String, u64>>,
     endpoint_errors: Mutex<HashMap<String, u64>>,
 
+    // Requests by (route, method, status class) and latency by route
+    http_requests: Mutex<BTreeMap<(String, String, &'static str), u64>>,
+    request_duration: Mutex<BTreeMap<String, Histogram>>,
+
+    // Latency of upstream Toyota calls by endpoint
+    upstream_duration: Mutex<BTreeMap<String, Histogram>>,
+
     // Cache statistics
     cache_hits: AtomicU64,
     cache_misses: AtomicU64,
+    coalesced_requests: AtomicU64,
 
     // Authentication metrics
     login_attempts: AtomicU64,
@@ -45,8 +139,12 @@ impl Metrics {
             total_errors: AtomicU64::new(0),
             endpoint_requests: Mutex::new(HashMap::new()),
             endpoint_errors: Mutex::new(HashMap::new()),
+            http_requests: Mutex::new(BTreeMap::new()),
+            request_duration: Mutex::new(BTreeMap::new()),
+            upstream_duration: Mutex::new(BTreeMap::new()),
             cache_hits: AtomicU64::new(0),
             cache_misses: AtomicU64::new(0),
+            coalesced_requests: AtomicU64::new(0),
             login_attempts: AtomicU64::new(0),
             login_failures: AtomicU64::new(0),
             active_sessions: AtomicU64::new(0),
@@ -75,6 +173,32 @@ impl Metrics {
         }
     }
 
+    /// Record a completed request against its route template
+    pub fn record_response(&self, route: &str, method: &str, status: u16, duration: Duration) {
+        if let Ok(mut map) = self.request_duration.lock() {
+            let route = bounded_label(map.keys().map(String::as_str), route);
+            map.entry(route.to_string())
+                .or_default()
+                .observe(duration.as_secs_f64());
+        }
+
+        if let Ok(mut map) = self.http_requests.lock() {
+            let route = bounded_label(map.keys().map(|(key, _, _)| key.as_str()), route);
+            *map.entry((route.to_string(), method.to_string(), status_class(status)))
+                .or_insert(0) += 1;
+        }
+    }
+
+    /// Record the duration of a call to a Toyota API endpoint
+    pub fn record_upstream_call(&self, endpoint: &str, duration: Duration) {
+        if let Ok(mut map) = self.upstream_duration.lock() {
+            let endpoint = bounded_label(map.keys().map(String::as_str), endpoint);
+            map.entry(endpoint.to_string())
+                .or_default()
+                .observe(duration.as_secs_f64());
+        }
+    }
+
     // Cache tracking
     pub fn record_cache_hit(&self) {
         self.cache_hits.fetch_add(1, Ordering::Relaxed);
@@ -84,6 +208,10 @@ impl Metrics {
         self.cache_misses.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn record_coalesced_request(&self) {
+        self.coalesced_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
     // Authentication tracking
     pub fn record_login_attempt(&self) {
         self.login_attempts.fetch_add(1, Ordering::Relaxed);
@@ -170,6 +298,54 @@ impl Metrics {
         }
         output.push('\n');
 
+        // Requests by route, method and status class
+        output.push_str(
+            "# HELP myt2abrp_http_requests_total Requests by route, method and status class\n",
+        );
+        output.push_str("# TYPE myt2abrp_http_requests_total counter\n");
+        if let Ok(map) = self.http_requests.lock() {
+            for ((route, method, class), count) in map.iter() {
+                output.push_str(&format!(
+                    "myt2abrp_http_requests_total{{route=\"{}\",method=\"{}\",status_class=\"{}\"}} {}\n",
+                    escape_label_value(route),
+                    escape_label_value(method),
+                    class,
+                    count
+                ));
+            }
+        }
+        output.push('\n');
+
+        // Latency histograms
+        output
+            .push_str("# HELP myt2abrp_http_request_duration_seconds Request duration by route\n");
+        output.push_str("# TYPE myt2abrp_http_request_duration_seconds histogram\n");
+        if let Ok(map) = self.request_duration.lock() {
+            for (route, histogram) in map.iter() {
+                histogram.render(
+                    "myt2abrp_http_request_duration_seconds",
+                    &format!("route=\"{}\"", escape_label_value(route)),
+                    &mut output,
+                );
+            }
+        }
+        output.push('\n');
+
+        output.push_str(
+            "# HELP myt2abrp_upstream_request_duration_seconds Toyota API call duration by endpoint\n",
+        );
+        output.push_str("# TYPE myt2abrp_upstream_request_duration_seconds histogram\n");
+        if let Ok(map) = self.upstream_duration.lock() {
+            for (endpoint, histogram) in map.iter() {
+                histogram.render(
+                    "myt2abrp_upstream_request_duration_seconds",
+                    &format!("endpoint=\"{}\"", escape_label_value(endpoint)),
+                    &mut output,
+                );
+            }
+        }
+        output.push('\n');
+
         // Cache statistics
         let hits = self.cache_hits.load(Ordering::Relaxed);
         let misses = self.cache_misses.load(Ordering::Relaxed);
@@ -195,6 +371,15 @@ impl Metrics {
             hit_rate
         ));
 
+        output.push_str(
+            "# HELP myt2abrp_coalesced_requests_total Requests served by waiting for an in-flight upstream fetch\n",
+        );
+        output.push_str("# TYPE myt2abrp_coalesced_requests_total counter\n");
+        output.push_str(&format!(
+            "myt2abrp_coalesced_requests_total {}\n\n",
+            self.coalesced_requests.load(Ordering::Relaxed)
+        ));
+
         // Authentication metrics
         output.push_str("# HELP myt2abrp_login_attempts_total Total login attempts\n");
         output.push_str("# TYPE myt2abrp_login_attempts_total counter\n");
@@ -329,6 +514,9 @@ mod tests {
 
         assert_eq!(metrics.cache_hits.load(Ordering::Relaxed), 3);
         assert_eq!(metrics.cache_misses.load(Ordering::Relaxed), 1);
+
+        metrics.record_coalesced_request();
+        assert_eq!(metrics.coalesced_requests.load(Ordering::Relaxed), 1);
     }
 
     #[test]
@@ -345,4 +533,74 @@ mod tests {
         assert!(output.contains("# TYPE"));
         assert!(output.contains("# HELP"));
     }
+
+    #[test]
+    fn test_latency_histograms() {
+        let metrics = Metrics::new();
+
+        metrics.record_response("/vehicles/{vin}", "GET", 200, Duration::from_millis(40));
+        metrics.record_response("/vehicles/{vin}", "GET", 200, Duration::from_millis(700));
+        metrics.record_response("/vehicles/{vin}", "GET", 502, Duration::from_secs(60));
+        metrics.record_upstream_call("/v1/global/remote/status", Duration::from_millis(300));
+
+        let output = metrics.to_prometheus_format();
+
+        assert!(output.contains("# TYPE myt2abrp_http_request_duration_seconds histogram"));
+        assert!(output.contains(
+            "myt2abrp_http_request_duration_seconds_bucket{route=\"/vehicles/{vin}\",le=\"0.05\"} 1"
+        ));
+        assert!(output.contains(
+            "myt2abrp_http_request_duration_seconds_bucket{route=\"/vehicles/{vin}\",le=\"1\"} 2"
+        ));
+        assert!(output.contains(
+            "myt2abrp_http_request_duration_seconds_bucket{route=\"/vehicles/{vin}\",le=\"30\"} 2"
+        ));
+        assert!(output.contains(
+            "myt2abrp_http_request_duration_seconds_bucket{route=\"/vehicles/{vin}\",le=\"+Inf\"} 3"
+        ));
+        assert!(output.contains(
+            "myt2abrp_http_request_duration_seconds_sum{route=\"/vehicles/{vin}\"} 60.74"
+        ));
+        assert!(output
+            .contains("myt2abrp_http_request_duration_seconds_count{route=\"/vehicles/{vin}\"} 3"));
+        assert!(output.contains(
+            "myt2abrp_http_requests_total{route=\"/vehicles/{vin}\",method=\"GET\",status_class=\"2xx\"} 2"
+        ));
+        assert!(output.contains(
+            "myt2abrp_http_requests_total{route=\"/vehicles/{vin}\",method=\"GET\",status_class=\"5xx\"} 1"
+        ));
+        assert!(output.contains(
+            "myt2abrp_upstream_request_duration_seconds_count{endpoint=\"/v1/global/remote/status\"} 1"
+        ));
+    }
+
+    #[test]
+    fn test_route_labels_are_bounded() {
+        let metrics = Metrics::new();
+
+        for i in 0..MAX_LABEL_VALUES + 10 {
+            metrics.record_response(&format!("/probe{}", i), "GET", 404, Duration::ZERO);
+        }
+        // Known routes keep their label once the limit is reached
+        metrics.record_response("/probe0", "GET", 404, Duration::ZERO);
+
+        let durations = metrics.request_duration.lock().unwrap();
+        assert_eq!(durations.len(), MAX_LABEL_VALUES + 1);
+        assert_eq!(durations["other"].count, 10);
+        assert_eq!(durations["/probe0"].count, 2);
+        assert_eq!(status_class(404), "4xx");
+        assert_eq!(escape_label_value("a\"b"), "a\\\"b");
+        drop(durations);
+
+        // Method and status class do not count against the route limit
+        let metrics = Metrics::new();
+        for i in 0..MAX_LABEL_VALUES {
+            let route = format!("/probe{}", i);
+            metrics.record_response(&route, "GET", 200, Duration::ZERO);
+            metrics.record_response(&route, "POST", 500, Duration::ZERO);
+        }
+        let requests = metrics.http_requests.lock().unwrap();
+        assert_eq!(requests.len(), 2 * MAX_LABEL_VALUES);
+        assert!(!requests.keys().any(|(route, _, _)| route == "other"));
+    }
 }
@@ -0,0 +1,532 @@
+// MQTT publishing for Home Assistant
+//
+// Every new snapshot updates the vehicle's last known state (SOC, range,
+// charging state, location and odometer), which is published as JSON to
+// `<prefix>/<vin>/state`, with the location also on
+// `<prefix>/<vin>/location` for a device_tracker. Home Assistant MQTT
+// discovery configs under `<discovery prefix>/<component>/myt2abrp_<vin>/...`
+// turn these into entities of one device per VIN.
+//
+// All messages are retained, so Home Assistant picks up discovery configs
+// and the last state when it or the broker restarts. Each vehicle is
+// published over its own connection whose last will sets the vehicle's
+// availability topic to "offline" if the connection drops mid-publish; a
+// clean session sets it to "online". Because the gateway only connects
+// while it publishes, a gateway that stopped entirely cannot trigger the
+// will, so entities also expire when nothing was published for
+// `EXPIRE_AFTER_SECONDS` and the scheduler tick republishes everything.
+//
+// Spin's outbound MQTT cannot retain messages or register a will, so the
+// gateway speaks MQTT itself over TCP (see `mqtt_client`). Publishing is
+// enabled by the `mqtt_broker_url` variable, e.g. `mqtt://localhost:1883`
+// for a local Mosquitto, whose host must also be in the component's
+// `allowed_outbound_hosts` as `*://localhost:1883`.
+
+use crate::charging;
+use crate::history::{HistoryRecord, SnapshotKind};
+use crate::mqtt_client::{BrokerAddress, Client, ConnectOptions, Will};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+const STATE_KEY_PREFIX: &str = "mqtt_state_";
+const VINS_KEY: &str = "mqtt_vins";
+
+pub const TOPIC_PREFIX_DEFAULT: &str = "myt2abrp";
+pub const DISCOVERY_PREFIX_DEFAULT: &str = "homeassistant";
+
+/// Client id prefix used when the broker URL does not name one
+const CLIENT_ID_DEFAULT: &str = "myt2abrp";
+
+const KEEP_ALIVE_SECONDS: u16 = 30;
+
+/// Entities become unavailable when nothing was published for this long
+pub const EXPIRE_AFTER_SECONDS: u64 = 3600;
+
+const PAYLOAD_ONLINE: &str = "online";
+const PAYLOAD_OFFLINE: &str = "offline";
+
+/// Broker and topics, from the `mqtt_*` variables
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    pub broker: BrokerAddress,
+    pub username: String,
+    pub password: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+}
+
+/// A message to publish
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publication {
+    pub topic: String,
+    pub payload: String,
+}
+
+impl Publication {
+    fn new(topic: String, payload: impl ToString) -> Self {
+        Publication {
+            topic,
+            payload: payload.to_string(),
+        }
+    }
+}
+
+/// Topic prefix without surrounding slashes, `None` if it contains wildcards
+fn clean_prefix(prefix: &str) -> Option<String> {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() || prefix.contains(['+', '#']) {
+        return None;
+    }
+    Some(prefix.to_string())
+}
+
+impl MqttConfig {
+    /// Configuration of this deployment; `None` while no broker is set
+    pub fn from_variables() -> Option<Self> {
+        let variable = |name: &str| variables::get(name).unwrap_or_default();
+        let broker_url = variable("mqtt_broker_url");
+        if broker_url.trim().is_empty() {
+            return None;
+        }
+        let broker = match BrokerAddress::parse(&broker_url) {
+            Ok(broker) => broker,
+            Err(e) => {
+                warn!(error = %e, "Ignoring invalid mqtt_broker_url");
+                return None;
+            }
+        };
+        let prefix = |name: &str, default: &str| {
+            clean_prefix(&variable(name)).unwrap_or_else(|| default.to_string())
+        };
+        Some(MqttConfig {
+            broker,
+            username: variable("mqtt_username"),
+            password: variable("mqtt_password"),
+            topic_prefix: prefix("mqtt_topic_prefix", TOPIC_PREFIX_DEFAULT),
+            discovery_prefix: prefix("mqtt_discovery_prefix", DISCOVERY_PREFIX_DEFAULT),
+        })
+    }
+
+    fn vehicle_topic(&self, vin: &str, name: &str) -> String {
+        format!("{}/{}/{}", self.topic_prefix, vin, name)
+    }
+
+    /// Client id of a new connection
+    ///
+    /// A random suffix keeps concurrent requests from taking over each
+    /// other's session, which would make the broker publish the will.
+    fn client_id(&self) -> String {
+        let prefix = self
+            .broker
+            .client_id
+            .as_deref()
+            .unwrap_or(CLIENT_ID_DEFAULT);
+        let suffix = Uuid::new_v4().simple().to_string();
+        format!("{}-{}", prefix, &suffix[..8])
+    }
+
+    fn will(&self, vin: &str) -> Will {
+        Will {
+            topic: self.vehicle_topic(vin, "availability"),
+            payload: PAYLOAD_OFFLINE.to_string(),
+        }
+    }
+}
+
+/// Last known state of a vehicle, merged from all snapshot kinds
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VehicleState {
+    pub soc: Option<i32>,
+    pub range_km: Option<f32>,
+    pub charging_state: Option<String>,
+    pub plugged_in: Option<bool>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub odometer: Option<f64>,
+    pub odometer_unit: Option<String>,
+    /// Timestamp of the newest snapshot merged in
+    pub updated_at: Option<i64>,
+}
+
+impl VehicleState {
+    /// Merge a snapshot; values it does not carry are kept
+    pub fn apply(&mut self, record: &HistoryRecord) {
+        match record.kind {
+            SnapshotKind::Status => {
+                if let Some(soc) = record.soc {
+                    self.soc = Some(soc);
+                }
+                if record.ev_range.is_some() {
+                    self.range_km = record.ev_range;
+                }
+                if let Some(status) = &record.charging_status {
+                    self.plugged_in = Some(charging::is_plugged_in(status));
+                    self.charging_state = Some(status.to_ascii_lowercase());
+                }
+            }
+            SnapshotKind::Location => {
+                if let (Some(lat), Some(lon)) = (record.lat, record.lon) {
+                    self.latitude = Some(lat);
+                    self.longitude = Some(lon);
+                }
+            }
+            SnapshotKind::Odometer => {
+                if record.odometer.is_some() {
+                    self.odometer = record.odometer;
+                    self.odometer_unit = record.odometer_unit.clone();
+                }
+            }
+        }
+        self.updated_at = self.updated_at.max(Some(record.timestamp));
+    }
+}
+
+/// Home Assistant unit of an odometer unit reported by Toyota
+fn distance_unit(unit: Option<&str>) -> &'static str {
+    match unit.map(str::to_ascii_lowercase).as_deref() {
+        Some("mi") | Some("miles") => "mi",
+        _ => "km",
+    }
+}
+
+/// Discovery config messages for the entities of a vehicle
+pub fn discovery_messages(
+    config: &MqttConfig,
+    vin: &str,
+    state: &VehicleState,
+) -> Vec<Publication> {
+    let node_id = format!("myt2abrp_{}", vin.to_ascii_lowercase());
+    let device = serde_json::json!({
+        "identifiers": [node_id],
+        "name": format!("Toyota {}", vin),
+        "manufacturer": "Toyota",
+        "sw_version": crate::VERSION,
+    });
+    let availability_topic = config.vehicle_topic(vin, "availability");
+    let state_topic = config.vehicle_topic(vin, "state");
+
+    let sensor = |key: &str, name: &str, extra: serde_json::Value| {
+        let mut entity = serde_json::json!({
+            "name": name,
+            "unique_id": format!("{}_{}", node_id, key),
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", key),
+            "availability_topic": availability_topic,
+            "expire_after": EXPIRE_AFTER_SECONDS,
+            "device": device,
+        });
+        if let (Some(entity), serde_json::Value::Object(extra)) = (entity.as_object_mut(), extra) {
+            entity.extend(extra);
+        }
+        entity
+    };
+
+    let entities = [
+        (
+            "sensor",
+            "soc",
+            sensor(
+                "soc",
+                "Battery",
+                serde_json::json!({
+                    "device_class": "battery",
+                    "unit_of_measurement": "%",
+                    "state_class": "measurement",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "range_km",
+            sensor(
+                "range_km",
+                "Range",
+                serde_json::json!({
+                    "device_class": "distance",
+                    "unit_of_measurement": "km",
+                    "state_class": "measurement",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "charging_state",
+            sensor(
+                "charging_state",
+                "Charging state",
+                serde_json::json!({ "icon": "mdi:ev-station" }),
+            ),
+        ),
+        (
+            "binary_sensor",
+            "plugged_in",
+            sensor(
+                "plugged_in",
+                "Plugged in",
+                serde_json::json!({
+                    "device_class": "plug",
+                    "value_template": "{{ 'ON' if value_json.plugged_in else 'OFF' }}",
+                }),
+            ),
+        ),
+        (
+            "sensor",
+            "odometer",
+            sensor(
+                "odometer",
+                "Odometer",
+                serde_json::json!({
+                    "device_class": "distance",
+                    "unit_of_measurement": distance_unit(state.odometer_unit.as_deref()),
+                    "state_class": "total_increasing",
+                }),
+            ),
+        ),
+        (
+            "device_tracker",
+            "location",
+            serde_json::json!({
+                "name": "Location",
+                "unique_id": format!("{}_location", node_id),
+                "json_attributes_topic": config.vehicle_topic(vin, "location"),
+                "availability_topic": availability_topic,
+                "source_type": "gps",
+                "device": device,
+            }),
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object_id, entity)| {
+            Publication::new(
+                format!(
+                    "{}/{}/{}/{}/config",
+                    config.discovery_prefix, component, node_id, object_id
+                ),
+                entity,
+            )
+        })
+        .collect()
+}
+
+/// Availability, state and location messages of a vehicle
+pub fn state_messages(config: &MqttConfig, vin: &str, state: &VehicleState) -> Vec<Publication> {
+    let mut messages = vec![
+        Publication::new(config.vehicle_topic(vin, "availability"), PAYLOAD_ONLINE),
+        Publication::new(
+            config.vehicle_topic(vin, "state"),
+            serde_json::to_value(state).unwrap_or_default(),
+        ),
+    ];
+    if let (Some(latitude), Some(longitude)) = (state.latitude, state.longitude) {
+        messages.push(Publication::new(
+            config.vehicle_topic(vin, "location"),
+            serde_json::json!({
+                "latitude": latitude,
+                "longitude": longitude,
+                "gps_accuracy": 0,
+            }),
+        ));
+    }
+    messages
+}
+
+/// Publish retained messages of one vehicle under its availability will
+fn publish(config: &MqttConfig, vin: &str, messages: &[Publication]) -> anyhow::Result<()> {
+    let will = config.will(vin);
+    let mut client = Client::connect(
+        &config.broker,
+        &ConnectOptions {
+            client_id: &config.client_id(),
+            username: &config.username,
+            password: &config.password,
+            keep_alive_seconds: KEEP_ALIVE_SECONDS,
+            will: Some(&will),
+        },
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to connect to MQTT broker: {}", e))?;
+    for message in messages {
+        client
+            .publish(&message.topic, message.payload.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Failed to publish to {}: {}", message.topic, e))?;
+    }
+    client
+        .disconnect()
+        .map_err(|e| anyhow::anyhow!("Failed to disconnect from MQTT broker: {}", e))?;
+    debug!("Published {} MQTT messages for VIN {}", messages.len(), vin);
+    Ok(())
+}
+
+fn state_key(vin: &str) -> String {
+    format!("{}{}", STATE_KEY_PREFIX, vin)
+}
+
+async fn load_state(store: &Store, vin: &str) -> Option<VehicleState> {
+    match store.get(&state_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+async fn load_vins(store: &Store) -> Vec<String> {
+    match store.get(VINS_KEY) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+fn vehicle_messages(config: &MqttConfig, vin: &str, state: &VehicleState) -> Vec<Publication> {
+    let mut messages = discovery_messages(config, vin, state);
+    messages.extend(state_messages(config, vin, state));
+    messages
+}
+
+/// Merge a new snapshot into the vehicle's state and publish it
+pub async fn process_snapshot(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+) -> anyhow::Result<()> {
+    let Some(config) = MqttConfig::from_variables() else {
+        return Ok(());
+    };
+
+    let mut state = match load_state(store, vin).await {
+        Some(state) => state,
+        None => {
+            let mut vins = load_vins(store).await;
+            vins.push(vin.to_string());
+            store.set(VINS_KEY, &serde_json::to_vec(&vins)?)?;
+            VehicleState::default()
+        }
+    };
+    state.apply(record);
+    store.set(&state_key(vin), &serde_json::to_vec(&state)?)?;
+
+    publish(&config, vin, &vehicle_messages(&config, vin, &state))
+}
+
+/// Publish discovery configs and state of every known vehicle again;
+/// returns the number of vehicles
+pub async fn republish_all(store: &Store) -> anyhow::Result<usize> {
+    let Some(config) = MqttConfig::from_variables() else {
+        return Ok(0);
+    };
+
+    let mut vehicles = 0;
+    for vin in load_vins(store).await {
+        if let Some(state) = load_state(store, &vin).await {
+            publish(&config, &vin, &vehicle_messages(&config, &vin, &state))?;
+            vehicles += 1;
+        }
+    }
+    Ok(vehicles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::status_record;
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            broker: BrokerAddress::parse("mqtt://localhost:1883").unwrap(),
+            username: String::new(),
+            password: String::new(),
+            topic_prefix: "car".to_string(),
+            discovery_prefix: DISCOVERY_PREFIX_DEFAULT.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_client_id_will_and_prefix() {
+        let mut config = config();
+        assert!(config.client_id().starts_with("myt2abrp-"));
+        assert_ne!(config.client_id(), config.client_id());
+        config.broker = BrokerAddress::parse("mqtt://broker?client_id=car").unwrap();
+        assert!(config.client_id().starts_with("car-"));
+        assert_eq!(
+            config.will("JT123"),
+            Will {
+                topic: "car/JT123/availability".to_string(),
+                payload: "offline".to_string(),
+            }
+        );
+        assert_eq!(clean_prefix(" /home/car/ "), Some("home/car".to_string()));
+        assert_eq!(clean_prefix("car/#"), None);
+        assert_eq!(clean_prefix("/"), None);
+    }
+
+    #[test]
+    fn test_state_merges_snapshots() {
+        let mut state = VehicleState::default();
+        state.apply(&HistoryRecord {
+            ev_range: Some(210.5),
+            ..status_record(100, 64, "CHARGING")
+        });
+        state.apply(&HistoryRecord {
+            lat: Some(52.5),
+            lon: Some(13.4),
+            ..HistoryRecord::empty(90, SnapshotKind::Location)
+        });
+        state.apply(&HistoryRecord {
+            odometer: Some(12345.0),
+            odometer_unit: Some("km".to_string()),
+            ..HistoryRecord::empty(110, SnapshotKind::Odometer)
+        });
+        // A status without SOC keeps the last known value
+        state.apply(&HistoryRecord::empty(120, SnapshotKind::Status));
+
+        assert_eq!(state.soc, Some(64));
+        assert_eq!(state.charging_state.as_deref(), Some("charging"));
+        assert_eq!(state.plugged_in, Some(true));
+        assert_eq!(state.latitude, Some(52.5));
+        assert_eq!(state.odometer, Some(12345.0));
+        assert_eq!(state.updated_at, Some(120));
+    }
+
+    #[test]
+    fn test_messages() {
+        let state = VehicleState {
+            soc: Some(80),
+            latitude: Some(52.5),
+            longitude: Some(13.4),
+            odometer_unit: Some("mi".to_string()),
+            ..VehicleState::default()
+        };
+        let discovery = discovery_messages(&config(), "JT123", &state);
+        assert_eq!(discovery.len(), 6);
+        assert_eq!(
+            discovery[0].topic,
+            "homeassistant/sensor/myt2abrp_jt123/soc/config"
+        );
+        let soc: serde_json::Value = serde_json::from_str(&discovery[0].payload).unwrap();
+        assert_eq!(soc["state_topic"], "car/JT123/state");
+        assert_eq!(soc["value_template"], "{{ value_json.soc }}");
+        assert_eq!(soc["availability_topic"], "car/JT123/availability");
+        assert_eq!(soc["device"]["identifiers"][0], "myt2abrp_jt123");
+        let odometer: serde_json::Value = serde_json::from_str(&discovery[4].payload).unwrap();
+        assert_eq!(odometer["unit_of_measurement"], "mi");
+        assert_eq!(
+            discovery[5].topic,
+            "homeassistant/device_tracker/myt2abrp_jt123/location/config"
+        );
+
+        let messages = state_messages(&config(), "JT123", &state);
+        assert_eq!(messages[0].payload, "online");
+        let payload: serde_json::Value = serde_json::from_str(&messages[1].payload).unwrap();
+        assert_eq!(payload["soc"], 80);
+        assert!(payload["range_km"].is_null());
+        assert_eq!(messages[2].topic, "car/JT123/location");
+
+        // No location message before the first location snapshot
+        assert_eq!(
+            state_messages(&config(), "JT123", &VehicleState::default()).len(),
+            2
+        );
+    }
+}
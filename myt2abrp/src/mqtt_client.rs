@@ -0,0 +1,497 @@
+// Minimal MQTT 3.1.1 publisher over WASI sockets
+//
+// Spin's outbound MQTT interface can neither retain messages nor register a
+// last will, both of which Home Assistant relies on. This client speaks just
+// enough of MQTT 3.1.1 to do that: CONNECT with an optional will, PUBLISH at
+// QoS 1 with the retain flag, waiting for each PUBACK, and DISCONNECT. It
+// connects over plain TCP through `wasi:sockets`, so the broker has to be
+// listed in the component's `allowed_outbound_hosts` (e.g.
+// `*://localhost:1883`). TLS is not supported.
+//
+// Every network wait is bounded by one deadline for the whole session, so a
+// broker that stops answering cannot hold up the request that publishes.
+
+use wasi::clocks::monotonic_clock;
+use wasi::io::poll::{self, Pollable};
+use wasi::io::streams::{InputStream, OutputStream, StreamError};
+use wasi::sockets::network::{
+    ErrorCode, IpAddress, IpAddressFamily, IpSocketAddress, Ipv4SocketAddress, Ipv6SocketAddress,
+};
+use wasi::sockets::tcp::TcpSocket;
+use wasi::sockets::{instance_network, ip_name_lookup, tcp_create_socket};
+
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// Time allowed for connecting, publishing and disconnecting
+const SESSION_TIMEOUT_MS: u64 = 10_000;
+
+const PROTOCOL_LEVEL: u8 = 4;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const DISCONNECT: u8 = 0xE0;
+
+const PUBLISH_QOS_1: u8 = 0x02;
+const PUBLISH_RETAIN: u8 = 0x01;
+
+const CONNECT_USERNAME: u8 = 0x80;
+const CONNECT_PASSWORD: u8 = 0x40;
+const CONNECT_WILL_RETAIN: u8 = 0x20;
+const CONNECT_WILL_QOS_1: u8 = 0x08;
+const CONNECT_WILL: u8 = 0x04;
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+
+/// Largest remaining length MQTT can encode
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+/// Broker address parsed from an `mqtt://host:port?client_id=...` URL
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerAddress {
+    pub host: String,
+    pub port: u16,
+    pub client_id: Option<String>,
+}
+
+impl BrokerAddress {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .trim()
+            .strip_prefix("mqtt://")
+            .ok_or_else(|| "Broker URL must start with mqtt://".to_string())?;
+        let (authority, query) = match rest.split_once('?') {
+            Some((authority, query)) => (authority, Some(query)),
+            None => (rest, None),
+        };
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = match authority.rsplit_once(':') {
+            // An IPv6 literal without a port ends in "]"
+            Some((host, port)) if !port.ends_with(']') => (
+                host,
+                port.parse::<u16>()
+                    .map_err(|_| format!("Invalid broker port '{}'", port))?,
+            ),
+            _ => (authority, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err("Broker URL has no host".to_string());
+        }
+        let client_id = query.and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(name, _)| *name == "client_id")
+                .map(|(_, value)| value.to_string())
+                .filter(|value| !value.is_empty())
+        });
+        Ok(BrokerAddress {
+            host: host.to_string(),
+            port,
+            client_id,
+        })
+    }
+}
+
+/// Message the broker publishes when the connection drops without DISCONNECT
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// CONNECT options
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectOptions<'a> {
+    pub client_id: &'a str,
+    pub username: &'a str,
+    pub password: &'a str,
+    pub keep_alive_seconds: u16,
+    pub will: Option<&'a Will>,
+}
+
+fn put_remaining_length(packet: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+}
+
+fn put_bytes(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    packet.extend_from_slice(bytes);
+}
+
+fn packet(first_byte: u8, body: &[u8]) -> Result<Vec<u8>, String> {
+    if body.len() > MAX_REMAINING_LENGTH {
+        return Err("MQTT packet too large".to_string());
+    }
+    let mut packet = vec![first_byte];
+    put_remaining_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    Ok(packet)
+}
+
+fn check_field(name: &str, value: &str) -> Result<(), String> {
+    if value.len() > u16::MAX as usize {
+        return Err(format!("MQTT {} too long", name));
+    }
+    Ok(())
+}
+
+pub fn connect_packet(options: &ConnectOptions) -> Result<Vec<u8>, String> {
+    check_field("client id", options.client_id)?;
+    check_field("username", options.username)?;
+    check_field("password", options.password)?;
+
+    let mut flags = CONNECT_CLEAN_SESSION;
+    if !options.username.is_empty() {
+        flags |= CONNECT_USERNAME;
+        if !options.password.is_empty() {
+            flags |= CONNECT_PASSWORD;
+        }
+    }
+    if let Some(will) = options.will {
+        check_field("will topic", &will.topic)?;
+        check_field("will payload", &will.payload)?;
+        flags |= CONNECT_WILL | CONNECT_WILL_QOS_1 | CONNECT_WILL_RETAIN;
+    }
+
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT");
+    body.push(PROTOCOL_LEVEL);
+    body.push(flags);
+    body.extend_from_slice(&options.keep_alive_seconds.to_be_bytes());
+    put_bytes(&mut body, options.client_id.as_bytes());
+    if let Some(will) = options.will {
+        put_bytes(&mut body, will.topic.as_bytes());
+        put_bytes(&mut body, will.payload.as_bytes());
+    }
+    if flags & CONNECT_USERNAME != 0 {
+        put_bytes(&mut body, options.username.as_bytes());
+    }
+    if flags & CONNECT_PASSWORD != 0 {
+        put_bytes(&mut body, options.password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+/// Retained QoS 1 PUBLISH
+pub fn publish_packet(topic: &str, payload: &[u8], packet_id: u16) -> Result<Vec<u8>, String> {
+    check_field("topic", topic)?;
+    let mut body = Vec::with_capacity(topic.len() + payload.len() + 4);
+    put_bytes(&mut body, topic.as_bytes());
+    body.extend_from_slice(&packet_id.to_be_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH | PUBLISH_QOS_1 | PUBLISH_RETAIN, &body)
+}
+
+pub fn disconnect_packet() -> Vec<u8> {
+    vec![DISCONNECT, 0]
+}
+
+/// Check a CONNACK body; `Err` carries the broker's reason
+pub fn check_connack(first_byte: u8, body: &[u8]) -> Result<(), String> {
+    if first_byte != CONNACK || body.len() != 2 {
+        return Err("Broker did not answer with CONNACK".to_string());
+    }
+    match body[1] {
+        0 => Ok(()),
+        1 => Err("Broker refused the protocol version".to_string()),
+        2 => Err("Broker rejected the client id".to_string()),
+        3 => Err("Broker unavailable".to_string()),
+        4 => Err("Bad MQTT username or password".to_string()),
+        5 => Err("Not authorized by the broker".to_string()),
+        code => Err(format!("Broker refused the connection ({})", code)),
+    }
+}
+
+/// Packet id of a PUBACK, `None` for any other packet
+pub fn puback_id(first_byte: u8, body: &[u8]) -> Option<u16> {
+    if first_byte & 0xF0 != PUBACK || body.len() != 2 {
+        return None;
+    }
+    Some(u16::from_be_bytes([body[0], body[1]]))
+}
+
+/// An open broker connection
+///
+/// The streams are declared before the socket so they are dropped first.
+pub struct Client {
+    input: InputStream,
+    output: OutputStream,
+    _socket: TcpSocket,
+    deadline: monotonic_clock::Instant,
+    next_packet_id: u16,
+}
+
+fn socket_error(context: &str, error: ErrorCode) -> String {
+    format!("{}: {:?}", context, error)
+}
+
+fn stream_error(error: StreamError) -> String {
+    match error {
+        StreamError::Closed => "Broker closed the connection".to_string(),
+        StreamError::LastOperationFailed(e) => {
+            format!("Connection failed: {}", e.to_debug_string())
+        }
+    }
+}
+
+/// Wait for a pollable until the deadline
+fn wait(pollable: &Pollable, deadline: monotonic_clock::Instant) -> Result<(), String> {
+    let timer = monotonic_clock::subscribe_instant(deadline);
+    if poll::poll(&[pollable, &timer]).contains(&0) {
+        Ok(())
+    } else {
+        Err(format!(
+            "No answer from broker within {} ms",
+            SESSION_TIMEOUT_MS
+        ))
+    }
+}
+
+fn resolve(host: &str, deadline: monotonic_clock::Instant) -> Result<IpAddress, String> {
+    if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+        return Ok(match ip {
+            std::net::IpAddr::V4(ip) => {
+                let [a, b, c, d] = ip.octets();
+                IpAddress::Ipv4((a, b, c, d))
+            }
+            std::net::IpAddr::V6(ip) => {
+                let [a, b, c, d, e, f, g, h] = ip.segments();
+                IpAddress::Ipv6((a, b, c, d, e, f, g, h))
+            }
+        });
+    }
+    let network = instance_network::instance_network();
+    let addresses = ip_name_lookup::resolve_addresses(&network, host)
+        .map_err(|e| socket_error("Failed to resolve broker host", e))?;
+    loop {
+        match addresses.resolve_next_address() {
+            Ok(Some(address)) => return Ok(address),
+            Ok(None) => return Err(format!("Broker host '{}' has no address", host)),
+            Err(ErrorCode::WouldBlock) => wait(&addresses.subscribe(), deadline)?,
+            Err(e) => return Err(socket_error("Failed to resolve broker host", e)),
+        }
+    }
+}
+
+impl Client {
+    /// Connect and wait for the broker to accept the session
+    pub fn connect(address: &BrokerAddress, options: &ConnectOptions) -> Result<Self, String> {
+        let deadline = monotonic_clock::now() + SESSION_TIMEOUT_MS * 1_000_000;
+        let (family, remote) = match resolve(&address.host, deadline)? {
+            IpAddress::Ipv4(ip) => (
+                IpAddressFamily::Ipv4,
+                IpSocketAddress::Ipv4(Ipv4SocketAddress {
+                    port: address.port,
+                    address: ip,
+                }),
+            ),
+            IpAddress::Ipv6(ip) => (
+                IpAddressFamily::Ipv6,
+                IpSocketAddress::Ipv6(Ipv6SocketAddress {
+                    port: address.port,
+                    flow_info: 0,
+                    address: ip,
+                    scope_id: 0,
+                }),
+            ),
+        };
+
+        let network = instance_network::instance_network();
+        let socket = tcp_create_socket::create_tcp_socket(family)
+            .map_err(|e| socket_error("Failed to create socket", e))?;
+        socket
+            .start_connect(&network, remote)
+            .map_err(|e| socket_error("Failed to connect to broker", e))?;
+        let (input, output) = loop {
+            match socket.finish_connect() {
+                Ok(streams) => break streams,
+                Err(ErrorCode::WouldBlock) => wait(&socket.subscribe(), deadline)?,
+                Err(e) => return Err(socket_error("Failed to connect to broker", e)),
+            }
+        };
+
+        let mut client = Client {
+            input,
+            output,
+            _socket: socket,
+            deadline,
+            next_packet_id: 1,
+        };
+        client.write_all(&connect_packet(options)?)?;
+        let (first_byte, body) = client.read_packet()?;
+        check_connack(first_byte, &body)?;
+        Ok(client)
+    }
+
+    /// Publish a retained message and wait for the broker's PUBACK
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), String> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.write_all(&publish_packet(topic, payload, packet_id)?)?;
+        loop {
+            let (first_byte, body) = self.read_packet()?;
+            // Anything else (e.g. a PINGRESP) is ignored
+            if puback_id(first_byte, &body) == Some(packet_id) {
+                return Ok(());
+            }
+        }
+    }
+
+    /// End the session cleanly, so the broker does not publish the will
+    pub fn disconnect(mut self) -> Result<(), String> {
+        self.write_all(&disconnect_packet())
+    }
+
+    fn write_all(&mut self, mut bytes: &[u8]) -> Result<(), String> {
+        while !bytes.is_empty() {
+            let permitted = self.output.check_write().map_err(stream_error)? as usize;
+            if permitted == 0 {
+                wait(&self.output.subscribe(), self.deadline)?;
+                continue;
+            }
+            let (now, later) = bytes.split_at(permitted.min(bytes.len()));
+            self.output.write(now).map_err(stream_error)?;
+            bytes = later;
+        }
+        self.output.flush().map_err(stream_error)?;
+        // check-write reports no capacity until the flush has completed
+        while self.output.check_write().map_err(stream_error)? == 0 {
+            wait(&self.output.subscribe(), self.deadline)?;
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, length: usize) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(length);
+        while bytes.len() < length {
+            let chunk = self
+                .input
+                .read((length - bytes.len()) as u64)
+                .map_err(stream_error)?;
+            if chunk.is_empty() {
+                wait(&self.input.subscribe(), self.deadline)?;
+            }
+            bytes.extend(chunk);
+        }
+        Ok(bytes)
+    }
+
+    fn read_packet(&mut self) -> Result<(u8, Vec<u8>), String> {
+        let first_byte = self.read_exact(1)?[0];
+        let mut length = 0usize;
+        let mut multiplier = 1usize;
+        loop {
+            let byte = self.read_exact(1)?[0];
+            length += (byte & 0x7F) as usize * multiplier;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            multiplier *= 128;
+            if multiplier > 128 * 128 * 128 {
+                return Err("Malformed packet length from broker".to_string());
+            }
+        }
+        Ok((first_byte, self.read_exact(length)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_broker_address() {
+        assert_eq!(
+            BrokerAddress::parse("mqtt://localhost:1884?client_id=car").unwrap(),
+            BrokerAddress {
+                host: "localhost".to_string(),
+                port: 1884,
+                client_id: Some("car".to_string()),
+            }
+        );
+        let address = BrokerAddress::parse("mqtt://192.168.1.5").unwrap();
+        assert_eq!(address.port, DEFAULT_PORT);
+        assert_eq!(address.client_id, None);
+        assert_eq!(BrokerAddress::parse("mqtt://[::1]").unwrap().host, "::1");
+        assert_eq!(
+            BrokerAddress::parse("mqtt://[::1]:1885").unwrap().port,
+            1885
+        );
+        assert!(BrokerAddress::parse("mqtts://broker:8883").is_err());
+        assert!(BrokerAddress::parse("mqtt://broker:port").is_err());
+        assert!(BrokerAddress::parse("mqtt://").is_err());
+    }
+
+    #[test]
+    fn test_connect_packet() {
+        let will = Will {
+            topic: "car/VIN/availability".to_string(),
+            payload: "offline".to_string(),
+        };
+        let packet = connect_packet(&ConnectOptions {
+            client_id: "gw",
+            username: "user",
+            password: "pw",
+            keep_alive_seconds: 30,
+            will: Some(&will),
+        })
+        .unwrap();
+
+        let mut expected = vec![0x10, 55, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xEE, 0, 30];
+        expected.extend([0, 2, b'g', b'w', 0, 20]);
+        expected.extend(b"car/VIN/availability");
+        expected.extend([0, 7]);
+        expected.extend(b"offline");
+        expected.extend([0, 4, b'u', b's', b'e', b'r', 0, 2, b'p', b'w']);
+        assert_eq!(packet, expected);
+
+        // No credentials and no will: clean session only
+        let packet = connect_packet(&ConnectOptions {
+            client_id: "gw",
+            username: "",
+            password: "",
+            keep_alive_seconds: 30,
+            will: None,
+        })
+        .unwrap();
+        assert_eq!(packet[9], 0x02);
+    }
+
+    #[test]
+    fn test_publish_packet() {
+        let packet = publish_packet("a/b", b"on", 7).unwrap();
+        assert_eq!(
+            packet,
+            vec![0x33, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'o', b'n']
+        );
+
+        // Remaining lengths above 127 take more than one byte
+        let packet = publish_packet("t", &[0; 200], 1).unwrap();
+        assert_eq!(&packet[..3], &[0x33, 205, 1]);
+    }
+
+    #[test]
+    fn test_acks() {
+        assert!(check_connack(0x20, &[0, 0]).is_ok());
+        assert_eq!(
+            check_connack(0x20, &[0, 4]).unwrap_err(),
+            "Bad MQTT username or password"
+        );
+        assert!(check_connack(0x40, &[0, 0]).is_err());
+        assert_eq!(puback_id(0x40, &[0, 7]), Some(7));
+        assert_eq!(puback_id(0xD0, &[]), None);
+        assert_eq!(disconnect_packet(), vec![0xE0, 0]);
+    }
+}
@@ -0,0 +1,1064 @@
+// Notification channels
+//
+// Users register channels through which alerts reach them outside the app:
+// an ntfy topic, a Gotify server, an e-mail relay that takes JSON over HTTP,
+// or a Telegram chat. When an alert fires, a message is rendered from the
+// user's template for the alert kind and queued for each of their channels,
+// unless it falls within their quiet hours. The scheduler tick sends the
+// queue, so a slow server never holds up the status request that raised
+// the alert. Messages that fail are logged and not retried; messages during
+// quiet hours are dropped.
+//
+// Channel secrets (tokens, API keys and ntfy topics) are encrypted with
+// ChaCha20-Poly1305 under a key derived from `hmac_key` before they are
+// stored, and are never returned by the API. Server URLs must match an
+// origin in the `notification_allowed_hosts` variable (and the component's
+// `allowed_outbound_hosts`), so a channel can also point at a local stand-in
+// server such as `http://localhost:8080`.
+
+use crate::alerts::{AlertInstance, AlertKind};
+use crate::webhooks::is_allowed;
+use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use spin_sdk::http::{Request, Response};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const CHANNELS_KEY_PREFIX: &str = "notification_channels_";
+const PREFERENCES_KEY_PREFIX: &str = "notification_preferences_";
+const QUEUE_KEY: &str = "notification_queue";
+
+/// Channels per user
+pub const MAX_CHANNELS: usize = 5;
+
+const MAX_URL_LENGTH: usize = 2048;
+
+/// Hard limit for one send
+const SEND_TIMEOUT_MS: u64 = 10_000;
+const MAX_FIELD_LENGTH: usize = 256;
+const MAX_TEMPLATE_LENGTH: usize = 1000;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+
+const NTFY_DEFAULT_SERVER: &str = "https://ntfy.sh";
+const TELEGRAM_DEFAULT_API: &str = "https://api.telegram.org";
+
+/// Event name of test messages, next to the alert kinds
+pub const TEST_EVENT: &str = "test";
+
+/// Placeholders a template may use
+const PLACEHOLDERS: [&str; 5] = ["title", "message", "soc", "vin", "event"];
+
+/// Marks an encrypted secret: "enc:v1:" + base64(nonce + ciphertext)
+const SEALED_PREFIX: &str = "enc:v1:";
+
+/// Label the secret encryption key is derived with
+const KEY_DERIVATION_LABEL: &[u8] = b"myt2abrp notification channel secrets";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    Default,
+    High,
+}
+
+/// A rendered notification
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    /// Alert kind ("low_battery", ...) or "test"
+    pub event: String,
+    pub title: String,
+    pub body: String,
+    pub priority: Priority,
+}
+
+/// The HTTP POST that delivers a message through a channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelRequest {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    /// JSON body
+    pub body: serde_json::Value,
+}
+
+/// A way to deliver messages to a user
+pub trait NotificationChannel {
+    /// Request that delivers the message
+    fn request(&self, message: &Message) -> ChannelRequest;
+
+    /// Whether the server's answer means the message was accepted
+    fn accepted(&self, status: u16, _body: &[u8]) -> bool {
+        (200..300).contains(&status)
+    }
+}
+
+/// ntfy (https://ntfy.sh or self-hosted), published as JSON
+pub struct Ntfy {
+    pub server_url: String,
+    pub topic: String,
+    pub token: Option<String>,
+}
+
+impl NotificationChannel for Ntfy {
+    fn request(&self, message: &Message) -> ChannelRequest {
+        let priority = match message.priority {
+            Priority::Low => 2,
+            Priority::Default => 3,
+            Priority::High => 4,
+        };
+        ChannelRequest {
+            url: self.server_url.trim_end_matches('/').to_string(),
+            headers: self
+                .token
+                .iter()
+                .map(|token| ("authorization", format!("Bearer {}", token)))
+                .collect(),
+            body: serde_json::json!({
+                "topic": self.topic,
+                "title": message.title,
+                "message": message.body,
+                "priority": priority,
+                "tags": [message.event],
+            }),
+        }
+    }
+}
+
+/// Gotify application message
+pub struct Gotify {
+    pub server_url: String,
+    pub app_token: String,
+}
+
+impl NotificationChannel for Gotify {
+    fn request(&self, message: &Message) -> ChannelRequest {
+        let priority = match message.priority {
+            Priority::Low => 2,
+            Priority::Default => 5,
+            Priority::High => 8,
+        };
+        ChannelRequest {
+            url: format!("{}/message", self.server_url.trim_end_matches('/')),
+            headers: vec![("x-gotify-key", self.app_token.clone())],
+            body: serde_json::json!({
+                "title": message.title,
+                "message": message.body,
+                "priority": priority,
+            }),
+        }
+    }
+}
+
+/// E-mail through a relay that accepts `{from, to, subject, text}` as JSON
+pub struct SmtpRelay {
+    pub relay_url: String,
+    pub api_key: Option<String>,
+    pub from: String,
+    pub to: String,
+}
+
+impl NotificationChannel for SmtpRelay {
+    fn request(&self, message: &Message) -> ChannelRequest {
+        ChannelRequest {
+            url: self.relay_url.clone(),
+            headers: self
+                .api_key
+                .iter()
+                .map(|key| ("authorization", format!("Bearer {}", key)))
+                .collect(),
+            body: serde_json::json!({
+                "from": self.from,
+                "to": self.to,
+                "subject": message.title,
+                "text": message.body,
+            }),
+        }
+    }
+}
+
+/// Telegram Bot API `sendMessage`
+pub struct Telegram {
+    pub api_url: String,
+    pub bot_token: String,
+    pub chat_id: String,
+}
+
+impl NotificationChannel for Telegram {
+    fn request(&self, message: &Message) -> ChannelRequest {
+        ChannelRequest {
+            url: format!(
+                "{}/bot{}/sendMessage",
+                self.api_url.trim_end_matches('/'),
+                self.bot_token
+            ),
+            headers: Vec::new(),
+            body: serde_json::json!({
+                "chat_id": self.chat_id,
+                "text": format!("{}\n\n{}", message.title, message.body),
+                "disable_notification": message.priority == Priority::Low,
+            }),
+        }
+    }
+
+    /// Telegram reports some failures with `"ok": false`
+    fn accepted(&self, status: u16, body: &[u8]) -> bool {
+        (200..300).contains(&status)
+            && serde_json::from_slice::<serde_json::Value>(body)
+                .is_ok_and(|answer| answer["ok"] == true)
+    }
+}
+
+fn ntfy_default_server() -> String {
+    NTFY_DEFAULT_SERVER.to_string()
+}
+
+fn telegram_default_api() -> String {
+    TELEGRAM_DEFAULT_API.to_string()
+}
+
+/// Configuration of a channel, also the body of `POST /notifications/channels`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ChannelConfig {
+    Ntfy {
+        #[serde(default = "ntfy_default_server")]
+        server_url: String,
+        /// Secret: anyone who knows the topic can read it
+        topic: String,
+        token: Option<String>,
+    },
+    Gotify {
+        server_url: String,
+        app_token: String,
+    },
+    Smtp {
+        relay_url: String,
+        api_key: Option<String>,
+        from: String,
+        to: String,
+    },
+    Telegram {
+        #[serde(default = "telegram_default_api")]
+        api_url: String,
+        bot_token: String,
+        chat_id: String,
+    },
+}
+
+impl ChannelConfig {
+    fn server_url(&self) -> &str {
+        match self {
+            ChannelConfig::Ntfy { server_url, .. } | ChannelConfig::Gotify { server_url, .. } => {
+                server_url
+            }
+            ChannelConfig::Smtp { relay_url, .. } => relay_url,
+            ChannelConfig::Telegram { api_url, .. } => api_url,
+        }
+    }
+
+    fn secrets_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            ChannelConfig::Ntfy { topic, token, .. } => {
+                let mut secrets = vec![topic];
+                secrets.extend(token.as_mut());
+                secrets
+            }
+            ChannelConfig::Gotify { app_token, .. } => vec![app_token],
+            ChannelConfig::Smtp { api_key, .. } => api_key.as_mut().into_iter().collect(),
+            ChannelConfig::Telegram { bot_token, .. } => vec![bot_token],
+        }
+    }
+
+    /// Check a new configuration
+    pub fn validate(&self, allowed_hosts: &[String]) -> Result<(), String> {
+        let url = self.server_url();
+        if url.len() > MAX_URL_LENGTH {
+            return Err(format!("URL must be at most {} characters", MAX_URL_LENGTH));
+        }
+        if !is_allowed(url, allowed_hosts) {
+            return Err(format!(
+                "{} is not in the allowed notification hosts",
+                url.trim_end_matches('/')
+            ));
+        }
+
+        let required = |field: &str, value: &str| {
+            if value.trim().is_empty() {
+                Err(format!("{} is required", field))
+            } else if value.len() > MAX_FIELD_LENGTH {
+                Err(format!(
+                    "{} must be at most {} characters",
+                    field, MAX_FIELD_LENGTH
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        match self {
+            ChannelConfig::Ntfy { topic, token, .. } => {
+                required("topic", topic)?;
+                if !topic
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err("topic may only contain letters, digits, - and _".to_string());
+                }
+                if let Some(token) = token {
+                    required("token", token)?;
+                }
+            }
+            ChannelConfig::Gotify { app_token, .. } => required("app_token", app_token)?,
+            ChannelConfig::Smtp {
+                api_key, from, to, ..
+            } => {
+                if let Some(api_key) = api_key {
+                    required("api_key", api_key)?;
+                }
+                for (field, address) in [("from", from), ("to", to)] {
+                    required(field, address)?;
+                    if !crate::settings::is_valid_email(address) {
+                        return Err(format!("{} must be a valid email address", field));
+                    }
+                }
+            }
+            ChannelConfig::Telegram {
+                bot_token, chat_id, ..
+            } => {
+                required("bot_token", bot_token)?;
+                if bot_token.contains(['/', '?', '#']) {
+                    return Err("bot_token is not a valid Telegram bot token".to_string());
+                }
+                required("chat_id", chat_id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypt the secrets for storage
+    pub fn seal(mut self, key: &[u8; 32]) -> Self {
+        for secret in self.secrets_mut() {
+            *secret = encrypt(key, secret);
+        }
+        self
+    }
+
+    /// Decrypt the secrets of a stored configuration
+    pub fn unseal(mut self, key: &[u8; 32]) -> Result<Self, String> {
+        for secret in self.secrets_mut() {
+            *secret = decrypt(key, secret)?;
+        }
+        Ok(self)
+    }
+
+    /// Channel of an unsealed configuration
+    pub fn into_channel(self) -> Box<dyn NotificationChannel> {
+        match self {
+            ChannelConfig::Ntfy {
+                server_url,
+                topic,
+                token,
+            } => Box::new(Ntfy {
+                server_url,
+                topic,
+                token,
+            }),
+            ChannelConfig::Gotify {
+                server_url,
+                app_token,
+            } => Box::new(Gotify {
+                server_url,
+                app_token,
+            }),
+            ChannelConfig::Smtp {
+                relay_url,
+                api_key,
+                from,
+                to,
+            } => Box::new(SmtpRelay {
+                relay_url,
+                api_key,
+                from,
+                to,
+            }),
+            ChannelConfig::Telegram {
+                api_url,
+                bot_token,
+                chat_id,
+            } => Box::new(Telegram {
+                api_url,
+                bot_token,
+                chat_id,
+            }),
+        }
+    }
+
+    /// Public view, which only reveals which secrets are set
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let serde_json::Value::Object(map) = &mut value {
+            for field in ["topic", "token", "app_token", "api_key", "bot_token"] {
+                if let Some(secret) = map.remove(field) {
+                    map.insert(format!("{}_configured", field), (!secret.is_null()).into());
+                }
+            }
+        }
+        value
+    }
+}
+
+/// A stored channel, secrets sealed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub id: String,
+    pub config: ChannelConfig,
+    pub created_at: i64,
+}
+
+impl Channel {
+    pub fn new(config: ChannelConfig, key: &[u8; 32], now: i64) -> Self {
+        Channel {
+            id: Uuid::new_v4().to_string(),
+            config: config.seal(key),
+            created_at: now,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut value = self.config.to_json();
+        value["id"] = self.id.clone().into();
+        value["created_at"] = self.created_at.into();
+        value
+    }
+}
+
+/// Key that encrypts channel secrets, derived from a server secret
+pub fn derive_key(secret: &[u8]) -> [u8; 32] {
+    type HmacSha256 = Hmac<Sha256>;
+
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(KEY_DERIVATION_LABEL);
+    mac.finalize().into_bytes().into()
+}
+
+/// Channel secret key of this deployment
+pub fn get_encryption_key() -> [u8; 32] {
+    derive_key(&crate::get_hmac_key())
+}
+
+pub(crate) fn encrypt(key: &[u8; 32], plaintext: &str) -> String {
+    let cipher = ChaCha20Poly1305::new(&Key::from(*key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext.as_bytes())
+        .expect("ChaCha20-Poly1305 encrypts any message that fits in memory");
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    format!(
+        "{}{}",
+        SEALED_PREFIX,
+        general_purpose::STANDARD.encode(sealed)
+    )
+}
+
+pub(crate) fn decrypt(key: &[u8; 32], sealed: &str) -> Result<String, String> {
+    let bytes = sealed
+        .strip_prefix(SEALED_PREFIX)
+        .and_then(|encoded| general_purpose::STANDARD.decode(encoded).ok())
+        .filter(|bytes| bytes.len() > 12)
+        .ok_or_else(|| "Secret is not encrypted".to_string())?;
+    let (nonce, ciphertext) = bytes.split_at(12);
+    let nonce: [u8; 12] = nonce.try_into().expect("split at the nonce length");
+    let plaintext = ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt(&Nonce::from(nonce), ciphertext)
+        .map_err(|_| "Secret cannot be decrypted, was hmac_key changed?".to_string())?;
+    String::from_utf8(plaintext).map_err(|_| "Secret is not valid UTF-8".to_string())
+}
+
+/// Title and body of the messages for one event
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub title: String,
+    pub body: String,
+}
+
+/// Local time window in which no messages are sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    /// "HH:MM"; may be later than `end` for a window past midnight
+    pub start: String,
+    pub end: String,
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+}
+
+impl QuietHours {
+    pub fn contains(&self, timestamp: i64) -> bool {
+        let (Some(start), Some(end)) = (parse_time(&self.start), parse_time(&self.end)) else {
+            return false;
+        };
+        let local = timestamp + self.utc_offset_minutes as i64 * 60;
+        let minute = local.rem_euclid(86_400) / 60;
+        if start <= end {
+            (start..end).contains(&minute)
+        } else {
+            minute >= start || minute < end
+        }
+    }
+}
+
+fn parse_time(value: &str) -> Option<i64> {
+    crate::charge_schedule::parse_time(value).map(i64::from)
+}
+
+/// Body of `PUT /notifications/preferences`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationPreferences {
+    /// Templates by event ("low_battery", ..., "test"); others use the defaults
+    #[serde(default)]
+    pub templates: BTreeMap<String, Template>,
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+/// Name of an alert kind as used in templates and message tags
+fn event_name(kind: AlertKind) -> String {
+    serde_json::to_value(kind)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn is_event(name: &str) -> bool {
+    name == TEST_EVENT || AlertKind::ALL.iter().any(|kind| event_name(*kind) == name)
+}
+
+/// Names of the `{placeholder}`s in a template
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+impl NotificationPreferences {
+    /// Validate the preferences, returning all errors
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        for (event, template) in &self.templates {
+            if !is_event(event) {
+                errors.push(format!("templates.{}: unknown event", event));
+                continue;
+            }
+            for (field, text) in [("title", &template.title), ("body", &template.body)] {
+                if text.trim().is_empty() || text.len() > MAX_TEMPLATE_LENGTH {
+                    errors.push(format!(
+                        "templates.{}.{} must be 1 to {} characters",
+                        event, field, MAX_TEMPLATE_LENGTH
+                    ));
+                }
+                for name in placeholders(text) {
+                    if !PLACEHOLDERS.contains(&name) {
+                        errors.push(format!(
+                            "templates.{}.{}: unknown placeholder {{{}}}",
+                            event, field, name
+                        ));
+                    }
+                }
+            }
+        }
+        if let Some(quiet_hours) = &self.quiet_hours {
+            match (parse_time(&quiet_hours.start), parse_time(&quiet_hours.end)) {
+                (Some(start), Some(end)) if start == end => {
+                    errors.push("quiet_hours start and end must differ".to_string())
+                }
+                (Some(_), Some(_)) => {}
+                _ => errors.push("quiet_hours start and end must be HH:MM times".to_string()),
+            }
+            if quiet_hours.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+                errors.push("quiet_hours.utc_offset_minutes must be between -840 and 840".into());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_quiet(&self, timestamp: i64) -> bool {
+        self.quiet_hours
+            .as_ref()
+            .is_some_and(|quiet_hours| quiet_hours.contains(timestamp))
+    }
+
+    /// Message for a fired alert, rendered with the user's template
+    pub fn alert_message(&self, vin: &str, alert: &AlertInstance) -> Message {
+        let event = event_name(alert.kind);
+        let values = [
+            ("title", alert.title.clone()),
+            ("message", alert.message.clone()),
+            ("soc", alert.soc.to_string()),
+            ("vin", vin.to_string()),
+            ("event", event.clone()),
+        ];
+        let (title, body) = match self.templates.get(&event) {
+            Some(template) => (
+                render(&template.title, &values),
+                render(&template.body, &values),
+            ),
+            None => (alert.title.clone(), alert.message.clone()),
+        };
+        Message {
+            event,
+            title,
+            body,
+            priority: match alert.kind.severity() {
+                "warning" => Priority::High,
+                _ => Priority::Default,
+            },
+        }
+    }
+
+    /// Message sent by `POST /notifications/channels/{id}/test`
+    pub fn test_message(&self) -> Message {
+        let values = [
+            ("title", "Test notification".to_string()),
+            (
+                "message",
+                "Notifications from myt2abrp reach this channel.".to_string(),
+            ),
+            ("soc", String::new()),
+            ("vin", String::new()),
+            ("event", TEST_EVENT.to_string()),
+        ];
+        let template = self.templates.get(TEST_EVENT);
+        Message {
+            event: TEST_EVENT.to_string(),
+            title: render(template.map_or("{title}", |t| t.title.as_str()), &values),
+            body: render(template.map_or("{message}", |t| t.body.as_str()), &values),
+            priority: Priority::Low,
+        }
+    }
+}
+
+/// Allowed channel server origins from the comma-separated
+/// `notification_allowed_hosts` variable; empty disables channels
+pub fn get_allowed_hosts() -> Vec<String> {
+    variables::get("notification_allowed_hosts")
+        .unwrap_or_default()
+        .split(',')
+        .map(|host| host.trim().trim_end_matches('/').to_string())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+/// Send a message through a stored channel, returning the server's status
+pub async fn send(channel: &Channel, message: &Message) -> Result<u16, String> {
+    if !is_allowed(channel.config.server_url(), &get_allowed_hosts()) {
+        return Err("Host is no longer in the allowed notification hosts".to_string());
+    }
+    let channel = channel
+        .config
+        .clone()
+        .unseal(&get_encryption_key())?
+        .into_channel();
+    let channel_request = channel.request(message);
+
+    let mut builder = Request::post(&channel_request.url, channel_request.body.to_string());
+    builder.header("content-type", "application/json").header(
+        "user-agent",
+        format!("myt2abrp-notifications/{}", crate::VERSION),
+    );
+    for (name, value) in &channel_request.headers {
+        builder.header(*name, value.as_str());
+    }
+
+    let sent = crate::single_flight::timeout(
+        SEND_TIMEOUT_MS,
+        spin_sdk::http::send::<Request, Response>(builder.build()),
+    )
+    .await;
+    match sent {
+        Some(Ok(response)) if channel.accepted(*response.status(), response.body()) => {
+            Ok(*response.status())
+        }
+        Some(Ok(response)) => Err(format!("Server answered HTTP {}", response.status())),
+        Some(Err(e)) => Err(format!("Request failed: {}", e)),
+        None => Err(format!("No response within {} ms", SEND_TIMEOUT_MS)),
+    }
+}
+
+fn channels_key(username_hash: &str) -> String {
+    format!("{}{}", CHANNELS_KEY_PREFIX, username_hash)
+}
+
+fn preferences_key(username_hash: &str) -> String {
+    format!("{}{}", PREFERENCES_KEY_PREFIX, username_hash)
+}
+
+pub async fn load_channels(store: &Store, username_hash: &str) -> Vec<Channel> {
+    match store.get(&channels_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+pub async fn save_channels(
+    store: &Store,
+    username_hash: &str,
+    channels: &[Channel],
+) -> anyhow::Result<()> {
+    store.set(&channels_key(username_hash), &serde_json::to_vec(channels)?)?;
+    Ok(())
+}
+
+pub async fn get_preferences(store: &Store, username_hash: &str) -> NotificationPreferences {
+    match store.get(&preferences_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => NotificationPreferences::default(),
+    }
+}
+
+pub async fn save_preferences(
+    store: &Store,
+    username_hash: &str,
+    preferences: &NotificationPreferences,
+) -> anyhow::Result<()> {
+    store.set(
+        &preferences_key(username_hash),
+        &serde_json::to_vec(preferences)?,
+    )?;
+    Ok(())
+}
+
+/// A message waiting for the scheduler tick
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct QueuedMessage {
+    username_hash: String,
+    channel_id: String,
+    message: Message,
+}
+
+fn load_queue(store: &Store) -> Vec<QueuedMessage> {
+    match store.get(QUEUE_KEY) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Queue the alerts that fired for a VIN for the channels of their users
+pub async fn notify_alerts(
+    store: &Store,
+    vin: &str,
+    fired: &[(String, AlertInstance)],
+    now: i64,
+) -> anyhow::Result<()> {
+    let mut queued = Vec::new();
+    for (username_hash, alert) in fired {
+        let channels = load_channels(store, username_hash).await;
+        if channels.is_empty() {
+            continue;
+        }
+        let preferences = get_preferences(store, username_hash).await;
+        if preferences.is_quiet(now) {
+            info!(vin = vin, kind = ?alert.kind, "Notification dropped during quiet hours");
+            continue;
+        }
+        let message = preferences.alert_message(vin, alert);
+        queued.extend(channels.iter().map(|channel| QueuedMessage {
+            username_hash: username_hash.clone(),
+            channel_id: channel.id.clone(),
+            message: message.clone(),
+        }));
+    }
+    if queued.is_empty() {
+        return Ok(());
+    }
+    let mut queue = load_queue(store);
+    queue.extend(queued);
+    store.set(QUEUE_KEY, &serde_json::to_vec(&queue)?)?;
+    Ok(())
+}
+
+/// Send the queued messages; returns how many were attempted
+pub async fn send_queued(store: &Store) -> anyhow::Result<usize> {
+    let queue = load_queue(store);
+    if queue.is_empty() {
+        return Ok(0);
+    }
+    // Removed before sending so an overlapping tick does not send twice
+    store.delete(QUEUE_KEY)?;
+
+    for queued in &queue {
+        let channels = load_channels(store, &queued.username_hash).await;
+        // Messages for deleted channels are dropped
+        let Some(channel) = channels.iter().find(|c| c.id == queued.channel_id) else {
+            continue;
+        };
+        match send(channel, &queued.message).await {
+            Ok(status) => info!(
+                channel_id = %channel.id,
+                event = %queued.message.event,
+                status = status,
+                "Notification sent"
+            ),
+            Err(error) => warn!(
+                channel_id = %channel.id,
+                event = %queued.message.event,
+                error = %error,
+                "Notification failed"
+            ),
+        }
+    }
+    Ok(queue.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alerts::AlertState;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn message(priority: Priority) -> Message {
+        Message {
+            event: "low_battery".to_string(),
+            title: "Battery low".to_string(),
+            body: "Battery at 15%".to_string(),
+            priority,
+        }
+    }
+
+    fn alert(kind: AlertKind) -> AlertInstance {
+        AlertInstance {
+            id: "a".to_string(),
+            kind,
+            state: AlertState::Active,
+            title: "Battery low".to_string(),
+            message: "Battery at 15%".to_string(),
+            soc: 15,
+            created_at: 0,
+            acknowledged_at: None,
+            resolved_at: None,
+        }
+    }
+
+    fn config(json: serde_json::Value) -> Result<ChannelConfig, serde_json::Error> {
+        serde_json::from_value(json)
+    }
+
+    #[test]
+    fn test_channel_requests() {
+        // Requests as a local stand-in server would receive them
+        let ntfy = Ntfy {
+            server_url: "http://localhost:8080/".to_string(),
+            topic: "car".to_string(),
+            token: Some("tk".to_string()),
+        }
+        .request(&message(Priority::High));
+        assert_eq!(ntfy.url, "http://localhost:8080");
+        assert_eq!(
+            ntfy.headers,
+            vec![("authorization", "Bearer tk".to_string())]
+        );
+        assert_eq!(ntfy.body["topic"], "car");
+        assert_eq!(ntfy.body["priority"], 4);
+        assert_eq!(ntfy.body["tags"][0], "low_battery");
+
+        let gotify = Gotify {
+            server_url: "http://localhost:8080".to_string(),
+            app_token: "app".to_string(),
+        }
+        .request(&message(Priority::Default));
+        assert_eq!(gotify.url, "http://localhost:8080/message");
+        assert_eq!(gotify.headers, vec![("x-gotify-key", "app".to_string())]);
+        assert_eq!(gotify.body["priority"], 5);
+
+        let smtp = SmtpRelay {
+            relay_url: "http://localhost:8080/send".to_string(),
+            api_key: None,
+            from: "car@example.com".to_string(),
+            to: "me@example.com".to_string(),
+        }
+        .request(&message(Priority::Default));
+        assert!(smtp.headers.is_empty());
+        assert_eq!(smtp.body["subject"], "Battery low");
+        assert_eq!(smtp.body["text"], "Battery at 15%");
+
+        let telegram = Telegram {
+            api_url: "http://localhost:8080".to_string(),
+            bot_token: "123:abc".to_string(),
+            chat_id: "42".to_string(),
+        };
+        let request = telegram.request(&message(Priority::Low));
+        assert_eq!(request.url, "http://localhost:8080/bot123:abc/sendMessage");
+        assert_eq!(request.body["text"], "Battery low\n\nBattery at 15%");
+        assert_eq!(request.body["disable_notification"], true);
+        assert!(telegram.accepted(200, br#"{"ok":true,"result":{}}"#));
+        assert!(!telegram.accepted(200, br#"{"ok":false}"#));
+        assert!(!telegram.accepted(401, br#"{"ok":false}"#));
+    }
+
+    #[test]
+    fn test_channel_config_validation() {
+        let allowed = vec![
+            "https://ntfy.sh".to_string(),
+            "https://api.telegram.org".to_string(),
+        ];
+        let ntfy = config(serde_json::json!({"type": "ntfy", "topic": "my-car_1"})).unwrap();
+        assert!(ntfy.validate(&allowed).is_ok());
+        assert!(config(serde_json::json!({"type": "ntfy", "topic": "a b"}))
+            .unwrap()
+            .validate(&allowed)
+            .is_err());
+        assert!(config(serde_json::json!({
+            "type": "gotify",
+            "server_url": "https://gotify.example.com",
+            "app_token": "x",
+        }))
+        .unwrap()
+        .validate(&allowed)
+        .is_err());
+        assert!(config(serde_json::json!({
+            "type": "telegram",
+            "bot_token": "123:abc",
+            "chat_id": "42",
+        }))
+        .unwrap()
+        .validate(&allowed)
+        .is_ok());
+        assert!(config(serde_json::json!({
+            "type": "smtp",
+            "relay_url": "https://ntfy.sh/send",
+            "from": "car@example.com",
+            "to": "not an address",
+        }))
+        .unwrap()
+        .validate(&allowed)
+        .is_err());
+        assert!(config(serde_json::json!({"type": "sms", "to": "+4917"})).is_err());
+        assert!(config(serde_json::json!({"type": "ntfy", "topic": "a", "extra": 1})).is_err());
+    }
+
+    #[test]
+    fn test_secrets_are_sealed() {
+        let plain = config(serde_json::json!({
+            "type": "ntfy",
+            "topic": "secret-topic",
+            "token": "tk_secret",
+        }))
+        .unwrap();
+        let channel = Channel::new(plain.clone(), &KEY, 0);
+
+        let stored = serde_json::to_string(&channel).unwrap();
+        assert!(!stored.contains("secret-topic"));
+        assert!(!stored.contains("tk_secret"));
+        assert!(stored.contains(SEALED_PREFIX));
+
+        let public = channel.to_json();
+        assert_eq!(public["topic_configured"], true);
+        assert_eq!(public["token_configured"], true);
+        assert!(public.get("topic").is_none());
+        assert_eq!(public["server_url"], NTFY_DEFAULT_SERVER);
+
+        assert_eq!(channel.config.clone().unseal(&KEY), Ok(plain));
+        assert!(channel.config.unseal(&[8; 32]).is_err());
+        assert_ne!(derive_key(b"one"), derive_key(b"two"));
+    }
+
+    #[test]
+    fn test_templates() {
+        let mut preferences = NotificationPreferences::default();
+        let message = preferences.alert_message("VIN1", &alert(AlertKind::LowBattery));
+        assert_eq!(message.title, "Battery low");
+        assert_eq!(message.priority, Priority::High);
+
+        preferences.templates.insert(
+            "low_battery".to_string(),
+            Template {
+                title: "{vin}: {soc}%".to_string(),
+                body: "{message} ({event})".to_string(),
+            },
+        );
+        assert!(preferences.validate().is_ok());
+        let message = preferences.alert_message("VIN1", &alert(AlertKind::LowBattery));
+        assert_eq!(message.title, "VIN1: 15%");
+        assert_eq!(message.body, "Battery at 15% (low_battery)");
+        assert_eq!(
+            preferences
+                .alert_message("VIN1", &alert(AlertKind::ChargeComplete))
+                .priority,
+            Priority::Default
+        );
+        assert_eq!(preferences.test_message().title, "Test notification");
+
+        preferences.templates.insert(
+            "charge_complete".to_string(),
+            Template {
+                title: "{range}".to_string(),
+                body: "Done".to_string(),
+            },
+        );
+        preferences.templates.insert(
+            "parked".to_string(),
+            Template {
+                title: "x".to_string(),
+                body: "y".to_string(),
+            },
+        );
+        assert_eq!(preferences.validate().unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let quiet = QuietHours {
+            start: "22:00".to_string(),
+            end: "07:00".to_string(),
+            utc_offset_minutes: 60,
+        };
+        // 1_700_006_400 is midnight UTC
+        let utc = |hours: i64, minutes: i64| 1_700_006_400 + hours * 3600 + minutes * 60;
+        assert!(quiet.contains(utc(21, 0)));
+        assert!(quiet.contains(utc(5, 59)));
+        assert!(!quiet.contains(utc(6, 0)));
+        assert!(!quiet.contains(utc(20, 59)));
+
+        let daytime = QuietHours {
+            start: "12:00".to_string(),
+            end: "14:00".to_string(),
+            utc_offset_minutes: 0,
+        };
+        assert!(daytime.contains(utc(13, 0)));
+        assert!(!daytime.contains(utc(14, 0)));
+
+        let preferences = NotificationPreferences {
+            templates: BTreeMap::new(),
+            quiet_hours: Some(QuietHours {
+                start: "08:00".to_string(),
+                end: "08:00".to_string(),
+                utc_offset_minutes: 0,
+            }),
+        };
+        assert!(preferences.validate().is_err());
+    }
+}
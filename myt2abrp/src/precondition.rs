@@ -0,0 +1,374 @@
+// Scheduled climate actions
+//
+// A user can schedule one "precondition at departure time" per vehicle:
+// climate control starts `duration_minutes` before departure so the cabin is
+// ready when leaving, and is stopped again at departure. Climate starts with
+// a duration leave a scheduled stop the same way. All actions live in one
+// KV list and are run by the scheduler tick (`POST /scheduler/tick`), which
+// an external timer calls about once a minute.
+
+use crate::commands::{ClimateAction, ClimateCommandRequest};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use uuid::Uuid;
+
+const SCHEDULE_KEY: &str = "climate_schedule";
+
+/// Run time of a precondition when none is given
+pub const PRECONDITION_DEFAULT_MINUTES: i32 = 15;
+
+/// How far ahead a departure can be scheduled
+const MAX_LEAD_SECONDS: i64 = 7 * 24 * 3600;
+
+/// Actions missed by more than this (scheduler outage) are skipped instead
+/// of starting the climate long after departure
+const MISSED_GRACE_SECONDS: i64 = 15 * 60;
+
+/// Executed actions are kept this long so their outcome can be looked up
+const EXECUTED_RETENTION_SECONDS: i64 = 24 * 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledPurpose {
+    /// Start climate control ahead of a departure
+    Precondition,
+    /// Stop climate control after the requested duration
+    AutoStop,
+}
+
+/// Body of `PUT /vehicles/{vin}/climate/schedule`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PreconditionRequest {
+    /// Departure time as RFC 3339 or Unix seconds
+    pub departure_at: serde_json::Value,
+    pub target_temperature: Option<f32>,
+    pub front_defrost: Option<bool>,
+    pub rear_defrost: Option<bool>,
+    pub duration_minutes: Option<i32>,
+}
+
+impl PreconditionRequest {
+    /// Validate and turn the request into a scheduled start
+    pub fn into_action(
+        self,
+        username_hash: &str,
+        vin: &str,
+        now: i64,
+    ) -> Result<ScheduledAction, String> {
+        let departure_at = match &self.departure_at {
+            serde_json::Value::Number(n) => n.as_i64(),
+            serde_json::Value::String(s) => crate::parse_time_param(s),
+            _ => None,
+        }
+        .ok_or_else(|| "departure_at must be Unix seconds or an RFC 3339 timestamp".to_string())?;
+
+        let duration = self
+            .duration_minutes
+            .unwrap_or(PRECONDITION_DEFAULT_MINUTES);
+        let request = ClimateCommandRequest {
+            action: ClimateAction::Start,
+            target_temperature: self.target_temperature,
+            front_defrost: self.front_defrost,
+            rear_defrost: self.rear_defrost,
+            duration_minutes: Some(duration),
+        };
+        request.validate()?;
+
+        let run_at = departure_at - i64::from(duration) * 60;
+        if run_at < now {
+            return Err(format!(
+                "departure_at must be at least {} minutes ahead",
+                duration
+            ));
+        }
+        if departure_at - now > MAX_LEAD_SECONDS {
+            return Err("departure_at must be within the next 7 days".to_string());
+        }
+
+        Ok(ScheduledAction {
+            departure_at: Some(departure_at),
+            ..ScheduledAction::new(
+                username_hash,
+                vin,
+                ScheduledPurpose::Precondition,
+                request,
+                run_at,
+                now,
+            )
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduledAction {
+    pub id: String,
+    pub username_hash: String,
+    pub vin: String,
+    pub purpose: ScheduledPurpose,
+    pub request: ClimateCommandRequest,
+    pub run_at: i64,
+    pub departure_at: Option<i64>,
+    pub created_at: i64,
+    pub executed_at: Option<i64>,
+    /// Command sent when the action ran
+    pub command_id: Option<String>,
+    /// Why the action did not send a command
+    pub message: Option<String>,
+}
+
+impl ScheduledAction {
+    pub fn new(
+        username_hash: &str,
+        vin: &str,
+        purpose: ScheduledPurpose,
+        request: ClimateCommandRequest,
+        run_at: i64,
+        now: i64,
+    ) -> Self {
+        ScheduledAction {
+            id: Uuid::new_v4().to_string(),
+            username_hash: username_hash.to_string(),
+            vin: vin.to_string(),
+            purpose,
+            request,
+            run_at,
+            departure_at: None,
+            created_at: now,
+            executed_at: None,
+            command_id: None,
+            message: None,
+        }
+    }
+
+    /// Stop climate control at `run_at`
+    pub fn auto_stop(username_hash: &str, vin: &str, run_at: i64, now: i64) -> Self {
+        ScheduledAction::new(
+            username_hash,
+            vin,
+            ScheduledPurpose::AutoStop,
+            ClimateCommandRequest::stop(),
+            run_at,
+            now,
+        )
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.executed_at.is_none()
+    }
+
+    fn state(&self) -> &'static str {
+        match (self.executed_at, &self.command_id) {
+            (None, _) => "scheduled",
+            (Some(_), Some(_)) => "executed",
+            (Some(_), None) => "failed",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "vin": self.vin,
+            "purpose": self.purpose,
+            "state": self.state(),
+            "departure_at": self.departure_at,
+            "run_at": self.run_at,
+            "target_temperature": self.request.target_temperature,
+            "front_defrost": self.request.front_defrost,
+            "rear_defrost": self.request.rear_defrost,
+            "duration_minutes": self.request.duration_minutes,
+            "executed_at": self.executed_at,
+            "command_id": self.command_id,
+            "message": self.message,
+        })
+    }
+}
+
+fn is_precondition_of(action: &ScheduledAction, username_hash: &str, vin: &str) -> bool {
+    action.purpose == ScheduledPurpose::Precondition
+        && action.username_hash == username_hash
+        && action.vin == vin
+}
+
+/// Add a precondition, replacing a pending one for the same vehicle
+pub fn set_precondition(actions: &mut Vec<ScheduledAction>, action: ScheduledAction) {
+    actions
+        .retain(|a| !(a.is_pending() && is_precondition_of(a, &action.username_hash, &action.vin)));
+    actions.push(action);
+}
+
+/// Remove the pending precondition; returns whether there was one
+pub fn cancel_precondition(
+    actions: &mut Vec<ScheduledAction>,
+    username_hash: &str,
+    vin: &str,
+) -> bool {
+    let before = actions.len();
+    actions.retain(|a| !(a.is_pending() && is_precondition_of(a, username_hash, vin)));
+    actions.len() != before
+}
+
+/// Most recent precondition for the vehicle, pending or executed
+pub fn latest_precondition<'a>(
+    actions: &'a [ScheduledAction],
+    username_hash: &str,
+    vin: &str,
+) -> Option<&'a ScheduledAction> {
+    actions
+        .iter()
+        .filter(|a| is_precondition_of(a, username_hash, vin))
+        .max_by_key(|a| a.created_at)
+}
+
+/// Mark due actions as executed and return the ones to run
+///
+/// Marking happens before running so that an overlapping tick does not send
+/// the same command twice. Actions missed by too much are skipped, and
+/// executed actions past retention are dropped.
+pub fn take_due(actions: &mut Vec<ScheduledAction>, now: i64) -> Vec<ScheduledAction> {
+    actions.retain(|a| {
+        a.executed_at
+            .is_none_or(|executed| now - executed < EXECUTED_RETENTION_SECONDS)
+    });
+
+    let mut due = Vec::new();
+    for action in actions
+        .iter_mut()
+        .filter(|a| a.is_pending() && a.run_at <= now)
+    {
+        action.executed_at = Some(now);
+        if now - action.run_at > MISSED_GRACE_SECONDS {
+            action.message = Some("Skipped: scheduled time was missed".to_string());
+        } else {
+            due.push(action.clone());
+        }
+    }
+    due
+}
+
+/// Record the outcome of an executed action
+pub fn record_result(
+    actions: &mut [ScheduledAction],
+    id: &str,
+    command_id: Option<String>,
+    message: Option<String>,
+) {
+    if let Some(action) = actions.iter_mut().find(|a| a.id == id) {
+        action.command_id = command_id;
+        action.message = message;
+    }
+}
+
+pub async fn load_actions(store: &Store) -> Vec<ScheduledAction> {
+    match store.get(SCHEDULE_KEY) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+pub async fn save_actions(store: &Store, actions: &[ScheduledAction]) -> anyhow::Result<()> {
+    store.set(SCHEDULE_KEY, &serde_json::to_vec(actions)?)?;
+    Ok(())
+}
+
+/// Schedule a stop, e.g. after a climate start with a duration
+pub async fn schedule_auto_stop(
+    store: &Store,
+    username_hash: &str,
+    vin: &str,
+    run_at: i64,
+    now: i64,
+) -> anyhow::Result<()> {
+    let mut actions = load_actions(store).await;
+    actions.push(ScheduledAction::auto_stop(username_hash, vin, run_at, now));
+    save_actions(store, &actions).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn precondition(departure_at: serde_json::Value) -> PreconditionRequest {
+        PreconditionRequest {
+            departure_at,
+            target_temperature: Some(21.0),
+            front_defrost: None,
+            rear_defrost: None,
+            duration_minutes: Some(20),
+        }
+    }
+
+    #[test]
+    fn test_precondition_request() {
+        let now = 1_736_900_000;
+        let action = precondition(serde_json::json!(now + 3600))
+            .into_action("user", "VIN", now)
+            .unwrap();
+        assert_eq!(action.run_at, now + 3600 - 20 * 60);
+        assert_eq!(action.departure_at, Some(now + 3600));
+        assert_eq!(action.request.action, ClimateAction::Start);
+
+        let rfc3339 = precondition(serde_json::json!("2025-01-15T07:30:00Z"))
+            .into_action("user", "VIN", 1_736_900_000)
+            .unwrap();
+        assert_eq!(rfc3339.departure_at, Some(1_736_926_200));
+
+        // Too soon for the run time, too far ahead, or not a time
+        assert!(precondition(serde_json::json!(now + 600))
+            .into_action("user", "VIN", now)
+            .is_err());
+        assert!(precondition(serde_json::json!(now + 30 * 24 * 3600))
+            .into_action("user", "VIN", now)
+            .is_err());
+        assert!(precondition(serde_json::json!(true))
+            .into_action("user", "VIN", now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_schedule_replace_and_cancel() {
+        let mut actions = Vec::new();
+        let first = precondition(serde_json::json!(10_000))
+            .into_action("user", "VIN", 0)
+            .unwrap();
+        let second = precondition(serde_json::json!(20_000))
+            .into_action("user", "VIN", 1)
+            .unwrap();
+        set_precondition(&mut actions, first);
+        set_precondition(&mut actions, second.clone());
+        actions.push(ScheduledAction::auto_stop("user", "VIN", 500, 0));
+        assert_eq!(actions.len(), 2);
+        assert_eq!(latest_precondition(&actions, "user", "VIN"), Some(&second));
+        assert_eq!(latest_precondition(&actions, "other", "VIN"), None);
+
+        assert!(cancel_precondition(&mut actions, "user", "VIN"));
+        assert!(!cancel_precondition(&mut actions, "user", "VIN"));
+        // The auto-stop is not a precondition
+        assert_eq!(actions.len(), 1);
+    }
+
+    #[test]
+    fn test_take_due() {
+        let mut actions = vec![
+            ScheduledAction::auto_stop("user", "VIN", 100, 0),
+            ScheduledAction::auto_stop("user", "VIN", 500, 0),
+            ScheduledAction::auto_stop("user", "VIN", 5000, 0),
+        ];
+
+        assert!(take_due(&mut actions, 50).is_empty());
+        let due = take_due(&mut actions, 1100);
+        // The first action was missed by more than the grace period
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].run_at, 500);
+        assert_eq!(actions[0].to_json()["state"], "failed");
+
+        // Already executed actions do not run again
+        assert!(take_due(&mut actions, 1300).is_empty());
+        record_result(&mut actions, &due[0].id, Some("cmd".to_string()), None);
+        assert_eq!(actions[1].to_json()["state"], "executed");
+
+        // Executed actions are dropped after retention
+        take_due(&mut actions, 1100 + EXECUTED_RETENTION_SECONDS);
+        assert_eq!(actions.len(), 1);
+    }
+}
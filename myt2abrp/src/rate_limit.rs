@@ -0,0 +1,398 @@
+// Rate limiting
+//
+// Token buckets kept in the KV store, one per route group (auth, data,
+// commands), dimension (user, client IP, API key) and identifier. A bucket
+// holds up to `limit` tokens and refills continuously at `limit` tokens per
+// `window_seconds`, so clients can burst up to the limit and then continue at
+// the sustained rate instead of waiting for a fixed window to end.
+//
+// The most restrictive bucket checked while serving a request is reported in
+// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+
+use serde::{Deserialize, Serialize};
+use spin_sdk::http::{Method, Response};
+use spin_sdk::key_value::Store;
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BUCKET_KEY_PREFIX: &str = "ratelimit_bucket_";
+
+thread_local! {
+    static CURRENT: RefCell<Option<Decision>> = const { RefCell::new(None) };
+}
+
+/// Routes sharing a rate limit policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// Login, token refresh, logout and other `/auth` endpoints
+    Auth,
+    /// Reading and configuring data
+    Data,
+    /// Remote commands sent to a vehicle
+    Commands,
+}
+
+/// Whether a write to a route below `/vehicles/{vin}/` reaches the vehicle
+fn is_command_route(vehicle_route: &str) -> bool {
+    vehicle_route.starts_with("commands/")
+        || vehicle_route == "climate"
+        || vehicle_route == "climate/schedule"
+        || vehicle_route == "charge-schedule"
+        || vehicle_route.starts_with("charge-schedule/")
+}
+
+impl RouteGroup {
+    pub fn of(path: &str, method: &Method) -> Self {
+        let route = crate::telemetry::route_template(path);
+        let writes = !matches!(method, Method::Get | Method::Head | Method::Options);
+        if route.starts_with("/auth/") {
+            RouteGroup::Auth
+        } else if writes
+            && route
+                .strip_prefix("/vehicles/{vin}/")
+                .is_some_and(is_command_route)
+        {
+            RouteGroup::Commands
+        } else {
+            RouteGroup::Data
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "auth",
+            RouteGroup::Data => "data",
+            RouteGroup::Commands => "commands",
+        }
+    }
+
+    /// Spin variable overriding the group's policy
+    pub fn variable(&self) -> &'static str {
+        match self {
+            RouteGroup::Auth => "rate_limit_auth",
+            RouteGroup::Data => "rate_limit_data",
+            RouteGroup::Commands => "rate_limit_commands",
+        }
+    }
+}
+
+/// What a bucket is keyed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    /// Username hash
+    User,
+    /// Client IP address
+    Ip,
+    /// HMAC of a service secret (scheduler, admin) presented as Bearer token
+    ApiKey,
+}
+
+impl Dimension {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Dimension::User => "user",
+            Dimension::Ip => "ip",
+            Dimension::ApiKey => "key",
+        }
+    }
+}
+
+/// `limit` requests per `window_seconds`, with bursts of up to `limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub limit: u32,
+    pub window_seconds: u32,
+}
+
+impl Policy {
+    pub fn per_hour(limit: u32) -> Self {
+        Policy {
+            limit,
+            window_seconds: 3600,
+        }
+    }
+
+    /// Parse "<requests>/<seconds>", e.g. "100/3600"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (limit, window) = value.trim().split_once('/')?;
+        let policy = Policy {
+            limit: limit.trim().parse().ok()?,
+            window_seconds: window.trim().parse().ok()?,
+        };
+        (policy.limit > 0 && policy.window_seconds > 0).then_some(policy)
+    }
+
+    fn tokens_per_ms(&self) -> f64 {
+        self.limit as f64 / (self.window_seconds as f64 * 1000.0)
+    }
+}
+
+/// Stored state of a bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_ms: i64,
+}
+
+/// Outcome of taking a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset_seconds: u64,
+    /// Seconds until the next token, when the request was refused
+    pub retry_after_seconds: Option<u64>,
+}
+
+impl Decision {
+    /// Whether this decision should be reported instead of `other`
+    fn is_more_restrictive_than(&self, other: &Decision) -> bool {
+        (!self.allowed && other.allowed)
+            || (self.allowed == other.allowed
+                && (self.remaining, std::cmp::Reverse(self.reset_seconds))
+                    < (other.remaining, std::cmp::Reverse(other.reset_seconds)))
+    }
+
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("ratelimit-limit", self.limit.to_string()),
+            ("ratelimit-remaining", self.remaining.to_string()),
+            ("ratelimit-reset", self.reset_seconds.to_string()),
+        ]
+    }
+
+    pub fn apply(&self, response: &mut Response) {
+        for (name, value) in self.headers() {
+            response.set_header(name, value);
+        }
+    }
+}
+
+fn seconds_for(tokens: f64, policy: &Policy) -> u64 {
+    (tokens.max(0.0) / policy.tokens_per_ms() / 1000.0).ceil() as u64
+}
+
+/// Refill `bucket` up to `now_ms` and take one token if there is one
+pub fn take(bucket: Option<Bucket>, policy: &Policy, now_ms: i64) -> (Bucket, Decision) {
+    let limit = policy.limit as f64;
+    let mut bucket = bucket.unwrap_or(Bucket {
+        tokens: limit,
+        updated_ms: now_ms,
+    });
+    let elapsed_ms = (now_ms - bucket.updated_ms).max(0) as f64;
+    bucket.tokens = (bucket.tokens + elapsed_ms * policy.tokens_per_ms()).min(limit);
+    bucket.updated_ms = now_ms;
+
+    let allowed = bucket.tokens >= 1.0;
+    if allowed {
+        bucket.tokens -= 1.0;
+    }
+    let decision = Decision {
+        allowed,
+        limit: policy.limit,
+        remaining: bucket.tokens.floor() as u32,
+        reset_seconds: seconds_for(limit - bucket.tokens, policy),
+        retry_after_seconds: (!allowed).then(|| seconds_for(1.0 - bucket.tokens, policy).max(1)),
+    };
+    (bucket, decision)
+}
+
+fn bucket_key(group: RouteGroup, dimension: Dimension, id: &str) -> String {
+    format!(
+        "{}{}_{}_{}",
+        BUCKET_KEY_PREFIX,
+        group.as_str(),
+        dimension.as_str(),
+        id
+    )
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Take a token from the bucket of `id`, noting the decision for the
+/// response headers
+pub async fn check(
+    store: &Store,
+    group: RouteGroup,
+    dimension: Dimension,
+    id: &str,
+    policy: &Policy,
+) -> anyhow::Result<Decision> {
+    let key = bucket_key(group, dimension, id);
+    let bucket = store
+        .get(&key)?
+        .and_then(|bytes| serde_json::from_slice::<Bucket>(&bytes).ok());
+    let (bucket, decision) = take(bucket, policy, now_ms());
+    store.set(&key, &serde_json::to_vec(&bucket)?)?;
+    note(decision.clone());
+    Ok(decision)
+}
+
+/// Remember `decision` for the response if it is the most restrictive so far
+fn note(decision: Decision) {
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        if current
+            .as_ref()
+            .is_none_or(|noted| decision.is_more_restrictive_than(noted))
+        {
+            *current = Some(decision);
+        }
+    });
+}
+
+/// Decision to report for the request being served
+pub fn current() -> Option<Decision> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Take the decision to report, ending the request
+pub fn end_request() -> Option<Decision> {
+    CURRENT.with(|current| current.borrow_mut().take())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let policy = Policy {
+            limit: 3,
+            window_seconds: 60,
+        };
+
+        // A new bucket allows a burst up to the limit
+        let (bucket, decision) = take(None, &policy, 0);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 2);
+        assert_eq!(decision.reset_seconds, 20);
+        let (bucket, _) = take(Some(bucket), &policy, 0);
+        let (bucket, decision) = take(Some(bucket), &policy, 0);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.reset_seconds, 60);
+
+        // Then refuses until the next token, which arrives after 20s
+        let (bucket, decision) = take(Some(bucket), &policy, 5_000);
+        assert!(!decision.allowed);
+        assert_eq!(decision.retry_after_seconds, Some(15));
+        let (bucket, decision) = take(Some(bucket), &policy, 20_000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 0);
+
+        // Refills continuously, but never beyond the limit
+        let (_, decision) = take(Some(bucket), &policy, 3_600_000);
+        assert!(decision.allowed);
+        assert_eq!(decision.remaining, 2);
+    }
+
+    #[test]
+    fn test_policy_parse() {
+        assert_eq!(
+            Policy::parse("100/3600"),
+            Some(Policy {
+                limit: 100,
+                window_seconds: 3600
+            })
+        );
+        assert_eq!(
+            Policy::parse(" 5 / 60 "),
+            Some(Policy {
+                limit: 5,
+                window_seconds: 60
+            })
+        );
+        assert_eq!(Policy::parse("0/60"), None);
+        assert_eq!(Policy::parse("10/0"), None);
+        assert_eq!(Policy::parse("10"), None);
+        assert_eq!(Policy::parse("ten/60"), None);
+    }
+
+    #[test]
+    fn test_route_group() {
+        assert_eq!(
+            RouteGroup::of("/auth/login", &Method::Post),
+            RouteGroup::Auth
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/commands/lock", &Method::Post),
+            RouteGroup::Commands
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/climate", &Method::Post),
+            RouteGroup::Commands
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/climate", &Method::Get),
+            RouteGroup::Data
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/climate/schedule", &Method::Put),
+            RouteGroup::Commands
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/charge-schedule", &Method::Post),
+            RouteGroup::Commands
+        );
+        assert_eq!(
+            RouteGroup::of(
+                "/vehicles/JTDKN3DU0A0000001/charge-schedule/2",
+                &Method::Delete
+            ),
+            RouteGroup::Commands
+        );
+        assert_eq!(
+            RouteGroup::of("/vehicles/JTDKN3DU0A0000001/charge-schedule", &Method::Get),
+            RouteGroup::Data
+        );
+        // Only vehicle routes count, wherever "/commands/" appears
+        assert_eq!(
+            RouteGroup::of("/commands/abc", &Method::Get),
+            RouteGroup::Data
+        );
+        assert_eq!(
+            RouteGroup::of("/webhooks/commands/test", &Method::Post),
+            RouteGroup::Data
+        );
+        assert_eq!(RouteGroup::of("/history", &Method::Get), RouteGroup::Data);
+    }
+
+    #[test]
+    fn test_most_restrictive_decision_is_reported() {
+        let policy = Policy::per_hour(10);
+        let (_, roomy) = take(None, &policy, 0);
+        let (_, tight) = take(
+            Some(Bucket {
+                tokens: 2.0,
+                updated_ms: 0,
+            }),
+            &policy,
+            0,
+        );
+        let (_, refused) = take(
+            Some(Bucket {
+                tokens: 0.0,
+                updated_ms: 0,
+            }),
+            &policy,
+            0,
+        );
+
+        note(roomy.clone());
+        note(tight.clone());
+        note(roomy);
+        assert_eq!(current(), Some(tight.clone()));
+        note(refused.clone());
+        note(tight);
+        assert_eq!(end_request(), Some(refused));
+        assert_eq!(end_request(), None);
+    }
+}
@@ -0,0 +1,660 @@
+// User settings
+//
+// One typed settings document per user (keyed by username hash) with three
+// sections: vehicle, api and notifications. Sections are replaced as a whole
+// by PUT, guarded by the document version so that two clients editing the
+// same settings cannot silently overwrite each other. Input is validated
+// field by field so the UI can show errors next to the offending inputs.
+//
+// The ABRP token is sealed like notification channel secrets before the
+// document is stored, and unsealed when it is loaded.
+
+use crate::export::DistanceUnit;
+use crate::notifications;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use spin_sdk::key_value::Store;
+use tracing::warn;
+
+const SETTINGS_KEY_PREFIX: &str = "settings_";
+
+/// Longest accepted ABRP user token
+const MAX_ABRP_TOKEN_LENGTH: usize = 128;
+
+/// Accepted range for the usable battery capacity
+const MIN_BATTERY_CAPACITY_KWH: f64 = 1.0;
+const MAX_BATTERY_CAPACITY_KWH: f64 = 300.0;
+
+/// Temperature unit shown in the dashboard
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "celsius" => Some(TemperatureUnit::Celsius),
+            "fahrenheit" => Some(TemperatureUnit::Fahrenheit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VehicleSettings {
+    /// VIN used when a request does not name one
+    pub default_vin: Option<String>,
+    pub battery_capacity_kwh: Option<f64>,
+    pub distance_unit: DistanceUnit,
+    pub temperature_unit: TemperatureUnit,
+}
+
+impl Default for VehicleSettings {
+    fn default() -> Self {
+        VehicleSettings {
+            default_vin: None,
+            battery_capacity_kwh: None,
+            distance_unit: DistanceUnit::Km,
+            temperature_unit: TemperatureUnit::Celsius,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ApiSettings {
+    /// ABRP generic user token (secret, never returned, encrypted at rest)
+    pub abrp_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NotificationSettings {
+    pub push_enabled: bool,
+    pub email_enabled: bool,
+    pub sms_enabled: bool,
+    pub email: Option<String>,
+    /// Phone number in E.164 format, e.g. "+491701234567"
+    pub phone: Option<String>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        NotificationSettings {
+            push_enabled: true,
+            email_enabled: false,
+            sms_enabled: false,
+            email: None,
+            phone: None,
+        }
+    }
+}
+
+/// Complete settings document of a user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct UserSettings {
+    /// Incremented on every update, 0 while nothing has been saved
+    pub version: u64,
+    pub updated_at: i64,
+    pub vehicle: VehicleSettings,
+    pub api: ApiSettings,
+    pub notifications: NotificationSettings,
+}
+
+/// A section of the settings document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    Vehicle,
+    Api,
+    Notifications,
+}
+
+impl Section {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "vehicle" => Some(Section::Vehicle),
+            "api" => Some(Section::Api),
+            "notifications" => Some(Section::Notifications),
+            _ => None,
+        }
+    }
+}
+
+/// Validation error of a single input field
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Why a section update was rejected
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpdateError {
+    Invalid(Vec<FieldError>),
+    /// The document changed since the client read it
+    Conflict {
+        current_version: u64,
+    },
+}
+
+/// Field-by-field reader of a JSON object that collects errors
+struct Fields<'a> {
+    input: &'a Map<String, Value>,
+    errors: Vec<FieldError>,
+}
+
+impl<'a> Fields<'a> {
+    fn new(input: &'a Map<String, Value>, allowed: &[&str]) -> Self {
+        let errors = input
+            .keys()
+            .filter(|key| key.as_str() != "version" && !allowed.contains(&key.as_str()))
+            .map(|key| FieldError::new(key, "Unknown field"))
+            .collect();
+        Fields { input, errors }
+    }
+
+    fn error(&mut self, field: &str, message: &str) {
+        self.errors.push(FieldError::new(field, message));
+    }
+
+    /// Optional trimmed string, empty strings and null count as absent
+    fn string(&mut self, field: &str) -> Option<String> {
+        match self.input.get(field) {
+            None | Some(Value::Null) => None,
+            Some(Value::String(s)) => Some(s.trim().to_string()).filter(|s| !s.is_empty()),
+            Some(_) => {
+                self.error(field, "Must be a string");
+                None
+            }
+        }
+    }
+
+    /// Optional number; numeric strings are accepted as sent by HTML forms
+    fn number(&mut self, field: &str) -> Option<f64> {
+        match self.input.get(field) {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(n)) => n.as_f64(),
+            Some(Value::String(s)) if s.trim().is_empty() => None,
+            Some(Value::String(s)) => match s.trim().parse::<f64>() {
+                Ok(n) if n.is_finite() => Some(n),
+                _ => {
+                    self.error(field, "Must be a number");
+                    None
+                }
+            },
+            Some(_) => {
+                self.error(field, "Must be a number");
+                None
+            }
+        }
+    }
+
+    fn bool(&mut self, field: &str, default: bool) -> bool {
+        match self.input.get(field) {
+            None | Some(Value::Null) => default,
+            Some(Value::Bool(b)) => *b,
+            Some(_) => {
+                self.error(field, "Must be true or false");
+                default
+            }
+        }
+    }
+
+    fn finish<T>(self, value: T) -> Result<T, Vec<FieldError>> {
+        if self.errors.is_empty() {
+            Ok(value)
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+/// ISO 3779 VIN: 17 characters, digits and capitals except I, O and Q
+pub fn is_valid_vin(vin: &str) -> bool {
+    vin.len() == 17
+        && vin
+            .chars()
+            .all(|c| c.is_ascii_digit() || (c.is_ascii_uppercase() && !"IOQ".contains(c)))
+}
+
+pub fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+fn is_valid_phone(phone: &str) -> bool {
+    match phone.strip_prefix('+') {
+        Some(digits) => {
+            (8..=15).contains(&digits.len()) && digits.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+impl VehicleSettings {
+    pub fn parse(input: &Map<String, Value>) -> Result<Self, Vec<FieldError>> {
+        let mut fields = Fields::new(
+            input,
+            &[
+                "default_vin",
+                "battery_capacity_kwh",
+                "distance_unit",
+                "temperature_unit",
+            ],
+        );
+
+        let default_vin = fields.string("default_vin").map(|vin| vin.to_uppercase());
+        if default_vin.as_deref().is_some_and(|vin| !is_valid_vin(vin)) {
+            fields.error("default_vin", "Must be a 17 character VIN");
+        }
+
+        let battery_capacity_kwh = fields.number("battery_capacity_kwh");
+        if battery_capacity_kwh
+            .is_some_and(|c| !(MIN_BATTERY_CAPACITY_KWH..=MAX_BATTERY_CAPACITY_KWH).contains(&c))
+        {
+            fields.error(
+                "battery_capacity_kwh",
+                &format!(
+                    "Must be between {} and {} kWh",
+                    MIN_BATTERY_CAPACITY_KWH, MAX_BATTERY_CAPACITY_KWH
+                ),
+            );
+        }
+
+        let distance_unit = match fields.string("distance_unit") {
+            None => DistanceUnit::Km,
+            Some(unit) => DistanceUnit::parse(&unit).unwrap_or_else(|| {
+                fields.error("distance_unit", "Must be \"km\" or \"mi\"");
+                DistanceUnit::Km
+            }),
+        };
+        let temperature_unit = match fields.string("temperature_unit") {
+            None => TemperatureUnit::Celsius,
+            Some(unit) => TemperatureUnit::parse(&unit).unwrap_or_else(|| {
+                fields.error("temperature_unit", "Must be \"celsius\" or \"fahrenheit\"");
+                TemperatureUnit::Celsius
+            }),
+        };
+
+        fields.finish(VehicleSettings {
+            default_vin,
+            battery_capacity_kwh,
+            distance_unit,
+            temperature_unit,
+        })
+    }
+}
+
+impl ApiSettings {
+    /// Parse the api section; an absent `abrp_token` keeps `current`, an
+    /// empty string or null clears it
+    pub fn parse(
+        input: &Map<String, Value>,
+        current: &ApiSettings,
+    ) -> Result<Self, Vec<FieldError>> {
+        let mut fields = Fields::new(input, &["abrp_token"]);
+
+        let abrp_token = if input.contains_key("abrp_token") {
+            fields.string("abrp_token")
+        } else {
+            current.abrp_token.clone()
+        };
+        if let Some(token) = &abrp_token {
+            if token.len() > MAX_ABRP_TOKEN_LENGTH || token.contains(char::is_whitespace) {
+                fields.error(
+                    "abrp_token",
+                    &format!(
+                        "Must be at most {} characters without spaces",
+                        MAX_ABRP_TOKEN_LENGTH
+                    ),
+                );
+            }
+        }
+
+        fields.finish(ApiSettings { abrp_token })
+    }
+
+    /// Public view, which only reveals whether a token is stored
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "abrp_token_configured": self.abrp_token.is_some(),
+        })
+    }
+
+    /// Encrypt the token for storage
+    pub fn seal(self, key: &[u8; 32]) -> Self {
+        ApiSettings {
+            abrp_token: self
+                .abrp_token
+                .map(|token| notifications::encrypt(key, &token)),
+        }
+    }
+
+    /// Decrypt the token of a stored document
+    pub fn unseal(self, key: &[u8; 32]) -> Result<Self, String> {
+        Ok(ApiSettings {
+            abrp_token: self
+                .abrp_token
+                .map(|token| notifications::decrypt(key, &token))
+                .transpose()?,
+        })
+    }
+}
+
+impl NotificationSettings {
+    pub fn parse(input: &Map<String, Value>) -> Result<Self, Vec<FieldError>> {
+        let mut fields = Fields::new(
+            input,
+            &[
+                "push_enabled",
+                "email_enabled",
+                "sms_enabled",
+                "email",
+                "phone",
+            ],
+        );
+
+        let defaults = NotificationSettings::default();
+        let push_enabled = fields.bool("push_enabled", defaults.push_enabled);
+        let email_enabled = fields.bool("email_enabled", defaults.email_enabled);
+        let sms_enabled = fields.bool("sms_enabled", defaults.sms_enabled);
+
+        let email = fields.string("email");
+        match &email {
+            Some(email) if !is_valid_email(email) => {
+                fields.error("email", "Must be a valid email address")
+            }
+            None if email_enabled => {
+                fields.error("email", "Required when email notifications are enabled")
+            }
+            _ => {}
+        }
+
+        let phone = fields.string("phone");
+        match &phone {
+            Some(phone) if !is_valid_phone(phone) => fields.error(
+                "phone",
+                "Must be in international format, e.g. +491701234567",
+            ),
+            None if sms_enabled => {
+                fields.error("phone", "Required when SMS notifications are enabled")
+            }
+            _ => {}
+        }
+
+        fields.finish(NotificationSettings {
+            push_enabled,
+            email_enabled,
+            sms_enabled,
+            email,
+            phone,
+        })
+    }
+}
+
+impl UserSettings {
+    fn section_value(&self, section: Section) -> Value {
+        match section {
+            Section::Vehicle => serde_json::to_value(&self.vehicle).unwrap_or_default(),
+            Section::Api => self.api.to_json(),
+            Section::Notifications => serde_json::to_value(&self.notifications).unwrap_or_default(),
+        }
+    }
+
+    /// JSON of one section together with the document version
+    pub fn section_json(&self, section: Section) -> Value {
+        let mut value = self.section_value(section);
+        if let Value::Object(map) = &mut value {
+            map.insert("version".to_string(), self.version.into());
+            map.insert("updated_at".to_string(), self.updated_at.into());
+        }
+        value
+    }
+
+    /// JSON of the whole document with secrets redacted
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "version": self.version,
+            "updated_at": self.updated_at,
+            "vehicle": self.section_value(Section::Vehicle),
+            "api": self.section_value(Section::Api),
+            "notifications": self.section_value(Section::Notifications),
+        })
+    }
+
+    /// Replace one section if `input.version` matches the stored version
+    pub fn apply_update(
+        &mut self,
+        section: Section,
+        input: &Value,
+        now: i64,
+    ) -> Result<(), UpdateError> {
+        let input = input.as_object().ok_or_else(|| {
+            UpdateError::Invalid(vec![FieldError::new("body", "Must be a JSON object")])
+        })?;
+
+        let expected_version = match input.get("version") {
+            Some(Value::Number(n)) => n.as_u64(),
+            Some(Value::String(s)) => s.trim().parse::<u64>().ok(),
+            _ => None,
+        };
+        let expected_version = expected_version.ok_or_else(|| {
+            UpdateError::Invalid(vec![FieldError::new(
+                "version",
+                "Required: the version returned by GET",
+            )])
+        })?;
+        if expected_version != self.version {
+            return Err(UpdateError::Conflict {
+                current_version: self.version,
+            });
+        }
+
+        match section {
+            Section::Vehicle => {
+                self.vehicle = VehicleSettings::parse(input).map_err(UpdateError::Invalid)?
+            }
+            Section::Api => {
+                self.api = ApiSettings::parse(input, &self.api).map_err(UpdateError::Invalid)?
+            }
+            Section::Notifications => {
+                self.notifications =
+                    NotificationSettings::parse(input).map_err(UpdateError::Invalid)?
+            }
+        }
+        self.version += 1;
+        self.updated_at = now;
+        Ok(())
+    }
+}
+
+fn settings_key(username_hash: &str) -> String {
+    format!("{}{}", SETTINGS_KEY_PREFIX, username_hash)
+}
+
+/// Load the settings of a user, defaults when nothing is stored
+pub async fn get_settings(store: &Store, username_hash: &str) -> UserSettings {
+    let mut settings: UserSettings = match store.get(&settings_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => return UserSettings::default(),
+    };
+    settings.api = match settings.api.unseal(&notifications::get_encryption_key()) {
+        Ok(api) => api,
+        Err(e) => {
+            // The token has to be entered again
+            warn!("Dropping unreadable ABRP token: {}", e);
+            ApiSettings::default()
+        }
+    };
+    settings
+}
+
+/// Store the settings of a user
+pub async fn save_settings(
+    store: &Store,
+    username_hash: &str,
+    settings: &UserSettings,
+) -> anyhow::Result<()> {
+    let stored = UserSettings {
+        api: settings
+            .api
+            .clone()
+            .seal(&notifications::get_encryption_key()),
+        ..settings.clone()
+    };
+    store.set(&settings_key(username_hash), &serde_json::to_vec(&stored)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_api_settings_sealing() {
+        let key = notifications::derive_key(b"test-hmac-key");
+        let api = ApiSettings {
+            abrp_token: Some("abc-123".to_string()),
+        };
+
+        let sealed = api.clone().seal(&key);
+        assert_ne!(sealed.abrp_token, api.abrp_token);
+        assert_eq!(sealed.clone().unseal(&key), Ok(api));
+        assert!(sealed
+            .unseal(&notifications::derive_key(b"other-key"))
+            .is_err());
+        assert_eq!(
+            ApiSettings::default().seal(&key).unseal(&key),
+            Ok(ApiSettings::default())
+        );
+    }
+
+    #[test]
+    fn test_vehicle_settings_validation() {
+        let input = json!({
+            "version": 0,
+            "default_vin": "jtdkb20u093123456",
+            "battery_capacity_kwh": "71.4",
+            "distance_unit": "mi"
+        });
+        let mut settings = UserSettings::default();
+        settings
+            .apply_update(Section::Vehicle, &input, 100)
+            .unwrap();
+        assert_eq!(settings.version, 1);
+        assert_eq!(settings.updated_at, 100);
+        assert_eq!(
+            settings.vehicle.default_vin.as_deref(),
+            Some("JTDKB20U093123456")
+        );
+        assert_eq!(settings.vehicle.battery_capacity_kwh, Some(71.4));
+        assert_eq!(settings.vehicle.distance_unit, DistanceUnit::Mi);
+        assert_eq!(settings.vehicle.temperature_unit, TemperatureUnit::Celsius);
+
+        let invalid = json!({
+            "version": 1,
+            "default_vin": "SHORT",
+            "battery_capacity_kwh": "lots",
+            "distance_unit": "furlong",
+            "colour": "red"
+        });
+        let errors = match settings.apply_update(Section::Vehicle, &invalid, 200) {
+            Err(UpdateError::Invalid(errors)) => errors,
+            other => panic!("expected validation errors, got {:?}", other),
+        };
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            vec![
+                "colour",
+                "default_vin",
+                "battery_capacity_kwh",
+                "distance_unit"
+            ]
+        );
+        // Rejected updates leave the document untouched
+        assert_eq!(settings.version, 1);
+        assert_eq!(settings.vehicle.distance_unit, DistanceUnit::Mi);
+    }
+
+    #[test]
+    fn test_version_conflict() {
+        let mut settings = UserSettings {
+            version: 3,
+            ..UserSettings::default()
+        };
+        let stale = json!({ "version": 2, "push_enabled": false });
+        assert_eq!(
+            settings.apply_update(Section::Notifications, &stale, 0),
+            Err(UpdateError::Conflict { current_version: 3 })
+        );
+
+        let missing = json!({ "push_enabled": false });
+        assert!(matches!(
+            settings.apply_update(Section::Notifications, &missing, 0),
+            Err(UpdateError::Invalid(_))
+        ));
+        assert!(settings.notifications.push_enabled);
+    }
+
+    #[test]
+    fn test_notification_settings_require_contact() {
+        let input = json!({ "email_enabled": true, "sms_enabled": true, "phone": "0170 123" });
+        let errors = NotificationSettings::parse(input.as_object().unwrap()).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].field, "email");
+        assert_eq!(errors[1].field, "phone");
+
+        let input = json!({ "email_enabled": true, "email": "driver@example.com" });
+        let parsed = NotificationSettings::parse(input.as_object().unwrap()).unwrap();
+        assert!(parsed.email_enabled);
+        assert!(parsed.push_enabled);
+        assert!(!parsed.sms_enabled);
+    }
+
+    #[test]
+    fn test_api_token_is_kept_and_redacted() {
+        let mut settings = UserSettings::default();
+        let input = json!({ "version": 0, "abrp_token": "abc-123" });
+        settings.apply_update(Section::Api, &input, 0).unwrap();
+        assert_eq!(settings.api.abrp_token.as_deref(), Some("abc-123"));
+
+        // Saving without the token keeps it, the JSON view never contains it
+        settings
+            .apply_update(Section::Api, &json!({ "version": 1 }), 0)
+            .unwrap();
+        assert_eq!(settings.api.abrp_token.as_deref(), Some("abc-123"));
+        let view = settings.to_json().to_string();
+        assert!(!view.contains("abc-123"));
+        assert_eq!(
+            settings.section_json(Section::Api)["abrp_token_configured"],
+            true
+        );
+
+        // An empty string clears it
+        settings
+            .apply_update(Section::Api, &json!({ "version": 2, "abrp_token": "" }), 0)
+            .unwrap();
+        assert_eq!(settings.api.abrp_token, None);
+    }
+}
@@ -0,0 +1,179 @@
+// Single-flight request coalescing for upstream Toyota API calls
+//
+// When several clients (phone, watch, Home Assistant) hit the gateway right
+// after a cache entry expired, only the first request should call Toyota.
+// The others wait briefly for the cache to be populated instead.
+//
+// The lock lives in the Spin key-value store so it is shared between
+// instances. Spin KV has no compare-and-swap, so acquisition is best effort:
+// write the lock, read it back and check that we still own it. Locks carry an
+// expiry so a crashed instance cannot block a key forever.
+
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use std::task::Poll;
+use tracing::debug;
+use wasi::clocks::monotonic_clock;
+
+const SINGLE_FLIGHT_KEY_PREFIX: &str = "inflight_";
+
+/// Seconds after which a lock is considered abandoned
+pub const SINGLE_FLIGHT_LOCK_TTL_SECONDS: i64 = 30;
+
+/// Maximum time a follower waits for the leader to populate the cache
+pub const SINGLE_FLIGHT_MAX_WAIT_MS: u64 = 5000;
+
+/// Delay between cache polls while waiting for the leader
+pub const SINGLE_FLIGHT_POLL_INTERVAL_MS: u64 = 250;
+
+/// In-flight marker stored while one request fetches from upstream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FlightLock {
+    pub owner: String,
+    pub acquired_at: i64, // Unix timestamp
+    pub ttl_seconds: i64,
+}
+
+impl FlightLock {
+    pub fn new(owner: String, current_time: i64, ttl_seconds: i64) -> Self {
+        FlightLock {
+            owner,
+            acquired_at: current_time,
+            ttl_seconds,
+        }
+    }
+
+    pub fn is_expired(&self, current_time: i64) -> bool {
+        (current_time - self.acquired_at) > self.ttl_seconds
+    }
+
+    /// Whether another request currently owns the fetch for this key
+    pub fn is_held_by_other(&self, owner: &str, current_time: i64) -> bool {
+        self.owner != owner && !self.is_expired(current_time)
+    }
+}
+
+/// Wait without blocking the executor, so the request's other futures keep running
+pub async fn sleep(ms: u64) {
+    let deadline = monotonic_clock::now() + ms * 1_000_000;
+    let mut registration: Option<spin_executor::CancelToken> = None;
+
+    std::future::poll_fn(|cx| {
+        // Drop the timer of a previous poll before registering a new one
+        if let Some(token) = registration.take() {
+            token.cancel();
+        }
+        if monotonic_clock::now() >= deadline {
+            return Poll::Ready(());
+        }
+        let pollable = monotonic_clock::subscribe_instant(deadline);
+        registration = Some(spin_executor::push_waker_and_get_token(
+            pollable,
+            cx.waker().clone(),
+        ));
+        Poll::Pending
+    })
+    .await
+}
+
+/// Run a future for at most `ms` milliseconds; `None` when time ran out
+pub async fn timeout<F: std::future::Future>(ms: u64, future: F) -> Option<F::Output> {
+    let future = std::pin::pin!(future);
+    let timer = std::pin::pin!(sleep(ms));
+    match futures::future::select(future, timer).await {
+        futures::future::Either::Left((output, _)) => Some(output),
+        futures::future::Either::Right(_) => None,
+    }
+}
+
+fn lock_key(vin: &str, data_type: &str) -> String {
+    format!("{}{}{}", SINGLE_FLIGHT_KEY_PREFIX, vin, data_type)
+}
+
+/// Read the current lock for a key, ignoring unreadable entries
+pub async fn get_lock(store: &Store, vin: &str, data_type: &str) -> Option<FlightLock> {
+    match store.get(&lock_key(vin, data_type)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Try to become the leader for `(vin, data_type)`
+///
+/// Returns `Ok(true)` if this request should call upstream and `Ok(false)` if
+/// another live request already does.
+pub async fn try_acquire(
+    store: &Store,
+    vin: &str,
+    data_type: &str,
+    owner: &str,
+    current_time: i64,
+) -> anyhow::Result<bool> {
+    if let Some(existing) = get_lock(store, vin, data_type).await {
+        if existing.is_held_by_other(owner, current_time) {
+            return Ok(false);
+        }
+        if existing.is_expired(current_time) {
+            debug!(
+                "Taking over expired single-flight lock for VIN {} data_type {}",
+                vin, data_type
+            );
+        }
+    }
+
+    let lock = FlightLock::new(
+        owner.to_string(),
+        current_time,
+        SINGLE_FLIGHT_LOCK_TTL_SECONDS,
+    );
+    let bytes = serde_json::to_vec(&lock)?;
+    store.set(&lock_key(vin, data_type), &bytes)?;
+
+    // Read back: if a concurrent writer won, follow instead of leading
+    match get_lock(store, vin, data_type).await {
+        Some(current) => Ok(current.owner == owner),
+        None => Ok(false),
+    }
+}
+
+/// Release the lock if it is still owned by `owner`
+pub async fn release(store: &Store, vin: &str, data_type: &str, owner: &str) {
+    if let Some(current) = get_lock(store, vin, data_type).await {
+        if current.owner == owner {
+            let _ = store.delete(&lock_key(vin, data_type));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flight_lock_expiry() {
+        let lock = FlightLock::new("owner-a".to_string(), 1000, 30);
+
+        assert!(!lock.is_expired(1000));
+        assert!(!lock.is_expired(1030));
+        assert!(lock.is_expired(1031));
+    }
+
+    #[test]
+    fn test_flight_lock_held_by_other() {
+        let lock = FlightLock::new("owner-a".to_string(), 1000, 30);
+
+        // Own lock never blocks
+        assert!(!lock.is_held_by_other("owner-a", 1010));
+
+        // Live lock of someone else blocks
+        assert!(lock.is_held_by_other("owner-b", 1010));
+
+        // Expired lock of a crashed instance can be taken over
+        assert!(!lock.is_held_by_other("owner-b", 1100));
+    }
+
+    #[test]
+    fn test_lock_key_format() {
+        assert_eq!(lock_key("VIN123", "_status"), "inflight_VIN123_status");
+    }
+}
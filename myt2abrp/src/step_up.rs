@@ -0,0 +1,183 @@
+// Step-up confirmation for sensitive commands
+//
+// Unlocking the car needs more than an access token: either the user's
+// command PIN in the `X-Command-PIN` header, or a step-up token obtained with
+// that PIN from `POST /auth/step-up` and sent as `X-Step-Up-Token`. Step-up
+// tokens are valid for a few minutes and only once. Wrong PINs count towards
+// a lockout like failed logins. PINs are stored as keyed hashes only.
+
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+
+const PIN_KEY_PREFIX: &str = "command_pin_";
+
+const PIN_MIN_LENGTH: usize = 4;
+const PIN_MAX_LENGTH: usize = 8;
+
+/// Wrong PINs in a row before the PIN is locked
+pub const PIN_MAX_ATTEMPTS: u32 = 5;
+pub const PIN_LOCKOUT_SECONDS: i64 = 900;
+
+/// Lifetime of a step-up token
+pub const STEP_UP_TOKEN_EXPIRY: i64 = 300;
+
+/// Token type of step-up JWTs
+pub const STEP_UP_TOKEN_TYPE: &str = "step_up";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PinRecord {
+    hash: String,
+    failed_attempts: u32,
+    locked_until: Option<i64>,
+    updated_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinError {
+    NotSet,
+    Locked { until: i64 },
+    Wrong { remaining_attempts: u32 },
+}
+
+/// Body of `PUT /auth/pin`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SetPinRequest {
+    pub pin: String,
+    /// Required to change an existing PIN
+    pub current_pin: Option<String>,
+}
+
+/// Body of `POST /auth/step-up`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct StepUpRequest {
+    pub pin: String,
+}
+
+pub fn validate_pin(pin: &str) -> Result<(), String> {
+    if !(PIN_MIN_LENGTH..=PIN_MAX_LENGTH).contains(&pin.len())
+        || !pin.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(format!(
+            "PIN must be {} to {} digits",
+            PIN_MIN_LENGTH, PIN_MAX_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// Keyed hash of a user's PIN
+pub fn hash_pin(username_hash: &str, pin: &str) -> String {
+    crate::hash_username(&format!("pin:{}:{}", username_hash, pin))
+}
+
+impl PinRecord {
+    pub fn new(pin_hash: String, now: i64) -> Self {
+        PinRecord {
+            hash: pin_hash,
+            failed_attempts: 0,
+            locked_until: None,
+            updated_at: now,
+        }
+    }
+
+    /// Check a PIN (by its hash), counting failures towards the lockout
+    pub fn verify(&mut self, pin_hash: &str, now: i64) -> Result<(), PinError> {
+        if let Some(until) = self.locked_until {
+            if now < until {
+                return Err(PinError::Locked { until });
+            }
+            self.locked_until = None;
+            self.failed_attempts = 0;
+        }
+        self.updated_at = now;
+
+        if pin_hash == self.hash {
+            self.failed_attempts = 0;
+            return Ok(());
+        }
+
+        self.failed_attempts += 1;
+        if self.failed_attempts >= PIN_MAX_ATTEMPTS {
+            let until = now + PIN_LOCKOUT_SECONDS;
+            self.locked_until = Some(until);
+            return Err(PinError::Locked { until });
+        }
+        Err(PinError::Wrong {
+            remaining_attempts: PIN_MAX_ATTEMPTS - self.failed_attempts,
+        })
+    }
+}
+
+fn pin_key(username_hash: &str) -> String {
+    format!("{}{}", PIN_KEY_PREFIX, username_hash)
+}
+
+pub async fn get_pin(store: &Store, username_hash: &str) -> Option<PinRecord> {
+    match store.get(&pin_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+pub async fn save_pin(
+    store: &Store,
+    username_hash: &str,
+    record: &PinRecord,
+) -> anyhow::Result<()> {
+    store.set(&pin_key(username_hash), &serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+/// Verify the user's PIN and store the updated failure count
+pub async fn check_pin(
+    store: &Store,
+    username_hash: &str,
+    pin: &str,
+    now: i64,
+) -> anyhow::Result<Result<(), PinError>> {
+    let mut record = match get_pin(store, username_hash).await {
+        Some(record) => record,
+        None => return Ok(Err(PinError::NotSet)),
+    };
+    let result = record.verify(&hash_pin(username_hash, pin), now);
+    save_pin(store, username_hash, &record).await?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_pin() {
+        assert!(validate_pin("1234").is_ok());
+        assert!(validate_pin("12345678").is_ok());
+        assert!(validate_pin("123").is_err());
+        assert!(validate_pin("12a4").is_err());
+    }
+
+    #[test]
+    fn test_pin_lockout() {
+        let mut record = PinRecord::new("right".to_string(), 0);
+        assert_eq!(record.verify("right", 1), Ok(()));
+        assert!(record.verify("wrong", 2).is_err());
+        // A correct PIN resets the failure count
+        assert_eq!(record.verify("right", 3), Ok(()));
+
+        for attempt in 1..PIN_MAX_ATTEMPTS {
+            assert_eq!(
+                record.verify("wrong", 10),
+                Err(PinError::Wrong {
+                    remaining_attempts: PIN_MAX_ATTEMPTS - attempt
+                })
+            );
+        }
+        let until = 10 + PIN_LOCKOUT_SECONDS;
+        assert_eq!(record.verify("wrong", 10), Err(PinError::Locked { until }));
+        // Even the right PIN is refused while locked
+        assert_eq!(record.verify("right", 20), Err(PinError::Locked { until }));
+        assert_eq!(record.verify("right", until), Ok(()));
+    }
+}
@@ -0,0 +1,417 @@
+// Electricity tariffs and charging costs
+//
+// Users configure a tariff per place: one for charging at home and an
+// optional one for public charging. A tariff is either a flat price per kWh
+// or a time-of-use schedule with daily price windows. The cost of a charging
+// session spreads its estimated energy evenly over the session duration and
+// prices each slice with the window it falls into.
+
+use crate::charging::ChargingSession;
+use crate::geo::PlaceKind;
+use chrono::{DateTime, Datelike};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use std::collections::BTreeMap;
+
+const TARIFF_KEY_PREFIX: &str = "tariff_";
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Largest accepted UTC offset (UTC+14:00)
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+
+/// A daily price window in local time, `end` may wrap past midnight
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeWindow {
+    /// Local start time, "HH:MM"
+    pub start: String,
+    /// Local end time (exclusive), "HH:MM"
+    pub end: String,
+    pub price_per_kwh: f64,
+}
+
+impl TimeWindow {
+    fn bounds(&self) -> Option<(i64, i64)> {
+        Some((
+            parse_time_of_day(&self.start)?,
+            parse_time_of_day(&self.end)?,
+        ))
+    }
+
+    fn contains(&self, second_of_day: i64) -> bool {
+        match self.bounds() {
+            Some((start, end)) if start < end => second_of_day >= start && second_of_day < end,
+            Some((start, end)) => second_of_day >= start || second_of_day < end,
+            None => false,
+        }
+    }
+}
+
+/// Pricing model of one tariff
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Tariff {
+    Flat {
+        price_per_kwh: f64,
+    },
+    TimeOfUse {
+        /// Price outside of all windows
+        default_price_per_kwh: f64,
+        windows: Vec<TimeWindow>,
+    },
+}
+
+impl Tariff {
+    /// Price per kWh at a local second of the day
+    pub fn price_at(&self, second_of_day: i64) -> f64 {
+        match self {
+            Tariff::Flat { price_per_kwh } => *price_per_kwh,
+            Tariff::TimeOfUse {
+                default_price_per_kwh,
+                windows,
+            } => windows
+                .iter()
+                .find(|w| w.contains(second_of_day))
+                .map(|w| w.price_per_kwh)
+                .unwrap_or(*default_price_per_kwh),
+        }
+    }
+
+    /// Cost of `energy_kwh` drawn evenly between two local timestamps
+    pub fn cost(&self, local_start: i64, local_end: i64, energy_kwh: f64) -> f64 {
+        let windows = match self {
+            Tariff::Flat { price_per_kwh } => return energy_kwh * price_per_kwh,
+            Tariff::TimeOfUse { windows, .. } => windows,
+        };
+        if local_end <= local_start {
+            return energy_kwh * self.price_at(local_start.rem_euclid(SECONDS_PER_DAY));
+        }
+
+        let mut boundaries: Vec<i64> = windows
+            .iter()
+            .filter_map(TimeWindow::bounds)
+            .flat_map(|(start, end)| [start, end])
+            .collect();
+        boundaries.push(SECONDS_PER_DAY);
+
+        let duration = (local_end - local_start) as f64;
+        let mut total = 0.0;
+        let mut t = local_start;
+        while t < local_end {
+            let second_of_day = t.rem_euclid(SECONDS_PER_DAY);
+            let next_boundary = boundaries
+                .iter()
+                .copied()
+                .filter(|b| *b > second_of_day)
+                .min()
+                .unwrap_or(SECONDS_PER_DAY);
+            let segment_end = local_end.min(t + (next_boundary - second_of_day));
+            total +=
+                energy_kwh * (segment_end - t) as f64 / duration * self.price_at(second_of_day);
+            t = segment_end;
+        }
+        total
+    }
+
+    fn validate(&self, name: &str, errors: &mut Vec<String>) {
+        let check_price = |field: String, price: f64, errors: &mut Vec<String>| {
+            if !price.is_finite() || price < 0.0 {
+                errors.push(format!("{} must be a non-negative number", field));
+            }
+        };
+        match self {
+            Tariff::Flat { price_per_kwh } => {
+                check_price(format!("{}.price_per_kwh", name), *price_per_kwh, errors)
+            }
+            Tariff::TimeOfUse {
+                default_price_per_kwh,
+                windows,
+            } => {
+                check_price(
+                    format!("{}.default_price_per_kwh", name),
+                    *default_price_per_kwh,
+                    errors,
+                );
+                for (i, window) in windows.iter().enumerate() {
+                    let field = format!("{}.windows[{}]", name, i);
+                    check_price(
+                        format!("{}.price_per_kwh", field),
+                        window.price_per_kwh,
+                        errors,
+                    );
+                    match window.bounds() {
+                        Some((start, end)) if start == end => {
+                            errors.push(format!("{} start and end must differ", field))
+                        }
+                        Some(_) => {}
+                        None => errors.push(format!("{} times must be HH:MM", field)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse "HH:MM" into seconds since midnight
+pub fn parse_time_of_day(value: &str) -> Option<i64> {
+    let (hours, minutes) = value.split_once(':')?;
+    let hours: i64 = hours.parse().ok()?;
+    let minutes: i64 = minutes.parse().ok()?;
+    if !(0..24).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60)
+}
+
+/// Tariff configuration of one user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TariffConfig {
+    /// ISO 4217 currency code, e.g. "EUR"
+    pub currency: String,
+    /// Offset of local time from UTC, used for windows and monthly totals
+    #[serde(default)]
+    pub utc_offset_minutes: i32,
+    /// Tariff for charging at home, also used when the place is unknown
+    pub home: Tariff,
+    /// Tariff for public charging, falls back to `home`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public: Option<Tariff>,
+}
+
+impl TariffConfig {
+    /// Validate the configuration, returning all field errors
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.currency.len() != 3 || !self.currency.chars().all(|c| c.is_ascii_uppercase()) {
+            errors.push("currency must be a three-letter ISO 4217 code".to_string());
+        }
+        if self.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            errors.push("utc_offset_minutes must be between -840 and 840".to_string());
+        }
+        self.home.validate("home", &mut errors);
+        if let Some(public) = &self.public {
+            public.validate("public", &mut errors);
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn tariff_for(&self, place: PlaceKind) -> &Tariff {
+        match (place, &self.public) {
+            (PlaceKind::Away, Some(public)) => public,
+            _ => &self.home,
+        }
+    }
+
+    fn to_local(&self, timestamp: i64) -> i64 {
+        timestamp + self.utc_offset_minutes as i64 * 60
+    }
+
+    /// Cost of a session, `None` without an energy estimate
+    pub fn session_cost(&self, session: &ChargingSession) -> Option<f64> {
+        let energy = session.energy_added_kwh?;
+        let end = session.ended_at.unwrap_or(session.last_seen_at);
+        Some(self.tariff_for(session.place).cost(
+            self.to_local(session.started_at),
+            self.to_local(end),
+            energy,
+        ))
+    }
+
+    /// Local "YYYY-MM" month a session started in
+    pub fn month_of(&self, timestamp: i64) -> String {
+        DateTime::from_timestamp(self.to_local(timestamp), 0)
+            .map(|dt| format!("{:04}-{:02}", dt.year(), dt.month()))
+            .unwrap_or_default()
+    }
+}
+
+/// Aggregated costs of one month
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct MonthlyCost {
+    pub month: String,
+    pub sessions: usize,
+    pub energy_kwh: f64,
+    pub cost: f64,
+}
+
+/// Sum session costs per local month, newest month first
+pub fn monthly_totals(config: &TariffConfig, sessions: &[ChargingSession]) -> Vec<MonthlyCost> {
+    let mut months: BTreeMap<String, MonthlyCost> = BTreeMap::new();
+    for session in sessions {
+        let month = config.month_of(session.started_at);
+        let entry = months.entry(month.clone()).or_insert_with(|| MonthlyCost {
+            month,
+            ..MonthlyCost::default()
+        });
+        entry.sessions += 1;
+        entry.energy_kwh += session.energy_added_kwh.unwrap_or(0.0);
+        entry.cost += config.session_cost(session).unwrap_or(0.0);
+    }
+    months.into_values().rev().collect()
+}
+
+/// Round a monetary amount to cents for presentation
+pub fn round_money(amount: f64) -> f64 {
+    (amount * 100.0).round() / 100.0
+}
+
+fn tariff_key(username_hash: &str) -> String {
+    format!("{}{}", TARIFF_KEY_PREFIX, username_hash)
+}
+
+/// Load the tariff configuration of a user
+pub async fn get_tariff(store: &Store, username_hash: &str) -> Option<TariffConfig> {
+    match store.get(&tariff_key(username_hash)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Store the tariff configuration of a user
+pub async fn save_tariff(
+    store: &Store,
+    username_hash: &str,
+    config: &TariffConfig,
+) -> anyhow::Result<()> {
+    store.set(&tariff_key(username_hash), &serde_json::to_vec(config)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn night_tariff() -> Tariff {
+        Tariff::TimeOfUse {
+            default_price_per_kwh: 0.40,
+            windows: vec![TimeWindow {
+                start: "22:00".to_string(),
+                end: "06:00".to_string(),
+                price_per_kwh: 0.20,
+            }],
+        }
+    }
+
+    fn session(started_at: i64, ended_at: i64, energy: f64, place: PlaceKind) -> ChargingSession {
+        ChargingSession {
+            id: "s1".to_string(),
+            started_at,
+            ended_at: Some(ended_at),
+            last_seen_at: ended_at,
+            start_soc: 20,
+            end_soc: 80,
+            place,
+            lat: None,
+            lon: None,
+            energy_added_kwh: Some(energy),
+        }
+    }
+
+    #[test]
+    fn test_parse_time_of_day() {
+        assert_eq!(parse_time_of_day("00:00"), Some(0));
+        assert_eq!(parse_time_of_day("22:30"), Some(81_000));
+        assert_eq!(parse_time_of_day("24:00"), None);
+        assert_eq!(parse_time_of_day("7"), None);
+    }
+
+    #[test]
+    fn test_time_of_use_price_wraps_midnight() {
+        let tariff = night_tariff();
+        assert_eq!(tariff.price_at(23 * 3600), 0.20);
+        assert_eq!(tariff.price_at(3 * 3600), 0.20);
+        assert_eq!(tariff.price_at(12 * 3600), 0.40);
+    }
+
+    #[test]
+    fn test_cost_splits_across_windows() {
+        let tariff = night_tariff();
+        // 20:00 to 00:00 local: 2h at 0.40, 2h at 0.20, 40 kWh evenly
+        let cost = tariff.cost(20 * 3600, 24 * 3600, 40.0);
+        assert!((cost - (20.0 * 0.40 + 20.0 * 0.20)).abs() < 1e-9);
+
+        let flat = Tariff::Flat {
+            price_per_kwh: 0.30,
+        };
+        assert!((flat.cost(0, 3600, 10.0) - 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_session_cost_uses_place_and_offset() {
+        let config = TariffConfig {
+            currency: "EUR".to_string(),
+            utc_offset_minutes: 120,
+            home: night_tariff(),
+            public: Some(Tariff::Flat {
+                price_per_kwh: 0.59,
+            }),
+        };
+
+        // 21:00-22:00 UTC is 23:00-00:00 local: night price
+        let home = session(21 * 3600, 22 * 3600, 10.0, PlaceKind::Home);
+        assert!((config.session_cost(&home).unwrap() - 2.0).abs() < 1e-9);
+
+        let public = session(0, 3600, 10.0, PlaceKind::Away);
+        assert!((config.session_cost(&public).unwrap() - 5.9).abs() < 1e-9);
+
+        let mut unknown_energy = home.clone();
+        unknown_energy.energy_added_kwh = None;
+        assert_eq!(config.session_cost(&unknown_energy), None);
+    }
+
+    #[test]
+    fn test_monthly_totals() {
+        let config = TariffConfig {
+            currency: "EUR".to_string(),
+            utc_offset_minutes: 0,
+            home: Tariff::Flat {
+                price_per_kwh: 0.25,
+            },
+            public: None,
+        };
+        // 2024-01-15 and 2024-02-10
+        let sessions = vec![
+            session(1_705_312_800, 1_705_316_400, 20.0, PlaceKind::Home),
+            session(1_705_320_000, 1_705_323_600, 10.0, PlaceKind::Away),
+            session(1_707_566_400, 1_707_570_000, 8.0, PlaceKind::Home),
+        ];
+        let months = monthly_totals(&config, &sessions);
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].month, "2024-02");
+        assert_eq!(months[1].month, "2024-01");
+        assert_eq!(months[1].sessions, 2);
+        assert!((months[1].cost - 7.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_validate_reports_field_errors() {
+        let config = TariffConfig {
+            currency: "euro".to_string(),
+            utc_offset_minutes: 0,
+            home: Tariff::TimeOfUse {
+                default_price_per_kwh: -1.0,
+                windows: vec![TimeWindow {
+                    start: "25:00".to_string(),
+                    end: "06:00".to_string(),
+                    price_per_kwh: 0.2,
+                }],
+            },
+            public: None,
+        };
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("currency"));
+        assert!(errors[1].starts_with("home.default_price_per_kwh"));
+        assert!(errors[2].starts_with("home.windows[0]"));
+
+        let valid: TariffConfig = serde_json::from_str(
+            r#"{"currency":"EUR","home":{"type":"flat","price_per_kwh":0.3}}"#,
+        )
+        .unwrap();
+        assert!(valid.validate().is_ok());
+    }
+}
@@ -0,0 +1,546 @@
+// OpenTelemetry tracing
+//
+// Each request gets a server span named after its route, and every call to
+// Toyota a client span below it. A W3C `traceparent` header sent by the
+// client is continued, otherwise a new trace is started. Toyota calls carry
+// the `traceparent` of their span, and the trace id (as a UUID) is sent as
+// Toyota's `x-correlationid`, so one id ties both sides together.
+//
+// Every request also has a request id: the client's `X-Request-Id` if it
+// sent a usable one, otherwise the trace id. It is returned in the
+// `X-Request-Id` response header and in error bodies, attached to every log
+// event, and takes the trace id's place as Toyota's `x-correlationid`, so a
+// user's report can be matched with the logs on both sides.
+//
+// The OpenTelemetry SDK needs threads or an async runtime to export, which a
+// Spin component has neither of. Spans are therefore collected for the
+// current request only (a component instance serves one request) and sent
+// as OTLP/HTTP JSON to `<otel_exporter_otlp_endpoint>/v1/traces` once the
+// response is written. An empty endpoint disables the export; propagation
+// works either way.
+
+use serde_json::{json, Value};
+use spin_sdk::http::{Request, Response};
+use spin_sdk::variables;
+use std::cell::RefCell;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// `service.name` of exported spans
+const SERVICE_NAME: &str = "myt2abrp";
+
+const TRACEPARENT_VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// Spans kept per request; later ones are dropped
+const MAX_SPANS: usize = 128;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestTrace>> = const { RefCell::new(None) };
+}
+
+/// Trace and parent span of an incoming `traceparent`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+fn decode_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl TraceContext {
+    /// Parse a W3C `traceparent` header
+    ///
+    /// Invalid headers are ignored (a new trace is started), as the
+    /// specification asks.
+    pub fn parse(header: &str) -> Option<Self> {
+        let mut parts = header.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        // Version 00 has exactly four fields; later versions may add more
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        decode_hex::<1>(version)?;
+        // Upper-case hex is not allowed
+        if [trace_id, span_id, flags]
+            .iter()
+            .any(|part| part.bytes().any(|b| b.is_ascii_uppercase()))
+        {
+            return None;
+        }
+        let trace_id = decode_hex::<16>(trace_id)?;
+        let span_id = decode_hex::<8>(span_id)?;
+        let [flags] = decode_hex::<1>(flags)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & FLAG_SAMPLED != 0,
+        })
+    }
+
+    pub fn to_traceparent(self) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            TRACEPARENT_VERSION,
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            if self.sampled { FLAG_SAMPLED } else { 0 }
+        )
+    }
+}
+
+/// The trace id as UUID, the format Toyota expects for `x-correlationid`
+pub fn correlation_id_of(trace_id: [u8; 16]) -> String {
+    Uuid::from_bytes(trace_id).to_string()
+}
+
+fn new_trace_id() -> [u8; 16] {
+    Uuid::new_v4().into_bytes()
+}
+
+fn new_span_id() -> [u8; 8] {
+    Uuid::new_v4().as_u64_pair().1.to_be_bytes()
+}
+
+fn now_unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
+
+/// Route of a path with ids replaced, to keep span names low-cardinality
+///
+/// `/vehicles/JT.../commands/4f1c...` becomes
+/// `/vehicles/{vin}/commands/{id}`.
+pub fn route_template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if crate::settings::is_valid_vin(segment) {
+                "{vin}"
+            } else if Uuid::parse_str(segment).is_ok()
+                || (!segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit()))
+            {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value)
+    }
+}
+
+/// A timed operation within a trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub kind: SpanKind,
+    pub sampled: bool,
+    pub start_unix_nanos: u64,
+    pub end_unix_nanos: u64,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    pub error: Option<String>,
+}
+
+impl Span {
+    fn new(name: String, kind: SpanKind, parent: Option<TraceContext>) -> Self {
+        Span {
+            trace_id: parent.map_or_else(new_trace_id, |p| p.trace_id),
+            span_id: new_span_id(),
+            parent_span_id: parent.map(|p| p.span_id),
+            name,
+            kind,
+            sampled: parent.is_none_or(|p| p.sampled),
+            start_unix_nanos: now_unix_nanos(),
+            end_unix_nanos: 0,
+            attributes: Vec::new(),
+            error: None,
+        }
+    }
+
+    fn context(&self) -> TraceContext {
+        TraceContext {
+            trace_id: self.trace_id,
+            span_id: self.span_id,
+            sampled: self.sampled,
+        }
+    }
+
+    pub fn attribute(&mut self, key: &'static str, value: impl Into<AttributeValue>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    pub fn set_error(&mut self, message: impl ToString) {
+        self.error = Some(message.to_string());
+    }
+
+    /// `traceparent` that makes a downstream service a child of this span
+    pub fn traceparent(&self) -> String {
+        self.context().to_traceparent()
+    }
+
+    /// End the span and add it to the current request's trace
+    pub fn end(mut self) {
+        self.end_unix_nanos = now_unix_nanos();
+        CURRENT.with(|current| {
+            if let Some(trace) = current.borrow_mut().as_mut() {
+                if trace.finished.len() < MAX_SPANS {
+                    trace.finished.push(self);
+                }
+            }
+        });
+    }
+
+    fn to_otlp(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    AttributeValue::String(s) => json!({ "stringValue": s }),
+                    // OTLP/JSON encodes 64-bit integers as strings
+                    AttributeValue::Int(i) => json!({ "intValue": i.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let status = match &self.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 0 }),
+        };
+        let mut span = json!({
+            "traceId": encode_hex(&self.trace_id),
+            "spanId": encode_hex(&self.span_id),
+            "name": self.name,
+            "kind": match self.kind {
+                SpanKind::Server => 2,
+                SpanKind::Client => 3,
+            },
+            "startTimeUnixNano": self.start_unix_nanos.to_string(),
+            "endTimeUnixNano": self.end_unix_nanos.to_string(),
+            "attributes": attributes,
+            "status": status,
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = encode_hex(&parent).into();
+        }
+        span
+    }
+}
+
+/// A client-supplied request id, if it is safe to log and echo
+///
+/// Up to 128 letters, digits and `-_.:`; anything else is replaced by a
+/// generated id.
+pub fn valid_request_id(value: &str) -> Option<&str> {
+    let value = value.trim();
+    let valid = !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LENGTH
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'));
+    valid.then_some(value)
+}
+
+/// Spans of the request being served
+struct RequestTrace {
+    request_id: String,
+    root: Span,
+    finished: Vec<Span>,
+}
+
+/// Start the server span of a request, continuing the client's trace
+///
+/// Returns the request's id.
+pub fn begin_request(
+    method: &str,
+    path: &str,
+    traceparent: Option<&str>,
+    request_id: Option<&str>,
+) -> String {
+    let parent = traceparent.and_then(TraceContext::parse);
+    let route = route_template(path);
+    let mut root = Span::new(format!("{} {}", method, route), SpanKind::Server, parent);
+    let request_id = request_id
+        .and_then(valid_request_id)
+        .map(str::to_string)
+        .unwrap_or_else(|| correlation_id_of(root.trace_id));
+    root.attribute("http.request.method", method);
+    root.attribute("http.route", route);
+    root.attribute("request.id", request_id.clone());
+    CURRENT.with(|current| {
+        *current.borrow_mut() = Some(RequestTrace {
+            request_id: request_id.clone(),
+            root,
+            finished: Vec::new(),
+        })
+    });
+    request_id
+}
+
+/// Id of the request being served
+pub fn request_id() -> Option<String> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|t| t.request_id.clone()))
+}
+
+/// Start a span below the current request's server span
+pub fn start_span(name: impl Into<String>, kind: SpanKind) -> Span {
+    let parent = CURRENT.with(|current| current.borrow().as_ref().map(|t| t.root.context()));
+    Span::new(name.into(), kind, parent)
+}
+
+/// Correlation id for upstream calls: the current request id, or a fresh
+/// one outside of a request
+pub fn correlation_id() -> String {
+    request_id().unwrap_or_else(|| correlation_id_of(new_trace_id()))
+}
+
+/// End the request's server span; returns its spans if they are sampled
+pub fn end_request(status: Option<u16>) -> Option<Vec<Span>> {
+    let trace = CURRENT.with(|current| current.borrow_mut().take())?;
+    let mut root = trace.root;
+    if let Some(status) = status {
+        root.attribute("http.response.status_code", status as i64);
+        if status >= 500 {
+            root.set_error(format!("HTTP {}", status));
+        }
+    }
+    root.end_unix_nanos = now_unix_nanos();
+    if !root.sampled {
+        return None;
+    }
+    let mut spans = trace.finished;
+    spans.push(root);
+    Some(spans)
+}
+
+/// OTLP/JSON `ExportTraceServiceRequest` for a set of spans
+pub fn to_otlp_json(spans: &[Span]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": SERVICE_NAME } },
+                    { "key": "service.version", "value": { "stringValue": crate::VERSION } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME, "version": crate::VERSION },
+                "spans": spans.iter().map(Span::to_otlp).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+/// OTLP/HTTP collector base URL from `otel_exporter_otlp_endpoint`
+fn get_endpoint() -> Option<String> {
+    let endpoint = variables::get("otel_exporter_otlp_endpoint").unwrap_or_default();
+    let endpoint = endpoint.trim().trim_end_matches('/');
+    (!endpoint.is_empty()).then(|| endpoint.to_string())
+}
+
+/// Send spans to the configured collector, if any
+pub async fn export(spans: Vec<Span>) {
+    let Some(endpoint) = get_endpoint() else {
+        return;
+    };
+    if spans.is_empty() {
+        return;
+    }
+    let request = Request::post(
+        format!("{}/v1/traces", endpoint),
+        to_otlp_json(&spans).to_string(),
+    )
+    .header("content-type", "application/json")
+    .build();
+    match spin_sdk::http::send::<Request, Response>(request).await {
+        Ok(response) if (200..300).contains(response.status()) => {
+            debug!("Exported {} spans", spans.len())
+        }
+        Ok(response) => warn!(status = *response.status(), "Span export rejected"),
+        Err(e) => warn!(error = %e, "Span export failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent() {
+        let context = TraceContext::parse(TRACEPARENT).unwrap();
+        assert!(context.sampled);
+        assert_eq!(context.to_traceparent(), TRACEPARENT);
+        assert_eq!(
+            correlation_id_of(context.trace_id),
+            "4bf92f35-77b3-4da6-a3ce-929d0e0e4736"
+        );
+
+        let unsampled = TraceContext::parse(&TRACEPARENT.replace("-01", "-00")).unwrap();
+        assert!(!unsampled.sampled);
+        // Future versions may append fields
+        assert!(TraceContext::parse(&format!("01{}-extra", &TRACEPARENT[2..])).is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_route_template() {
+        assert_eq!(
+            route_template(
+                "/vehicles/JTDKB20U093123456/commands/4f1c2a34-7a3b-4c5d-9e8f-0a1b2c3d4e5f"
+            ),
+            "/vehicles/{vin}/commands/{id}"
+        );
+        assert_eq!(
+            route_template("/vehicles/JTDKB20U093123456/charge-schedule/2"),
+            "/vehicles/{vin}/charge-schedule/{id}"
+        );
+        assert_eq!(route_template("/health"), "/health");
+    }
+
+    #[test]
+    fn test_request_spans() {
+        let request_id = begin_request(
+            "GET",
+            "/vehicles/JTDKB20U093123456/status",
+            Some(TRACEPARENT),
+            None,
+        );
+        // Without a client id the trace id is the request id
+        assert_eq!(request_id, "4bf92f35-77b3-4da6-a3ce-929d0e0e4736");
+        let mut upstream = start_span("GET ctpa-oneapi", SpanKind::Client);
+        let traceparent = upstream.traceparent();
+        upstream.attribute("http.response.status_code", 200);
+        upstream.end();
+        assert_eq!(correlation_id(), "4bf92f35-77b3-4da6-a3ce-929d0e0e4736");
+
+        let spans = end_request(Some(502)).unwrap();
+        assert_eq!(spans.len(), 2);
+        let (client, server) = (&spans[0], &spans[1]);
+        assert_eq!(server.name, "GET /vehicles/{vin}/status");
+        assert_eq!(
+            server.parent_span_id,
+            Some([0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7])
+        );
+        assert_eq!(client.parent_span_id, Some(server.span_id));
+        assert_eq!(client.trace_id, server.trace_id);
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert_eq!(server.error.as_deref(), Some("HTTP 502"));
+
+        let otlp = to_otlp_json(&spans);
+        let exported = &otlp["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(exported[1]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(exported[1]["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(exported[1]["kind"], 2);
+        assert_eq!(exported[1]["status"]["code"], 2);
+        assert_eq!(exported[0]["attributes"][0]["value"]["intValue"], "200");
+
+        // Nothing is kept once the request ended
+        assert_eq!(end_request(None), None);
+        begin_request(
+            "GET",
+            "/health",
+            Some(&TRACEPARENT.replace("-01", "-00")),
+            None,
+        );
+        assert_eq!(end_request(Some(200)), None);
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(
+            valid_request_id(" app-1234:retry.2 "),
+            Some("app-1234:retry.2")
+        );
+        assert_eq!(valid_request_id(""), None);
+        assert_eq!(valid_request_id("a b"), None);
+        assert_eq!(valid_request_id("id\r\nx-injected: 1"), None);
+        assert_eq!(valid_request_id(&"a".repeat(129)), None);
+
+        assert_eq!(
+            begin_request("GET", "/health", None, Some("app-1234")),
+            "app-1234"
+        );
+        assert_eq!(request_id().as_deref(), Some("app-1234"));
+        // The client's id is sent to Toyota instead of the trace id
+        assert_eq!(correlation_id(), "app-1234");
+        end_request(Some(200));
+        assert_eq!(request_id(), None);
+
+        let generated = begin_request("GET", "/health", None, Some("not valid"));
+        assert_eq!(generated.len(), 36);
+        end_request(Some(200));
+    }
+}
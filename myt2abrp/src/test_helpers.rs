@@ -5,9 +5,19 @@
 
 #![cfg(test)]
 
+use crate::history::{HistoryRecord, SnapshotKind};
 use base64::{engine::general_purpose, Engine as _};
 use serde_json::json;
 
+/// Status snapshot with SOC and charging status, all other fields empty
+pub fn status_record(timestamp: i64, soc: i32, charging_status: &str) -> HistoryRecord {
+    HistoryRecord {
+        soc: Some(soc),
+        charging_status: Some(charging_status.to_string()),
+        ..HistoryRecord::empty(timestamp, SnapshotKind::Status)
+    }
+}
+
 /// Generate a valid test JWT token with uuid claim
 pub fn generate_test_id_token(uuid: &str) -> String {
     // Header: {"alg":"HS256","typ":"JWT"}
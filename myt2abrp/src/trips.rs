@@ -0,0 +1,476 @@
+// Trip detection from odometer and location changes
+//
+// Reconstructs trips by comparing consecutive stored snapshots. Every new
+// status, location or odometer record updates the latest known vehicle
+// position; an odometer increase or a position change opens a trip from the
+// last parked position, and the trip closes once the vehicle has been idle
+// for a while or starts charging.
+
+use crate::charging::is_actively_charging;
+use crate::geo::{distance_meters, HomeLocation, PlaceKind};
+use crate::history::{HistoryRecord, SnapshotKind};
+use serde::{Deserialize, Serialize};
+use spin_sdk::key_value::Store;
+use tracing::info;
+use uuid::Uuid;
+
+const TRIP_STATE_KEY_PREFIX: &str = "trip_state_";
+const TRIPS_KEY_PREFIX: &str = "trips_";
+
+/// Finished trips kept per VIN (oldest are dropped first)
+const MAX_STORED_TRIPS: usize = 2000;
+
+/// Odometer increase (km) that counts as movement
+const MIN_ODOMETER_DELTA_KM: f64 = 0.1;
+
+/// Position change (m) that counts as movement, above GPS jitter
+const MIN_POSITION_DELTA_METERS: f64 = 200.0;
+
+/// Idle time after the last movement that ends a trip
+pub const TRIP_END_IDLE_SECONDS: i64 = 600;
+
+/// Distance below which no efficiency figure is reported
+const MIN_DISTANCE_FOR_EFFICIENCY_KM: f64 = 1.0;
+
+const KM_PER_MILE: f64 = 1.609344;
+
+/// Default and maximum page size for trip listings
+pub const TRIPS_PAGE_SIZE_DEFAULT: usize = 20;
+pub const TRIPS_PAGE_SIZE_MAX: usize = 200;
+
+/// Latest known vehicle state assembled from snapshots
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VehiclePoint {
+    pub timestamp: i64,
+    pub odometer_km: Option<f64>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub soc: Option<i32>,
+}
+
+impl VehiclePoint {
+    fn position(&self) -> Option<(f64, f64)> {
+        Some((self.lat?, self.lon?))
+    }
+
+    /// Merge a snapshot into this point, returning the updated point
+    fn merged_with(&self, record: &HistoryRecord) -> VehiclePoint {
+        let mut point = self.clone();
+        point.timestamp = point.timestamp.max(record.timestamp);
+        if let Some(odometer) = record.odometer {
+            point.odometer_km = Some(odometer_to_km(odometer, record.odometer_unit.as_deref()));
+        }
+        if let (Some(lat), Some(lon)) = (record.lat, record.lon) {
+            point.lat = Some(lat);
+            point.lon = Some(lon);
+        }
+        if record.soc.is_some() {
+            point.soc = record.soc;
+        }
+        point
+    }
+}
+
+/// Convert an odometer reading to kilometers
+pub fn odometer_to_km(value: f64, unit: Option<&str>) -> f64 {
+    match unit.map(|u| u.to_lowercase()) {
+        Some(u) if u == "mi" || u == "miles" => value * KM_PER_MILE,
+        _ => value,
+    }
+}
+
+/// One detected trip
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Trip {
+    pub id: String,
+    pub start: VehiclePoint,
+    pub end: VehiclePoint,
+    pub start_place: PlaceKind,
+    pub end_place: PlaceKind,
+    /// Distance along GPS fixes, used when no odometer is available
+    pub gps_distance_km: f64,
+    /// Unix timestamp of the trip end, `None` while driving
+    pub ended_at: Option<i64>,
+}
+
+impl Trip {
+    pub fn distance_km(&self) -> f64 {
+        match (self.start.odometer_km, self.end.odometer_km) {
+            (Some(start), Some(end)) if end >= start => end - start,
+            _ => self.gps_distance_km,
+        }
+    }
+
+    pub fn duration_minutes(&self) -> i64 {
+        (self.end.timestamp - self.start.timestamp).max(0) / 60
+    }
+
+    pub fn soc_consumed(&self) -> Option<i32> {
+        Some((self.start.soc? - self.end.soc?).max(0))
+    }
+
+    pub fn energy_kwh(&self, capacity_kwh: Option<f64>) -> Option<f64> {
+        Some(capacity_kwh? * self.soc_consumed()? as f64 / 100.0)
+    }
+
+    /// Consumption in kWh/100km, if the trip is long enough to be meaningful
+    pub fn efficiency_kwh_per_100km(&self, capacity_kwh: Option<f64>) -> Option<f64> {
+        let distance = self.distance_km();
+        if distance < MIN_DISTANCE_FOR_EFFICIENCY_KM {
+            return None;
+        }
+        Some(self.energy_kwh(capacity_kwh)? / distance * 100.0)
+    }
+
+    pub fn to_json(&self, capacity_kwh: Option<f64>) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "started_at": self.start.timestamp,
+            "ended_at": self.ended_at,
+            "in_progress": self.ended_at.is_none(),
+            "duration_minutes": self.duration_minutes(),
+            "start": self.start,
+            "end": self.end,
+            "start_place": self.start_place,
+            "end_place": self.end_place,
+            "distance_km": self.distance_km(),
+            "soc_consumed": self.soc_consumed(),
+            "energy_kwh": self.energy_kwh(capacity_kwh),
+            "efficiency_kwh_per_100km": self.efficiency_kwh_per_100km(capacity_kwh),
+        })
+    }
+}
+
+/// Per-VIN detector state persisted between snapshots
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct TripTrackerState {
+    pub last: Option<VehiclePoint>,
+    pub active: Option<Trip>,
+    pub last_movement_at: i64,
+}
+
+fn has_moved(previous: &VehiclePoint, current: &VehiclePoint) -> bool {
+    let odometer_moved = match (previous.odometer_km, current.odometer_km) {
+        (Some(before), Some(after)) => after - before >= MIN_ODOMETER_DELTA_KM,
+        _ => false,
+    };
+    let position_moved = match (previous.position(), current.position()) {
+        (Some((lat1, lon1)), Some((lat2, lon2))) => {
+            distance_meters(lat1, lon1, lat2, lon2) >= MIN_POSITION_DELTA_METERS
+        }
+        _ => false,
+    };
+    odometer_moved || position_moved
+}
+
+/// Feed one snapshot into the detector, returning a trip when one finishes
+pub fn apply_snapshot(
+    state: &mut TripTrackerState,
+    record: &HistoryRecord,
+    home: Option<&HomeLocation>,
+) -> Option<Trip> {
+    let previous = match state.last.clone() {
+        Some(previous) => previous,
+        None => {
+            state.last = Some(VehiclePoint::default().merged_with(record));
+            return None;
+        }
+    };
+    let current = previous.merged_with(record);
+    state.last = Some(current.clone());
+
+    if has_moved(&previous, &current) {
+        let trip = state.active.get_or_insert_with(|| Trip {
+            id: Uuid::new_v4().to_string(),
+            start: previous.clone(),
+            end: previous.clone(),
+            start_place: HomeLocation::classify(home, previous.position()),
+            end_place: PlaceKind::Unknown,
+            gps_distance_km: 0.0,
+            ended_at: None,
+        });
+        if let (Some((lat1, lon1)), Some((lat2, lon2))) = (previous.position(), current.position())
+        {
+            trip.gps_distance_km += distance_meters(lat1, lon1, lat2, lon2) / 1000.0;
+        }
+        trip.end = current.clone();
+        trip.end_place = HomeLocation::classify(home, current.position());
+        state.last_movement_at = current.timestamp;
+        return None;
+    }
+
+    // Keep the latest SOC of a trip that is still open
+    if let Some(trip) = state.active.as_mut() {
+        if record.kind == SnapshotKind::Status && record.soc.is_some() {
+            trip.end.soc = record.soc;
+        }
+    }
+
+    let started_charging = record
+        .charging_status
+        .as_deref()
+        .is_some_and(is_actively_charging);
+    let idle_long_enough = current.timestamp - state.last_movement_at >= TRIP_END_IDLE_SECONDS;
+
+    if state.active.is_some() && (started_charging || idle_long_enough) {
+        let mut trip = state.active.take()?;
+        trip.ended_at = Some(trip.end.timestamp);
+        return Some(trip);
+    }
+
+    None
+}
+
+fn state_key(vin: &str) -> String {
+    format!("{}{}", TRIP_STATE_KEY_PREFIX, vin)
+}
+
+fn trips_key(vin: &str) -> String {
+    format!("{}{}", TRIPS_KEY_PREFIX, vin)
+}
+
+async fn load_state(store: &Store, vin: &str) -> TripTrackerState {
+    match store.get(&state_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => TripTrackerState::default(),
+    }
+}
+
+/// Finished trips of a VIN, oldest first
+pub async fn load_trips(store: &Store, vin: &str) -> Vec<Trip> {
+    match store.get(&trips_key(vin)) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+/// Run a new snapshot through the detector and persist the result
+pub async fn process_snapshot(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+) -> anyhow::Result<Option<Trip>> {
+    let mut state = load_state(store, vin).await;
+    let home = HomeLocation::from_variables();
+    let finished = apply_snapshot(&mut state, record, home.as_ref());
+
+    if let Some(trip) = &finished {
+        info!(
+            vin = vin,
+            distance_km = trip.distance_km(),
+            duration_minutes = trip.duration_minutes(),
+            "Trip finished"
+        );
+        let mut trips = load_trips(store, vin).await;
+        trips.push(trip.clone());
+        if trips.len() > MAX_STORED_TRIPS {
+            let excess = trips.len() - MAX_STORED_TRIPS;
+            trips.drain(..excess);
+        }
+        store.set(&trips_key(vin), &serde_json::to_vec(&trips)?)?;
+    }
+
+    store.set(&state_key(vin), &serde_json::to_vec(&state)?)?;
+    Ok(finished)
+}
+
+/// Whether the vehicle is on a trip that has not ended yet
+pub async fn is_moving(store: &Store, vin: &str) -> bool {
+    load_state(store, vin).await.active.is_some()
+}
+
+/// All trips of a VIN, newest first, including one in progress
+pub async fn all_trips_newest_first(store: &Store, vin: &str) -> Vec<Trip> {
+    let mut trips = load_trips(store, vin).await;
+    if let Some(active) = load_state(store, vin).await.active {
+        trips.push(active);
+    }
+    trips.reverse();
+    trips
+}
+
+/// Trips that started within `[from, to]`
+pub fn filter_trips(trips: Vec<Trip>, from: Option<i64>, to: Option<i64>) -> Vec<Trip> {
+    trips
+        .into_iter()
+        .filter(|t| from.is_none_or(|from| t.start.timestamp >= from))
+        .filter(|t| to.is_none_or(|to| t.start.timestamp <= to))
+        .collect()
+}
+
+/// Aggregate consumption metrics over a set of trips
+pub fn summarize(trips: &[Trip], capacity_kwh: Option<f64>) -> serde_json::Value {
+    let total_distance_km: f64 = trips.iter().map(Trip::distance_km).sum();
+    // Energy and distance of the trips with SOC data
+    let energies: Vec<(f64, f64)> = trips
+        .iter()
+        .filter_map(|t| Some((t.energy_kwh(capacity_kwh)?, t.distance_km())))
+        .collect();
+    let total_energy_kwh: Option<f64> = if energies.is_empty() {
+        None
+    } else {
+        Some(energies.iter().map(|(energy, _)| energy).sum())
+    };
+    let measured_distance_km: f64 = energies.iter().map(|(_, distance)| distance).sum();
+    let average_efficiency = match total_energy_kwh {
+        Some(energy) if measured_distance_km >= MIN_DISTANCE_FOR_EFFICIENCY_KM => {
+            Some(energy / measured_distance_km * 100.0)
+        }
+        _ => None,
+    };
+
+    serde_json::json!({
+        "trip_count": trips.len(),
+        "total_distance_km": total_distance_km,
+        "total_energy_kwh": total_energy_kwh,
+        "average_efficiency_kwh_per_100km": average_efficiency,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::status_record;
+
+    fn odometer(timestamp: i64, km: f64) -> HistoryRecord {
+        HistoryRecord {
+            odometer: Some(km),
+            odometer_unit: Some("km".to_string()),
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Odometer)
+        }
+    }
+
+    fn status(timestamp: i64, soc: i32) -> HistoryRecord {
+        status_record(timestamp, soc, "NOT_CHARGING")
+    }
+
+    fn location(timestamp: i64, lat: f64, lon: f64) -> HistoryRecord {
+        HistoryRecord {
+            lat: Some(lat),
+            lon: Some(lon),
+            ..HistoryRecord::empty(timestamp, SnapshotKind::Location)
+        }
+    }
+
+    #[test]
+    fn test_odometer_to_km() {
+        assert_eq!(odometer_to_km(100.0, Some("km")), 100.0);
+        assert_eq!(odometer_to_km(100.0, None), 100.0);
+        assert!((odometer_to_km(100.0, Some("mi")) - 160.9344).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_trip_from_odometer_changes() {
+        let mut state = TripTrackerState::default();
+
+        assert_eq!(apply_snapshot(&mut state, &status(1000, 80), None), None);
+        assert_eq!(
+            apply_snapshot(&mut state, &odometer(1000, 15000.0), None),
+            None
+        );
+
+        // Driving: odometer increases, trip opens
+        assert_eq!(
+            apply_snapshot(&mut state, &odometer(2000, 15020.0), None),
+            None
+        );
+        assert!(state.active.is_some());
+        assert_eq!(apply_snapshot(&mut state, &status(2100, 75), None), None);
+        assert_eq!(
+            apply_snapshot(&mut state, &odometer(2800, 15040.0), None),
+            None
+        );
+
+        // Parked long enough: trip closes
+        assert_eq!(apply_snapshot(&mut state, &status(3000, 72), None), None);
+        let trip = apply_snapshot(&mut state, &odometer(3500, 15040.0), None).unwrap();
+
+        assert_eq!(trip.start.timestamp, 1000);
+        assert_eq!(trip.end.timestamp, 2800);
+        assert_eq!(trip.distance_km(), 40.0);
+        assert_eq!(trip.soc_consumed(), Some(8));
+        assert_eq!(trip.ended_at, Some(2800));
+
+        let energy = trip.energy_kwh(Some(60.0)).unwrap();
+        assert!((energy - 4.8).abs() < 1e-9);
+        let efficiency = trip.efficiency_kwh_per_100km(Some(60.0)).unwrap();
+        assert!((efficiency - 12.0).abs() < 1e-9);
+        assert!(state.active.is_none());
+    }
+
+    #[test]
+    fn test_trip_from_location_changes_and_charging_end() {
+        let home = HomeLocation {
+            lat: 52.52,
+            lon: 13.405,
+            radius_meters: 150.0,
+        };
+        let mut state = TripTrackerState::default();
+
+        apply_snapshot(&mut state, &location(1000, 52.52, 13.405), Some(&home));
+        apply_snapshot(&mut state, &location(1600, 52.53, 13.405), Some(&home));
+        let trip_state = state.active.clone().unwrap();
+        assert_eq!(trip_state.start_place, PlaceKind::Home);
+        assert_eq!(trip_state.end_place, PlaceKind::Away);
+        assert!(trip_state.gps_distance_km > 1.0);
+
+        // Plugging in ends the trip immediately
+        let charging = HistoryRecord {
+            charging_status: Some("CHARGING".to_string()),
+            ..status(1700, 60)
+        };
+        let trip = apply_snapshot(&mut state, &charging, Some(&home)).unwrap();
+        assert_eq!(trip.end.soc, Some(60));
+        assert!((trip.distance_km() - 1.11).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_gps_jitter_is_not_a_trip() {
+        let mut state = TripTrackerState::default();
+        apply_snapshot(&mut state, &location(1000, 52.52, 13.405), None);
+        apply_snapshot(&mut state, &location(1300, 52.5201, 13.4051), None);
+        assert!(state.active.is_none());
+    }
+
+    #[test]
+    fn test_summarize_and_filter() {
+        let trip = Trip {
+            id: "t1".to_string(),
+            start: VehiclePoint {
+                timestamp: 1000,
+                odometer_km: Some(100.0),
+                soc: Some(80),
+                ..VehiclePoint::default()
+            },
+            end: VehiclePoint {
+                timestamp: 2000,
+                odometer_km: Some(150.0),
+                soc: Some(70),
+                ..VehiclePoint::default()
+            },
+            start_place: PlaceKind::Unknown,
+            end_place: PlaceKind::Unknown,
+            gps_distance_km: 0.0,
+            ended_at: Some(2000),
+        };
+        let mut later = trip.clone();
+        later.start.timestamp = 5000;
+        let mut without_soc = trip.clone();
+        without_soc.end.soc = None;
+
+        let summary = summarize(&[trip.clone(), later.clone(), without_soc], Some(50.0));
+        assert_eq!(summary["trip_count"], 3);
+        assert_eq!(summary["total_distance_km"], 150.0);
+        assert_eq!(summary["total_energy_kwh"], 10.0);
+        // The trip without SOC data does not dilute the average
+        assert_eq!(summary["average_efficiency_kwh_per_100km"], 10.0);
+
+        let no_capacity = summarize(std::slice::from_ref(&trip), None);
+        assert!(no_capacity["total_energy_kwh"].is_null());
+
+        assert_eq!(
+            filter_trips(vec![trip, later.clone()], Some(3000), None),
+            vec![later]
+        );
+    }
+}
@@ -0,0 +1,763 @@
+// Outbound webhooks
+//
+// Users subscribe a URL to events of one vehicle. Every new snapshot is
+// checked for events (charging finished, SOC dropped below the webhook's
+// threshold, plugged in, car started moving) and each matching webhook gets
+// a JSON POST signed the way Stripe signs its webhooks:
+//
+//     X-Webhook-Signature: t=<unix seconds>,v1=<hex HMAC-SHA256>
+//
+// where the HMAC is keyed with the webhook secret and covers "<t>.<body>".
+// Receivers should recompute it and reject old timestamps.
+//
+// Deliveries are queued and sent by the scheduler tick, so a slow receiver
+// never holds up the status request that produced the event. Failed
+// deliveries are retried with growing delays; after the last attempt they
+// move to the user's dead-letter list. Webhook URLs must point to an
+// origin listed in the `webhook_allowed_hosts` variable, which also has to
+// be in the component's `allowed_outbound_hosts`.
+
+use crate::charging::{self, ChargingSession, ChargingTransition};
+use crate::history::{HistoryRecord, SnapshotKind};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use spin_sdk::http::{Request, Response};
+use spin_sdk::key_value::Store;
+use spin_sdk::variables;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+const WEBHOOKS_KEY_PREFIX: &str = "webhooks_";
+const WEBHOOK_SUBSCRIBERS_KEY_PREFIX: &str = "webhook_subscribers_";
+const EVENT_STATE_KEY_PREFIX: &str = "webhook_events_";
+const DEAD_LETTERS_KEY_PREFIX: &str = "webhook_dead_letters_";
+const QUEUE_KEY: &str = "webhook_queue";
+
+/// Webhooks per user
+pub const MAX_WEBHOOKS: usize = 10;
+
+const MAX_URL_LENGTH: usize = 2048;
+
+/// Hard limit for one delivery attempt
+const SEND_TIMEOUT_MS: u64 = 10_000;
+
+/// Dead letters kept per user (oldest are dropped first)
+const MAX_DEAD_LETTERS: usize = 100;
+
+/// Delay before each retry; a delivery is dead after the last one fails
+const RETRY_DELAYS_SECONDS: [i64; 5] = [60, 300, 1800, 7200, 21600];
+
+/// SOC threshold of `soc_below` when none is given
+pub const SOC_THRESHOLD_DEFAULT: i32 = 20;
+const SOC_THRESHOLD_MIN: i32 = 5;
+const SOC_THRESHOLD_MAX: i32 = 95;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    ChargingFinished,
+    SocBelow,
+    PluggedIn,
+    CarMoved,
+    /// Sent by `POST /webhooks/{id}/test` only
+    Test,
+}
+
+impl EventType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventType::ChargingFinished => "charging_finished",
+            EventType::SocBelow => "soc_below",
+            EventType::PluggedIn => "plugged_in",
+            EventType::CarMoved => "car_moved",
+            EventType::Test => "test",
+        }
+    }
+}
+
+/// Something that happened to a vehicle
+#[derive(Debug, Clone, PartialEq)]
+pub enum VehicleEvent {
+    ChargingFinished(ChargingSession),
+    SocDropped { from: i32, to: i32 },
+    PluggedIn { soc: Option<i32> },
+    CarMoved { lat: Option<f64>, lon: Option<f64> },
+    Test,
+}
+
+impl VehicleEvent {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            VehicleEvent::ChargingFinished(_) => EventType::ChargingFinished,
+            VehicleEvent::SocDropped { .. } => EventType::SocBelow,
+            VehicleEvent::PluggedIn { .. } => EventType::PluggedIn,
+            VehicleEvent::CarMoved { .. } => EventType::CarMoved,
+            VehicleEvent::Test => EventType::Test,
+        }
+    }
+
+    fn data(&self, webhook: &Webhook) -> serde_json::Value {
+        match self {
+            VehicleEvent::ChargingFinished(session) => session.to_json(),
+            VehicleEvent::SocDropped { from, to } => serde_json::json!({
+                "previous_soc": from,
+                "soc": to,
+                "threshold": webhook.soc_threshold,
+            }),
+            VehicleEvent::PluggedIn { soc } => serde_json::json!({ "soc": soc }),
+            VehicleEvent::CarMoved { lat, lon } => serde_json::json!({ "lat": lat, "lon": lon }),
+            VehicleEvent::Test => serde_json::json!({
+                "message": "Test event from the myt2abrp gateway",
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Webhook {
+    pub id: String,
+    pub username_hash: String,
+    pub vin: String,
+    pub url: String,
+    pub events: Vec<EventType>,
+    pub soc_threshold: i32,
+    /// Signing key, only shown when the webhook is created
+    pub secret: String,
+    pub created_at: i64,
+}
+
+impl Webhook {
+    /// Whether the event should be sent to this webhook
+    pub fn wants(&self, event: &VehicleEvent) -> bool {
+        if !self.events.contains(&event.event_type()) {
+            return false;
+        }
+        match event {
+            VehicleEvent::SocDropped { from, to } => {
+                *from >= self.soc_threshold && *to < self.soc_threshold
+            }
+            _ => true,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "vin": self.vin,
+            "url": self.url,
+            "events": self.events,
+            "soc_threshold": self.soc_threshold,
+            "created_at": self.created_at,
+        })
+    }
+}
+
+/// Body of `POST /webhooks`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookRequest {
+    pub url: String,
+    pub events: Vec<EventType>,
+    pub soc_threshold: Option<i32>,
+}
+
+impl WebhookRequest {
+    /// Validate the request and create the webhook with a fresh secret
+    pub fn into_webhook(
+        self,
+        username_hash: &str,
+        vin: &str,
+        allowed_hosts: &[String],
+        now: i64,
+    ) -> Result<Webhook, String> {
+        if self.url.len() > MAX_URL_LENGTH {
+            return Err(format!("url must be at most {} characters", MAX_URL_LENGTH));
+        }
+        if origin(&self.url).is_none() {
+            return Err("url must be an http or https URL".to_string());
+        }
+        if !is_allowed(&self.url, allowed_hosts) {
+            return Err("url host is not in the allowed webhook hosts".to_string());
+        }
+
+        if self.events.is_empty() {
+            return Err("events must name at least one event".to_string());
+        }
+        let mut events: Vec<EventType> = Vec::with_capacity(self.events.len());
+        for event in self.events {
+            if event == EventType::Test {
+                return Err("test events are only sent on request".to_string());
+            }
+            if !events.contains(&event) {
+                events.push(event);
+            }
+        }
+
+        let soc_threshold = self.soc_threshold.unwrap_or(SOC_THRESHOLD_DEFAULT);
+        if !(SOC_THRESHOLD_MIN..=SOC_THRESHOLD_MAX).contains(&soc_threshold) {
+            return Err(format!(
+                "soc_threshold must be between {} and {}",
+                SOC_THRESHOLD_MIN, SOC_THRESHOLD_MAX
+            ));
+        }
+
+        Ok(Webhook {
+            id: Uuid::new_v4().to_string(),
+            username_hash: username_hash.to_string(),
+            vin: vin.to_string(),
+            url: self.url,
+            events,
+            soc_threshold,
+            secret: format!(
+                "whsec_{}{}",
+                Uuid::new_v4().simple(),
+                Uuid::new_v4().simple()
+            ),
+            created_at: now,
+        })
+    }
+}
+
+/// `scheme://host[:port]` of an http(s) URL, lowercased
+fn origin(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let scheme = scheme.to_ascii_lowercase();
+    if scheme != "http" && scheme != "https" {
+        return None;
+    }
+    let authority = rest.split(['/', '?', '#']).next()?;
+    if authority.is_empty() || authority.contains('@') || authority.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(format!("{}://{}", scheme, authority.to_ascii_lowercase()))
+}
+
+/// Whether the URL's origin is allowed
+///
+/// Entries are origins (`https://hooks.example.com`,
+/// `http://localhost:8080`) or subdomain wildcards
+/// (`https://*.example.com`).
+pub fn is_allowed(url: &str, allowed_hosts: &[String]) -> bool {
+    let Some(origin) = origin(url) else {
+        return false;
+    };
+    allowed_hosts.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.split_once("://*.") {
+            Some((scheme, domain)) => origin
+                .strip_prefix(&format!("{}://", scheme))
+                .is_some_and(|host| host.ends_with(&format!(".{}", domain))),
+            None => origin == allowed,
+        }
+    })
+}
+
+/// Allowed webhook origins from the comma-separated `webhook_allowed_hosts`
+/// variable; empty disables webhooks
+pub fn get_allowed_hosts() -> Vec<String> {
+    variables::get("webhook_allowed_hosts")
+        .unwrap_or_default()
+        .split(',')
+        .map(|host| host.trim().trim_end_matches('/').to_string())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+/// Signature header value for a payload
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    type HmacSha256 = Hmac<Sha256>;
+
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={:x}", timestamp, mac.finalize().into_bytes())
+}
+
+/// Last observed state of a vehicle, to turn snapshots into events
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EventState {
+    pub soc: Option<i32>,
+    pub plugged_in: Option<bool>,
+    pub moving: bool,
+}
+
+/// Derive events from a new snapshot
+///
+/// `transition` is the charging tracker's result for the same snapshot and
+/// `moving` whether the trip detector has an open trip after it. Nothing
+/// fires for the first observation of a value.
+pub fn detect(
+    state: &mut EventState,
+    record: &HistoryRecord,
+    transition: Option<&ChargingTransition>,
+    moving: bool,
+) -> Vec<VehicleEvent> {
+    let mut events = Vec::new();
+
+    if let Some(ChargingTransition::Finished(session)) = transition {
+        events.push(VehicleEvent::ChargingFinished(session.clone()));
+    }
+
+    if record.kind == SnapshotKind::Status {
+        if let Some(soc) = record.soc {
+            if let Some(previous) = state.soc {
+                if soc < previous {
+                    events.push(VehicleEvent::SocDropped {
+                        from: previous,
+                        to: soc,
+                    });
+                }
+            }
+            state.soc = Some(soc);
+        }
+        if let Some(status) = record.charging_status.as_deref() {
+            let plugged_in = charging::is_plugged_in(status);
+            if state.plugged_in == Some(false) && plugged_in {
+                events.push(VehicleEvent::PluggedIn { soc: record.soc });
+            }
+            state.plugged_in = Some(plugged_in);
+        }
+    }
+
+    if moving && !state.moving {
+        events.push(VehicleEvent::CarMoved {
+            lat: record.lat,
+            lon: record.lon,
+        });
+    }
+    state.moving = moving;
+
+    events
+}
+
+/// One event on its way to one webhook
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Delivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub username_hash: String,
+    pub event: EventType,
+    /// JSON payload as sent
+    pub body: String,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+}
+
+impl Delivery {
+    pub fn new(webhook: &Webhook, event: &VehicleEvent, now: i64) -> Self {
+        let id = Uuid::new_v4().to_string();
+        let body = serde_json::json!({
+            "id": id,
+            "type": event.event_type(),
+            "created_at": now,
+            "vin": webhook.vin,
+            "data": event.data(webhook),
+        });
+        Delivery {
+            id,
+            webhook_id: webhook.id.clone(),
+            username_hash: webhook.username_hash.clone(),
+            event: event.event_type(),
+            body: body.to_string(),
+            attempts: 0,
+            next_attempt_at: now,
+            last_error: None,
+            created_at: now,
+        }
+    }
+
+    /// Record a failed attempt and schedule the next one
+    ///
+    /// Returns false when no attempts are left.
+    pub fn record_failure(&mut self, error: String, now: i64) -> bool {
+        self.attempts += 1;
+        self.last_error = Some(error);
+        match RETRY_DELAYS_SECONDS.get(self.attempts as usize - 1) {
+            Some(delay) => {
+                self.next_attempt_at = now + delay;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "webhook_id": self.webhook_id,
+            "event": self.event,
+            "attempts": self.attempts,
+            "last_error": self.last_error,
+            "created_at": self.created_at,
+            "payload": serde_json::from_str::<serde_json::Value>(&self.body).ok(),
+        })
+    }
+}
+
+/// Remove and return the queued deliveries that are due
+pub fn take_due(queue: &mut Vec<Delivery>, now: i64) -> Vec<Delivery> {
+    let (due, waiting) = queue.drain(..).partition(|d| d.next_attempt_at <= now);
+    *queue = waiting;
+    due
+}
+
+/// POST a delivery to the webhook, returning the receiver's status
+pub async fn send(webhook: &Webhook, delivery: &Delivery, now: i64) -> Result<u16, String> {
+    if !is_allowed(&webhook.url, &get_allowed_hosts()) {
+        return Err("Host is no longer in the allowed webhook hosts".to_string());
+    }
+    let request = Request::post(&webhook.url, delivery.body.clone())
+        .header("content-type", "application/json")
+        .header(
+            "user-agent",
+            format!("myt2abrp-webhooks/{}", crate::VERSION),
+        )
+        .header("x-webhook-id", delivery.id.as_str())
+        .header("x-webhook-event", delivery.event.as_str())
+        .header(
+            "x-webhook-signature",
+            sign(&webhook.secret, now, &delivery.body),
+        )
+        .build();
+
+    let sent = crate::single_flight::timeout(
+        SEND_TIMEOUT_MS,
+        spin_sdk::http::send::<Request, Response>(request),
+    )
+    .await;
+    match sent {
+        Some(Ok(response)) if (200..300).contains(response.status()) => Ok(*response.status()),
+        Some(Ok(response)) => Err(format!("Receiver answered HTTP {}", response.status())),
+        Some(Err(e)) => Err(format!("Request failed: {}", e)),
+        None => Err(format!("No response within {} ms", SEND_TIMEOUT_MS)),
+    }
+}
+
+fn webhooks_key(username_hash: &str) -> String {
+    format!("{}{}", WEBHOOKS_KEY_PREFIX, username_hash)
+}
+
+fn subscribers_key(vin: &str) -> String {
+    format!("{}{}", WEBHOOK_SUBSCRIBERS_KEY_PREFIX, vin)
+}
+
+fn event_state_key(vin: &str) -> String {
+    format!("{}{}", EVENT_STATE_KEY_PREFIX, vin)
+}
+
+fn dead_letters_key(username_hash: &str) -> String {
+    format!("{}{}", DEAD_LETTERS_KEY_PREFIX, username_hash)
+}
+
+fn load_list<T: serde::de::DeserializeOwned>(store: &Store, key: &str) -> Vec<T> {
+    match store.get(key) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    }
+}
+
+pub async fn load_webhooks(store: &Store, username_hash: &str) -> Vec<Webhook> {
+    load_list(store, &webhooks_key(username_hash))
+}
+
+/// Store the user's webhooks and keep the per-VIN subscriber index in sync
+pub async fn save_webhooks(
+    store: &Store,
+    username_hash: &str,
+    webhooks: &[Webhook],
+    vin: &str,
+) -> anyhow::Result<()> {
+    store.set(&webhooks_key(username_hash), &serde_json::to_vec(webhooks)?)?;
+
+    let subscribed = webhooks.iter().any(|w| w.vin == vin);
+    let mut subscribers: Vec<String> = load_list(store, &subscribers_key(vin));
+    let listed = subscribers.iter().any(|s| s == username_hash);
+    if subscribed != listed {
+        subscribers.retain(|s| s != username_hash);
+        if subscribed {
+            subscribers.push(username_hash.to_string());
+        }
+        store.set(&subscribers_key(vin), &serde_json::to_vec(&subscribers)?)?;
+    }
+    Ok(())
+}
+
+pub async fn load_dead_letters(store: &Store, username_hash: &str) -> Vec<Delivery> {
+    load_list(store, &dead_letters_key(username_hash))
+}
+
+pub async fn clear_dead_letters(store: &Store, username_hash: &str) -> anyhow::Result<()> {
+    store.delete(&dead_letters_key(username_hash))?;
+    Ok(())
+}
+
+async fn add_dead_letter(store: &Store, delivery: Delivery) -> anyhow::Result<()> {
+    let key = dead_letters_key(&delivery.username_hash);
+    let mut dead_letters: Vec<Delivery> = load_list(store, &key);
+    dead_letters.push(delivery);
+    if dead_letters.len() > MAX_DEAD_LETTERS {
+        let excess = dead_letters.len() - MAX_DEAD_LETTERS;
+        dead_letters.drain(..excess);
+    }
+    store.set(&key, &serde_json::to_vec(&dead_letters)?)?;
+    Ok(())
+}
+
+async fn enqueue(store: &Store, deliveries: Vec<Delivery>) -> anyhow::Result<()> {
+    if deliveries.is_empty() {
+        return Ok(());
+    }
+    let mut queue: Vec<Delivery> = load_list(store, QUEUE_KEY);
+    queue.extend(deliveries);
+    store.set(QUEUE_KEY, &serde_json::to_vec(&queue)?)?;
+    Ok(())
+}
+
+/// Attempt a delivery; returns it if it should be retried
+async fn attempt(
+    store: &Store,
+    webhook: &Webhook,
+    mut delivery: Delivery,
+    now: i64,
+) -> anyhow::Result<Option<Delivery>> {
+    match send(webhook, &delivery, now).await {
+        Ok(status) => {
+            info!(
+                webhook_id = %webhook.id,
+                event = delivery.event.as_str(),
+                status = status,
+                "Webhook delivered"
+            );
+            Ok(None)
+        }
+        Err(error) => {
+            warn!(webhook_id = %webhook.id, error = %error, "Webhook delivery failed");
+            if delivery.record_failure(error, now) {
+                Ok(Some(delivery))
+            } else {
+                add_dead_letter(store, delivery).await?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Turn a new snapshot into events and queue them for subscribed webhooks
+pub async fn process_snapshot(
+    store: &Store,
+    vin: &str,
+    record: &HistoryRecord,
+    transition: Option<&ChargingTransition>,
+    moving: bool,
+    now: i64,
+) -> anyhow::Result<()> {
+    let key = event_state_key(vin);
+    let mut state: EventState = match store.get(&key) {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => EventState::default(),
+    };
+    let before = state.clone();
+    let events = detect(&mut state, record, transition, moving);
+    if state != before {
+        store.set(&key, &serde_json::to_vec(&state)?)?;
+    }
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let mut deliveries = Vec::new();
+    let subscribers: Vec<String> = load_list(store, &subscribers_key(vin));
+    for username_hash in &subscribers {
+        for webhook in load_webhooks(store, username_hash).await {
+            if webhook.vin != vin {
+                continue;
+            }
+            for event in events.iter().filter(|event| webhook.wants(event)) {
+                deliveries.push(Delivery::new(&webhook, event, now));
+            }
+        }
+    }
+    enqueue(store, deliveries).await
+}
+
+/// Send queued deliveries that are due; returns how many were attempted
+pub async fn retry_due(store: &Store, now: i64) -> anyhow::Result<usize> {
+    let mut queue: Vec<Delivery> = load_list(store, QUEUE_KEY);
+    let due = take_due(&mut queue, now);
+    if due.is_empty() {
+        return Ok(0);
+    }
+    // Saved before sending so an overlapping tick does not send twice
+    store.set(QUEUE_KEY, &serde_json::to_vec(&queue)?)?;
+
+    let attempted = due.len();
+    let mut retries = Vec::new();
+    for delivery in due {
+        let webhooks = load_webhooks(store, &delivery.username_hash).await;
+        // Deliveries of deleted webhooks are dropped
+        if let Some(webhook) = webhooks.iter().find(|w| w.id == delivery.webhook_id) {
+            retries.extend(attempt(store, webhook, delivery, now).await?);
+        }
+    }
+    enqueue(store, retries).await?;
+    Ok(attempted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::status_record;
+
+    fn webhook(events: Vec<EventType>) -> Webhook {
+        WebhookRequest {
+            url: "https://hooks.example.com/car".to_string(),
+            events,
+            soc_threshold: Some(30),
+        }
+        .into_webhook("user", "VIN", &["https://hooks.example.com".to_string()], 0)
+        .unwrap()
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let allowed = vec![
+            "https://hooks.example.com".to_string(),
+            "http://localhost:8080".to_string(),
+            "https://*.example.org".to_string(),
+        ];
+        assert!(is_allowed("https://hooks.example.com/a?b=c", &allowed));
+        assert!(is_allowed("HTTPS://Hooks.Example.com", &allowed));
+        assert!(is_allowed("http://localhost:8080/hook", &allowed));
+        assert!(is_allowed("https://a.b.example.org/x", &allowed));
+        assert!(!is_allowed("https://example.org/x", &allowed));
+        assert!(!is_allowed("http://hooks.example.com/a", &allowed));
+        assert!(!is_allowed("http://localhost:9090/hook", &allowed));
+        assert!(!is_allowed("https://hooks.example.com@evil.com/", &allowed));
+        assert!(!is_allowed("ftp://hooks.example.com", &allowed));
+    }
+
+    #[test]
+    fn test_webhook_request_validation() {
+        let hook = webhook(vec![EventType::SocBelow, EventType::SocBelow]);
+        assert_eq!(hook.events, vec![EventType::SocBelow]);
+        assert!(hook.secret.starts_with("whsec_"));
+        assert!(hook.to_json().get("secret").is_none());
+
+        let allowed = ["https://hooks.example.com".to_string()];
+        let request = |url: &str, events: Vec<EventType>, threshold| WebhookRequest {
+            url: url.to_string(),
+            events,
+            soc_threshold: threshold,
+        };
+        assert!(
+            request("https://other.example.com", vec![EventType::CarMoved], None)
+                .into_webhook("u", "V", &allowed, 0)
+                .is_err()
+        );
+        assert!(request("https://hooks.example.com", vec![], None)
+            .into_webhook("u", "V", &allowed, 0)
+            .is_err());
+        assert!(
+            request("https://hooks.example.com", vec![EventType::Test], None)
+                .into_webhook("u", "V", &allowed, 0)
+                .is_err()
+        );
+        assert!(request(
+            "https://hooks.example.com",
+            vec![EventType::SocBelow],
+            Some(99)
+        )
+        .into_webhook("u", "V", &allowed, 0)
+        .is_err());
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign("whsec_test", 1_700_000_000, r#"{"a":1}"#);
+        assert!(signature.starts_with("t=1700000000,v1="));
+        assert_eq!(signature.len(), "t=1700000000,v1=".len() + 64);
+        assert_ne!(signature, sign("whsec_other", 1_700_000_000, r#"{"a":1}"#));
+        assert_ne!(signature, sign("whsec_test", 1_700_000_001, r#"{"a":1}"#));
+    }
+
+    #[test]
+    fn test_detect_events() {
+        let mut state = EventState::default();
+        // First observation only sets the baseline
+        assert!(detect(
+            &mut state,
+            &status_record(100, 40, "NOT_CHARGING"),
+            None,
+            false
+        )
+        .is_empty());
+
+        let events = detect(
+            &mut state,
+            &status_record(200, 25, "CONNECTED"),
+            None,
+            false,
+        );
+        assert_eq!(
+            events,
+            vec![
+                VehicleEvent::SocDropped { from: 40, to: 25 },
+                VehicleEvent::PluggedIn { soc: Some(25) },
+            ]
+        );
+        // Still plugged in: no new event
+        assert!(detect(&mut state, &status_record(300, 30, "CHARGING"), None, false).is_empty());
+
+        let events = detect(
+            &mut state,
+            &status_record(400, 30, "NOT_CHARGING"),
+            None,
+            true,
+        );
+        assert!(matches!(events[0], VehicleEvent::CarMoved { .. }));
+        assert!(detect(
+            &mut state,
+            &status_record(500, 30, "NOT_CHARGING"),
+            None,
+            true
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_wants_soc_threshold() {
+        let hook = webhook(vec![EventType::SocBelow]);
+        assert!(hook.wants(&VehicleEvent::SocDropped { from: 31, to: 29 }));
+        assert!(hook.wants(&VehicleEvent::SocDropped { from: 30, to: 29 }));
+        // Already below, or still above the threshold
+        assert!(!hook.wants(&VehicleEvent::SocDropped { from: 29, to: 20 }));
+        assert!(!hook.wants(&VehicleEvent::SocDropped { from: 50, to: 31 }));
+        assert!(!hook.wants(&VehicleEvent::PluggedIn { soc: None }));
+    }
+
+    #[test]
+    fn test_retry_backoff_and_queue() {
+        let hook = webhook(vec![EventType::PluggedIn]);
+        let mut delivery = Delivery::new(&hook, &VehicleEvent::PluggedIn { soc: Some(50) }, 0);
+        let payload: serde_json::Value = serde_json::from_str(&delivery.body).unwrap();
+        assert_eq!(payload["type"], "plugged_in");
+        assert_eq!(payload["data"]["soc"], 50);
+
+        assert!(delivery.record_failure("HTTP 500".to_string(), 1000));
+        assert_eq!(delivery.next_attempt_at, 1000 + RETRY_DELAYS_SECONDS[0]);
+
+        let mut queue = vec![delivery.clone()];
+        assert!(take_due(&mut queue, 1000).is_empty());
+        assert_eq!(take_due(&mut queue, 1060).len(), 1);
+        assert!(queue.is_empty());
+
+        for _ in 1..RETRY_DELAYS_SECONDS.len() {
+            assert!(delivery.record_failure("HTTP 500".to_string(), 2000));
+        }
+        assert!(!delivery.record_failure("HTTP 500".to_string(), 3000));
+        assert_eq!(delivery.attempts as usize, RETRY_DELAYS_SECONDS.len() + 1);
+    }
+}
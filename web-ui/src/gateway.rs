@@ -0,0 +1,255 @@
+// Gateway client
+//
+// Fetches live vehicle data from the myt2abrp component via Spin local
+// service chaining. The caller's Authorization header is forwarded as-is, so
+// the gateway applies its own authentication, rate limits and VIN checks.
+// Command headers are forwarded too: Idempotency-Key, so retried commands
+// are not sent to the vehicle twice, and the unlock confirmation
+// (X-Command-PIN, X-Step-Up-Token).
+// Failures are reduced to a small set of "not connected" states the
+// dashboard can show instead of numbers.
+
+use serde_json::json;
+use spin_sdk::http::{Method, Request, Response, ResponseBuilder};
+use spin_sdk::variables;
+
+/// Request headers passed on to the gateway with commands
+const FORWARDED_HEADERS: &[&str] = &["idempotency-key", "x-command-pin", "x-step-up-token"];
+
+/// Default gateway address (Spin local service chaining)
+pub const GATEWAY_URL_DEFAULT: &str = "http://myt2abrp.spin.internal";
+
+/// Why live data could not be obtained
+#[derive(Debug, Clone, PartialEq)]
+pub enum GatewayError {
+    /// No access token, or the gateway rejected it (no Toyota login)
+    NotAuthenticated(String),
+    /// The gateway could not be reached
+    Unavailable(String),
+    /// The gateway answered with an error
+    Upstream(u16, String),
+}
+
+impl GatewayError {
+    fn reason(&self) -> &'static str {
+        match self {
+            GatewayError::NotAuthenticated(_) => "not_authenticated",
+            GatewayError::Unavailable(_) => "gateway_unavailable",
+            GatewayError::Upstream(_, _) => "upstream_error",
+        }
+    }
+
+    fn status(&self) -> u16 {
+        match self {
+            GatewayError::NotAuthenticated(_) => 401,
+            GatewayError::Unavailable(_) => 503,
+            GatewayError::Upstream(_, _) => 502,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            GatewayError::NotAuthenticated(message)
+            | GatewayError::Unavailable(message)
+            | GatewayError::Upstream(_, message) => message,
+        }
+    }
+
+    /// JSON body describing the "not connected" state
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "connected": false,
+            "reason": self.reason(),
+            "message": self.message(),
+        })
+    }
+
+    /// Response for the dashboard's "not connected" state
+    pub fn into_response(self) -> Response {
+        ResponseBuilder::new(self.status())
+            .header("content-type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(self.to_json().to_string())
+            .build()
+    }
+}
+
+impl std::fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.reason(), self.message())
+    }
+}
+
+/// Client for one incoming dashboard request
+pub struct Gateway {
+    base_url: String,
+    authorization: Option<String>,
+    forwarded_headers: Vec<(&'static str, String)>,
+    vin: Option<String>,
+}
+
+/// Extract a query parameter from a URI query string
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Message of a gateway JSON error body, falling back to `default`
+fn error_message(body: &[u8], default: &str) -> String {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| default.to_string())
+}
+
+impl Gateway {
+    /// Build a client from the `myt2abrp_url` variable and the request's
+    /// Authorization and command headers and `vin` query parameter
+    pub fn from_request(req: &Request) -> Self {
+        let base_url = variables::get("myt2abrp_url")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .unwrap_or_else(|| GATEWAY_URL_DEFAULT.to_string());
+        let authorization = req
+            .header("authorization")
+            .and_then(|value| value.as_str())
+            .map(str::to_string);
+        let forwarded_headers = FORWARDED_HEADERS
+            .iter()
+            .filter_map(|&name| {
+                req.header(name)
+                    .and_then(|value| value.as_str())
+                    .map(|value| (name, value.to_string()))
+            })
+            .collect();
+        let vin = query_param(req.query(), "vin")
+            .or_else(|| variables::get("vin").ok().filter(|vin| !vin.is_empty()));
+
+        Gateway {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            authorization,
+            forwarded_headers,
+            vin,
+        }
+    }
+
+    /// VIN the data is requested for, if known
+    pub fn vin(&self) -> Option<&str> {
+        self.vin.as_deref()
+    }
+
+    fn url(&self, path: &str) -> String {
+        match &self.vin {
+            Some(vin) => {
+                let separator = if path.contains('?') { '&' } else { '?' };
+                format!("{}{}{}vin={}", self.base_url, path, separator, vin)
+            }
+            None => format!("{}{}", self.base_url, path),
+        }
+    }
+
+    /// GET a JSON document from the gateway
+    pub async fn get_json(&self, path: &str) -> Result<serde_json::Value, GatewayError> {
+        let authorization = self.authorization.as_deref().ok_or_else(|| {
+            GatewayError::NotAuthenticated(
+                "Sign in with your Toyota account to see live data".to_string(),
+            )
+        })?;
+
+        let request = Request::get(self.url(path))
+            .header("authorization", authorization)
+            .header("accept", "application/json")
+            .build();
+        let response: Response = spin_sdk::http::send(request)
+            .await
+            .map_err(|e| GatewayError::Unavailable(format!("Gateway unreachable: {}", e)))?;
+
+        let status = *response.status();
+        let body = response.body();
+        match status {
+            200..=299 => serde_json::from_slice(body).map_err(|e| {
+                GatewayError::Upstream(status, format!("Invalid gateway response: {}", e))
+            }),
+            401 => Err(GatewayError::NotAuthenticated(error_message(
+                body,
+                "Toyota login required",
+            ))),
+            _ => Err(GatewayError::Upstream(
+                status,
+                error_message(body, "Gateway request failed"),
+            )),
+        }
+    }
+
+    /// Pass a request body through to the gateway and return its response
+    ///
+    /// Used for endpoints such as login where the gateway's answer is
+    /// returned to the browser unchanged.
+    pub async fn forward(
+        &self,
+        method: Method,
+        path: &str,
+        body: Vec<u8>,
+    ) -> Result<Response, GatewayError> {
+        let mut builder = Request::builder();
+        builder
+            .method(method)
+            .uri(self.url(path))
+            .header("content-type", "application/json");
+        if let Some(authorization) = &self.authorization {
+            builder.header("authorization", authorization.as_str());
+        }
+        for (name, value) in &self.forwarded_headers {
+            builder.header(*name, value.as_str());
+        }
+        let request = builder.body(body).build();
+
+        let response: Response = spin_sdk::http::send(request)
+            .await
+            .map_err(|e| GatewayError::Unavailable(format!("Gateway unreachable: {}", e)))?;
+        Ok(ResponseBuilder::new(*response.status())
+            .header("content-type", "application/json")
+            .header("Cache-Control", "no-store")
+            .body(response.body().to_vec())
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("vin=ABC&x=1", "vin"), Some("ABC".to_string()));
+        assert_eq!(query_param("x=1", "vin"), None);
+        assert_eq!(query_param("vin=", "vin"), None);
+        assert_eq!(query_param("", "vin"), None);
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(
+            error_message(br#"{"error":"x","message":"Please login again"}"#, "d"),
+            "Please login again"
+        );
+        assert_eq!(error_message(b"not json", "default"), "default");
+    }
+
+    #[test]
+    fn test_not_connected_json() {
+        let error = GatewayError::NotAuthenticated("Sign in".to_string());
+        let value = error.to_json();
+        assert_eq!(value["connected"], false);
+        assert_eq!(value["reason"], "not_authenticated");
+        assert_eq!(value["message"], "Sign in");
+        assert_eq!(error.status(), 401);
+
+        assert_eq!(GatewayError::Unavailable(String::new()).status(), 503);
+        assert_eq!(GatewayError::Upstream(500, String::new()).status(), 502);
+    }
+}
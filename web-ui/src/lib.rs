@@ -14,8 +14,12 @@
 // - Client-side rendering with vanilla JavaScript
 // - Uses minimal dependencies for optimal WASM binary size
 // - Stateless design (all state managed externally)
+// - Live vehicle data comes from the myt2abrp gateway (see gateway.rs)
+
+mod gateway;
 
 use chrono::Utc;
+use gateway::{Gateway, GatewayError};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use spin_sdk::http::{IntoResponse, Method, Request, ResponseBuilder};
@@ -97,49 +101,76 @@ struct Location {
 
 /// Represents the current charging session status
 ///
-/// Provides detailed information about an active or recent charging session,
-/// including power delivery, timing, and target charge level.
+/// Provides detailed information about an active or recent charging session.
+/// Values Toyota does not report (power, target level) are `None`.
 #[derive(Serialize)]
 struct ChargingStatus {
     /// Whether charging is currently active
     is_charging: bool,
+    /// Raw charging status reported by Toyota (e.g., "CHARGING", "NOT_CHARGING")
+    charging_status: Option<String>,
     /// Current battery level (0-100%)
     current_level: u8,
     /// Target charge level (0-100%)
-    target_level: u8,
+    target_level: Option<u8>,
     /// Charging power in kilowatts (kW)
-    power_kw: f32,
+    power_kw: Option<f32>,
     /// Estimated time to complete charging (in minutes)
     time_remaining_minutes: Option<u16>,
     /// Energy delivery rate in kWh
-    charge_rate_kwh: f32,
+    charge_rate_kwh: Option<f32>,
 }
 
 /// Battery health metrics and diagnostics
 ///
 /// Tracks the long-term health and condition of the vehicle's battery pack,
-/// including degradation, cycle count, and operating temperature.
+/// as estimated by the gateway's `/battery/health` endpoint from stored
+/// range/SOC samples and charging sessions.
 #[derive(Serialize)]
 struct BatteryHealth {
     /// Current battery capacity as percentage of original (0-100%)
     capacity_percentage: u8,
     /// Human-readable health status (e.g., "Excellent", "Good", "Fair", "Poor")
     health_status: String,
-    /// Number of complete charge/discharge cycles
+    /// Equivalent full charge cycles
     cycles: u32,
-    /// Current battery pack temperature in Celsius
-    temperature_celsius: f32,
+    /// Confidence of the estimate (0.0-1.0)
+    confidence: f32,
+    /// Estimated usable capacity in kWh, from charging sessions or the
+    /// nominal capacity
+    estimated_capacity_kwh: Option<f32>,
+}
+
+impl BatteryHealth {
+    /// Build from a gateway `/battery/health` response
+    fn from_estimate(estimate: &serde_json::Value) -> Self {
+        BatteryHealth {
+            capacity_percentage: estimate["state_of_health_percent"]
+                .as_f64()
+                .map(|soh| soh.round().clamp(0.0, 100.0) as u8)
+                .unwrap_or(0),
+            health_status: estimate["health_status"]
+                .as_str()
+                .unwrap_or("Unknown")
+                .to_string(),
+            cycles: estimate["equivalent_full_cycles"]
+                .as_f64()
+                .map(|cycles| cycles.round() as u32)
+                .unwrap_or(0),
+            confidence: estimate["confidence"].as_f64().unwrap_or(0.0) as f32,
+            estimated_capacity_kwh: estimate["estimated_usable_capacity_kwh"]
+                .as_f64()
+                .map(|kwh| kwh as f32),
+        }
+    }
 }
 
 /// Configuration for charging alerts and notifications
 ///
 /// Allows users to customize when they receive notifications about their
-/// vehicle's charging status.
-///
-/// Note: This structure is currently unused but reserved for future implementation
-/// of the POST /api/alerts/save endpoint.
-#[allow(dead_code)]
-#[derive(Deserialize)]
+/// vehicle's charging status. This is the shape of the alert settings form;
+/// the gateway stores the custom level as `null` when it is disabled.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
 struct AlertConfig {
     /// Send alert when charging is complete (100%)
     charge_complete: bool,
@@ -147,14 +178,341 @@ struct AlertConfig {
     optimal_charge: bool,
     /// Custom alert level (50-100%)
     custom_level: u8,
+    /// Send alert when charging reaches the custom level
+    #[serde(default)]
+    custom_level_enabled: bool,
     /// Alert when battery falls below 20%
     low_battery: bool,
     /// Alert when charging is slower than expected
     charging_slow: bool,
-    /// Alert when vehicle is ready for a planned trip
+    /// Alert when charging finished at the trip level (custom level, else 80%)
     ready_for_trip: bool,
 }
 
+/// Alert shown in the Active Alerts card
+#[derive(Serialize)]
+struct ActiveAlert {
+    id: String,
+    /// Severity used for styling: success, info or warning
+    #[serde(rename = "type")]
+    alert_type: String,
+    /// "active" or "acknowledged"
+    state: String,
+    title: String,
+    message: String,
+    time_ago: String,
+}
+
+/// Session shown in the Charging History card
+#[derive(Serialize)]
+struct ChargingHistoryItem {
+    /// Start of the session, e.g. "2 days ago"
+    date: String,
+    start_level: u8,
+    end_level: u8,
+    duration_minutes: i64,
+    /// Energy added, `None` without a configured battery capacity
+    energy_kwh: Option<f64>,
+    in_progress: bool,
+}
+
+// ============================================================================
+// Gateway Data Mapping
+// ============================================================================
+// The gateway returns `CurrentStatus` at `/`, Toyota's location payload at
+// `/location`, the battery estimate at `/battery/health`, recorded charging
+// sessions at `/charging/sessions` and trips at `/trips`.
+
+fn status_soc(status: &serde_json::Value) -> u8 {
+    status["soc"].as_i64().unwrap_or(0).clamp(0, 100) as u8
+}
+
+/// The gateway derives the flag from Toyota's charging status
+fn status_is_charging(status: &serde_json::Value) -> bool {
+    status["is_charging"].as_bool().unwrap_or(false)
+}
+
+impl VehicleStatus {
+    fn from_gateway(
+        vin: Option<&str>,
+        status: &serde_json::Value,
+        location: Option<&serde_json::Value>,
+    ) -> Self {
+        let location = location.and_then(|l| {
+            let position = &l["payload"]["vehicleInfo"]["location"];
+            Some(Location {
+                lat: position["lat"].as_f64()?,
+                lon: position["lon"].as_f64()?,
+            })
+        });
+        VehicleStatus {
+            vin: vin.unwrap_or_default().to_string(),
+            battery_level: status_soc(status),
+            range_km: status["ev_range"]
+                .as_f64()
+                .map(|r| r.round().clamp(0.0, u16::MAX as f64) as u16)
+                .unwrap_or(0),
+            is_charging: status_is_charging(status),
+            is_connected: true,
+            location,
+        }
+    }
+}
+
+impl ChargingStatus {
+    fn from_gateway(status: &serde_json::Value) -> Self {
+        ChargingStatus {
+            is_charging: status_is_charging(status),
+            charging_status: status["charging_status"].as_str().map(str::to_string),
+            current_level: status_soc(status),
+            target_level: None,
+            power_kw: None,
+            time_remaining_minutes: status["remaining_charge_time"]
+                .as_i64()
+                .filter(|minutes| *minutes > 0)
+                .map(|minutes| minutes.clamp(0, u16::MAX as i64) as u16),
+            charge_rate_kwh: None,
+        }
+    }
+}
+
+/// Current range and the range extrapolated to 80% and 100%
+fn range_from_gateway(status: &serde_json::Value) -> serde_json::Value {
+    let range = status["ev_range"].as_f64();
+    let soc = status["soc"].as_f64().filter(|soc| *soc > 0.0);
+    let at = |percent: f64| {
+        range
+            .zip(soc)
+            .map(|(range, soc)| (range / soc * percent).round())
+    };
+    json!({
+        "estimated_range_km": range.map(f64::round),
+        "estimated_range_with_ac_km": status["ev_range_with_ac"].as_f64().map(f64::round),
+        "range_at_80_percent_km": at(80.0),
+        "range_at_100_percent_km": at(100.0),
+    })
+}
+
+/// Custom level shown when the gateway has none configured
+const DEFAULT_CUSTOM_LEVEL: u8 = 90;
+
+impl AlertConfig {
+    /// Map the gateway's alert configuration to the form shape
+    fn from_gateway(config: &serde_json::Value) -> Self {
+        let flag = |name: &str| config[name].as_bool().unwrap_or(false);
+        let custom_level = config["custom_level"].as_u64().map(|level| level as u8);
+        AlertConfig {
+            charge_complete: flag("charge_complete"),
+            optimal_charge: flag("optimal_charge"),
+            custom_level: custom_level.unwrap_or(DEFAULT_CUSTOM_LEVEL),
+            custom_level_enabled: custom_level.is_some(),
+            low_battery: flag("low_battery"),
+            charging_slow: flag("charging_slow"),
+            ready_for_trip: flag("ready_for_trip"),
+        }
+    }
+
+    /// Body for the gateway's `PUT /alerts/config`
+    fn to_gateway(&self) -> serde_json::Value {
+        json!({
+            "charge_complete": self.charge_complete,
+            "optimal_charge": self.optimal_charge,
+            "custom_level": self.custom_level_enabled.then_some(self.custom_level),
+            "low_battery": self.low_battery,
+            "charging_slow": self.charging_slow,
+            "ready_for_trip": self.ready_for_trip,
+        })
+    }
+}
+
+/// Window of the "this week" analytics, in seconds
+const WEEK_SECONDS: i64 = 7 * 86_400;
+
+/// Window of the efficiency analytics, in seconds
+const MONTH_SECONDS: i64 = 30 * 86_400;
+
+/// Sessions shown in the Charging History card
+const HISTORY_SESSIONS: usize = 5;
+
+fn round_to(value: f64, decimals: i32) -> f64 {
+    let factor = 10f64.powi(decimals);
+    (value * factor).round() / factor
+}
+
+fn soc_level(value: &serde_json::Value) -> u8 {
+    value.as_i64().unwrap_or(0).clamp(0, 100) as u8
+}
+
+/// Map the gateway's `/charging/sessions` to the Charging History card
+fn charging_history_from_gateway(
+    response: &serde_json::Value,
+    now: i64,
+) -> Vec<ChargingHistoryItem> {
+    response["sessions"]
+        .as_array()
+        .map(|sessions| {
+            sessions
+                .iter()
+                .map(|session| ChargingHistoryItem {
+                    date: time_ago(session["started_at"].as_i64().unwrap_or(now), now),
+                    start_level: soc_level(&session["start_soc"]),
+                    end_level: soc_level(&session["end_soc"]),
+                    duration_minutes: session["duration_minutes"].as_i64().unwrap_or(0),
+                    energy_kwh: session["energy_added_kwh"]
+                        .as_f64()
+                        .map(|kwh| round_to(kwh, 1)),
+                    in_progress: session["in_progress"].as_bool().unwrap_or(false),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Summarize the last week's `/charging/sessions` for the weekly card
+///
+/// Energy is `null` while no session has a known energy.
+fn weekly_from_gateway(response: &serde_json::Value) -> serde_json::Value {
+    let sessions = response["sessions"].as_array().cloned().unwrap_or_default();
+    let energies: Vec<f64> = sessions
+        .iter()
+        .filter_map(|session| session["energy_added_kwh"].as_f64())
+        .collect();
+    let durations: Vec<i64> = sessions
+        .iter()
+        .filter_map(|session| session["duration_minutes"].as_i64())
+        .collect();
+    json!({
+        "charging_sessions": response["total"].as_u64().unwrap_or(sessions.len() as u64),
+        "total_energy_kwh": (!energies.is_empty())
+            .then(|| round_to(energies.iter().sum(), 1)),
+        "avg_duration_minutes": (!durations.is_empty())
+            .then(|| durations.iter().sum::<i64>() / durations.len() as i64),
+    })
+}
+
+/// Efficiency card from the last 30 days of `/trips` and, if available,
+/// the `/battery/health` estimate
+fn efficiency_from_gateway(
+    trips: &serde_json::Value,
+    health: Option<&serde_json::Value>,
+) -> serde_json::Value {
+    let summary = &trips["summary"];
+    json!({
+        "avg_consumption_kwh_per_100km": summary["average_efficiency_kwh_per_100km"]
+            .as_f64()
+            .map(|value| round_to(value, 1)),
+        "total_distance_km": summary["total_distance_km"].as_f64().map(f64::round),
+        "trip_count": summary["trip_count"].as_u64().unwrap_or(0),
+        "battery_health_percent": health
+            .and_then(|health| health["state_of_health_percent"].as_f64())
+            .map(f64::round),
+    })
+}
+
+/// Map the gateway's `/charging/costs` for the last week to the cost card
+///
+/// Costs are `null` while no session has a known energy.
+fn costs_from_gateway(costs: &serde_json::Value) -> serde_json::Value {
+    json!({
+        "this_week_cost": costs["total_cost"].as_f64(),
+        "per_session_avg": costs["per_session_avg"].as_f64(),
+        "avg_price_per_kwh": costs["avg_price_per_kwh"].as_f64(),
+        "currency": costs["currency"].as_str().unwrap_or("EUR"),
+        "sessions": costs["session_count"].as_u64().unwrap_or(0),
+    })
+}
+
+/// Human-readable age of a timestamp, e.g. "5 minutes ago"
+fn time_ago(timestamp: i64, now: i64) -> String {
+    let seconds = (now - timestamp).max(0);
+    let (value, unit) = match seconds {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86_399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86_400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        value,
+        unit,
+        if value == 1 { "" } else { "s" }
+    )
+}
+
+/// Map the gateway's open alerts to the Active Alerts card
+fn active_alerts_from_gateway(response: &serde_json::Value, now: i64) -> Vec<ActiveAlert> {
+    let text = |alert: &serde_json::Value, name: &str| {
+        alert[name].as_str().unwrap_or_default().to_string()
+    };
+    response["alerts"]
+        .as_array()
+        .map(|alerts| {
+            alerts
+                .iter()
+                .map(|alert| ActiveAlert {
+                    id: text(alert, "id"),
+                    alert_type: text(alert, "severity"),
+                    state: text(alert, "state"),
+                    title: text(alert, "title"),
+                    message: text(alert, "message"),
+                    time_ago: time_ago(alert["created_at"].as_i64().unwrap_or(now), now),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Send a remote command (`charge`, `climate`, `lock`, ...) for the selected vehicle
+///
+/// Fields of the browser's JSON body (e.g. `target_soc`,
+/// `target_temperature`) are passed on with the action; the gateway
+/// validates them. Its answer (command record or error) is returned
+/// unchanged.
+async fn vehicle_command(
+    gateway: &Gateway,
+    kind: &str,
+    action: &str,
+    body: &[u8],
+) -> spin_sdk::http::Response {
+    let Some(vin) = gateway.vin() else {
+        return vin_required();
+    };
+    let mut command = match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(fields)) => serde_json::Value::Object(fields),
+        _ => json!({}),
+    };
+    command["action"] = json!(action);
+
+    let path = format!("/vehicles/{}/commands/{}", vin, kind);
+    match gateway
+        .forward(Method::Post, &path, command.to_string().into_bytes())
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => not_connected(e),
+    }
+}
+
+/// Response for vehicle endpoints called without a selected vehicle
+fn vin_required() -> spin_sdk::http::Response {
+    ResponseBuilder::new(400)
+        .header("content-type", "application/json")
+        .body(
+            json!({
+                "error": "VIN required",
+                "message": "Select a vehicle in the settings first"
+            })
+            .to_string(),
+        )
+        .build()
+}
+
+/// "Not connected" response, logged for the operator
+fn not_connected(error: GatewayError) -> spin_sdk::http::Response {
+    eprintln!("[WARN] Not connected: {}", error);
+    error.into_response()
+}
+
 // ============================================================================
 // Main HTTP Handler
 // ============================================================================
@@ -174,9 +532,10 @@ struct AlertConfig {
 /// ## Error Handling
 /// Errors are logged to stderr and returned as appropriate HTTP error responses.
 #[http_component]
-fn handle_request(req: Request) -> anyhow::Result<impl IntoResponse> {
+async fn handle_request(req: Request) -> anyhow::Result<impl IntoResponse> {
     let path = req.path();
     let method = req.method();
+    let gateway = Gateway::from_request(&req);
 
     // Increment request counter
     REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed);
@@ -194,145 +553,274 @@ fn handle_request(req: Request) -> anyhow::Result<impl IntoResponse> {
         (Method::Get, "/app.js") => serve_static_file("app.js", "application/javascript"),
 
         // API Endpoints - Vehicle Status (JSON)
-        (Method::Get, "/api/vehicle/status") => {
-            let status = VehicleStatus {
-                vin: "TESTVIN123456789".to_string(),
-                battery_level: 85,
-                range_km: 320,
-                is_charging: true,
-                is_connected: true,
-                location: Some(Location {
-                    lat: 52.52,
-                    lon: 13.405,
-                }),
-            };
-
-            Ok(json_response(&serde_json::to_value(&status)?))
-        }
+        (Method::Get, "/api/vehicle/status") => match gateway.get_json("/").await {
+            Ok(status) => {
+                // Location is optional, the status is still useful without it
+                let location = gateway.get_json("/location").await.ok();
+                let status = VehicleStatus::from_gateway(gateway.vin(), &status, location.as_ref());
+                Ok(json_response(&serde_json::to_value(&status)?))
+            }
+            Err(e) => Ok(not_connected(e)),
+        },
 
         // API Endpoints - Charging Status (JSON)
-        (Method::Get, "/api/charging/status") => {
-            let status = ChargingStatus {
-                is_charging: true,
-                current_level: 85,
-                target_level: 100,
-                power_kw: 50.0,
-                time_remaining_minutes: Some(45),
-                charge_rate_kwh: 48.5,
-            };
-
-            Ok(json_response(&serde_json::to_value(&status)?))
-        }
+        (Method::Get, "/api/charging/status") => match gateway.get_json("/").await {
+            Ok(status) => {
+                let status = ChargingStatus::from_gateway(&status);
+                Ok(json_response(&serde_json::to_value(&status)?))
+            }
+            Err(e) => Ok(not_connected(e)),
+        },
 
         // API Endpoints - Range (JSON)
-        (Method::Get, "/api/range") => Ok(json_response(&json!({
-            "estimated_range_km": 320,
-            "range_at_80_percent_km": 280,
-            "range_at_100_percent_km": 400,
-        }))),
+        (Method::Get, "/api/range") => match gateway.get_json("/").await {
+            Ok(status) => Ok(json_response(&range_from_gateway(&status))),
+            Err(e) => Ok(not_connected(e)),
+        },
 
         // API Endpoints - Battery Health (JSON)
-        (Method::Get, "/api/battery/health") => {
-            let health = BatteryHealth {
-                capacity_percentage: 98,
-                health_status: "Excellent".to_string(),
-                cycles: 120,
-                temperature_celsius: 22.5,
-            };
+        (Method::Get, "/api/battery/health") => match gateway.get_json("/battery/health").await {
+            Ok(estimate) => {
+                let health = BatteryHealth::from_estimate(&estimate);
+                Ok(json_response(&serde_json::to_value(&health)?))
+            }
+            Err(e) => Ok(not_connected(e)),
+        },
 
-            Ok(json_response(&serde_json::to_value(&health)?))
+        // Authentication - passed through to the gateway
+        (Method::Post, "/api/auth/login") => {
+            match gateway
+                .forward(Method::Post, "/auth/login", req.body().to_vec())
+                .await
+            {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(not_connected(e)),
+            }
         }
 
         // API Endpoints - Charging History (JSON)
-        (Method::Get, "/api/charging/history") => Ok(json_response(&json!([
-            {
-                "date": "Today, 08:30",
-                "start_level": 45,
-                "end_level": 100,
-                "duration_minutes": 135,
-                "energy_kwh": 35.5
-            },
+        (Method::Get, "/api/charging/history") => {
+            match gateway
+                .get_json(&format!("/charging/sessions?limit={}", HISTORY_SESSIONS))
+                .await
             {
-                "date": "Yesterday, 19:45",
-                "start_level": 20,
-                "end_level": 80,
-                "duration_minutes": 90,
-                "energy_kwh": 42.0
-            },
-            {
-                "date": "2 days ago, 10:15",
-                "start_level": 55,
-                "end_level": 100,
-                "duration_minutes": 105,
-                "energy_kwh": 32.5
+                Ok(sessions) => {
+                    let history = charging_history_from_gateway(&sessions, Utc::now().timestamp());
+                    Ok(json_response(&serde_json::to_value(&history)?))
+                }
+                Err(e) => Ok(not_connected(e)),
             }
-        ]))),
+        }
 
         // API Endpoints - Active Alerts (JSON)
-        (Method::Get, "/api/alerts/active") => Ok(json_response(&json!([
-            {
-                "type": "success",
-                "title": "Charging Complete",
-                "message": "Your vehicle is fully charged and ready to go!",
-                "time_ago": "5 minutes ago"
-            },
-            {
-                "type": "info",
-                "title": "Optimal Charge Level Reached",
-                "message": "Battery at 80% - optimal for battery longevity",
-                "time_ago": "2 hours ago"
+        (Method::Get, "/api/alerts/active") => match gateway.get_json("/alerts/active").await {
+            Ok(alerts) => {
+                let alerts = active_alerts_from_gateway(&alerts, Utc::now().timestamp());
+                Ok(json_response(&serde_json::to_value(&alerts)?))
             }
-        ]))),
+            Err(e) => Ok(not_connected(e)),
+        },
+
+        // Alert configuration (form shape)
+        (Method::Get, "/api/alerts/config") => match gateway.get_json("/alerts/config").await {
+            Ok(config) => Ok(json_response(&serde_json::to_value(
+                AlertConfig::from_gateway(&config),
+            )?)),
+            Err(e) => Ok(not_connected(e)),
+        },
 
         // API Endpoints - Analytics - Weekly (JSON)
-        (Method::Get, "/api/analytics/weekly") => Ok(json_response(&json!({
-            "charging_sessions": 7,
-            "total_energy_kwh": 245,
-            "avg_duration_minutes": 155
-        }))),
+        (Method::Get, "/api/analytics/weekly") => {
+            let from = Utc::now().timestamp() - WEEK_SECONDS;
+            match gateway
+                .get_json(&format!("/charging/sessions?from={}&limit=200", from))
+                .await
+            {
+                Ok(sessions) => Ok(json_response(&weekly_from_gateway(&sessions))),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
 
         // API Endpoints - Analytics - Costs (JSON)
-        (Method::Get, "/api/analytics/costs") => Ok(json_response(&json!({
-            "this_week_cost": 42.50,
-            "per_session_avg": 6.08,
-            "avg_price_per_kwh": 0.173,
-            "currency": "EUR"
-        }))),
+        (Method::Get, "/api/analytics/costs") => {
+            let from = Utc::now().timestamp() - WEEK_SECONDS;
+            match gateway
+                .get_json(&format!("/charging/costs?from={}", from))
+                .await
+            {
+                Ok(costs) => Ok(json_response(&costs_from_gateway(&costs))),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
 
         // API Endpoints - Analytics - Efficiency (JSON)
-        (Method::Get, "/api/analytics/efficiency") => Ok(json_response(&json!({
-            "charging_efficiency_percent": 92,
-            "avg_consumption_kwh_per_100km": 18.5,
-            "battery_health_percent": 98
-        }))),
-
-        // POST Endpoints - Actions
-        (Method::Post, "/api/charging/start") => Ok(json_response(&json!({
-            "success": true,
-            "message": "Charging started"
-        }))),
-
-        (Method::Post, "/api/charging/stop") => Ok(json_response(&json!({
-            "success": true,
-            "message": "Charging stopped"
-        }))),
-
-        (Method::Post, "/api/precondition") => Ok(json_response(&json!({
-            "success": true,
-            "message": "Pre-conditioning started"
-        }))),
-
-        (Method::Post, "/api/alerts/save") => Ok(json_response(&json!({
-            "success": true,
-            "message": "Alert settings saved"
-        }))),
-
-        (Method::Post, "/api/settings/vehicle")
-        | (Method::Post, "/api/settings/api")
-        | (Method::Post, "/api/settings/notifications") => Ok(json_response(&json!({
-            "success": true,
-            "message": "Settings updated"
-        }))),
+        (Method::Get, "/api/analytics/efficiency") => {
+            let from = Utc::now().timestamp() - MONTH_SECONDS;
+            match gateway
+                .get_json(&format!("/trips?from={}&limit=1", from))
+                .await
+            {
+                Ok(trips) => {
+                    // Battery health is optional, consumption is still useful without it
+                    let health = gateway.get_json("/battery/health").await.ok();
+                    Ok(json_response(&efficiency_from_gateway(
+                        &trips,
+                        health.as_ref(),
+                    )))
+                }
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
+
+        // POST Endpoints - Actions (remote commands, polled via /api/commands/{id})
+        (Method::Post, "/api/charging/start") => {
+            Ok(vehicle_command(&gateway, "charge", "start", req.body()).await)
+        }
+
+        (Method::Post, "/api/charging/stop") => {
+            Ok(vehicle_command(&gateway, "charge", "stop", req.body()).await)
+        }
+
+        (Method::Get, command_path) if command_path.starts_with("/api/commands/") => {
+            match gateway.get_json(&command_path["/api".len()..]).await {
+                Ok(command) => Ok(json_response(&command)),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
+
+        (Method::Post, "/api/precondition") => {
+            Ok(vehicle_command(&gateway, "climate", "start", req.body()).await)
+        }
+
+        (Method::Post, "/api/climate/stop") => {
+            Ok(vehicle_command(&gateway, "climate", "stop", req.body()).await)
+        }
+
+        // Climate status and settings, departure precondition schedule
+        (Method::Get, "/api/climate") | (_, "/api/climate/schedule") => match gateway.vin() {
+            Some(vin) => {
+                let path = format!("/vehicles/{}{}", vin, &path["/api".len()..]);
+                // The browser saves forms with POST, the gateway expects PUT
+                let method = match method {
+                    Method::Post => Method::Put,
+                    other => other.clone(),
+                };
+                match gateway.forward(method, &path, req.body().to_vec()).await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(not_connected(e)),
+                }
+            }
+            None => Ok(vin_required()),
+        },
+
+        // Doors and locks; unlocking needs the command PIN (X-Command-PIN)
+        (Method::Get, "/api/doors/status") => match gateway.vin() {
+            Some(vin) => {
+                let path = format!("/vehicles/{}/remote-status", vin);
+                match gateway.get_json(&path).await {
+                    Ok(status) => Ok(json_response(&status)),
+                    Err(e) => Ok(not_connected(e)),
+                }
+            }
+            None => Ok(vin_required()),
+        },
+
+        (Method::Post, "/api/doors/lock") => {
+            Ok(vehicle_command(&gateway, "lock", "lock", req.body()).await)
+        }
+
+        (Method::Post, "/api/doors/unlock") => {
+            Ok(vehicle_command(&gateway, "lock", "unlock", req.body()).await)
+        }
+
+        (Method::Post, "/api/hazard/on") => {
+            Ok(vehicle_command(&gateway, "hazard", "on", req.body()).await)
+        }
+
+        (Method::Post, "/api/hazard/off") => {
+            Ok(vehicle_command(&gateway, "hazard", "off", req.body()).await)
+        }
+
+        (Method::Post, "/api/find") => {
+            Ok(vehicle_command(&gateway, "find", "horn_and_lights", req.body()).await)
+        }
+
+        (Method::Post, "/api/auth/pin") => {
+            match gateway
+                .forward(Method::Put, "/auth/pin", req.body().to_vec())
+                .await
+            {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
+
+        (Method::Post, "/api/alerts/save") => {
+            match serde_json::from_slice::<AlertConfig>(req.body()) {
+                Ok(config) => {
+                    let body = config.to_gateway().to_string().into_bytes();
+                    match gateway.forward(Method::Put, "/alerts/config", body).await {
+                        Ok(response) if *response.status() == 200 => {
+                            let saved: serde_json::Value =
+                                serde_json::from_slice(response.body()).unwrap_or_default();
+                            Ok(json_response(&serde_json::to_value(
+                                AlertConfig::from_gateway(&saved),
+                            )?))
+                        }
+                        // Field errors (400) reach the form unchanged
+                        Ok(response) => Ok(response),
+                        Err(e) => Ok(not_connected(e)),
+                    }
+                }
+                Err(e) => Ok(ResponseBuilder::new(400)
+                    .header("content-type", "application/json")
+                    .body(
+                        json!({
+                            "error": "Invalid alert config",
+                            "message": e.to_string(),
+                            "errors": [{ "field": "body", "message": e.to_string() }]
+                        })
+                        .to_string(),
+                    )
+                    .build()),
+            }
+        }
+
+        (Method::Post, ack_path)
+            if ack_path.starts_with("/api/alerts/") && ack_path.ends_with("/ack") =>
+        {
+            match gateway
+                .forward(Method::Post, &ack_path["/api".len()..], Vec::new())
+                .await
+            {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
+
+        // Settings - stored per user by the gateway (vehicle, api, notifications)
+        (Method::Get, settings_path) if settings_path.starts_with("/api/settings/") => {
+            match gateway.get_json(&settings_path["/api".len()..]).await {
+                Ok(settings) => Ok(json_response(&settings)),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
+
+        // Saving passes the gateway's answer through so that field errors
+        // (400) and version conflicts (409) reach the form
+        (Method::Post, settings_path) if settings_path.starts_with("/api/settings/") => {
+            match gateway
+                .forward(
+                    Method::Put,
+                    &settings_path["/api".len()..],
+                    req.body().to_vec(),
+                )
+                .await
+            {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(not_connected(e)),
+            }
+        }
 
         // Health Check & Monitoring Endpoints
         (Method::Get, "/health") | (Method::Get, "/api/health") => {
@@ -478,3 +966,136 @@ fn json_response_with_security(value: &serde_json::Value) -> spin_sdk::http::Res
         .body(value.to_string())
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_ago() {
+        assert_eq!(time_ago(1000, 1030), "just now");
+        assert_eq!(time_ago(1000, 1060), "1 minute ago");
+        assert_eq!(time_ago(0, 5 * 3600), "5 hours ago");
+        assert_eq!(time_ago(0, 2 * 86_400), "2 days ago");
+    }
+
+    #[test]
+    fn test_alert_config_mapping() {
+        let gateway = json!({
+            "charge_complete": true,
+            "optimal_charge": false,
+            "custom_level": null,
+            "low_battery": true,
+            "charging_slow": false,
+            "ready_for_trip": false
+        });
+        let config = AlertConfig::from_gateway(&gateway);
+        assert!(!config.custom_level_enabled);
+        assert_eq!(config.custom_level, DEFAULT_CUSTOM_LEVEL);
+        assert_eq!(config.to_gateway(), gateway);
+
+        let form: AlertConfig = serde_json::from_value(json!({
+            "charge_complete": false,
+            "optimal_charge": true,
+            "custom_level": 85,
+            "custom_level_enabled": true,
+            "low_battery": false,
+            "charging_slow": true,
+            "ready_for_trip": true
+        }))
+        .unwrap();
+        assert_eq!(form.to_gateway()["custom_level"], 85);
+        assert_eq!(AlertConfig::from_gateway(&form.to_gateway()), form);
+    }
+
+    #[test]
+    fn test_charging_history_mapping() {
+        let sessions = json!({
+            "total": 2,
+            "sessions": [
+                {
+                    "started_at": 1000,
+                    "start_soc": 40,
+                    "end_soc": 62,
+                    "duration_minutes": 45,
+                    "energy_added_kwh": 14.337,
+                    "in_progress": true
+                },
+                {
+                    "started_at": 1000 - 2 * 86_400,
+                    "start_soc": 20,
+                    "end_soc": 80,
+                    "duration_minutes": 120,
+                    "energy_added_kwh": null,
+                    "in_progress": false
+                }
+            ]
+        });
+
+        let history = charging_history_from_gateway(&sessions, 1000 + 120);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].date, "2 minutes ago");
+        assert_eq!(history[0].energy_kwh, Some(14.3));
+        assert!(history[0].in_progress);
+        assert_eq!(history[1].end_level, 80);
+        assert_eq!(history[1].energy_kwh, None);
+
+        let weekly = weekly_from_gateway(&sessions);
+        assert_eq!(weekly["charging_sessions"], 2);
+        assert_eq!(weekly["total_energy_kwh"], 14.3);
+        assert_eq!(weekly["avg_duration_minutes"], 82);
+
+        let empty = weekly_from_gateway(&json!({ "total": 0, "sessions": [] }));
+        assert_eq!(empty["charging_sessions"], 0);
+        assert!(empty["total_energy_kwh"].is_null());
+        assert!(empty["avg_duration_minutes"].is_null());
+    }
+
+    #[test]
+    fn test_efficiency_mapping() {
+        let trips = json!({
+            "summary": {
+                "trip_count": 4,
+                "total_distance_km": 212.4,
+                "total_energy_kwh": 36.5,
+                "average_efficiency_kwh_per_100km": 17.184
+            }
+        });
+        let health = json!({ "state_of_health_percent": 96.6 });
+
+        let efficiency = efficiency_from_gateway(&trips, Some(&health));
+        assert_eq!(efficiency["avg_consumption_kwh_per_100km"], 17.2);
+        assert_eq!(efficiency["total_distance_km"], 212.0);
+        assert_eq!(efficiency["trip_count"], 4);
+        assert_eq!(efficiency["battery_health_percent"], 97.0);
+
+        let unknown = efficiency_from_gateway(&json!({ "summary": {} }), None);
+        assert!(unknown["avg_consumption_kwh_per_100km"].is_null());
+        assert!(unknown["battery_health_percent"].is_null());
+    }
+
+    #[test]
+    fn test_costs_mapping() {
+        let costs = costs_from_gateway(&json!({
+            "currency": "CHF",
+            "session_count": 3,
+            "total_cost": 12.4,
+            "per_session_avg": 4.13,
+            "avg_price_per_kwh": 0.31
+        }));
+        assert_eq!(costs["this_week_cost"], 12.4);
+        assert_eq!(costs["per_session_avg"], 4.13);
+        assert_eq!(costs["currency"], "CHF");
+        assert_eq!(costs["sessions"], 3);
+
+        let empty = costs_from_gateway(&json!({
+            "currency": "EUR",
+            "session_count": 0,
+            "total_cost": 0.0,
+            "per_session_avg": null,
+            "avg_price_per_kwh": null
+        }));
+        assert!(empty["per_session_avg"].is_null());
+        assert!(empty["avg_price_per_kwh"].is_null());
+    }
+}
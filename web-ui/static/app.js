@@ -5,20 +5,31 @@
 // API Fetching Functions
 // ============================================================================
 
+const ACCESS_TOKEN_KEY = 'myt2abrp_access_token';
+
 /**
  * Generic fetch wrapper with error handling
+ *
+ * Sends the stored gateway access token. "Not connected" answers
+ * ({connected: false}) are returned to the caller instead of thrown.
  */
 async function fetchJSON(url, options = {}) {
+    const token = localStorage.getItem(ACCESS_TOKEN_KEY);
     try {
         const response = await fetch(url, {
             ...options,
             headers: {
                 'Content-Type': 'application/json',
+                ...(token ? { 'Authorization': `Bearer ${token}` } : {}),
                 ...options.headers
             }
         });
 
         if (!response.ok) {
+            const body = await response.json().catch(() => null);
+            if (body && body.connected === false) {
+                return body;
+            }
             throw new Error(`HTTP ${response.status}: ${response.statusText}`);
         }
 
@@ -30,11 +41,64 @@ async function fetchJSON(url, options = {}) {
     }
 }
 
+/**
+ * Render the "not connected" state instead of vehicle data
+ */
+function renderNotConnected(elementId, data) {
+    const loginForm = data.reason === 'not_authenticated' ? `
+        <form class="connect-form" onsubmit="connectAccount(event)">
+            <input type="email" name="username" placeholder="Toyota account email" required>
+            <input type="password" name="password" placeholder="Password" required>
+            <button type="submit" class="btn">Connect</button>
+        </form>
+    ` : '';
+    document.getElementById(elementId).innerHTML = `
+        <div class="not-connected">
+            <h3>Not connected</h3>
+            <p>${data.message}</p>
+            ${loginForm}
+        </div>
+    `;
+}
+
+/**
+ * Log in through the gateway and reload live data
+ */
+async function connectAccount(event) {
+    event.preventDefault();
+    const form = event.target;
+    const credentials = Object.fromEntries(new FormData(form));
+    // The dashboard's control buttons send remote commands
+    credentials.scope = 'vehicle:read vehicle:command';
+
+    const response = await fetch('/api/auth/login', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify(credentials)
+    });
+    const result = await response.json().catch(() => ({}));
+
+    if (response.ok && result.access_token) {
+        localStorage.setItem(ACCESS_TOKEN_KEY, result.access_token);
+        showNotification('Toyota account connected');
+        loadVehicleStatus();
+        loadChargingStatus();
+        loadRange();
+        loadBatteryHealth();
+    } else {
+        showNotification(result.message || 'Login failed', 'error');
+    }
+}
+
 /**
  * Fetch and render vehicle status
  */
 async function loadVehicleStatus() {
     const data = await fetchJSON('/api/vehicle/status');
+    if (data.connected === false) {
+        renderNotConnected('vehicle-status', data);
+        return;
+    }
     const html = `
         <div>
             <h2>Vehicle Status</h2>
@@ -68,6 +132,10 @@ async function loadVehicleStatus() {
  */
 async function loadChargingStatus() {
     const data = await fetchJSON('/api/charging/status');
+    if (data.connected === false) {
+        renderNotConnected('charging-status', data);
+        return;
+    }
     const timeRemaining = data.time_remaining_minutes
         ? formatTimeRemaining(data.time_remaining_minutes)
         : 'N/A';
@@ -80,12 +148,12 @@ async function loadChargingStatus() {
                     <div class="progress-fill" style="width: ${data.current_level}%"></div>
                 </div>
                 <div style="margin-top: 10px; font-size: 14px;">
-                    ${data.current_level}% → ${data.target_level}% target
+                    ${data.target_level != null ? `${data.current_level}% → ${data.target_level}% target` : `${data.current_level}%`}
                 </div>
             </div>
             <div class="charging-stats">
                 <div class="stat">
-                    <div class="stat-value">${data.power_kw.toFixed(1)} kW</div>
+                    <div class="stat-value">${data.power_kw != null ? data.power_kw.toFixed(1) + ' kW' : 'N/A'}</div>
                     <div class="stat-label">Power</div>
                 </div>
                 <div class="stat">
@@ -93,7 +161,7 @@ async function loadChargingStatus() {
                     <div class="stat-label">Time Left</div>
                 </div>
                 <div class="stat">
-                    <div class="stat-value">${data.charge_rate_kwh.toFixed(1)} kWh</div>
+                    <div class="stat-value">${data.charge_rate_kwh != null ? data.charge_rate_kwh.toFixed(1) + ' kWh' : 'N/A'}</div>
                     <div class="stat-label">Rate</div>
                 </div>
             </div>
@@ -107,15 +175,19 @@ async function loadChargingStatus() {
  */
 async function loadRange() {
     const data = await fetchJSON('/api/range');
+    if (data.connected === false) {
+        renderNotConnected('range-info', data);
+        return;
+    }
     const html = `
         <div class="range-info">
             <div>
                 <div class="stat-label">Estimated Range</div>
-                <div class="range-value">${data.estimated_range_km} km</div>
+                <div class="range-value">${data.estimated_range_km ?? 'N/A'} km</div>
             </div>
             <div>
                 <div class="stat-label">Range @ 80%</div>
-                <div class="stat-value">${data.range_at_80_percent_km} km</div>
+                <div class="stat-value">${data.range_at_80_percent_km ?? 'N/A'} km</div>
             </div>
         </div>
     `;
@@ -125,8 +197,50 @@ async function loadRange() {
 /**
  * Fetch and render battery health
  */
+async function loadDoorStatus() {
+    const data = await fetchJSON('/api/doors/status');
+    if (data.connected === false) {
+        renderNotConnected('door-status', data);
+        return;
+    }
+    const names = {
+        driver: 'Driver door',
+        passenger: 'Passenger door',
+        rear_left: 'Rear left door',
+        rear_right: 'Rear right door'
+    };
+    const open = [];
+    for (const [key, door] of Object.entries(data.doors || {})) {
+        if (door.closed === false) open.push(names[key]);
+    }
+    for (const [key, closed] of Object.entries(data.windows || {})) {
+        if (closed === false) open.push(names[key].replace('door', 'window'));
+    }
+    if (data.trunk && data.trunk.closed === false) open.push('Trunk');
+    if (data.hood && data.hood.closed === false) open.push('Hood');
+
+    const lockLabel = data.locked === true ? '🔒 Locked' : data.locked === false ? '🔓 Unlocked' : 'Unknown';
+    const html = `
+        <div style="display: grid; grid-template-columns: repeat(2, 1fr); gap: 15px;">
+            <div class="stat">
+                <div class="stat-label">Lock State</div>
+                <div class="stat-value">${lockLabel}</div>
+            </div>
+            <div class="stat">
+                <div class="stat-label">Open</div>
+                <div class="stat-value">${open.length ? open.join(', ') : (data.all_closed ? 'All closed' : '–')}</div>
+            </div>
+        </div>
+    `;
+    document.getElementById('door-status').innerHTML = html;
+}
+
 async function loadBatteryHealth() {
     const data = await fetchJSON('/api/battery/health');
+    if (data.connected === false) {
+        renderNotConnected('battery-health', data);
+        return;
+    }
     const html = `
         <div>
             <div class="health-status" style="text-align: center; margin: 20px 0;">
@@ -143,8 +257,12 @@ async function loadBatteryHealth() {
                     <div class="stat-value">${data.cycles}</div>
                 </div>
                 <div class="stat">
-                    <div class="stat-label">Temperature</div>
-                    <div class="stat-value">${data.temperature_celsius.toFixed(1)}°C</div>
+                    <div class="stat-label">Confidence</div>
+                    <div class="stat-value">${Math.round(data.confidence * 100)}%</div>
+                </div>
+                <div class="stat">
+                    <div class="stat-label">Usable Capacity</div>
+                    <div class="stat-value">${data.estimated_capacity_kwh != null ? data.estimated_capacity_kwh.toFixed(1) + ' kWh' : '–'}</div>
                 </div>
             </div>
         </div>
@@ -157,12 +275,16 @@ async function loadBatteryHealth() {
  */
 async function loadChargingHistory() {
     const data = await fetchJSON('/api/charging/history');
-    const items = data.map(session => `
+    if (data.connected === false) {
+        renderNotConnected('charging-history', data);
+        return;
+    }
+    const items = data.length ? data.map(session => `
         <div class="history-item">
-            <div><strong>${session.date}</strong></div>
-            <div>${session.start_level}% → ${session.end_level}% | ${formatTimeRemaining(session.duration_minutes)} | ${session.energy_kwh} kWh</div>
+            <div><strong>${session.date}</strong>${session.in_progress ? ' (charging)' : ''}</div>
+            <div>${session.start_level}% → ${session.end_level}% | ${formatTimeRemaining(session.duration_minutes)} | ${session.energy_kwh === null ? '–' : session.energy_kwh} kWh</div>
         </div>
-    `).join('');
+    `).join('') : '<div class="history-item">No charging sessions recorded yet</div>';
 
     const html = `
         <div class="history-list">
@@ -182,11 +304,22 @@ async function loadChargingHistory() {
  */
 async function loadActiveAlerts() {
     const data = await fetchJSON('/api/alerts/active');
+    if (data.connected === false) {
+        renderNotConnected('active-alerts', data);
+        return;
+    }
+    if (data.length === 0) {
+        document.getElementById('active-alerts').innerHTML = '<p>No active alerts</p>';
+        return;
+    }
     const items = data.map(alert => `
-        <div class="alert-item ${alert.type}">
+        <div class="alert-item ${alert.type} ${alert.state}">
             <div><strong>${alert.title}</strong></div>
             <div>${alert.message}</div>
             <div style="font-size: 12px; color: var(--text-secondary); margin-top: 5px;">${alert.time_ago}</div>
+            ${alert.state === 'active'
+                ? `<button class="btn" onclick="acknowledgeAlert('${alert.id}')">Acknowledge</button>`
+                : ''}
         </div>
     `).join('');
 
@@ -197,16 +330,33 @@ async function loadActiveAlerts() {
         <style>
             .alerts-list { display: flex; flex-direction: column; gap: 10px; }
             .alert-item.success { border-left-color: var(--success-color); }
+            .alert-item.acknowledged { opacity: 0.6; }
         </style>
     `;
     document.getElementById('active-alerts').innerHTML = html;
 }
 
+/**
+ * Acknowledge an active alert
+ */
+async function acknowledgeAlert(id) {
+    try {
+        await fetchJSON(`/api/alerts/${encodeURIComponent(id)}/ack`, { method: 'POST' });
+        loadActiveAlerts();
+    } catch (error) {
+        // fetchJSON already reported the failure
+    }
+}
+
 /**
  * Fetch and render weekly analytics
  */
 async function loadWeeklyAnalytics() {
     const data = await fetchJSON('/api/analytics/weekly');
+    if (data.connected === false) {
+        renderNotConnected('weekly-analytics', data);
+        return;
+    }
     const html = `
         <div>
             <h3>Weekly Charging Stats</h3>
@@ -216,11 +366,11 @@ async function loadWeeklyAnalytics() {
                     <div class="stat-label">Charging Sessions</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">${data.total_energy_kwh} kWh</div>
+                    <div class="stat-value">${data.total_energy_kwh === null ? '–' : data.total_energy_kwh} kWh</div>
                     <div class="stat-label">Total Energy</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">${formatTimeRemaining(data.avg_duration_minutes)}</div>
+                    <div class="stat-value">${data.avg_duration_minutes === null ? '–' : formatTimeRemaining(data.avg_duration_minutes)}</div>
                     <div class="stat-label">Avg. Duration</div>
                 </div>
             </div>
@@ -234,20 +384,26 @@ async function loadWeeklyAnalytics() {
  */
 async function loadCostAnalytics() {
     const data = await fetchJSON('/api/analytics/costs');
+    if (data.connected === false) {
+        renderNotConnected('cost-analytics', data);
+        return;
+    }
+    const money = (value, digits) =>
+        value === null ? '–' : `${value.toFixed(digits)} ${data.currency}`;
     const html = `
         <div>
             <h3>Cost Analysis</h3>
             <div style="margin: 20px 0;">
                 <div class="stat">
-                    <div class="stat-value">€${data.this_week_cost.toFixed(2)}</div>
+                    <div class="stat-value">${money(data.this_week_cost, 2)}</div>
                     <div class="stat-label">This Week</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">€${data.per_session_avg.toFixed(2)}</div>
+                    <div class="stat-value">${money(data.per_session_avg, 2)}</div>
                     <div class="stat-label">Per Session Avg.</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">€${data.avg_price_per_kwh.toFixed(3)}/kWh</div>
+                    <div class="stat-value">${money(data.avg_price_per_kwh, 3)}/kWh</div>
                     <div class="stat-label">Avg. Price</div>
                 </div>
             </div>
@@ -261,20 +417,25 @@ async function loadCostAnalytics() {
  */
 async function loadEfficiencyAnalytics() {
     const data = await fetchJSON('/api/analytics/efficiency');
+    if (data.connected === false) {
+        renderNotConnected('efficiency-analytics', data);
+        return;
+    }
+    const known = value => value !== null;
     const html = `
         <div>
             <h3>Efficiency Metrics</h3>
             <div style="margin: 20px 0;">
                 <div class="stat">
-                    <div class="stat-value">${data.charging_efficiency_percent}%</div>
-                    <div class="stat-label">Charging Efficiency</div>
+                    <div class="stat-value">${known(data.avg_consumption_kwh_per_100km) ? data.avg_consumption_kwh_per_100km : '–'} kWh/100km</div>
+                    <div class="stat-label">Avg. Consumption (30 days)</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">${data.avg_consumption_kwh_per_100km} kWh/100km</div>
-                    <div class="stat-label">Avg. Consumption</div>
+                    <div class="stat-value">${known(data.total_distance_km) ? data.total_distance_km : '–'} km</div>
+                    <div class="stat-label">Distance (${data.trip_count} trips)</div>
                 </div>
                 <div class="stat" style="margin-top: 15px;">
-                    <div class="stat-value">${data.battery_health_percent}%</div>
+                    <div class="stat-value">${known(data.battery_health_percent) ? data.battery_health_percent + '%' : '–'}</div>
                     <div class="stat-label">Battery Health</div>
                 </div>
             </div>
@@ -287,17 +448,38 @@ async function loadEfficiencyAnalytics() {
 // Action Handlers
 // ============================================================================
 
+const COMMAND_POLL_INTERVAL_MS = 5000;
+
 /**
- * Handle charging control actions
+ * Handle vehicle control actions
+ *
+ * Remote commands are sent with a fresh Idempotency-Key and answer with a
+ * command record, which is polled until the vehicle confirms or the
+ * command fails.
  */
-async function handleAction(action, endpoint) {
+async function handleAction(action, endpoint, stepUp) {
+    const token = localStorage.getItem(ACCESS_TOKEN_KEY);
+    const headers = {
+        'Content-Type': 'application/json',
+        'Idempotency-Key': crypto.randomUUID(),
+        ...(token ? { 'Authorization': `Bearer ${token}` } : {})
+    };
+    // Sensitive commands (unlock) are confirmed with the command PIN
+    if (stepUp === 'pin') {
+        const pin = prompt(`Enter your command PIN to ${action}`);
+        if (!pin) return;
+        headers['X-Command-PIN'] = pin;
+    }
     try {
-        const result = await fetchJSON(endpoint, { method: 'POST' });
-        if (result.success) {
-            showNotification(result.message, 'success');
-            // Refresh relevant data
-            if (action.includes('charging')) {
-                await loadChargingStatus();
+        const response = await fetch(endpoint, { method: 'POST', headers });
+        const result = await response.json().catch(() => ({}));
+
+        if (result.id && result.status_url) {
+            if (result.final) {
+                finishCommand(action, result);
+            } else {
+                showNotification(`Sending ${action} to vehicle...`);
+                setTimeout(() => pollCommand(action, result.id), COMMAND_POLL_INTERVAL_MS);
             }
         } else {
             showNotification(result.message || 'Action failed', 'error');
@@ -307,6 +489,35 @@ async function handleAction(action, endpoint) {
     }
 }
 
+/**
+ * Poll a remote command until it reaches a final state
+ */
+async function pollCommand(action, id) {
+    try {
+        const command = await fetchJSON(`/api/commands/${encodeURIComponent(id)}`);
+        if (command.final) {
+            finishCommand(action, command);
+        } else {
+            setTimeout(() => pollCommand(action, id), COMMAND_POLL_INTERVAL_MS);
+        }
+    } catch (error) {
+        showNotification(`Could not check ${action}`, 'error');
+    }
+}
+
+function finishCommand(action, command) {
+    if (command.state === 'succeeded') {
+        showNotification(command.message || `${action} done`, 'success');
+        if (action.includes('charging')) {
+            loadChargingStatus();
+        } else if (action.includes('lock')) {
+            loadDoorStatus();
+        }
+    } else {
+        showNotification(command.message || `${action} failed`, 'error');
+    }
+}
+
 // ============================================================================
 // UI Functions
 // ============================================================================
@@ -441,6 +652,7 @@ document.addEventListener('DOMContentLoaded', () => {
     loadChargingStatus();
     loadRange();
     loadBatteryHealth();
+    loadDoorStatus();
     loadChargingHistory();
 
     // Start auto-refresh
@@ -449,41 +661,163 @@ document.addEventListener('DOMContentLoaded', () => {
     // Set up action button handlers
     document.querySelectorAll('[data-action]').forEach(button => {
         button.addEventListener('click', async (e) => {
-            const action = e.target.dataset.action;
-            const endpoint = e.target.dataset.endpoint;
-            await handleAction(action, endpoint);
+            const { action, endpoint, stepUp } = e.currentTarget.dataset;
+            await handleAction(action, endpoint, stepUp);
         });
     });
 
-    // Set up form submission handlers
+    // Settings and alert forms are loaded from and saved to the gateway
     document.querySelectorAll('form[data-api]').forEach(form => {
         form.addEventListener('submit', async (e) => {
             e.preventDefault();
-            const endpoint = form.dataset.api;
-            const formData = new FormData(form);
-            const data = Object.fromEntries(formData);
-
-            try {
-                const result = await fetchJSON(endpoint, {
-                    method: 'POST',
-                    body: JSON.stringify(data)
-                });
-
-                if (result.success) {
-                    showNotification(result.message || 'Settings saved successfully!');
-                } else {
-                    showNotification(result.message || 'Failed to save settings', 'error');
-                }
-            } catch (error) {
-                showNotification('Failed to save settings', 'error');
-            }
+            await saveForm(form);
         });
     });
+    loadForms();
 
     // Initialize UI enhancements
     initializeUI();
 });
 
+/**
+ * Convert a form into a typed JSON document
+ */
+function formToJSON(form) {
+    const data = {};
+    for (const input of form.elements) {
+        if (!input.name || input.name === 'clear_abrp_token') continue;
+        if (input.type === 'checkbox') {
+            data[input.name] = input.checked;
+        } else if (input.type === 'number' || input.type === 'range') {
+            data[input.name] = input.value === '' ? null : Number(input.value);
+        } else if (input.name === 'version') {
+            data.version = Number(input.value);
+        } else {
+            data[input.name] = input.value;
+        }
+    }
+
+    // The stored ABRP token is never sent back, so an empty field keeps it
+    if ('abrp_token' in data) {
+        if (form.elements.clear_abrp_token && form.elements.clear_abrp_token.checked) {
+            data.abrp_token = '';
+        } else if (data.abrp_token === '') {
+            delete data.abrp_token;
+        }
+    }
+
+    // The current PIN is only needed when replacing an existing one
+    if (data.current_pin === '') {
+        delete data.current_pin;
+    }
+    return data;
+}
+
+/**
+ * Fill a form from a JSON document
+ */
+function fillForm(form, settings) {
+    for (const input of form.elements) {
+        if (!input.name || !(input.name in settings)) continue;
+        const value = settings[input.name];
+        if (input.type === 'checkbox') {
+            input.checked = Boolean(value);
+        } else {
+            input.value = value === null || value === undefined ? '' : value;
+            if (input.type === 'range') {
+                input.dispatchEvent(new Event('input'));
+            }
+        }
+    }
+    if (form.elements.abrp_token) {
+        form.elements.abrp_token.value = '';
+        form.elements.abrp_token.placeholder = settings.abrp_token_configured
+            ? 'Token stored - leave empty to keep it'
+            : 'No token stored';
+    }
+    if (form.elements.clear_abrp_token) {
+        form.elements.clear_abrp_token.checked = false;
+    }
+    // PINs are never shown again once saved
+    if ('pin_set' in settings) {
+        form.reset();
+    }
+}
+
+/**
+ * Mark invalid inputs with the field errors returned by the gateway
+ */
+function showFieldErrors(form, errors) {
+    form.querySelectorAll('.field-error').forEach(el => el.remove());
+    form.querySelectorAll('.field-invalid').forEach(el => el.classList.remove('field-invalid'));
+
+    for (const error of errors || []) {
+        const input = form.elements[error.field];
+        const message = document.createElement('span');
+        message.className = 'field-error';
+        message.textContent = error.message;
+        if (input && input.closest) {
+            input.classList.add('field-invalid');
+            input.closest('label').appendChild(message);
+        } else {
+            message.textContent = `${error.field}: ${error.message}`;
+            form.insertBefore(message, form.querySelector('button[type="submit"]'));
+        }
+    }
+}
+
+/**
+ * Load all forms that have a data-load source
+ */
+async function loadForms() {
+    const forms = document.querySelectorAll('form[data-load]');
+    for (const form of forms) {
+        try {
+            const settings = await fetchJSON(form.dataset.load);
+            if (settings.connected === false) continue;
+            fillForm(form, settings);
+        } catch (error) {
+            // fetchJSON already reported the failure
+        }
+    }
+}
+
+/**
+ * Save a form, handling field errors and version conflicts
+ */
+async function saveForm(form) {
+    const token = localStorage.getItem(ACCESS_TOKEN_KEY);
+    try {
+        const response = await fetch(form.dataset.api, {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json',
+                ...(token ? { 'Authorization': `Bearer ${token}` } : {})
+            },
+            body: JSON.stringify(formToJSON(form))
+        });
+        const result = await response.json().catch(() => ({}));
+
+        if (response.ok) {
+            showFieldErrors(form, []);
+            fillForm(form, result);
+            showNotification('Settings saved successfully!');
+        } else if (response.status === 400 && result.errors) {
+            showFieldErrors(form, result.errors);
+            showNotification('Please correct the highlighted fields', 'error');
+        } else if (response.status === 409) {
+            showNotification('Settings were changed elsewhere and have been reloaded', 'warning');
+            showFieldErrors(form, []);
+            const current = await fetchJSON(form.dataset.load);
+            fillForm(form, current);
+        } else {
+            showNotification(result.message || 'Failed to save settings', 'error');
+        }
+    } catch (error) {
+        showNotification('Failed to save settings', 'error');
+    }
+}
+
 function initializeUI() {
     // Add smooth scroll behavior
     document.documentElement.style.scrollBehavior = 'smooth';